@@ -17,15 +17,18 @@ object YaOkCore {
     @JvmStatic external fun sendVoiceTo(data: ByteArray, recipientId: String): Int
     @JvmStatic external fun startListening(): Int
     @JvmStatic external fun stopListening(): Int
+    @JvmStatic external fun applyRelayList(signedJson: String): Int
     @JvmStatic external fun setPolicy(policyType: Int): Int
     @JvmStatic external fun getStats(): String?
     @JvmStatic external fun getIdentityX25519PublicKeyHex(): String?
+    @JvmStatic external fun createInvite(name: String?): String?
     @JvmStatic external fun addPeer(peerId: String, x25519PublicKeyHex: String): Int
     @JvmStatic external fun getRecentMessages(limit: Int): String?
     @JvmStatic external fun getRecentMessagesFull(limit: Int): String?
     @JvmStatic external fun getPeerList(): String?
     @JvmStatic external fun getAcksForMessage(messageId: String): String?
     @JvmStatic external fun exportPendingPackets(limit: Int): String?
+    @JvmStatic external fun exportPendingFrames(limit: Int, transportType: Int, mtu: Int): String?
     @JvmStatic external fun exportPendingMessages(limit: Int): String?
     @JvmStatic external fun importPackets(packetsBase64: String): Int
     @JvmStatic external fun importPacketsWithPeer(packetsBase64: String, transportType: Int, address: String): Int
@@ -14,6 +14,7 @@ object CoreGateway {
 
     fun getIdentityId(): String? = YaOkCore.getIdentityId()
     fun getIdentityX25519PublicKeyHex(): String? = YaOkCore.getIdentityX25519PublicKeyHex()
+    fun createInvite(name: String?): String? = YaOkCore.createInvite(name)
     fun addPeer(peerId: String, x25519PublicKeyHex: String): Int = YaOkCore.addPeer(peerId, x25519PublicKeyHex)
 
     fun ensureIdentity(): Boolean {
@@ -33,6 +34,12 @@ object CoreGateway {
 
     fun sendVoiceTo(data: ByteArray, recipientId: String): Int = YaOkCore.sendVoiceTo(data, recipientId)
 
+    fun startListening(): Int = YaOkCore.startListening()
+
+    fun stopListening(): Int = YaOkCore.stopListening()
+
+    fun applyRelayList(signedJson: String): Int = YaOkCore.applyRelayList(signedJson)
+
     fun getRecentMessages(limit: Int): String? = YaOkCore.getRecentMessages(limit)
 
     fun getRecentMessagesFull(limit: Int): String? = YaOkCore.getRecentMessagesFull(limit)
@@ -43,6 +50,9 @@ object CoreGateway {
 
     fun exportPendingPackets(limit: Int): String? = YaOkCore.exportPendingPackets(limit)
 
+    fun exportPendingFrames(limit: Int, transportType: Int, mtu: Int = 0): String? =
+        YaOkCore.exportPendingFrames(limit, transportType, mtu)
+
     fun exportPendingMessages(limit: Int): String? = YaOkCore.exportPendingMessages(limit)
 
     fun importPackets(packetsBase64: String): Int = YaOkCore.importPackets(packetsBase64)
@@ -10,12 +10,14 @@ import android.os.Looper
 import app.poruch.ya_ok.core.CoreGateway
 import app.poruch.ya_ok.data.ContactStore
 import org.json.JSONArray
+import java.util.concurrent.Executors
 
 class TransportService : Service() {
     private val handler = Handler(Looper.getMainLooper())
     private lateinit var udpTransport: UdpTransport
     private lateinit var bleTransport: BleTransport
     private lateinit var wifiDirectController: WifiDirectController
+    private val relayExecutor = Executors.newSingleThreadExecutor()
 
     private val syncRunnable = object : Runnable {
         override fun run() {
@@ -31,7 +33,7 @@ class TransportService : Service() {
         startForeground(NotificationHelper.FOREGROUND_ID, NotificationHelper.buildForeground(this))
 
         println("🔵 Creating UdpTransport...")
-        udpTransport = UdpTransport(this) { payload, address ->
+        udpTransport = UdpTransport { payload, address ->
             handleIncoming(payload, TRANSPORT_UDP, address)
         }
         bleTransport = BleTransport(this) { payload, address ->
@@ -43,6 +45,9 @@ class TransportService : Service() {
         udpTransport.start()
         bleTransport.start()
         wifiDirectController.start()
+        // Соединение с relay держит ядро: оно принимает пакеты и отправляет
+        // исходящие через пул relay
+        relayExecutor.execute { CoreGateway.startListening() }
 
         handler.post(syncRunnable)
     }
@@ -52,20 +57,31 @@ class TransportService : Service() {
         udpTransport.stop()
         bleTransport.stop()
         wifiDirectController.stop()
+        relayExecutor.execute { CoreGateway.stopListening() }
+        relayExecutor.shutdown()
         super.onDestroy()
     }
 
     override fun onBind(intent: Intent?): IBinder? = null
 
     private fun syncOutgoing() {
-        val packets = CoreGateway.exportPendingPackets(50).orEmpty()
-        if (packets.isBlank()) return
-
-        println("📤 syncOutgoing: ${packets.length} bytes to send")
-        udpTransport.send(packets)
-        bleTransport.send(packets)
+        // Ядро режет большие пакеты (голос) под MTU транспорта;
+        // каждый кадр уходит отдельным сообщением. UDP - только локальная
+        // сеть, к relay ядро отправляет само
+        val udpFrames = exportFrames(TRANSPORT_UDP)
+        val bleFrames = exportFrames(TRANSPORT_BLE)
+        if (udpFrames.isEmpty() && bleFrames.isEmpty()) return
+
+        println("📤 syncOutgoing: ${udpFrames.size} UDP / ${bleFrames.size} BLE frames to send")
+        udpFrames.forEach(udpTransport::send)
+        bleFrames.forEach(bleTransport::send)
     }
 
+    private fun exportFrames(transportType: Int): List<String> =
+        CoreGateway.exportPendingFrames(50, transportType).orEmpty()
+            .split(',')
+            .filter { it.isNotBlank() }
+
     private fun handleIncoming(payload: String, transportType: Int, address: String) {
         val importedPackets = CoreGateway.importPacketsWithPeer(payload, transportType, address)
         if (importedPackets > 0) {
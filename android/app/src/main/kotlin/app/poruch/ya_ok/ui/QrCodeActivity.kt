@@ -49,7 +49,13 @@ class QrCodeActivity : AppCompatActivity() {
             println("❌ UnsatisfiedLinkError: ${e.message}")
             ""  // Fallback if JNI function not available yet
         }
-        val link = buildQrLink(identityId, x25519Hex)
+        // Подписанное ядром приглашение: id, ключ X25519, имя и подпись
+        val invite = CoreGateway.createInvite(userName)
+        if (invite == null) {
+            finish()
+            return
+        }
+        val link = buildQrLink(invite)
         
         // Show user-friendly info about what's included in QR
         val infoText = buildString {
@@ -72,30 +78,21 @@ class QrCodeActivity : AppCompatActivity() {
         findViewById<ImageView>(R.id.qrCodeImage).setImageBitmap(generateQrCode(link))
         findViewById<TextView>(R.id.closeButton).setOnClickListener { finish() }
         findViewById<TextView>(R.id.shareButton).setOnClickListener {
-            shareQrLink(identityId, x25519Hex)
+            shareQrLink(invite)
         }
     }
 
-    private fun shareQrLink(identityId: String, x25519Hex: String) {
+    private fun shareQrLink(invite: String) {
         val userName = getSharedPreferences("ya_ok_prefs", MODE_PRIVATE)
             .getString("user_name", null)
             ?.trim()
             ?.takeIf { it.isNotBlank() }
         
         // Use HTTPS link for messengers (clickable)
-        val params = buildString {
-            append("id=$identityId")
-            if (x25519Hex.isNotBlank()) {
-                append("&x=$x25519Hex")
-            }
-            if (userName != null) {
-                append("&name=${android.net.Uri.encode(userName)}")
-            }
-        }
-        val httpsLink = "https://i-am-ok-relay.fly.dev/add?$params"
+        val httpsLink = "https://i-am-ok-relay.fly.dev/add?$invite"
         
         // Generate QR code image with branding
-        val qrBitmap = generateShareableQrCode(buildQrLink(identityId, x25519Hex), userName, httpsLink)
+        val qrBitmap = generateShareableQrCode(buildQrLink(invite), userName, httpsLink)
         
         // Save to cache and share
         try {
@@ -193,29 +190,9 @@ class QrCodeActivity : AppCompatActivity() {
         return bitmap
     }
 
-    private fun buildQrLink(identityId: String, x25519Hex: String): String {
-        // Include X25519 public key for initial key exchange (needed for E2E encryption).
-        val userName = getSharedPreferences("ya_ok_prefs", MODE_PRIVATE)
-            .getString("user_name", null)
-            ?.trim()
-            ?.takeIf { it.isNotBlank() }
-        
-        println("🔵 QR Generation:")
-        println("  - identityId: $identityId")
-        println("  - x25519Hex: ${if (x25519Hex.isNotBlank()) "✅ ${x25519Hex.length} chars" else "❌ EMPTY"}")
-        println("  - userName: ${userName ?: "❌ NULL"}")
-        
-        val params = buildString {
-            append("id=$identityId")
-            if (x25519Hex.isNotBlank()) {
-                append("&x=$x25519Hex")
-            }
-            if (userName != null) {
-                append("&name=${android.net.Uri.encode(userName)}")
-            }
-        }
-        val qrUrl = "yaok://add?$params"
-        println("  - Final QR: $qrUrl")
+    private fun buildQrLink(invite: String): String {
+        val qrUrl = "yaok://add?$invite"
+        println("🔵 QR Generation: $qrUrl")
         return qrUrl
     }
 
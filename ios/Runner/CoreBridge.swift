@@ -11,6 +11,7 @@ import Foundation
 @_silgen_name("ya_ok_get_recent_messages") private func ya_ok_get_recent_messages(_ limit: Int32) -> UnsafeMutablePointer<CChar>?
 @_silgen_name("ya_ok_get_recent_messages_full") private func ya_ok_get_recent_messages_full(_ limit: Int32) -> UnsafeMutablePointer<CChar>?
 @_silgen_name("ya_ok_export_pending_packets") private func ya_ok_export_pending_packets(_ limit: Int32) -> UnsafeMutablePointer<CChar>?
+@_silgen_name("ya_ok_export_pending_frames") private func ya_ok_export_pending_frames(_ limit: Int32, _ transport: Int32, _ mtu: Int32) -> UnsafeMutablePointer<CChar>?
 @_silgen_name("ya_ok_export_pending_messages") private func ya_ok_export_pending_messages(_ limit: Int32) -> UnsafeMutablePointer<CChar>?
 @_silgen_name("ya_ok_import_packets") private func ya_ok_import_packets(_ json: UnsafePointer<CChar>) -> Int32
 @_silgen_name("ya_ok_import_packets_with_peer") private func ya_ok_import_packets_with_peer(_ json: UnsafePointer<CChar>, _ transport: Int32, _ address: UnsafePointer<CChar>) -> Int32
@@ -18,7 +19,11 @@ import Foundation
 @_silgen_name("ya_ok_mark_delivered") private func ya_ok_mark_delivered(_ messageId: UnsafePointer<CChar>) -> Int32
 @_silgen_name("ya_ok_wipe_local_data") private func ya_ok_wipe_local_data() -> Int32
 @_silgen_name("ya_ok_get_identity_x25519_public_key_hex") private func ya_ok_get_identity_x25519_public_key_hex() -> UnsafeMutablePointer<CChar>?
+@_silgen_name("ya_ok_create_invite") private func ya_ok_create_invite(_ name: UnsafePointer<CChar>?) -> UnsafeMutablePointer<CChar>?
 @_silgen_name("ya_ok_add_peer") private func ya_ok_add_peer(_ peerId: UnsafePointer<CChar>, _ x25519Hex: UnsafePointer<CChar>) -> Int32
+@_silgen_name("ya_ok_start_listening") private func ya_ok_start_listening() -> Int32
+@_silgen_name("ya_ok_stop_listening") private func ya_ok_stop_listening() -> Int32
+@_silgen_name("ya_ok_apply_relay_list") private func ya_ok_apply_relay_list(_ signedJson: UnsafePointer<CChar>) -> Int32
 
 final class CoreBridge {
     static let shared = CoreBridge()
@@ -102,6 +107,14 @@ final class CoreBridge {
         return data
     }
 
+    /// Кадры для транспорта (base64): большие пакеты порезаны под MTU
+    func exportPendingFrames(limit: Int = 50, transportType: Int32, mtu: Int32 = 0) -> [String] {
+        guard let ptr = ya_ok_export_pending_frames(Int32(limit), transportType, mtu) else { return [] }
+        let frames = String(cString: ptr)
+        ya_ok_free_string(ptr)
+        return frames.split(separator: ",").map(String.init).filter { !$0.isEmpty }
+    }
+
     func getIdentityX25519PublicKeyHex() -> String? {
         guard let ptr = ya_ok_get_identity_x25519_public_key_hex() else { return nil }
         let value = String(cString: ptr)
@@ -109,6 +122,20 @@ final class CoreBridge {
         return value.isEmpty ? nil : value
     }
 
+    /// Подписанное приглашение: query для `yaok://add?...`
+    func createInvite(name: String?) -> String? {
+        let ptr: UnsafeMutablePointer<CChar>?
+        if let name = name {
+            ptr = name.withCString { ya_ok_create_invite($0) }
+        } else {
+            ptr = ya_ok_create_invite(nil)
+        }
+        guard let ptr = ptr else { return nil }
+        let value = String(cString: ptr)
+        ya_ok_free_string(ptr)
+        return value.isEmpty ? nil : value
+    }
+
     func addPeer(peerId: String, x25519PublicKeyHex: String) -> Int32 {
         return peerId.withCString { peer in
             x25519PublicKeyHex.withCString { x in
@@ -117,6 +144,22 @@ final class CoreBridge {
         }
     }
 
+    /// Соединение с relay: ядро принимает пакеты и отправляет исходящие
+    func startListening() -> Int32 {
+        ya_ok_start_listening()
+    }
+
+    func stopListening() -> Int32 {
+        ya_ok_stop_listening()
+    }
+
+    /// Подписанный список relay; подпись проверяет ядро ключом издателя
+    func applyRelayList(signedJson: String) -> Int32 {
+        return signedJson.withCString { cString in
+            ya_ok_apply_relay_list(cString)
+        }
+    }
+
     func importPackets(packets: String) -> Int32 {
         return packets.withCString { cString in
             ya_ok_import_packets(cString)
@@ -193,8 +193,9 @@ final class FamilyViewController: UIViewController {
     }
 
     @objc private func openMyQr() {
-        guard let identityId = CoreBridge.shared.getIdentityId() else { return }
-        present(QrCodeViewController(identityId: identityId), animated: true)
+        guard let identityId = CoreBridge.shared.getIdentityId(),
+              let invite = CoreBridge.shared.createInvite(name: nil) else { return }
+        present(QrCodeViewController(identityId: identityId, invite: invite), animated: true)
     }
 
     @objc private func sendFeedback() {
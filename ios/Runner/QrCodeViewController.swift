@@ -8,14 +8,10 @@ final class QrCodeViewController: UIViewController {
     private let imageView = UIImageView()
     private let idLabel = UILabel()
 
-    init(identityId: String) {
+    /// `invite` - подписанное ядром приглашение (`CoreBridge.createInvite`)
+    init(identityId: String, invite: String) {
         self.identityId = identityId
-        let x = CoreBridge.shared.getIdentityX25519PublicKeyHex() ?? ""
-        if x.isEmpty {
-            self.link = "yaok://add?id=\(identityId)"
-        } else {
-            self.link = "yaok://add?id=\(identityId)&x=\(x)"
-        }
+        self.link = "yaok://add?\(invite)"
         super.init(nibName: nil, bundle: nil)
         modalPresentationStyle = .fullScreen
     }
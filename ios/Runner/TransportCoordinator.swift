@@ -4,6 +4,7 @@ final class TransportCoordinator {
     static let shared = TransportCoordinator()
     private let peerService = PeerService()
     private let udpService = UdpService()
+    private let relayQueue = DispatchQueue(label: "yaok.relay")
     private var timer: Timer?
 
     private init() {}
@@ -18,6 +19,8 @@ final class TransportCoordinator {
         }
         peerService.start()
         udpService.start()
+        // Соединение с relay держит ядро
+        relayQueue.async { _ = CoreBridge.shared.startListening() }
         scheduleSync()
     }
 
@@ -26,6 +29,7 @@ final class TransportCoordinator {
         timer = nil
         peerService.stop()
         udpService.stop()
+        relayQueue.async { _ = CoreBridge.shared.stopListening() }
     }
 
     private func scheduleSync() {
@@ -35,10 +39,15 @@ final class TransportCoordinator {
     }
 
     private func syncOutgoing() {
-        let packets = CoreBridge.shared.exportPendingPackets(limit: 50)
-        guard let data = packets.data(using: .utf8), !packets.isEmpty else { return }
-        peerService.send(data: data)
-        udpService.send(data: data)
+        // Ядро режет большие пакеты (голос) под MTU транспорта;
+        // каждый кадр уходит отдельным сообщением. UDP - только локальная
+        // сеть, к relay ядро отправляет само
+        for frame in CoreBridge.shared.exportPendingFrames(limit: 50, transportType: 0) {
+            peerService.send(data: Data(frame.utf8))
+        }
+        for frame in CoreBridge.shared.exportPendingFrames(limit: 50, transportType: 2) {
+            udpService.send(data: Data(frame.utf8))
+        }
     }
 
     private func handleIncoming(data: Data, transportType: Int32, address: String) {
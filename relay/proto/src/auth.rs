@@ -0,0 +1,282 @@
+//! Session authentication (Ed25519 challenge-response)
+//!
+//! Clients prove ownership of their node id ([`verify_auth`]) and sign
+//! their recipient tag registrations ([`verify_register`]); federated
+//! relays prove ownership of their relay id to each other
+//! ([`verify_peer_auth`]), each signing its role and both handshake
+//! nonces. Each has a separate signing context.
+//!
+//! Session signatures also cover the channel binding of the link
+//! ([`crate::MessageLink::binding`]): a relay that forwards a client's
+//! challenge from another relay gets a signature that is only valid on the
+//! client's own TLS or datagram session, not on the one it opened itself.
+
+use crate::ProtoError;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+/// Relay nonce length
+pub const NONCE_LEN: usize = 32;
+
+/// TLS exporter label (RFC 5705) for the channel binding of stream links
+pub const TLS_EXPORTER_LABEL: &[u8] = b"EXPORTER-yaok-relay-binding";
+
+/// Length of the exported TLS channel binding
+pub const CHANNEL_BINDING_LEN: usize = 32;
+
+/// Domain separation for the signed payload
+const AUTH_CONTEXT: &[u8] = b"yaok-relay-auth-v2";
+
+/// Domain separation for relay-to-relay authentication
+const PEER_AUTH_CONTEXT: &[u8] = b"yaok-relay-peer-v2";
+
+/// Domain separation for recipient tag registration
+const REGISTER_CONTEXT: &[u8] = b"yaok-relay-register-v1";
+
+/// Bytes the client signs to prove ownership of `node_id` on the link
+/// with channel binding `binding`
+pub fn auth_payload(nonce: &[u8], binding: &[u8], node_id: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(AUTH_CONTEXT.len() + nonce.len() + 1 + binding.len() + node_id.len());
+    payload.extend_from_slice(AUTH_CONTEXT);
+    payload.extend_from_slice(nonce);
+    payload.push(binding.len() as u8);
+    payload.extend_from_slice(binding);
+    payload.extend_from_slice(node_id.as_bytes());
+    payload
+}
+
+/// Node id for an Ed25519 public key (same as the core `Identity::id`)
+pub fn node_id_for_key(public_key: &[u8]) -> String {
+    hex::encode(public_key)
+}
+
+/// Side of a federation handshake. It is part of the signed bytes, so a
+/// relay's signature as acceptor is never valid as a dialer's `Auth`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerRole {
+    Dialer,
+    Acceptor,
+}
+
+/// Session state both relays sign: the nonces of both sides and the
+/// channel binding of the link ([`crate::MessageLink::binding`])
+#[derive(Clone, Copy, Debug)]
+pub struct PeerTranscript<'a> {
+    pub dialer_nonce: &'a [u8],
+    pub acceptor_nonce: &'a [u8],
+    pub binding: &'a [u8],
+}
+
+/// Bytes relay `relay_id`, in `role`, signs to prove its identity to relay
+/// `peer_id`. Both ids are included so a signature cannot be replayed to a
+/// third relay, both nonces so it is only valid in this handshake.
+pub fn peer_auth_payload(role: PeerRole, transcript: &PeerTranscript<'_>, relay_id: &str, peer_id: &str) -> Vec<u8> {
+    let PeerTranscript { dialer_nonce, acceptor_nonce, binding } = *transcript;
+    let mut payload = Vec::with_capacity(
+        PEER_AUTH_CONTEXT.len() + 2 + dialer_nonce.len() + acceptor_nonce.len() + binding.len() + relay_id.len() + 1 + peer_id.len(),
+    );
+    payload.extend_from_slice(PEER_AUTH_CONTEXT);
+    payload.push(match role {
+        PeerRole::Dialer => 0,
+        PeerRole::Acceptor => 1,
+    });
+    payload.extend_from_slice(dialer_nonce);
+    payload.extend_from_slice(acceptor_nonce);
+    payload.push(binding.len() as u8);
+    payload.extend_from_slice(binding);
+    payload.extend_from_slice(relay_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(peer_id.as_bytes());
+    payload
+}
+
+/// Bytes `node_id` signs to register `tags` (concatenated, see
+/// [`crate::tags::encode_tags`]) until unix time `expires_at`
+pub fn register_payload(node_id: &str, tags: &[u8], expires_at: u64) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(REGISTER_CONTEXT.len() + node_id.len() + 1 + 8 + tags.len());
+    payload.extend_from_slice(REGISTER_CONTEXT);
+    payload.extend_from_slice(node_id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(&expires_at.to_be_bytes());
+    payload.extend_from_slice(tags);
+    payload
+}
+
+/// Check that `public_key` belongs to `node_id` and signed the challenge
+/// on the link with channel binding `binding`
+pub fn verify_auth(
+    node_id: &str,
+    public_key: &[u8],
+    nonce: &[u8],
+    binding: &[u8],
+    signature: &[u8],
+) -> Result<(), ProtoError> {
+    if nonce.len() != NONCE_LEN || binding.len() > u8::MAX as usize {
+        return Err(ProtoError::AuthFailed);
+    }
+    verify_signed(node_id, public_key, signature, &auth_payload(nonce, binding, node_id))
+}
+
+/// Check that the key behind `node_id` (its hex encoding) signed the
+/// registration of `tags`
+pub fn verify_register(node_id: &str, tags: &[u8], expires_at: u64, signature: &[u8]) -> Result<(), ProtoError> {
+    let public_key = hex::decode(node_id).map_err(|_| ProtoError::AuthFailed)?;
+    verify_signed(node_id, &public_key, signature, &register_payload(node_id, tags, expires_at))
+}
+
+/// Check that `public_key` belongs to relay `relay_id` and signed
+/// `transcript` in `role` for the link to `peer_id`. Relay links must have
+/// a channel binding: without one the signatures do not protect the frames
+/// that follow the handshake.
+pub fn verify_peer_auth(
+    role: PeerRole,
+    transcript: &PeerTranscript<'_>,
+    relay_id: &str,
+    peer_id: &str,
+    public_key: &[u8],
+    signature: &[u8],
+) -> Result<(), ProtoError> {
+    let valid = transcript.dialer_nonce.len() == NONCE_LEN
+        && transcript.acceptor_nonce.len() == NONCE_LEN
+        && !transcript.binding.is_empty()
+        && transcript.binding.len() <= u8::MAX as usize;
+    if !valid {
+        return Err(ProtoError::AuthFailed);
+    }
+    verify_signed(relay_id, public_key, signature, &peer_auth_payload(role, transcript, relay_id, peer_id))
+}
+
+fn verify_signed(id: &str, public_key: &[u8], signature: &[u8], payload: &[u8]) -> Result<(), ProtoError> {
+    if id != node_id_for_key(public_key) {
+        return Err(ProtoError::AuthFailed);
+    }
+
+    let key_bytes: [u8; 32] = public_key.try_into().map_err(|_| ProtoError::AuthFailed)?;
+    let key = VerifyingKey::from_bytes(&key_bytes).map_err(|_| ProtoError::AuthFailed)?;
+    let signature = Signature::from_slice(signature).map_err(|_| ProtoError::AuthFailed)?;
+
+    key.verify(payload, &signature)
+        .map_err(|_| ProtoError::AuthFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn valid_signature_accepted() {
+        let key = signing_key(1);
+        let public_key = key.verifying_key().to_bytes();
+        let node_id = node_id_for_key(&public_key);
+        let nonce = [9u8; NONCE_LEN];
+
+        let binding = [4u8; CHANNEL_BINDING_LEN];
+
+        let signature = key.sign(&auth_payload(&nonce, &binding, &node_id));
+        assert!(verify_auth(&node_id, &public_key, &nonce, &binding, &signature.to_bytes()).is_ok());
+    }
+
+    #[test]
+    fn foreign_node_id_rejected() {
+        let key = signing_key(1);
+        let public_key = key.verifying_key().to_bytes();
+        let victim = node_id_for_key(&signing_key(2).verifying_key().to_bytes());
+        let nonce = [9u8; NONCE_LEN];
+
+        let signature = key.sign(&auth_payload(&nonce, &[], &victim));
+        assert!(verify_auth(&victim, &public_key, &nonce, &[], &signature.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn signature_bound_to_nonce() {
+        let key = signing_key(3);
+        let public_key = key.verifying_key().to_bytes();
+        let node_id = node_id_for_key(&public_key);
+
+        let signature = key.sign(&auth_payload(&[1u8; NONCE_LEN], &[], &node_id));
+        assert!(verify_auth(&node_id, &public_key, &[2u8; NONCE_LEN], &[], &signature.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn signature_bound_to_channel() {
+        let key = signing_key(3);
+        let public_key = key.verifying_key().to_bytes();
+        let node_id = node_id_for_key(&public_key);
+        let nonce = [1u8; NONCE_LEN];
+
+        // A relay in the middle passes the real relay's nonce on to the
+        // client, but its own link to the real relay has another binding
+        let signature = key.sign(&auth_payload(&nonce, &[5u8; CHANNEL_BINDING_LEN], &node_id)).to_bytes();
+        assert!(verify_auth(&node_id, &public_key, &nonce, &[6u8; CHANNEL_BINDING_LEN], &signature).is_err());
+        assert!(verify_auth(&node_id, &public_key, &nonce, &[], &signature).is_err());
+    }
+
+    #[test]
+    fn peer_signature_bound_to_both_relays() {
+        let key = signing_key(4);
+        let public_key = key.verifying_key().to_bytes();
+        let relay_id = node_id_for_key(&public_key);
+        let peer = node_id_for_key(&signing_key(5).verifying_key().to_bytes());
+        let other = node_id_for_key(&signing_key(6).verifying_key().to_bytes());
+        let transcript =
+            PeerTranscript { dialer_nonce: &[7u8; NONCE_LEN], acceptor_nonce: &[8u8; NONCE_LEN], binding: &[3u8; CHANNEL_BINDING_LEN] };
+
+        let signature = key.sign(&peer_auth_payload(PeerRole::Dialer, &transcript, &relay_id, &peer)).to_bytes();
+        assert!(verify_peer_auth(PeerRole::Dialer, &transcript, &relay_id, &peer, &public_key, &signature).is_ok());
+        assert!(verify_peer_auth(PeerRole::Dialer, &transcript, &relay_id, &other, &public_key, &signature).is_err());
+
+        // A link without a channel binding (plain TCP) is never accepted
+        let unbound = PeerTranscript { binding: &[], ..transcript };
+        let signature = key.sign(&peer_auth_payload(PeerRole::Dialer, &unbound, &relay_id, &peer)).to_bytes();
+        assert!(verify_peer_auth(PeerRole::Dialer, &unbound, &relay_id, &peer, &public_key, &signature).is_err());
+
+        // A client signature is not accepted as a relay signature
+        let client_signature = key.sign(&auth_payload(&[7u8; NONCE_LEN], &[], &relay_id)).to_bytes();
+        assert!(verify_peer_auth(PeerRole::Dialer, &transcript, &relay_id, &peer, &public_key, &client_signature).is_err());
+    }
+
+    #[test]
+    fn reflected_peer_signature_rejected() {
+        let key = signing_key(4);
+        let public_key = key.verifying_key().to_bytes();
+        let relay_id = node_id_for_key(&public_key);
+        let peer = node_id_for_key(&signing_key(5).verifying_key().to_bytes());
+        let transcript =
+            PeerTranscript { dialer_nonce: &[1u8; NONCE_LEN], acceptor_nonce: &[2u8; NONCE_LEN], binding: &[3u8; CHANNEL_BINDING_LEN] };
+
+        // What the relay signs as acceptor, before the dialer proved anything
+        let challenge = key.sign(&peer_auth_payload(PeerRole::Acceptor, &transcript, &relay_id, &peer)).to_bytes();
+        assert!(verify_peer_auth(PeerRole::Acceptor, &transcript, &relay_id, &peer, &public_key, &challenge).is_ok());
+
+        // Sent back as a dialer's `Auth`, in this or a parallel handshake
+        // that reuses the nonces the other way round, it does not verify
+        assert!(verify_peer_auth(PeerRole::Dialer, &transcript, &relay_id, &peer, &public_key, &challenge).is_err());
+        let swapped = PeerTranscript { dialer_nonce: &[2u8; NONCE_LEN], acceptor_nonce: &[1u8; NONCE_LEN], ..transcript };
+        assert!(verify_peer_auth(PeerRole::Dialer, &swapped, &relay_id, &peer, &public_key, &challenge).is_err());
+
+        // Nor in a handshake with another acceptor nonce or another link
+        let other_session = PeerTranscript { acceptor_nonce: &[3u8; NONCE_LEN], ..transcript };
+        assert!(verify_peer_auth(PeerRole::Acceptor, &other_session, &relay_id, &peer, &public_key, &challenge).is_err());
+        let other_link = PeerTranscript { binding: &[9u8; CHANNEL_BINDING_LEN], ..transcript };
+        assert!(verify_peer_auth(PeerRole::Acceptor, &other_link, &relay_id, &peer, &public_key, &challenge).is_err());
+    }
+
+    #[test]
+    fn registration_bound_to_tags_and_expiry() {
+        let key = signing_key(8);
+        let node_id = node_id_for_key(&key.verifying_key().to_bytes());
+        let tags = [1u8; 32];
+
+        let signature = key.sign(&register_payload(&node_id, &tags, 1_000)).to_bytes();
+        assert!(verify_register(&node_id, &tags, 1_000, &signature).is_ok());
+        assert!(verify_register(&node_id, &tags, 2_000, &signature).is_err());
+        assert!(verify_register(&node_id, &[2u8; 32], 1_000, &signature).is_err());
+
+        let other = node_id_for_key(&signing_key(9).verifying_key().to_bytes());
+        assert!(verify_register(&other, &tags, 1_000, &signature).is_err());
+    }
+}
@@ -0,0 +1,913 @@
+//! Encrypted datagram layer for the relay link over UDP
+//!
+//! Handshake is Noise `NX` (`Noise_NX_25519_ChaChaPoly_SHA256`): the client
+//! learns the relay's static X25519 key inside the encrypted response and
+//! compares its SHA-256 fingerprint against the configured pin. A resumed
+//! session uses `Noise_NNpsk0` with the PSK carried in a relay-issued
+//! ticket, so a client that lost its socket (network switch, NAT rebind)
+//! gets a fresh forward-secret session in one round trip without the
+//! static-key exchange or pin check.
+//!
+//! Anti-amplification: handshake initiations must be padded to
+//! [`MIN_INITIATION_LEN`] and carry a stateless cookie bound to the
+//! source address. Without one the relay answers with a small `Retry`
+//! and does no DH work; every reply is smaller than the request.
+//!
+//! ```text
+//! Initiation  [1][ver][cookie_len][cookie][e:32][padding]
+//! Retry       [2][cookie:24]
+//! Response    [3][session_id:8][e:32][enc(s):48][enc(payload)]
+//! Resume      [4][ver][cookie_len][cookie][ticket_len:2][ticket][e:32][tag:16][padding]
+//! Data        [5][session_id:8][counter:8][ciphertext]
+//! Reject      [6]                       (ticket not accepted, do a full handshake)
+//! ```
+//!
+//! The session id chosen by the relay is mixed into the handshake hash, so
+//! it cannot be swapped in transit. Data datagrams carry one CBOR-encoded
+//! [`crate::RelayMessage`] each and are protected against replay with a
+//! sliding window.
+
+use crate::noise::{hash, hkdf2, hmac, open, seal, SymmetricState, TAG_LEN};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::net::SocketAddr;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Datagram layer version carried in handshake initiations
+pub const DATAGRAM_VERSION: u8 = 1;
+
+/// Handshake initiations are padded to at least this size
+pub const MIN_INITIATION_LEN: usize = 1200;
+
+/// Largest datagram either side sends (IPv4 UDP payload limit)
+pub const MAX_DATAGRAM_LEN: usize = 65_507;
+
+/// Largest encoded message that fits in one data datagram
+pub const MAX_DATAGRAM_MESSAGE: usize = MAX_DATAGRAM_LEN - DATA_HEADER_LEN - TAG_LEN;
+
+/// Largest packet carried over a datagram session. Each message travels in
+/// one datagram, so this leaves room for the `Deliver` envelope, the data
+/// header and the tag; relays answer a larger `Send` with `TooLarge`.
+pub const MAX_DATAGRAM_PACKET: usize = 60_000;
+
+/// Cookie: time bucket (8 bytes) + truncated MAC (16 bytes)
+pub const COOKIE_LEN: usize = 24;
+
+/// Cookie time bucket; a cookie is valid for the current and previous bucket
+pub const COOKIE_BUCKET_SECS: u64 = 60;
+
+/// Lifetime of resumption tickets
+pub const TICKET_LIFETIME_SECS: u64 = 24 * 3600;
+
+const TYPE_INITIATION: u8 = 1;
+const TYPE_RETRY: u8 = 2;
+const TYPE_RESPONSE: u8 = 3;
+const TYPE_RESUME: u8 = 4;
+const TYPE_DATA: u8 = 5;
+const TYPE_REJECT: u8 = 6;
+
+const NX_PROTOCOL: &[u8] = b"Noise_NX_25519_ChaChaPoly_SHA256";
+const PSK_PROTOCOL: &[u8] = b"Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+const PROLOGUE: &[u8] = b"yaok-relay-datagram-v1";
+const TICKET_AD: &[u8] = b"yaok-relay-ticket-v1";
+
+const KEY_LEN: usize = 32;
+const DATA_HEADER_LEN: usize = 1 + 8 + 8;
+const TICKET_LEN: usize = 8 + KEY_LEN + 8 + TAG_LEN;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DatagramError {
+    #[error("Malformed datagram")]
+    Malformed,
+
+    #[error("Unsupported datagram version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Handshake initiation shorter than {MIN_INITIATION_LEN} bytes")]
+    TooShort,
+
+    #[error("Decryption failed")]
+    Decrypt,
+
+    #[error("Replayed datagram")]
+    Replay,
+
+    #[error("Weak Diffie-Hellman key")]
+    WeakKey,
+
+    #[error("Relay key pin mismatch: expected {expected}, got {actual}")]
+    PinMismatch { expected: String, actual: String },
+}
+
+/// Kind of a datagram, from its first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatagramKind {
+    Initiation,
+    Retry,
+    Response,
+    Resume,
+    Data,
+    Reject,
+}
+
+pub fn datagram_kind(datagram: &[u8]) -> Option<DatagramKind> {
+    match *datagram.first()? {
+        TYPE_INITIATION => Some(DatagramKind::Initiation),
+        TYPE_RETRY => Some(DatagramKind::Retry),
+        TYPE_RESPONSE => Some(DatagramKind::Response),
+        TYPE_RESUME => Some(DatagramKind::Resume),
+        TYPE_DATA => Some(DatagramKind::Data),
+        TYPE_REJECT => Some(DatagramKind::Reject),
+        _ => None,
+    }
+}
+
+/// Session id of a `Data` datagram
+pub fn data_session_id(datagram: &[u8]) -> Option<u64> {
+    if datagram_kind(datagram)? != DatagramKind::Data || datagram.len() < DATA_HEADER_LEN {
+        return None;
+    }
+    Some(u64::from_be_bytes(datagram[1..9].try_into().ok()?))
+}
+
+/// SHA-256 fingerprint of a static key, formatted like certificate pins
+/// ("A1:B2:...")
+pub fn key_fingerprint(public_key: &[u8; KEY_LEN]) -> String {
+    hash(&[public_key])
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Compare fingerprints ignoring case and separators
+pub fn fingerprint_matches(pin: &str, fingerprint: &str) -> bool {
+    let normalize = |s: &str| -> String {
+        s.chars()
+            .filter(|c| c.is_ascii_hexdigit())
+            .map(|c| c.to_ascii_uppercase())
+            .collect()
+    };
+    normalize(pin) == normalize(fingerprint)
+}
+
+/// Relay long-term X25519 key
+pub struct StaticKeypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl StaticKeypair {
+    pub fn generate() -> Self {
+        Self::from_secret_bytes(StaticSecret::random_from_rng(OsRng).to_bytes())
+    }
+
+    pub fn from_secret_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        let secret = StaticSecret::from(bytes);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn secret_bytes(&self) -> [u8; KEY_LEN] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        key_fingerprint(&self.public_key())
+    }
+}
+
+/// Ticket for resuming a session with the relay that issued it
+#[derive(Clone)]
+pub struct ResumptionTicket {
+    /// Opaque to the client
+    pub ticket: Vec<u8>,
+    /// Unix time after which the relay refuses the ticket
+    pub expires_at: u64,
+    /// Fingerprint of the relay key seen in the original handshake
+    pub relay_fingerprint: String,
+    psk: [u8; KEY_LEN],
+}
+
+impl ResumptionTicket {
+    pub fn is_expired(&self, now_secs: u64) -> bool {
+        now_secs >= self.expires_at
+    }
+}
+
+/// Replay protection for received counters (64-datagram window)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ReplayWindow {
+    highest: Option<u64>,
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub(crate) fn is_fresh(&self, counter: u64) -> bool {
+        match self.highest {
+            None => true,
+            Some(highest) if counter > highest => true,
+            Some(highest) => {
+                let offset = highest - counter;
+                offset < 64 && self.bitmap & (1 << offset) == 0
+            }
+        }
+    }
+
+    pub(crate) fn mark(&mut self, counter: u64) {
+        match self.highest {
+            Some(highest) if counter <= highest => self.bitmap |= 1 << (highest - counter),
+            previous => {
+                let shift = previous.map_or(64, |highest| counter - highest);
+                self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+                self.bitmap |= 1;
+                self.highest = Some(counter);
+            }
+        }
+    }
+}
+
+/// Sending half of an established session
+pub struct DatagramSender {
+    session_id: u64,
+    key: [u8; KEY_LEN],
+    counter: u64,
+}
+
+impl DatagramSender {
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Encrypt one payload into a `Data` datagram
+    pub fn seal(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(DATA_HEADER_LEN + plaintext.len() + TAG_LEN);
+        datagram.push(TYPE_DATA);
+        datagram.extend_from_slice(&self.session_id.to_be_bytes());
+        datagram.extend_from_slice(&self.counter.to_be_bytes());
+        let ciphertext = seal(&self.key, self.counter, &datagram, plaintext);
+        datagram.extend_from_slice(&ciphertext);
+        self.counter += 1;
+        datagram
+    }
+}
+
+/// Receiving half of an established session
+pub struct DatagramReceiver {
+    session_id: u64,
+    key: [u8; KEY_LEN],
+    window: ReplayWindow,
+}
+
+impl DatagramReceiver {
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Decrypt a `Data` datagram. The replay window only advances for
+    /// datagrams that authenticate.
+    pub fn open(&mut self, datagram: &[u8]) -> Result<Vec<u8>, DatagramError> {
+        if data_session_id(datagram) != Some(self.session_id) {
+            return Err(DatagramError::Malformed);
+        }
+        let (header, ciphertext) = datagram.split_at(DATA_HEADER_LEN);
+        let counter = u64::from_be_bytes(header[9..17].try_into().map_err(|_| DatagramError::Malformed)?);
+
+        if !self.window.is_fresh(counter) {
+            return Err(DatagramError::Replay);
+        }
+        let plaintext = open(&self.key, counter, header, ciphertext).ok_or(DatagramError::Decrypt)?;
+        self.window.mark(counter);
+        Ok(plaintext)
+    }
+}
+
+/// Established session; split it to send and receive from separate tasks
+pub struct DatagramSession {
+    pub sender: DatagramSender,
+    pub receiver: DatagramReceiver,
+    /// Noise handshake hash, the channel binding for session authentication
+    /// (see [`crate::auth::auth_payload`])
+    pub handshake_hash: [u8; KEY_LEN],
+}
+
+impl DatagramSession {
+    fn new(session_id: u64, send_key: [u8; KEY_LEN], recv_key: [u8; KEY_LEN], handshake_hash: [u8; KEY_LEN]) -> Self {
+        Self {
+            sender: DatagramSender { session_id, key: send_key, counter: 0 },
+            receiver: DatagramReceiver { session_id, key: recv_key, window: ReplayWindow::default() },
+            handshake_hash,
+        }
+    }
+
+    pub fn session_id(&self) -> u64 {
+        self.sender.session_id
+    }
+}
+
+/// What the client learned from the relay's reply
+pub enum ClientEvent {
+    /// Resend the initiation with this cookie
+    Retry(Vec<u8>),
+    /// Relay refused the resumption ticket
+    Rejected,
+    Established {
+        session: Box<DatagramSession>,
+        /// Ticket for the next resumption, if the relay issued one
+        ticket: Option<ResumptionTicket>,
+    },
+}
+
+enum ClientMode {
+    Full { pin: Option<String> },
+    Resume { relay_fingerprint: String },
+}
+
+/// Client side of the handshake
+pub struct ClientHandshake {
+    state: SymmetricState,
+    ephemeral: StaticSecret,
+    mode: ClientMode,
+    /// Handshake message after the cookie
+    body: Vec<u8>,
+}
+
+impl ClientHandshake {
+    /// Full `NX` handshake; with `pin` the relay key fingerprint must match
+    pub fn full(pin: Option<String>) -> Self {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let e_public = PublicKey::from(&ephemeral).to_bytes();
+
+        let mut state = SymmetricState::new(NX_PROTOCOL, PROLOGUE);
+        state.mix_hash(&e_public);
+        state.encrypt_and_hash(&[]);
+
+        Self {
+            state,
+            ephemeral,
+            mode: ClientMode::Full { pin },
+            body: e_public.to_vec(),
+        }
+    }
+
+    /// `NNpsk0` resumption with a ticket from an earlier session
+    pub fn resume(ticket: &ResumptionTicket) -> Self {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let e_public = PublicKey::from(&ephemeral).to_bytes();
+
+        let mut state = SymmetricState::new(PSK_PROTOCOL, PROLOGUE);
+        state.mix_key_and_hash(&ticket.psk);
+        state.mix_hash(&e_public);
+        state.mix_key(&e_public);
+        let tag = state.encrypt_and_hash(&[]);
+
+        let mut body = Vec::with_capacity(2 + ticket.ticket.len() + KEY_LEN + TAG_LEN);
+        body.extend_from_slice(&(ticket.ticket.len() as u16).to_be_bytes());
+        body.extend_from_slice(&ticket.ticket);
+        body.extend_from_slice(&e_public);
+        body.extend_from_slice(&tag);
+
+        Self {
+            state,
+            ephemeral,
+            mode: ClientMode::Resume { relay_fingerprint: ticket.relay_fingerprint.clone() },
+            body,
+        }
+    }
+
+    pub fn is_resumption(&self) -> bool {
+        matches!(self.mode, ClientMode::Resume { .. })
+    }
+
+    /// Initiation datagram, padded to [`MIN_INITIATION_LEN`]
+    pub fn initiation(&self, cookie: Option<&[u8]>) -> Vec<u8> {
+        let cookie = cookie.unwrap_or_default();
+        let kind = if self.is_resumption() { TYPE_RESUME } else { TYPE_INITIATION };
+
+        let mut datagram = Vec::with_capacity(MIN_INITIATION_LEN);
+        datagram.extend_from_slice(&[kind, DATAGRAM_VERSION, cookie.len() as u8]);
+        datagram.extend_from_slice(cookie);
+        datagram.extend_from_slice(&self.body);
+        if datagram.len() < MIN_INITIATION_LEN {
+            datagram.resize(MIN_INITIATION_LEN, 0);
+        }
+        datagram
+    }
+
+    /// Process a reply; spoofed or corrupted replies leave the handshake
+    /// untouched so the real one can still be accepted
+    pub fn read_reply(&self, datagram: &[u8], now_secs: u64) -> Result<ClientEvent, DatagramError> {
+        match datagram_kind(datagram) {
+            Some(DatagramKind::Retry) => {
+                let cookie = datagram.get(1..1 + COOKIE_LEN).ok_or(DatagramError::Malformed)?;
+                Ok(ClientEvent::Retry(cookie.to_vec()))
+            }
+            Some(DatagramKind::Reject) if self.is_resumption() => Ok(ClientEvent::Rejected),
+            Some(DatagramKind::Response) => self.read_response(&datagram[1..], now_secs),
+            _ => Err(DatagramError::Malformed),
+        }
+    }
+
+    fn read_response(&self, body: &[u8], now_secs: u64) -> Result<ClientEvent, DatagramError> {
+        let mut reader = ByteReader::new(body);
+        let session_id_bytes = reader.take(8)?;
+        let session_id = u64::from_be_bytes(session_id_bytes.try_into().map_err(|_| DatagramError::Malformed)?);
+        let re = public_key(reader.take(KEY_LEN)?)?;
+
+        let mut state = self.state.clone();
+        state.mix_hash(session_id_bytes);
+        state.mix_hash(re.as_bytes());
+        if self.is_resumption() {
+            state.mix_key(re.as_bytes());
+        }
+        state.mix_key(&dh(&self.ephemeral, &re)?);
+
+        let relay_fingerprint = match &self.mode {
+            ClientMode::Full { pin } => {
+                let rs_bytes = state
+                    .decrypt_and_hash(reader.take(KEY_LEN + TAG_LEN)?)
+                    .ok_or(DatagramError::Decrypt)?;
+                let rs = public_key(&rs_bytes)?;
+                state.mix_key(&dh(&self.ephemeral, &rs)?);
+
+                let fingerprint = key_fingerprint(rs.as_bytes());
+                if let Some(pin) = pin {
+                    if !fingerprint_matches(pin, &fingerprint) {
+                        return Err(DatagramError::PinMismatch { expected: pin.clone(), actual: fingerprint });
+                    }
+                }
+                fingerprint
+            }
+            ClientMode::Resume { relay_fingerprint } => relay_fingerprint.clone(),
+        };
+
+        let payload = state.decrypt_and_hash(reader.rest()).ok_or(DatagramError::Decrypt)?;
+        let ticket = parse_ticket_payload(&payload, state.resumption_secret(), relay_fingerprint, now_secs);
+
+        let (initiator_key, responder_key) = state.split();
+        Ok(ClientEvent::Established {
+            session: Box::new(DatagramSession::new(session_id, initiator_key, responder_key, state.handshake_hash())),
+            ticket,
+        })
+    }
+}
+
+/// What the relay does with a handshake datagram
+pub enum ResponderEvent {
+    /// Stateless reply (`Retry` or `Reject`); no session was created
+    Reply(Vec<u8>),
+    Established {
+        session: DatagramSession,
+        reply: Vec<u8>,
+        resumed: bool,
+    },
+}
+
+/// Relay side of the handshake. Holds no per-client state.
+pub struct Responder {
+    key: StaticKeypair,
+    cookie_secret: [u8; KEY_LEN],
+    ticket_key: [u8; KEY_LEN],
+}
+
+impl Responder {
+    /// Tickets are encrypted with a key derived from the static key, so
+    /// they survive a restart as long as the static key does
+    pub fn new(key: StaticKeypair) -> Self {
+        let mut cookie_secret = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut cookie_secret);
+        let ticket_key = hkdf2(&hash(&[&key.secret_bytes()]), b"yaok-relay-ticket-key").1;
+        Self { key, cookie_secret, ticket_key }
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.key.public_key()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        self.key.fingerprint()
+    }
+
+    /// Handle `Initiation`/`Resume`. `session_id` is used if a session is
+    /// established.
+    pub fn accept(
+        &self,
+        datagram: &[u8],
+        peer: SocketAddr,
+        now_secs: u64,
+        session_id: u64,
+    ) -> Result<ResponderEvent, DatagramError> {
+        let kind = datagram_kind(datagram).ok_or(DatagramError::Malformed)?;
+        if !matches!(kind, DatagramKind::Initiation | DatagramKind::Resume) {
+            return Err(DatagramError::Malformed);
+        }
+        if datagram.len() < MIN_INITIATION_LEN {
+            return Err(DatagramError::TooShort);
+        }
+
+        let mut reader = ByteReader::new(&datagram[1..]);
+        let version = reader.u8()?;
+        if version != DATAGRAM_VERSION {
+            return Err(DatagramError::UnsupportedVersion(version));
+        }
+        let cookie_len = reader.u8()? as usize;
+        let cookie = reader.take(cookie_len)?;
+        if !self.verify_cookie(cookie, peer, now_secs) {
+            // No DH before the source address is proven
+            let mut reply = vec![TYPE_RETRY];
+            reply.extend_from_slice(&self.make_cookie(peer, now_secs));
+            return Ok(ResponderEvent::Reply(reply));
+        }
+
+        if kind == DatagramKind::Resume {
+            return self.accept_resume(&mut reader, now_secs, session_id);
+        }
+
+        let re = public_key(reader.take(KEY_LEN)?)?;
+        let mut state = SymmetricState::new(NX_PROTOCOL, PROLOGUE);
+        state.mix_hash(re.as_bytes());
+        state.decrypt_and_hash(&[]);
+
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let e_public = PublicKey::from(&ephemeral).to_bytes();
+        let session_id_bytes = session_id.to_be_bytes();
+
+        let mut reply = vec![TYPE_RESPONSE];
+        reply.extend_from_slice(&session_id_bytes);
+        reply.extend_from_slice(&e_public);
+        state.mix_hash(&session_id_bytes);
+        state.mix_hash(&e_public);
+        state.mix_key(&dh(&ephemeral, &re)?);
+        reply.extend_from_slice(&state.encrypt_and_hash(&self.key.public_key()));
+        state.mix_key(&dh(&self.key.secret, &re)?);
+
+        let payload = self.ticket_payload(state.resumption_secret(), now_secs);
+        reply.extend_from_slice(&state.encrypt_and_hash(&payload));
+
+        let (initiator_key, responder_key) = state.split();
+        Ok(ResponderEvent::Established {
+            session: DatagramSession::new(session_id, responder_key, initiator_key, state.handshake_hash()),
+            reply,
+            resumed: false,
+        })
+    }
+
+    fn accept_resume(
+        &self,
+        reader: &mut ByteReader<'_>,
+        now_secs: u64,
+        session_id: u64,
+    ) -> Result<ResponderEvent, DatagramError> {
+        let ticket_len = reader.u16()? as usize;
+        let ticket = reader.take(ticket_len)?;
+        let Some(psk) = self.open_ticket(ticket, now_secs) else {
+            return Ok(ResponderEvent::Reply(vec![TYPE_REJECT]));
+        };
+
+        let re = public_key(reader.take(KEY_LEN)?)?;
+        let mut state = SymmetricState::new(PSK_PROTOCOL, PROLOGUE);
+        state.mix_key_and_hash(&psk);
+        state.mix_hash(re.as_bytes());
+        state.mix_key(re.as_bytes());
+        state.decrypt_and_hash(reader.take(TAG_LEN)?).ok_or(DatagramError::Decrypt)?;
+
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let e_public = PublicKey::from(&ephemeral).to_bytes();
+        let session_id_bytes = session_id.to_be_bytes();
+
+        let mut reply = vec![TYPE_RESPONSE];
+        reply.extend_from_slice(&session_id_bytes);
+        reply.extend_from_slice(&e_public);
+        state.mix_hash(&session_id_bytes);
+        state.mix_hash(&e_public);
+        state.mix_key(&e_public);
+        state.mix_key(&dh(&ephemeral, &re)?);
+
+        let payload = self.ticket_payload(state.resumption_secret(), now_secs);
+        reply.extend_from_slice(&state.encrypt_and_hash(&payload));
+
+        let (initiator_key, responder_key) = state.split();
+        Ok(ResponderEvent::Established {
+            session: DatagramSession::new(session_id, responder_key, initiator_key, state.handshake_hash()),
+            reply,
+            resumed: true,
+        })
+    }
+
+    fn make_cookie(&self, peer: SocketAddr, now_secs: u64) -> [u8; COOKIE_LEN] {
+        let bucket = now_secs / COOKIE_BUCKET_SECS;
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie[..8].copy_from_slice(&bucket.to_be_bytes());
+        cookie[8..].copy_from_slice(&self.cookie_mac(bucket, peer)[..COOKIE_LEN - 8]);
+        cookie
+    }
+
+    fn verify_cookie(&self, cookie: &[u8], peer: SocketAddr, now_secs: u64) -> bool {
+        if cookie.len() != COOKIE_LEN {
+            return false;
+        }
+        let bucket = u64::from_be_bytes(cookie[..8].try_into().unwrap());
+        let current = now_secs / COOKIE_BUCKET_SECS;
+        if bucket != current && current.checked_sub(1) != Some(bucket) {
+            return false;
+        }
+        let expected = self.cookie_mac(bucket, peer);
+        constant_time_eq(&cookie[8..], &expected[..COOKIE_LEN - 8])
+    }
+
+    fn cookie_mac(&self, bucket: u64, peer: SocketAddr) -> [u8; 32] {
+        let ip = match peer.ip() {
+            std::net::IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            std::net::IpAddr::V6(ip) => ip.octets(),
+        };
+        hmac(&self.cookie_secret, &[&bucket.to_be_bytes(), &ip, &peer.port().to_be_bytes()])
+    }
+
+    /// Response payload: expiry + ticket
+    fn ticket_payload(&self, psk: [u8; KEY_LEN], now_secs: u64) -> Vec<u8> {
+        let expires_at = now_secs + TICKET_LIFETIME_SECS;
+        let mut plaintext = Vec::with_capacity(KEY_LEN + 8);
+        plaintext.extend_from_slice(&psk);
+        plaintext.extend_from_slice(&expires_at.to_be_bytes());
+
+        let nonce = OsRng.next_u64();
+        let mut payload = Vec::with_capacity(8 + TICKET_LEN);
+        payload.extend_from_slice(&expires_at.to_be_bytes());
+        payload.extend_from_slice(&nonce.to_be_bytes());
+        payload.extend_from_slice(&seal(&self.ticket_key, nonce, TICKET_AD, &plaintext));
+        payload
+    }
+
+    fn open_ticket(&self, ticket: &[u8], now_secs: u64) -> Option<[u8; KEY_LEN]> {
+        if ticket.len() != TICKET_LEN {
+            return None;
+        }
+        let nonce = u64::from_be_bytes(ticket[..8].try_into().ok()?);
+        let plaintext = open(&self.ticket_key, nonce, TICKET_AD, &ticket[8..])?;
+        let expires_at = u64::from_be_bytes(plaintext[KEY_LEN..].try_into().ok()?);
+        if now_secs >= expires_at {
+            return None;
+        }
+        plaintext[..KEY_LEN].try_into().ok()
+    }
+}
+
+fn parse_ticket_payload(
+    payload: &[u8],
+    psk: [u8; KEY_LEN],
+    relay_fingerprint: String,
+    now_secs: u64,
+) -> Option<ResumptionTicket> {
+    if payload.len() != 8 + TICKET_LEN {
+        return None;
+    }
+    let expires_at = u64::from_be_bytes(payload[..8].try_into().ok()?);
+    (expires_at > now_secs).then(|| ResumptionTicket {
+        ticket: payload[8..].to_vec(),
+        expires_at,
+        relay_fingerprint,
+        psk,
+    })
+}
+
+fn public_key(bytes: &[u8]) -> Result<PublicKey, DatagramError> {
+    let bytes: [u8; KEY_LEN] = bytes.try_into().map_err(|_| DatagramError::Malformed)?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn dh(secret: &StaticSecret, public: &PublicKey) -> Result<[u8; KEY_LEN], DatagramError> {
+    let shared = secret.diffie_hellman(public);
+    if !shared.was_contributory() {
+        return Err(DatagramError::WeakKey);
+    }
+    Ok(shared.to_bytes())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+struct ByteReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DatagramError> {
+        if self.buf.len() < len {
+            return Err(DatagramError::Malformed);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DatagramError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DatagramError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn peer() -> SocketAddr {
+        "198.51.100.7:50000".parse().unwrap()
+    }
+
+    /// Run a handshake through the cookie exchange
+    fn handshake(
+        responder: &Responder,
+        client: &ClientHandshake,
+        session_id: u64,
+    ) -> (ResponderEvent, Result<ClientEvent, DatagramError>) {
+        let first = client.initiation(None);
+        let ResponderEvent::Reply(retry) = responder.accept(&first, peer(), NOW, session_id).unwrap() else {
+            panic!("expected retry without cookie");
+        };
+        assert!(retry.len() < first.len(), "retry must not amplify");
+
+        let ClientEvent::Retry(cookie) = client.read_reply(&retry, NOW).unwrap() else {
+            panic!("expected cookie");
+        };
+        let event = responder.accept(&client.initiation(Some(&cookie)), peer(), NOW, session_id).unwrap();
+        let reply = match &event {
+            ResponderEvent::Established { reply, .. } | ResponderEvent::Reply(reply) => reply.clone(),
+        };
+        assert!(reply.len() < MIN_INITIATION_LEN, "response must not amplify");
+        (event, client.read_reply(&reply, NOW))
+    }
+
+    fn established(
+        responder_event: ResponderEvent,
+        client_event: Result<ClientEvent, DatagramError>,
+    ) -> (DatagramSession, DatagramSession, Option<ResumptionTicket>, bool) {
+        let ResponderEvent::Established { session: relay, resumed, .. } = responder_event else {
+            panic!("relay did not establish");
+        };
+        let ClientEvent::Established { session: client, ticket } = client_event.unwrap() else {
+            panic!("client did not establish");
+        };
+        (*client, relay, ticket, resumed)
+    }
+
+    #[test]
+    fn full_handshake_with_pin_and_data_both_ways() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let client = ClientHandshake::full(Some(responder.fingerprint().to_lowercase()));
+
+        let (event, reply) = handshake(&responder, &client, 42);
+        let (mut client, mut relay, ticket, resumed) = established(event, reply);
+        assert!(!resumed);
+        assert!(ticket.is_some());
+        assert_eq!(client.session_id(), 42);
+
+        let datagram = client.sender.seal(b"hello relay");
+        assert_eq!(data_session_id(&datagram), Some(42));
+        assert_eq!(relay.receiver.open(&datagram).unwrap(), b"hello relay");
+        let datagram = relay.sender.seal(b"hello client");
+        assert_eq!(client.receiver.open(&datagram).unwrap(), b"hello client");
+    }
+
+    #[test]
+    fn pin_mismatch_rejected() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let other = StaticKeypair::generate();
+        let client = ClientHandshake::full(Some(other.fingerprint()));
+
+        let (_, reply) = handshake(&responder, &client, 1);
+        assert!(matches!(reply, Err(DatagramError::PinMismatch { .. })));
+    }
+
+    #[test]
+    fn cookie_bound_to_address_and_time() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let client = ClientHandshake::full(None);
+        let ResponderEvent::Reply(retry) = responder.accept(&client.initiation(None), peer(), NOW, 1).unwrap() else {
+            panic!("expected retry");
+        };
+        let ClientEvent::Retry(cookie) = client.read_reply(&retry, NOW).unwrap() else {
+            panic!("expected cookie");
+        };
+
+        let spoofed: SocketAddr = "203.0.113.9:50000".parse().unwrap();
+        let initiation = client.initiation(Some(&cookie));
+        assert!(matches!(responder.accept(&initiation, spoofed, NOW, 1), Ok(ResponderEvent::Reply(_))));
+        let later = NOW + 3 * COOKIE_BUCKET_SECS;
+        assert!(matches!(responder.accept(&initiation, peer(), later, 1), Ok(ResponderEvent::Reply(_))));
+        assert!(matches!(
+            responder.accept(&initiation, peer(), NOW + 1, 1),
+            Ok(ResponderEvent::Established { .. })
+        ));
+    }
+
+    #[test]
+    fn cookie_from_last_bucket_rejected_without_overflow() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(!responder.verify_cookie(&cookie, peer(), NOW));
+        let initiation = ClientHandshake::full(None).initiation(Some(&cookie));
+        assert!(matches!(responder.accept(&initiation, peer(), NOW, 1), Ok(ResponderEvent::Reply(_))));
+    }
+
+    #[test]
+    fn short_initiation_dropped() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let mut initiation = ClientHandshake::full(None).initiation(None);
+        initiation.truncate(200);
+        assert!(matches!(responder.accept(&initiation, peer(), NOW, 1), Err(DatagramError::TooShort)));
+    }
+
+    #[test]
+    fn resumption_survives_relay_restart_with_same_key() {
+        let key_bytes = StaticKeypair::generate().secret_bytes();
+        let responder = Responder::new(StaticKeypair::from_secret_bytes(key_bytes));
+        let (event, reply) = handshake(&responder, &ClientHandshake::full(None), 1);
+        let (_, _, ticket, _) = established(event, reply);
+        let ticket = ticket.unwrap();
+        assert_eq!(ticket.relay_fingerprint, responder.fingerprint());
+
+        // New process, new cookie secret, same static key
+        let restarted = Responder::new(StaticKeypair::from_secret_bytes(key_bytes));
+        let (event, reply) = handshake(&restarted, &ClientHandshake::resume(&ticket), 2);
+        let (mut client, mut relay, next_ticket, resumed) = established(event, reply);
+        assert!(resumed);
+        assert!(next_ticket.is_some());
+
+        let datagram = client.sender.seal(b"resumed");
+        assert_eq!(relay.receiver.open(&datagram).unwrap(), b"resumed");
+    }
+
+    #[test]
+    fn foreign_or_expired_ticket_rejected() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let (event, reply) = handshake(&responder, &ClientHandshake::full(None), 1);
+        let ticket = established(event, reply).2.unwrap();
+
+        let other_relay = Responder::new(StaticKeypair::generate());
+        let (event, reply) = handshake(&other_relay, &ClientHandshake::resume(&ticket), 2);
+        assert!(matches!(event, ResponderEvent::Reply(_)));
+        assert!(matches!(reply, Ok(ClientEvent::Rejected)));
+
+        let client = ClientHandshake::resume(&ticket);
+        let expired = ticket.expires_at + 1;
+        let ResponderEvent::Reply(retry) = responder.accept(&client.initiation(None), peer(), expired, 3).unwrap() else {
+            panic!("expected retry");
+        };
+        let ClientEvent::Retry(cookie) = client.read_reply(&retry, expired).unwrap() else {
+            panic!("expected cookie");
+        };
+        let event = responder.accept(&client.initiation(Some(&cookie)), peer(), expired, 3).unwrap();
+        assert!(matches!(event, ResponderEvent::Reply(reply) if reply == vec![TYPE_REJECT]));
+    }
+
+    #[test]
+    fn replayed_and_tampered_data_rejected() {
+        let responder = Responder::new(StaticKeypair::generate());
+        let (event, reply) = handshake(&responder, &ClientHandshake::full(None), 7);
+        let (mut client, mut relay, _, _) = established(event, reply);
+
+        let first = client.sender.seal(b"one");
+        let second = client.sender.seal(b"two");
+        // Reordering within the window is fine, duplicates are not
+        assert_eq!(relay.receiver.open(&second).unwrap(), b"two");
+        assert_eq!(relay.receiver.open(&first).unwrap(), b"one");
+        assert_eq!(relay.receiver.open(&first), Err(DatagramError::Replay));
+
+        let mut tampered = client.sender.seal(b"three");
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert_eq!(relay.receiver.open(&tampered), Err(DatagramError::Decrypt));
+    }
+
+    #[test]
+    fn replay_window_slides() {
+        let mut window = ReplayWindow::default();
+        window.mark(0);
+        window.mark(100);
+        assert!(!window.is_fresh(100));
+        assert!(!window.is_fresh(0));
+        assert!(window.is_fresh(99));
+        window.mark(99);
+        assert!(!window.is_fresh(99));
+        assert!(window.is_fresh(101));
+    }
+}
@@ -0,0 +1,69 @@
+//! Bounded set of recently seen ids
+//!
+//! Used wherever the same packet can arrive more than once: by clients
+//! that listen on several relays and by relays that forward between
+//! federation peers (loop prevention).
+
+use std::collections::{HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Remembers the last `capacity` ids; older ones are forgotten
+pub struct RecentIds<T> {
+    capacity: usize,
+    ids: HashSet<T>,
+    order: VecDeque<T>,
+}
+
+impl<T: Hash + Eq + Clone> RecentIds<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Record `id`; `false` if it was already seen
+    pub fn insert(&mut self, id: T) -> bool {
+        if !self.ids.insert(id.clone()) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(old) = self.order.pop_front() {
+                self.ids.remove(&old);
+            }
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &T) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicates_rejected_until_evicted() {
+        let mut recent = RecentIds::new(3);
+        assert!(recent.insert(1));
+        assert!(!recent.insert(1));
+        for id in 2..=4 {
+            assert!(recent.insert(id));
+        }
+        assert_eq!(recent.len(), 3);
+        assert!(!recent.contains(&1));
+        assert!(recent.insert(1));
+    }
+}
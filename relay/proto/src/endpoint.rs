@@ -0,0 +1,242 @@
+//! Relay endpoints as written in configuration and relay lists
+//!
+//! `host:port`, where the host is a DNS name, an IPv4 address or an IPv6
+//! address in brackets (`[2001:db8::1]:40100`). The port defaults to
+//! [`DEFAULT_PORT`]. An optional scheme names the link: `tls://` (TLS over
+//! TCP), `tcp://` (plain TCP, local testing) or `udp://` (encrypted
+//! datagrams). A trailing `/` path is ignored.
+//!
+//! IPv6 addresses without brackets are refused: in `2001:db8::1:40100`
+//! the port cannot be told from the address.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+
+/// Port used when an endpoint has none
+pub const DEFAULT_PORT: u16 = 40100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    Tls,
+    Tcp,
+    Udp,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Tls => "tls",
+            Scheme::Tcp => "tcp",
+            Scheme::Udp => "udp",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Host {
+    Ip(IpAddr),
+    /// DNS name, lowercase
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub scheme: Option<Scheme>,
+    pub host: Host,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum EndpointError {
+    #[error("Empty endpoint")]
+    Empty,
+
+    #[error("Unknown scheme '{0}' (expected tls, tcp or udp)")]
+    UnknownScheme(String),
+
+    #[error("IPv6 address must be in brackets, as [{0}]:port")]
+    UnbracketedIpv6(String),
+
+    #[error("Invalid host '{0}'")]
+    InvalidHost(String),
+
+    #[error("Invalid port '{0}'")]
+    InvalidPort(String),
+}
+
+impl Endpoint {
+    pub fn new(host: Host, port: u16) -> Self {
+        Self { scheme: None, host, port }
+    }
+
+    /// `host:port` with IPv6 in brackets, for `TcpStream::connect` and
+    /// `lookup_host`
+    pub fn authority(&self) -> String {
+        match &self.host {
+            Host::Ip(IpAddr::V6(ip)) => format!("[{}]:{}", ip, self.port),
+            Host::Ip(IpAddr::V4(ip)) => format!("{}:{}", ip, self.port),
+            Host::Name(name) => format!("{}:{}", name, self.port),
+        }
+    }
+
+    /// Host without brackets, as TLS expects it for the server name
+    pub fn server_name(&self) -> String {
+        match &self.host {
+            Host::Ip(ip) => ip.to_string(),
+            Host::Name(name) => name.clone(),
+        }
+    }
+
+    /// Loopback address or `localhost`
+    pub fn is_loopback(&self) -> bool {
+        match &self.host {
+            Host::Ip(ip) => ip.to_canonical().is_loopback(),
+            Host::Name(name) => name == "localhost" || name.ends_with(".localhost"),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (scheme, rest) = match s.split_once("://") {
+            Some((scheme, rest)) => {
+                let scheme = match scheme.to_ascii_lowercase().as_str() {
+                    "tls" => Scheme::Tls,
+                    "tcp" => Scheme::Tcp,
+                    "udp" => Scheme::Udp,
+                    _ => return Err(EndpointError::UnknownScheme(scheme.to_string())),
+                };
+                (Some(scheme), rest)
+            }
+            None => (None, s),
+        };
+        let authority = rest.split_once('/').map_or(rest, |(authority, _)| authority);
+        if authority.is_empty() {
+            return Err(EndpointError::Empty);
+        }
+
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (address, after) = bracketed.split_once(']').ok_or_else(|| EndpointError::InvalidHost(authority.to_string()))?;
+            let ip: Ipv6Addr = address.parse().map_err(|_| EndpointError::InvalidHost(address.to_string()))?;
+            let port = match after {
+                "" => None,
+                _ => Some(after.strip_prefix(':').ok_or_else(|| EndpointError::InvalidPort(after.to_string()))?),
+            };
+            (Host::Ip(IpAddr::V6(ip)), port)
+        } else {
+            if authority.matches(':').count() > 1 {
+                return Err(EndpointError::UnbracketedIpv6(authority.to_string()));
+            }
+            let (host, port) = match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            };
+            (parse_host(host)?, port)
+        };
+
+        let port = match port {
+            Some(port) => port.parse::<u16>().ok().filter(|port| *port != 0).ok_or_else(|| EndpointError::InvalidPort(port.to_string()))?,
+            None => DEFAULT_PORT,
+        };
+        Ok(Self { scheme, host, port })
+    }
+}
+
+fn parse_host(host: &str) -> Result<Host, EndpointError> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(Host::Ip(ip));
+    }
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        });
+    if !valid {
+        return Err(EndpointError::InvalidHost(host.to_string()));
+    }
+    Ok(Host::Name(host.to_ascii_lowercase()))
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(scheme) = self.scheme {
+            write!(f, "{}://", scheme.as_str())?;
+        }
+        write!(f, "{}", self.authority())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Endpoint {
+        s.parse().unwrap_or_else(|e| panic!("{}: {}", s, e))
+    }
+
+    #[test]
+    fn parses_names_addresses_and_schemes() {
+        let endpoint = parse("Relay.Example:8443");
+        assert_eq!(endpoint, Endpoint::new(Host::Name("relay.example".to_string()), 8443));
+        assert_eq!(endpoint.authority(), "relay.example:8443");
+
+        let endpoint = parse("tls://relay.example/");
+        assert_eq!(endpoint.scheme, Some(Scheme::Tls));
+        assert_eq!(endpoint.port, DEFAULT_PORT);
+        assert_eq!(endpoint.to_string(), "tls://relay.example:40100");
+
+        let endpoint = parse("192.0.2.1:40100");
+        assert_eq!(endpoint.host, Host::Ip("192.0.2.1".parse().unwrap()));
+        assert_eq!(parse("UDP://192.0.2.1").scheme, Some(Scheme::Udp));
+    }
+
+    #[test]
+    fn ipv6_needs_brackets() {
+        let endpoint = parse("[::1]:40100");
+        assert_eq!(endpoint.host, Host::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(endpoint.authority(), "[::1]:40100");
+        assert_eq!(endpoint.server_name(), "::1");
+        assert!(endpoint.is_loopback());
+
+        let endpoint = parse("tcp://[2001:db8::1]/path");
+        assert_eq!(endpoint.port, DEFAULT_PORT);
+        assert_eq!(endpoint.to_string(), "tcp://[2001:db8::1]:40100");
+        assert!(!endpoint.is_loopback());
+
+        assert_eq!("2001:db8::1:40100".parse::<Endpoint>(), Err(EndpointError::UnbracketedIpv6("2001:db8::1:40100".to_string())));
+        assert!(matches!("::1".parse::<Endpoint>(), Err(EndpointError::UnbracketedIpv6(_))));
+        assert!(matches!("[::1".parse::<Endpoint>(), Err(EndpointError::InvalidHost(_))));
+        assert!(matches!("[::1]40100".parse::<Endpoint>(), Err(EndpointError::InvalidPort(_))));
+        assert!(matches!("[relay.example]:1".parse::<Endpoint>(), Err(EndpointError::InvalidHost(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_endpoints() {
+        assert_eq!("".parse::<Endpoint>(), Err(EndpointError::Empty));
+        assert_eq!("tls://".parse::<Endpoint>(), Err(EndpointError::Empty));
+        assert!(matches!("https://relay.example".parse::<Endpoint>(), Err(EndpointError::UnknownScheme(_))));
+        assert!(matches!("relay.example:0".parse::<Endpoint>(), Err(EndpointError::InvalidPort(_))));
+        assert!(matches!("relay.example:70000".parse::<Endpoint>(), Err(EndpointError::InvalidPort(_))));
+        assert!(matches!("relay.example:".parse::<Endpoint>(), Err(EndpointError::InvalidPort(_))));
+        assert!(matches!("user@relay.example".parse::<Endpoint>(), Err(EndpointError::InvalidHost(_))));
+        assert!(matches!("-relay.example".parse::<Endpoint>(), Err(EndpointError::InvalidHost(_))));
+        assert!(matches!(":40100".parse::<Endpoint>(), Err(EndpointError::InvalidHost(_))));
+    }
+
+    #[test]
+    fn loopback_names_and_mapped_addresses() {
+        assert!(parse("localhost:1").is_loopback());
+        assert!(parse("relay.localhost").is_loopback());
+        assert!(parse("127.0.0.1").is_loopback());
+        assert!(parse("[::ffff:127.0.0.1]").is_loopback());
+        assert!(!parse("localhost.example").is_loopback());
+    }
+}
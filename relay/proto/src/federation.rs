@@ -0,0 +1,91 @@
+//! Relay-to-relay federation
+//!
+//! A relay dials its configured peers and opens a link with `PeerHello`;
+//! the accepting relay answers with `PeerChallenge` (its own identity, a
+//! nonce and its signature), the dialer replies with `Auth` and gets
+//! `Welcome`. Both signatures cover the signer's role, both nonces and
+//! the link's channel binding ([`crate::auth::peer_auth_payload`]), so
+//! neither can be reflected back or replayed into another handshake:
+//!
+//! ```text
+//! dialer                                          acceptor
+//!   PeerHello { version, relay_id, key, nonce } ->
+//!                 <- PeerChallenge { relay_id, key, nonce, signature }
+//!   Auth { signature }                          ->
+//!                                               <- Welcome
+//!   Presence / Forward / Ping                  <->
+//! ```
+//!
+//! Presence summaries carry [`presence_digest`]s of the node ids with a
+//! session on the sending relay, never the ids themselves. `Forward`
+//! carries a random [`ForwardId`] chosen by the first relay; every relay
+//! remembers the ids it has handled and drops repeats, so stale presence
+//! cannot make a packet circulate.
+
+use crate::ProtoError;
+use sha2::{Digest, Sha256};
+
+/// Length of a presence digest
+pub const PRESENCE_DIGEST_LEN: usize = 16;
+
+/// Hashed node id in presence summaries
+pub type PresenceDigest = [u8; PRESENCE_DIGEST_LEN];
+
+/// Length of a forwarded packet id
+pub const FORWARD_ID_LEN: usize = 16;
+
+/// Id of a forwarded packet, used for loop prevention
+pub type ForwardId = [u8; FORWARD_ID_LEN];
+
+/// Relays a packet may cross after the one the sender is connected to
+pub const MAX_FORWARD_HOPS: u8 = 3;
+
+/// Domain separation for presence digests
+const PRESENCE_CONTEXT: &[u8] = b"yaok-relay-presence-v1";
+
+/// Digest of `node_id` announced to federation peers
+pub fn presence_digest(node_id: &str) -> PresenceDigest {
+    let mut hasher = Sha256::new();
+    hasher.update(PRESENCE_CONTEXT);
+    hasher.update(node_id.as_bytes());
+    let hash = hasher.finalize();
+    let mut digest = [0u8; PRESENCE_DIGEST_LEN];
+    digest.copy_from_slice(&hash[..PRESENCE_DIGEST_LEN]);
+    digest
+}
+
+/// Pack digests into the `Presence` wire format (concatenated)
+pub fn encode_digests<'a>(digests: impl IntoIterator<Item = &'a PresenceDigest>) -> Vec<u8> {
+    digests.into_iter().flatten().copied().collect()
+}
+
+/// Unpack digests from a `Presence` message
+pub fn decode_digests(bytes: &[u8]) -> Result<Vec<PresenceDigest>, ProtoError> {
+    if !bytes.len().is_multiple_of(PRESENCE_DIGEST_LEN) {
+        return Err(ProtoError::Malformed(format!("presence of {} bytes", bytes.len())));
+    }
+    Ok(bytes
+        .chunks_exact(PRESENCE_DIGEST_LEN)
+        .map(|chunk| chunk.try_into().expect("chunk has digest length"))
+        .collect())
+}
+
+/// Parse the id of a `Forward` message
+pub fn forward_id(bytes: &[u8]) -> Result<ForwardId, ProtoError> {
+    bytes
+        .try_into()
+        .map_err(|_| ProtoError::Malformed(format!("forward id of {} bytes", bytes.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests_roundtrip() {
+        let digests = [presence_digest("alice"), presence_digest("bob")];
+        assert_ne!(digests[0], digests[1]);
+        assert_eq!(decode_digests(&encode_digests(&digests)).unwrap(), digests);
+        assert!(decode_digests(&[0u8; PRESENCE_DIGEST_LEN + 1]).is_err());
+    }
+}
@@ -0,0 +1,125 @@
+//! Length-prefixed CBOR framing
+
+use crate::{ProtoError, RelayMessage};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Length prefix size
+pub const FRAME_HEADER_LEN: usize = 4;
+
+/// Upper bound for a frame body. Core packets are capped at 128 KB,
+/// the rest is headroom for the envelope.
+pub const MAX_FRAME_LEN: usize = 192 * 1024;
+
+/// Encode a message body (CBOR, no length prefix), as carried in datagrams
+pub fn encode_message(message: &RelayMessage) -> Result<Vec<u8>, ProtoError> {
+    let mut body = Vec::new();
+    ciborium::ser::into_writer(message, &mut body)
+        .map_err(|e| ProtoError::Malformed(e.to_string()))?;
+    Ok(body)
+}
+
+/// Encode a message as a complete frame (length prefix included)
+pub fn encode_frame(message: &RelayMessage) -> Result<Vec<u8>, ProtoError> {
+    let mut frame = vec![0u8; FRAME_HEADER_LEN];
+    ciborium::ser::into_writer(message, &mut frame)
+        .map_err(|e| ProtoError::Malformed(e.to_string()))?;
+
+    let body_len = frame.len() - FRAME_HEADER_LEN;
+    if body_len > MAX_FRAME_LEN {
+        return Err(ProtoError::FrameTooLarge(body_len));
+    }
+    frame[..FRAME_HEADER_LEN].copy_from_slice(&(body_len as u32).to_be_bytes());
+    Ok(frame)
+}
+
+/// Decode a frame body (without the length prefix)
+pub fn decode_frame(body: &[u8]) -> Result<RelayMessage, ProtoError> {
+    ciborium::de::from_reader(body).map_err(|e| ProtoError::Malformed(e.to_string()))
+}
+
+/// Write one message to the stream and flush it
+pub async fn write_message<W>(writer: &mut W, message: &RelayMessage) -> Result<(), ProtoError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let frame = encode_frame(message)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one message. Returns `ProtoError::Closed` on a clean EOF
+/// between frames; frames larger than `max_len` are rejected before
+/// the body is read.
+pub async fn read_message<R>(reader: &mut R, max_len: usize) -> Result<RelayMessage, ProtoError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Err(ProtoError::Closed),
+        Err(e) => return Err(e.into()),
+    }
+
+    let body_len = u32::from_be_bytes(header) as usize;
+    if body_len > max_len.min(MAX_FRAME_LEN) {
+        return Err(ProtoError::FrameTooLarge(body_len));
+    }
+
+    let mut body = vec![0u8; body_len];
+    reader.read_exact(&mut body).await?;
+    decode_frame(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeliveryStatus;
+
+    #[test]
+    fn frame_roundtrip() {
+        let message = RelayMessage::Send {
+            seq: 7,
+            to: "ab".repeat(32),
+            packet: vec![0xFF; 1_000],
+            ttl_secs: Some(3_600),
+        };
+        let frame = encode_frame(&message).unwrap();
+
+        let body_len = u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize;
+        assert_eq!(body_len, frame.len() - FRAME_HEADER_LEN);
+        // Packet bytes are a CBOR byte string, not an array of integers
+        assert!(frame.len() < 1_200);
+        assert_eq!(decode_frame(&frame[FRAME_HEADER_LEN..]).unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn stream_read_write() {
+        let (mut client, mut server) = tokio::io::duplex(64 * 1024);
+        let messages = [
+            RelayMessage::Ping { seq: 1 },
+            RelayMessage::SendResult { seq: 1, status: DeliveryStatus::Offline },
+            RelayMessage::Bye,
+        ];
+
+        for message in &messages {
+            write_message(&mut client, message).await.unwrap();
+        }
+        drop(client);
+
+        for message in &messages {
+            assert_eq!(&read_message(&mut server, MAX_FRAME_LEN).await.unwrap(), message);
+        }
+        assert!(matches!(read_message(&mut server, MAX_FRAME_LEN).await, Err(ProtoError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_rejected_before_body() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(&(1_000_000u32).to_be_bytes()).await.unwrap();
+
+        let result = read_message(&mut server, MAX_FRAME_LEN).await;
+        assert!(matches!(result, Err(ProtoError::FrameTooLarge(1_000_000))));
+    }
+}
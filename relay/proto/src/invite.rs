@@ -0,0 +1,207 @@
+//! Contact invites, shared as `yaok://add?<query>` links and as the relay
+//! landing page `https://<relay>/add?<query>`
+//!
+//! The query carries `id` (node id, hex Ed25519 key), optionally `x` (hex
+//! X25519 key) and `name`, and `sig`: the inviter's signature over
+//! [`invite_payload`]. Anyone can put any name into an unsigned link, so
+//! the landing page only shows names from signed invites.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::fmt::Write;
+
+/// Domain separation for the signed invite
+const INVITE_CONTEXT: &[u8] = b"yaok-invite-v1";
+
+/// Longest display name, in characters
+pub const MAX_INVITE_NAME_CHARS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// Node id of the inviter
+    pub id: String,
+    /// Hex X25519 key for end-to-end encryption
+    pub x25519: Option<String>,
+    pub name: Option<String>,
+    pub signature: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InviteError {
+    #[error("Invite has no id")]
+    MissingId,
+
+    #[error("Invalid key in '{0}'")]
+    InvalidKey(&'static str),
+
+    #[error("Invalid name")]
+    InvalidName,
+
+    #[error("Invite is not signed")]
+    Unsigned,
+
+    #[error("Invalid invite signature")]
+    BadSignature,
+}
+
+/// Bytes the inviter signs; the name goes last so it needs no length
+pub fn invite_payload(id: &str, x25519: Option<&str>, name: Option<&str>) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(INVITE_CONTEXT.len() + id.len() + 66 + name.map_or(0, str::len));
+    payload.extend_from_slice(INVITE_CONTEXT);
+    payload.extend_from_slice(id.as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(x25519.unwrap_or("").as_bytes());
+    payload.push(0);
+    payload.extend_from_slice(name.unwrap_or("").as_bytes());
+    payload
+}
+
+impl Invite {
+    /// Signed invite from the inviter's Ed25519 key
+    pub fn sign(signing_key: &SigningKey, x25519: Option<&[u8; 32]>, name: Option<&str>) -> Result<Self, InviteError> {
+        let mut invite = Self {
+            id: hex::encode(signing_key.verifying_key().as_bytes()),
+            x25519: x25519.map(hex::encode),
+            name: name.map(normalize_name).transpose()?.flatten(),
+            signature: None,
+        };
+        let signature = signing_key.sign(&invite.payload());
+        invite.signature = Some(signature.to_bytes().to_vec());
+        Ok(invite)
+    }
+
+    /// Parse the query of an invite link. Unknown parameters are ignored
+    /// and the first of repeated ones wins.
+    pub fn from_query(query: &str) -> Result<Self, InviteError> {
+        let param = |key: &str| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+                .find(|(k, _)| *k == key)
+                .map(|(_, value)| decode(value))
+        };
+
+        let id = match param("id") {
+            Some(id) => parse_key(&id.ok_or(InviteError::InvalidKey("id"))?).ok_or(InviteError::InvalidKey("id"))?,
+            None => return Err(InviteError::MissingId),
+        };
+        let x25519 = match param("x") {
+            Some(x) => Some(parse_key(&x.ok_or(InviteError::InvalidKey("x"))?).ok_or(InviteError::InvalidKey("x"))?),
+            None => None,
+        };
+        let name = match param("name") {
+            Some(name) => normalize_name(&name.ok_or(InviteError::InvalidName)?)?,
+            None => None,
+        };
+        let signature = match param("sig") {
+            Some(sig) => Some(
+                sig.and_then(|sig| hex::decode(sig).ok())
+                    .filter(|sig| sig.len() == Signature::BYTE_SIZE)
+                    .ok_or(InviteError::BadSignature)?,
+            ),
+            None => None,
+        };
+        Ok(Self { id, x25519, name, signature })
+    }
+
+    /// Query with every value percent-encoded
+    pub fn to_query(&self) -> String {
+        let mut query = format!("id={}", self.id);
+        if let Some(x25519) = &self.x25519 {
+            let _ = write!(query, "&x={}", x25519);
+        }
+        if let Some(name) = &self.name {
+            let _ = write!(query, "&name={}", urlencoding::encode(name));
+        }
+        if let Some(signature) = &self.signature {
+            let _ = write!(query, "&sig={}", hex::encode(signature));
+        }
+        query
+    }
+
+    pub fn payload(&self) -> Vec<u8> {
+        invite_payload(&self.id, self.x25519.as_deref(), self.name.as_deref())
+    }
+
+    /// Check that the key behind `id` signed the invite
+    pub fn verify(&self) -> Result<(), InviteError> {
+        let signature = self.signature.as_deref().ok_or(InviteError::Unsigned)?;
+        let key: [u8; 32] = hex::decode(&self.id).ok().and_then(|key| key.try_into().ok()).ok_or(InviteError::InvalidKey("id"))?;
+        let key = VerifyingKey::from_bytes(&key).map_err(|_| InviteError::InvalidKey("id"))?;
+        let signature = Signature::from_slice(signature).map_err(|_| InviteError::BadSignature)?;
+        key.verify(&self.payload(), &signature).map_err(|_| InviteError::BadSignature)
+    }
+}
+
+/// Query value: `+` is a space, the rest percent-decoded
+fn decode(value: &str) -> Option<String> {
+    urlencoding::decode(&value.replace('+', " ")).ok().map(|value| value.into_owned())
+}
+
+/// 32-byte key in hex, lowercased
+fn parse_key(hex_key: &str) -> Option<String> {
+    let key = hex_key.trim().to_ascii_lowercase();
+    (key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit())).then_some(key)
+}
+
+/// Trimmed name, `None` when empty; control characters are refused
+fn normalize_name(name: &str) -> Result<Option<String>, InviteError> {
+    let name = name.trim();
+    if name.chars().count() > MAX_INVITE_NAME_CHARS || name.chars().any(char::is_control) {
+        return Err(InviteError::InvalidName);
+    }
+    Ok((!name.is_empty()).then(|| name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32])
+    }
+
+    #[test]
+    fn signed_invite_round_trips_through_the_query() {
+        let invite = Invite::sign(&key(), Some(&[9u8; 32]), Some(" Олексій & Co ")).unwrap();
+        assert_eq!(invite.name.as_deref(), Some("Олексій & Co"));
+        let query = invite.to_query();
+        assert!(query.contains("&name=%D0%9E"), "{}", query);
+        assert!(!query.contains(' '));
+
+        let parsed = Invite::from_query(&query).unwrap();
+        assert_eq!(parsed, invite);
+        parsed.verify().unwrap();
+    }
+
+    #[test]
+    fn tampered_or_unsigned_invites_do_not_verify() {
+        let invite = Invite::sign(&key(), None, Some("Alice")).unwrap();
+        let query = invite.to_query().replace("name=Alice", "name=Bank+support");
+        let tampered = Invite::from_query(&query).unwrap();
+        assert_eq!(tampered.name.as_deref(), Some("Bank support"));
+        assert_eq!(tampered.verify(), Err(InviteError::BadSignature));
+
+        let other = hex::encode(SigningKey::from_bytes(&[8u8; 32]).verifying_key().as_bytes());
+        let forged = Invite::from_query(&invite.to_query().replace(&invite.id, &other)).unwrap();
+        assert_eq!(forged.verify(), Err(InviteError::BadSignature));
+
+        let unsigned = Invite::from_query(&format!("id={}&name=Alice", invite.id)).unwrap();
+        assert_eq!(unsigned.verify(), Err(InviteError::Unsigned));
+    }
+
+    #[test]
+    fn rejects_malformed_queries() {
+        let id = "ab".repeat(32);
+        assert_eq!(Invite::from_query("name=Alice"), Err(InviteError::MissingId));
+        assert_eq!(Invite::from_query("id=abc"), Err(InviteError::InvalidKey("id")));
+        assert_eq!(Invite::from_query(&format!("id={}&x=zz", id)), Err(InviteError::InvalidKey("x")));
+        assert_eq!(Invite::from_query(&format!("id={}&sig=00", id)), Err(InviteError::BadSignature));
+        assert_eq!(Invite::from_query(&format!("id={}&name=a%0Ab", id)), Err(InviteError::InvalidName));
+        assert_eq!(Invite::from_query(&format!("id={}&name={}", id, "a".repeat(65))), Err(InviteError::InvalidName));
+        assert_eq!(Invite::from_query(&format!("id={}&name=%FF", id)), Err(InviteError::InvalidName));
+
+        let invite = Invite::from_query(&format!("id={}&name=&utm=1", id.to_uppercase())).unwrap();
+        assert_eq!(invite.id, id);
+        assert_eq!(invite.name, None);
+    }
+}
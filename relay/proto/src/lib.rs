@@ -0,0 +1,99 @@
+//! yaok relay wire protocol
+//!
+//! Shared by the relay server (`yaok-relay`) and the core relay client
+//! (`ya_ok_core::transport::udp`). Runs over any ordered byte stream
+//! (TCP, TLS over TCP) or over encrypted UDP datagrams ([`datagram`]);
+//! both ends drive either one through a [`MessageLink`].
+//!
+//! On streams every frame is a 4-byte big-endian length followed by a CBOR-encoded
+//! [`RelayMessage`]. A session looks like:
+//!
+//! ```text
+//! client                                relay
+//!   Hello { version, node_id, key } ->
+//!                                   <- Challenge { nonce, pow_difficulty }
+//!   Auth { signature, pow }         ->
+//!                                   <- Welcome { keepalive_secs }
+//!   Ping / Send { seq, to, packet } ->
+//!                                   <- Pong / SendResult { seq, status }
+//!                                   <- Deliver { from, packet }
+//! ```
+//!
+//! The node id is the hex-encoded Ed25519 public key, and the client proves
+//! ownership by signing [`auth::auth_payload`] for the relay's nonce and
+//! the link's channel binding (TLS exporter or Noise handshake hash); a
+//! loaded relay also asks for proof of work ([`pow`]). A
+//! client may also register rotating recipient tags ([`tags`]) and be
+//! addressed by them. Packets go to the addressed session only; `Flood`
+//! (emergency packets, rate limited) is the one broadcast.
+//!
+//! Relays federate over the same framing ([`federation`]): both ends sign
+//! their role and both nonces, then exchange presence summaries and forward
+//! packets for nodes homed on the other relay.
+//!
+//! Contact invites ([`invite`]) are signed by the inviter's node key.
+//!
+//! Two nodes that both ask for it are introduced for a direct UDP link
+//! ([`rendezvous`]); packets fall back to the relay when punching fails.
+//!
+//! A client may leave an opaque push token with `RegisterPush`; the relay
+//! then wakes the sleeping device, with no content, when a packet for it
+//! waits in the mailbox.
+
+pub mod auth;
+pub mod datagram;
+pub mod dedup;
+pub mod endpoint;
+pub mod federation;
+pub mod frame;
+pub mod invite;
+pub mod link;
+pub mod message;
+mod noise;
+pub mod pow;
+pub mod rendezvous;
+pub mod tags;
+
+pub use auth::{
+    auth_payload, node_id_for_key, peer_auth_payload, register_payload, verify_auth, verify_peer_auth,
+    verify_register, PeerRole, PeerTranscript, CHANNEL_BINDING_LEN, NONCE_LEN, TLS_EXPORTER_LABEL,
+};
+pub use endpoint::{Endpoint, EndpointError};
+pub use invite::{Invite, InviteError};
+pub use frame::{decode_frame, encode_frame, encode_message, read_message, write_message, FRAME_HEADER_LEN, MAX_FRAME_LEN};
+pub use link::{is_closing, stream_link, MessageLink};
+pub use message::{DeliveryStatus, ErrorCode, RelayMessage};
+
+/// Current protocol version, sent in `Hello`
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// Default keepalive interval announced by the relay in `Welcome`
+pub const DEFAULT_KEEPALIVE_SECS: u32 = 30;
+
+/// Errors shared by both ends of the protocol
+#[derive(Debug, thiserror::Error)]
+pub enum ProtoError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+
+    #[error("Malformed frame: {0}")]
+    Malformed(String),
+
+    #[error("Connection closed")]
+    Closed,
+
+    #[error("Unexpected message: {0}")]
+    Unexpected(&'static str),
+
+    #[error("Authentication failed")]
+    AuthFailed,
+
+    #[error("Replayed datagram")]
+    Replay,
+
+    #[error("Rejected by peer: {0:?} {1}")]
+    Rejected(ErrorCode, String),
+}
@@ -0,0 +1,99 @@
+//! Message-level link
+//!
+//! Session logic on both ends works on a pair of channels instead of a
+//! byte stream, so the same code runs over TCP/TLS ([`stream_link`]) and
+//! over encrypted datagrams ([`crate::datagram`]).
+
+use crate::{read_message, write_message, RelayMessage, MAX_FRAME_LEN};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// Messages buffered in each direction
+pub const LINK_QUEUE: usize = 256;
+
+/// One side of an established link. `incoming` closes when the peer
+/// disconnects; dropping `outgoing` (or sending `Bye`/`Error`) closes it.
+pub struct MessageLink {
+    pub incoming: mpsc::Receiver<RelayMessage>,
+    pub outgoing: mpsc::Sender<RelayMessage>,
+    /// Channel binding: the TLS exporter or the Noise handshake hash, empty
+    /// on plain TCP. Session signatures cover it, so they cannot be relayed
+    /// into another connection.
+    pub binding: Vec<u8>,
+}
+
+impl MessageLink {
+    /// Create a link and the opposite ends of its channels, for transports
+    /// that drive the IO themselves
+    pub fn channel() -> (Self, mpsc::Sender<RelayMessage>, mpsc::Receiver<RelayMessage>) {
+        let (in_tx, in_rx) = mpsc::channel(LINK_QUEUE);
+        let (out_tx, out_rx) = mpsc::channel(LINK_QUEUE);
+        (Self { incoming: in_rx, outgoing: out_tx, binding: Vec::new() }, in_tx, out_rx)
+    }
+
+    /// Attach the channel binding of the underlying transport
+    pub fn with_binding(mut self, binding: impl Into<Vec<u8>>) -> Self {
+        self.binding = binding.into();
+        self
+    }
+}
+
+/// Whether the link is closed after writing `message`
+pub fn is_closing(message: &RelayMessage) -> bool {
+    matches!(message, RelayMessage::Error { .. } | RelayMessage::Bye)
+}
+
+/// Drive a length-framed byte stream as a [`MessageLink`]
+pub fn stream_link<S>(stream: S) -> MessageLink
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (link, in_tx, mut out_rx) = MessageLink::channel();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                result = read_message(&mut reader, MAX_FRAME_LEN) => {
+                    let Ok(message) = result else { break };
+                    if in_tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                // Local side dropped the link: stop reading
+                _ = in_tx.closed() => break,
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(message) = out_rx.recv().await {
+            if write_message(&mut writer, &message).await.is_err() || is_closing(&message) {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    link
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stream_link_roundtrip_and_close() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let client = stream_link(client);
+        let mut server = stream_link(server);
+
+        client.outgoing.send(RelayMessage::Ping { seq: 5 }).await.unwrap();
+        assert_eq!(server.incoming.recv().await, Some(RelayMessage::Ping { seq: 5 }));
+
+        // Bye closes the writer, the other side sees the link end
+        client.outgoing.send(RelayMessage::Bye).await.unwrap();
+        assert_eq!(server.incoming.recv().await, Some(RelayMessage::Bye));
+        assert_eq!(server.incoming.recv().await, None);
+    }
+}
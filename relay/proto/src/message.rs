@@ -0,0 +1,267 @@
+//! Protocol messages
+
+use serde::{Deserialize, Serialize};
+
+/// A single protocol message (one frame)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RelayMessage {
+    /// Client -> relay: open a session for `node_id`
+    Hello {
+        version: u16,
+        node_id: String,
+        /// Ed25519 public key (32 bytes); `node_id` must be its hex encoding
+        #[serde(with = "serde_bytes")]
+        public_key: Vec<u8>,
+    },
+
+    /// Relay -> client: nonce to sign. Under load the relay also asks for
+    /// proof of work of `pow_difficulty` bits (see [`crate::pow`]).
+    Challenge {
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        pow_difficulty: u8,
+    },
+
+    /// Client -> relay: Ed25519 signature over
+    /// `auth_payload(nonce, link binding, node_id)`
+    /// and the proof of work, if the challenge asked for it
+    Auth {
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pow: Option<u64>,
+    },
+
+    /// Relay -> client: session registered
+    Welcome {
+        /// Client should send `Ping` at least this often
+        keepalive_secs: u32,
+    },
+
+    /// Keepalive, either direction
+    Ping { seq: u64 },
+
+    /// Keepalive reply
+    Pong { seq: u64 },
+
+    /// Client -> relay: deliver `packet` to the session registered as `to`
+    /// (a node id, or a hex recipient tag, see [`crate::tags`]).
+    /// If `to` is offline the relay may keep the packet in its mailbox for
+    /// at most `ttl_secs` (its own limit applies too; `None` - relay limit).
+    Send {
+        seq: u64,
+        to: String,
+        #[serde(with = "serde_bytes")]
+        packet: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u32>,
+    },
+
+    /// Relay -> client: outcome of `Send { seq }`
+    SendResult { seq: u64, status: DeliveryStatus },
+
+    /// Relay -> client: packet addressed to this node. Packets from the
+    /// mailbox carry `mailbox_id` and are delivered again on every new
+    /// session until the client sends `Ack`.
+    Deliver {
+        from: String,
+        #[serde(with = "serde_bytes")]
+        packet: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mailbox_id: Option<u64>,
+    },
+
+    /// Client -> relay: mailbox packet received, delete it
+    Ack { mailbox_id: u64 },
+
+    /// Client -> relay: route `Send`s addressed to these recipient tags
+    /// (concatenated, see [`crate::tags`]) to this node until unix time
+    /// `expires_at`. Replaces the node's previous registration. Signed
+    /// over [`crate::auth::register_payload`].
+    Register {
+        #[serde(with = "serde_bytes")]
+        tags: Vec<u8>,
+        expires_at: u64,
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    },
+
+    /// Relay -> client: outcome of `Register`; tags already held by
+    /// another node are not taken over
+    Registered { accepted: u32, expires_at: u64 },
+
+    /// Client -> relay: emergency packet for every session on the relay.
+    /// The only broadcast the relay does, limited per node; answered with
+    /// `SendResult`.
+    Flood {
+        seq: u64,
+        #[serde(with = "serde_bytes")]
+        packet: Vec<u8>,
+    },
+
+    /// Client -> relay: ask for a direct UDP link with `peer` (see
+    /// [`crate::rendezvous`]). Relay -> client: `peer` asks for a direct
+    /// link with this node; answering with the same `Punch` agrees.
+    Punch { peer: String },
+
+    /// Relay -> client: for the link with `peer`, send `Bind { token }` to
+    /// the rendezvous `port` so the relay learns this node's public UDP
+    /// address
+    PunchBind {
+        peer: String,
+        #[serde(with = "serde_bytes")]
+        token: Vec<u8>,
+        port: u16,
+    },
+
+    /// Relay -> both clients: they asked for a link with each other; punch
+    /// to `addr` with probes sealed under `key`
+    Introduce {
+        peer: String,
+        addr: String,
+        link_id: u64,
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+
+    /// Client -> relay: while this node has no session, wake it through
+    /// push `provider` (e.g. `"fcm"`) with the opaque `token` when a packet
+    /// for it is stored. Replaces the node's previous token; an empty
+    /// token removes it.
+    RegisterPush {
+        provider: String,
+        #[serde(with = "serde_bytes")]
+        token: Vec<u8>,
+    },
+
+    /// Relay -> client: outcome of `RegisterPush`; `enabled` is false when
+    /// the token was removed or the relay has no such provider
+    PushRegistered { enabled: bool },
+
+    /// Relay -> relay: open a federation link (see [`crate::federation`])
+    PeerHello {
+        version: u16,
+        relay_id: String,
+        /// Ed25519 public key (32 bytes); `relay_id` must be its hex encoding
+        #[serde(with = "serde_bytes")]
+        public_key: Vec<u8>,
+        /// Dialer's nonce; both relays sign it with the acceptor's
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+    },
+
+    /// Accepting relay -> dialing relay: its identity, its nonce and its
+    /// signature over both nonces (as acceptor); the dialer signs the same
+    /// nonces (as dialer) in `Auth`
+    PeerChallenge {
+        relay_id: String,
+        #[serde(with = "serde_bytes")]
+        public_key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        signature: Vec<u8>,
+    },
+
+    /// Relay -> relay: change in the set of nodes with a session on the
+    /// sender (concatenated presence digests). `full` replaces everything
+    /// announced before on this link.
+    Presence {
+        full: bool,
+        #[serde(with = "serde_bytes")]
+        added: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        removed: Vec<u8>,
+    },
+
+    /// Relay -> relay: packet for `to`, announced in the receiver's presence
+    Forward {
+        #[serde(with = "serde_bytes")]
+        id: Vec<u8>,
+        from: String,
+        to: String,
+        /// Relays the packet may still be forwarded to
+        hops: u8,
+        #[serde(with = "serde_bytes")]
+        packet: Vec<u8>,
+        /// Mailbox lifetime requested by the sender
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl_secs: Option<u32>,
+    },
+
+    /// Fatal error; the sender closes the connection afterwards
+    Error { code: ErrorCode, message: String },
+
+    /// Graceful close
+    Bye,
+}
+
+impl RelayMessage {
+    /// Short message name for logs and errors
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RelayMessage::Hello { .. } => "hello",
+            RelayMessage::Challenge { .. } => "challenge",
+            RelayMessage::Auth { .. } => "auth",
+            RelayMessage::Welcome { .. } => "welcome",
+            RelayMessage::Ping { .. } => "ping",
+            RelayMessage::Pong { .. } => "pong",
+            RelayMessage::Send { .. } => "send",
+            RelayMessage::SendResult { .. } => "send_result",
+            RelayMessage::Deliver { .. } => "deliver",
+            RelayMessage::Ack { .. } => "ack",
+            RelayMessage::Register { .. } => "register",
+            RelayMessage::Registered { .. } => "registered",
+            RelayMessage::Flood { .. } => "flood",
+            RelayMessage::Punch { .. } => "punch",
+            RelayMessage::PunchBind { .. } => "punch_bind",
+            RelayMessage::Introduce { .. } => "introduce",
+            RelayMessage::RegisterPush { .. } => "register_push",
+            RelayMessage::PushRegistered { .. } => "push_registered",
+            RelayMessage::PeerHello { .. } => "peer_hello",
+            RelayMessage::PeerChallenge { .. } => "peer_challenge",
+            RelayMessage::Presence { .. } => "presence",
+            RelayMessage::Forward { .. } => "forward",
+            RelayMessage::Error { .. } => "error",
+            RelayMessage::Bye => "bye",
+        }
+    }
+}
+
+fn is_zero(value: &u8) -> bool {
+    *value == 0
+}
+
+/// Outcome of a `Send`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Handed to the recipient's session, or to the federated relay
+    /// where the recipient has one
+    Delivered,
+    /// Recipient has no active session on this relay (for `Flood`: no
+    /// other session at all)
+    Offline,
+    /// Recipient is offline; the packet waits in the relay mailbox
+    Stored,
+    /// Recipient is offline and its mailbox is full
+    MailboxFull,
+    /// Sender exceeded its rate limit (or its `Flood` allowance)
+    RateLimited,
+    /// Packet exceeds the relay's size limit
+    TooLarge,
+}
+
+/// Reason for a fatal `Error`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    AuthFailed,
+    ProtocolViolation,
+    Overloaded,
+    /// Another session registered the same node id
+    Replaced,
+}
@@ -0,0 +1,338 @@
+//! Minimal Noise symmetric state (25519, ChaChaPoly, SHA256)
+//!
+//! Only what the datagram handshakes need: `MixHash`, `MixKey`,
+//! `MixKeyAndHash`, `EncryptAndHash`/`DecryptAndHash` and `Split`, as
+//! described in the Noise Protocol Framework, revision 34. The primitives
+//! come from the `sha2`, `hmac`, `hkdf` and `chacha20poly1305` crates; this
+//! module only chains them the way the framework prescribes, and is checked
+//! against the cacophony test vectors for the patterns in use.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+pub(crate) const HASH_LEN: usize = 32;
+pub(crate) const TAG_LEN: usize = 16;
+
+pub(crate) fn hash(parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// HMAC-SHA256 over the concatenation of `parts`
+pub(crate) fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; HASH_LEN] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Noise `HKDF`: RFC 5869 with the chaining key as salt and empty info
+fn hkdf<const N: usize>(chaining_key: &[u8; HASH_LEN], ikm: &[u8]) -> [[u8; HASH_LEN]; N] {
+    let mut okm = [0u8; 3 * HASH_LEN];
+    Hkdf::<Sha256>::new(Some(chaining_key), ikm)
+        .expand(&[], &mut okm[..N * HASH_LEN])
+        .expect("at most three outputs, well under the HKDF limit");
+
+    let mut outputs = [[0u8; HASH_LEN]; N];
+    for (output, chunk) in outputs.iter_mut().zip(okm.chunks_exact(HASH_LEN)) {
+        output.copy_from_slice(chunk);
+    }
+    outputs
+}
+
+/// Noise `HKDF` with two outputs
+pub(crate) fn hkdf2(chaining_key: &[u8; HASH_LEN], ikm: &[u8]) -> ([u8; HASH_LEN], [u8; HASH_LEN]) {
+    let [out1, out2] = hkdf(chaining_key, ikm);
+    (out1, out2)
+}
+
+/// Noise `HKDF` with three outputs
+pub(crate) fn hkdf3(
+    chaining_key: &[u8; HASH_LEN],
+    ikm: &[u8],
+) -> ([u8; HASH_LEN], [u8; HASH_LEN], [u8; HASH_LEN]) {
+    let [out1, out2, out3] = hkdf(chaining_key, ikm);
+    (out1, out2, out3)
+}
+
+/// ChaCha20-Poly1305 with the Noise nonce layout (32 zero bits + LE counter)
+pub(crate) fn seal(key: &[u8; 32], counter: u64, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&nonce(counter).into(), Payload { msg: plaintext, aad: ad })
+        .expect("ChaCha20-Poly1305 encryption cannot fail for in-memory buffers")
+}
+
+pub(crate) fn open(key: &[u8; 32], counter: u64, ad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&nonce(counter).into(), Payload { msg: ciphertext, aad: ad })
+        .ok()
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+#[derive(Clone)]
+pub(crate) struct SymmetricState {
+    chaining_key: [u8; HASH_LEN],
+    handshake_hash: [u8; HASH_LEN],
+    key: Option<[u8; 32]>,
+    counter: u64,
+}
+
+impl SymmetricState {
+    pub(crate) fn new(protocol_name: &[u8], prologue: &[u8]) -> Self {
+        let mut handshake_hash = [0u8; HASH_LEN];
+        if protocol_name.len() <= HASH_LEN {
+            handshake_hash[..protocol_name.len()].copy_from_slice(protocol_name);
+        } else {
+            handshake_hash = hash(&[protocol_name]);
+        }
+
+        let mut state = Self {
+            chaining_key: handshake_hash,
+            handshake_hash,
+            key: None,
+            counter: 0,
+        };
+        state.mix_hash(prologue);
+        state
+    }
+
+    pub(crate) fn mix_hash(&mut self, data: &[u8]) {
+        self.handshake_hash = hash(&[&self.handshake_hash, data]);
+    }
+
+    pub(crate) fn mix_key(&mut self, ikm: &[u8]) {
+        let (chaining_key, key) = hkdf2(&self.chaining_key, ikm);
+        self.chaining_key = chaining_key;
+        self.key = Some(key);
+        self.counter = 0;
+    }
+
+    pub(crate) fn mix_key_and_hash(&mut self, ikm: &[u8]) {
+        let (chaining_key, temp_hash, key) = hkdf3(&self.chaining_key, ikm);
+        self.chaining_key = chaining_key;
+        self.mix_hash(&temp_hash);
+        self.key = Some(key);
+        self.counter = 0;
+    }
+
+    pub(crate) fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match self.key {
+            Some(key) => {
+                let ciphertext = seal(&key, self.counter, &self.handshake_hash, plaintext);
+                self.counter += 1;
+                ciphertext
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    pub(crate) fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = match self.key {
+            Some(key) => {
+                let plaintext = open(&key, self.counter, &self.handshake_hash, ciphertext)?;
+                self.counter += 1;
+                plaintext
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    /// Secret both sides can derive after the handshake, used as the PSK
+    /// for later resumption
+    pub(crate) fn resumption_secret(&self) -> [u8; 32] {
+        hkdf2(&self.chaining_key, b"yaok-resumption").1
+    }
+
+    /// Transcript hash `h`; both ends hold the same value after the handshake
+    pub(crate) fn handshake_hash(&self) -> [u8; HASH_LEN] {
+        self.handshake_hash
+    }
+
+    /// Transport keys: (initiator -> responder, responder -> initiator)
+    pub(crate) fn split(&self) -> ([u8; 32], [u8; 32]) {
+        hkdf2(&self.chaining_key, &[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    // Cacophony test vectors (github.com/haskell-cryptography/cacophony):
+    // shared prologue and ephemeral keys, first three messages
+    const PROLOGUE: &str = "4a6f686e2047616c74";
+    const INIT_EPHEMERAL: &str = "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a";
+    const RESP_EPHEMERAL: &str = "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b";
+    const PAYLOADS: [&str; 3] = ["4c756477696720766f6e204d69736573", "4d757272617920526f746862617264", "462e20412e20486179656b"];
+
+    fn bytes(hex_str: &str) -> Vec<u8> {
+        hex::decode(hex_str).unwrap()
+    }
+
+    fn secret(hex_str: &str) -> StaticSecret {
+        StaticSecret::from(<[u8; 32]>::try_from(bytes(hex_str)).unwrap())
+    }
+
+    fn public(secret: &StaticSecret) -> [u8; 32] {
+        PublicKey::from(secret).to_bytes()
+    }
+
+    fn dh(secret: &StaticSecret, public: &[u8; 32]) -> [u8; 32] {
+        secret.diffie_hellman(&PublicKey::from(*public)).to_bytes()
+    }
+
+    /// Third message: first transport message, initiator to responder
+    fn transport_message(initiator: &SymmetricState, responder: &SymmetricState) -> Vec<u8> {
+        let (initiator_key, responder_key) = initiator.split();
+        assert_eq!(responder.split(), (initiator_key, responder_key));
+        let ciphertext = seal(&initiator_key, 0, &[], &bytes(PAYLOADS[2]));
+        assert_eq!(open(&initiator_key, 0, &[], &ciphertext).unwrap(), bytes(PAYLOADS[2]));
+        ciphertext
+    }
+
+    #[test]
+    fn noise_nx_matches_cacophony_vector() {
+        let protocol = b"Noise_NX_25519_ChaChaPoly_SHA256";
+        let init_e = secret(INIT_EPHEMERAL);
+        let resp_e = secret(RESP_EPHEMERAL);
+        let resp_s = secret("4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893");
+        let mut initiator = SymmetricState::new(protocol, &bytes(PROLOGUE));
+        let mut responder = SymmetricState::new(protocol, &bytes(PROLOGUE));
+
+        // -> e
+        let mut message = public(&init_e).to_vec();
+        initiator.mix_hash(&public(&init_e));
+        message.extend(initiator.encrypt_and_hash(&bytes(PAYLOADS[0])));
+        assert_eq!(
+            hex::encode(&message),
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573"
+        );
+        responder.mix_hash(&public(&init_e));
+        assert_eq!(responder.decrypt_and_hash(&message[32..]).unwrap(), bytes(PAYLOADS[0]));
+
+        // <- e, ee, s, es
+        let mut message = public(&resp_e).to_vec();
+        responder.mix_hash(&public(&resp_e));
+        responder.mix_key(&dh(&resp_e, &public(&init_e)));
+        message.extend(responder.encrypt_and_hash(&public(&resp_s)));
+        responder.mix_key(&dh(&resp_s, &public(&init_e)));
+        message.extend(responder.encrypt_and_hash(&bytes(PAYLOADS[1])));
+        assert_eq!(
+            hex::encode(&message),
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088430da8899553a0e2d18bb3bcdf63\
+             2634e25dd60e400ecc50c371de2cd83257c7636c5913e463b6bd3f3efe3eb1c9e92f10dde5d45c312e42ff98cfadd9f9e92b01ec\
+             7604e5d2150eef5db0aed53ab203"
+        );
+        initiator.mix_hash(&message[..32]);
+        initiator.mix_key(&dh(&init_e, &public(&resp_e)));
+        let rs: [u8; 32] = initiator.decrypt_and_hash(&message[32..80]).unwrap().try_into().unwrap();
+        initiator.mix_key(&dh(&init_e, &rs));
+        assert_eq!(initiator.decrypt_and_hash(&message[80..]).unwrap(), bytes(PAYLOADS[1]));
+
+        assert_eq!(
+            hex::encode(initiator.handshake_hash()),
+            "6959d38aed4b70824a50c722b47c07e00e88eb3eb14f351c11cbee4f56dac33b"
+        );
+        assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+        assert_eq!(
+            hex::encode(transport_message(&initiator, &responder)),
+            "deefd230bea16077f1ceecaad5e4284c3bf2c564e20f694a61b9d4"
+        );
+    }
+
+    #[test]
+    fn noise_nnpsk0_matches_cacophony_vector() {
+        let protocol = b"Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+        let psk = bytes("54686973206973206d7920417573747269616e20706572737065637469766521");
+        let init_e = secret(INIT_EPHEMERAL);
+        let resp_e = secret(RESP_EPHEMERAL);
+        let mut initiator = SymmetricState::new(protocol, &bytes(PROLOGUE));
+        let mut responder = SymmetricState::new(protocol, &bytes(PROLOGUE));
+
+        // -> psk, e (in PSK mode `e` is also mixed into the key)
+        initiator.mix_key_and_hash(&psk);
+        let mut message = public(&init_e).to_vec();
+        initiator.mix_hash(&public(&init_e));
+        initiator.mix_key(&public(&init_e));
+        message.extend(initiator.encrypt_and_hash(&bytes(PAYLOADS[0])));
+        assert_eq!(
+            hex::encode(&message),
+            "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c794479b962b8aff8485742ac32f905ba45369e\
+             2465fb59e138a93d67a0d1266b6a54"
+        );
+        responder.mix_key_and_hash(&psk);
+        responder.mix_hash(&message[..32]);
+        responder.mix_key(&message[..32]);
+        assert_eq!(responder.decrypt_and_hash(&message[32..]).unwrap(), bytes(PAYLOADS[0]));
+
+        // <- e, ee
+        let mut message = public(&resp_e).to_vec();
+        responder.mix_hash(&public(&resp_e));
+        responder.mix_key(&public(&resp_e));
+        responder.mix_key(&dh(&resp_e, &public(&init_e)));
+        message.extend(responder.encrypt_and_hash(&bytes(PAYLOADS[1])));
+        assert_eq!(
+            hex::encode(&message),
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843d6062704d5a9c422a8e834423f8c1f\
+             eada7e8d0d910a1a2cd030fb584221e3"
+        );
+        initiator.mix_hash(&message[..32]);
+        initiator.mix_key(&message[..32]);
+        initiator.mix_key(&dh(&init_e, &public(&resp_e)));
+        assert_eq!(initiator.decrypt_and_hash(&message[32..]).unwrap(), bytes(PAYLOADS[1]));
+
+        assert_eq!(
+            hex::encode(initiator.handshake_hash()),
+            "f4d03dc34495c95729ea6de9e1b59004b59733102488b3e24bc441e0be208eaf"
+        );
+        assert_eq!(
+            hex::encode(transport_message(&initiator, &responder)),
+            "e632c3763d7669067383433197a3baddf146e9e70ad4b4e9e59e0f"
+        );
+    }
+
+    #[test]
+    fn hmac_matches_rfc4231_case_2() {
+        let mac = hmac(b"Jefe", &[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(
+            hex::encode(mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn both_sides_derive_same_keys() {
+        let mut a = SymmetricState::new(b"Noise_NN_25519_ChaChaPoly_SHA256", b"test");
+        let mut b = SymmetricState::new(b"Noise_NN_25519_ChaChaPoly_SHA256", b"test");
+
+        a.mix_key(b"shared");
+        b.mix_key(b"shared");
+        let ciphertext = a.encrypt_and_hash(b"payload");
+        assert_eq!(b.decrypt_and_hash(&ciphertext).unwrap(), b"payload");
+        assert_eq!(a.split(), b.split());
+
+        // Tampering breaks authentication
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        let mut c = SymmetricState::new(b"Noise_NN_25519_ChaChaPoly_SHA256", b"test");
+        c.mix_key(b"shared");
+        assert!(c.decrypt_and_hash(&tampered).is_none());
+    }
+}
@@ -0,0 +1,299 @@
+//! Rendezvous and UDP hole punching for direct links between nodes
+//!
+//! A node that wants a direct link with `peer` sends `Punch { peer }` on
+//! its relay session. The relay answers with `PunchBind { peer, token,
+//! port }` and passes the request to `peer` as `Punch { peer: <node> }`;
+//! the node then sends [`RendezvousDatagram::Bind`] from its punching
+//! socket to the relay's rendezvous `port`, and the relay learns the
+//! node's public UDP address from the datagram's source. Only once both nodes asked for each
+//! other and both bound does the relay send each `Introduce` with the
+//! other's address and a fresh link key, so no node learns the address of
+//! one that did not agree.
+//!
+//! Both nodes then send [`PeerDatagram::Probe`] to each other's address:
+//! the first probes open the mappings of their NATs, later ones get
+//! through. Peer datagrams carry a truncated HMAC under a key derived from
+//! the link key for their direction, so a node never accepts its own
+//! datagrams reflected back, and a counter checked against a replay window;
+//! the packets themselves are end-to-end signed and encrypted as always.
+//!
+//! ```text
+//! Bind      [0x20][token:16][padding]               node -> relay
+//! Bound     [0x21][token:16][family][ip:4|16][port:2]  relay -> node
+//! Probe     [0x22][link_id:8][counter:8][nonce:8][mac:16]   node -> node
+//! ProbeAck  [0x23][link_id:8][counter:8][nonce:8][mac:16]
+//! Data      [0x24][link_id:8][counter:8][seq:8][packet][mac:16]
+//! DataAck   [0x25][link_id:8][counter:8][seq:8][mac:16]
+//! ```
+//!
+//! `Bind` is padded to [`MIN_BIND_LEN`] so the relay never answers with
+//! more bytes than it got.
+
+use crate::datagram::ReplayWindow;
+use crate::noise::hmac;
+use crate::ProtoError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Token the relay hands out in `PunchBind`
+pub const BIND_TOKEN_LEN: usize = 16;
+
+pub type BindToken = [u8; BIND_TOKEN_LEN];
+
+/// Length of the key in `Introduce`
+pub const LINK_KEY_LEN: usize = 32;
+
+/// `Bind` datagrams are padded to at least this size
+pub const MIN_BIND_LEN: usize = 64;
+
+/// Truncated HMAC-SHA256 on peer datagrams
+pub const PEER_MAC_LEN: usize = 16;
+
+const TYPE_BIND: u8 = 0x20;
+const TYPE_BOUND: u8 = 0x21;
+const TYPE_PROBE: u8 = 0x22;
+const TYPE_PROBE_ACK: u8 = 0x23;
+const TYPE_DATA: u8 = 0x24;
+const TYPE_DATA_ACK: u8 = 0x25;
+
+/// Domain separation for the per-direction keys of a link
+const PEER_KEY_CONTEXT: &[u8] = b"yaok-punch-v2";
+
+/// Type, link id, counter and nonce or sequence number
+const PEER_HEADER_LEN: usize = 25;
+
+/// Datagram between a node and the relay's rendezvous port
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RendezvousDatagram {
+    /// Node -> relay: the source address of this datagram belongs to the
+    /// node that got `token`
+    Bind { token: BindToken },
+    /// Relay -> node: the address the relay saw
+    Bound { token: BindToken, observed: SocketAddr },
+}
+
+impl RendezvousDatagram {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            RendezvousDatagram::Bind { token } => {
+                let mut datagram = Vec::with_capacity(MIN_BIND_LEN);
+                datagram.push(TYPE_BIND);
+                datagram.extend_from_slice(token);
+                datagram.resize(MIN_BIND_LEN, 0);
+                datagram
+            }
+            RendezvousDatagram::Bound { token, observed } => {
+                let mut datagram = vec![TYPE_BOUND];
+                datagram.extend_from_slice(token);
+                match observed.ip() {
+                    IpAddr::V4(ip) => {
+                        datagram.push(4);
+                        datagram.extend_from_slice(&ip.octets());
+                    }
+                    IpAddr::V6(ip) => {
+                        datagram.push(6);
+                        datagram.extend_from_slice(&ip.octets());
+                    }
+                }
+                datagram.extend_from_slice(&observed.port().to_be_bytes());
+                datagram
+            }
+        }
+    }
+
+    pub fn decode(datagram: &[u8]) -> Result<Self, ProtoError> {
+        let malformed = || ProtoError::Malformed("rendezvous datagram".to_string());
+        let (&kind, rest) = datagram.split_first().ok_or_else(malformed)?;
+        let token: BindToken = rest.get(..BIND_TOKEN_LEN).and_then(|t| t.try_into().ok()).ok_or_else(malformed)?;
+        let rest = &rest[BIND_TOKEN_LEN..];
+        match kind {
+            TYPE_BIND if datagram.len() >= MIN_BIND_LEN => Ok(RendezvousDatagram::Bind { token }),
+            TYPE_BOUND => {
+                let (ip, port) = match rest {
+                    [4, ip @ .., p0, p1] if ip.len() == 4 => {
+                        (IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())), u16::from_be_bytes([*p0, *p1]))
+                    }
+                    [6, ip @ .., p0, p1] if ip.len() == 16 => {
+                        (IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())), u16::from_be_bytes([*p0, *p1]))
+                    }
+                    _ => return Err(malformed()),
+                };
+                Ok(RendezvousDatagram::Bound { token, observed: SocketAddr::new(ip, port) })
+            }
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// Whether `datagram` is for the rendezvous port rather than a peer
+pub fn is_rendezvous_datagram(datagram: &[u8]) -> bool {
+    matches!(datagram.first(), Some(&TYPE_BIND | &TYPE_BOUND))
+}
+
+/// Datagram on a direct link
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerDatagram {
+    Probe { nonce: u64 },
+    ProbeAck { nonce: u64 },
+    /// One serialized packet
+    Data { seq: u64, packet: Vec<u8> },
+    DataAck { seq: u64 },
+}
+
+/// Link between two introduced nodes: both got the same id and key in
+/// `Introduce`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerLink {
+    pub link_id: u64,
+    send_key: [u8; LINK_KEY_LEN],
+    recv_key: [u8; LINK_KEY_LEN],
+    counter: u64,
+    window: ReplayWindow,
+}
+
+impl PeerLink {
+    /// Link of node `local` with node `peer`. Each direction gets its own
+    /// key, derived from `key` and the two node ids.
+    pub fn new(link_id: u64, key: &[u8], local: &str, peer: &str) -> Result<Self, ProtoError> {
+        let key: [u8; LINK_KEY_LEN] = key.try_into().map_err(|_| ProtoError::Malformed("link key".to_string()))?;
+        if local == peer {
+            return Err(ProtoError::Malformed("link to self".to_string()));
+        }
+        let direction = |from: &str, to: &str| hmac(&key, &[PEER_KEY_CONTEXT, from.as_bytes(), &[0], to.as_bytes()]);
+        Ok(Self {
+            link_id,
+            send_key: direction(local, peer),
+            recv_key: direction(peer, local),
+            counter: 0,
+            window: ReplayWindow::default(),
+        })
+    }
+
+    pub fn seal(&mut self, datagram: &PeerDatagram) -> Vec<u8> {
+        let (kind, number, packet): (u8, u64, &[u8]) = match datagram {
+            PeerDatagram::Probe { nonce } => (TYPE_PROBE, *nonce, &[]),
+            PeerDatagram::ProbeAck { nonce } => (TYPE_PROBE_ACK, *nonce, &[]),
+            PeerDatagram::Data { seq, packet } => (TYPE_DATA, *seq, packet),
+            PeerDatagram::DataAck { seq } => (TYPE_DATA_ACK, *seq, &[]),
+        };
+        let mut sealed = Vec::with_capacity(PEER_HEADER_LEN + packet.len() + PEER_MAC_LEN);
+        sealed.push(kind);
+        sealed.extend_from_slice(&self.link_id.to_be_bytes());
+        sealed.extend_from_slice(&self.counter.to_be_bytes());
+        sealed.extend_from_slice(&number.to_be_bytes());
+        sealed.extend_from_slice(packet);
+        let mac = peer_mac(&self.send_key, &sealed);
+        sealed.extend_from_slice(&mac);
+        self.counter += 1;
+        sealed
+    }
+
+    /// Check the MAC and the counter and decode; datagrams of other links
+    /// are refused. The replay window only advances for authentic datagrams.
+    pub fn open(&mut self, datagram: &[u8]) -> Result<PeerDatagram, ProtoError> {
+        if peer_link_id(datagram) != Some(self.link_id) || datagram.len() < PEER_HEADER_LEN + PEER_MAC_LEN {
+            return Err(ProtoError::Malformed("peer datagram".to_string()));
+        }
+        let (body, mac) = datagram.split_at(datagram.len() - PEER_MAC_LEN);
+        if peer_mac(&self.recv_key, body) != mac {
+            return Err(ProtoError::AuthFailed);
+        }
+        let counter = u64::from_be_bytes(body[9..17].try_into().unwrap());
+        if !self.window.is_fresh(counter) {
+            return Err(ProtoError::Replay);
+        }
+        let number = u64::from_be_bytes(body[17..25].try_into().unwrap());
+        let packet = &body[PEER_HEADER_LEN..];
+        let opened = match (body[0], packet.is_empty()) {
+            (TYPE_PROBE, true) => PeerDatagram::Probe { nonce: number },
+            (TYPE_PROBE_ACK, true) => PeerDatagram::ProbeAck { nonce: number },
+            (TYPE_DATA, _) => PeerDatagram::Data { seq: number, packet: packet.to_vec() },
+            (TYPE_DATA_ACK, true) => PeerDatagram::DataAck { seq: number },
+            _ => return Err(ProtoError::Malformed("peer datagram".to_string())),
+        };
+        self.window.mark(counter);
+        Ok(opened)
+    }
+}
+
+fn peer_mac(key: &[u8; LINK_KEY_LEN], body: &[u8]) -> [u8; PEER_MAC_LEN] {
+    let mut mac = [0u8; PEER_MAC_LEN];
+    mac.copy_from_slice(&hmac(key, &[body])[..PEER_MAC_LEN]);
+    mac
+}
+
+/// Link id of a peer datagram, from its header
+pub fn peer_link_id(datagram: &[u8]) -> Option<u64> {
+    match *datagram.first()? {
+        TYPE_PROBE | TYPE_PROBE_ACK | TYPE_DATA | TYPE_DATA_ACK => {
+            Some(u64::from_be_bytes(datagram.get(1..9)?.try_into().ok()?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendezvous_datagrams_round_trip() {
+        let token = [7u8; BIND_TOKEN_LEN];
+        let bind = RendezvousDatagram::Bind { token }.encode();
+        assert_eq!(bind.len(), MIN_BIND_LEN);
+        assert!(is_rendezvous_datagram(&bind));
+        assert_eq!(RendezvousDatagram::decode(&bind).unwrap(), RendezvousDatagram::Bind { token });
+        // Unpadded binds are refused
+        assert!(RendezvousDatagram::decode(&bind[..1 + BIND_TOKEN_LEN]).is_err());
+
+        for observed in ["203.0.113.7:40123", "[2001:db8::1]:5"] {
+            let bound = RendezvousDatagram::Bound { token, observed: observed.parse().unwrap() };
+            let encoded = bound.encode();
+            assert!(encoded.len() <= MIN_BIND_LEN);
+            assert_eq!(RendezvousDatagram::decode(&encoded).unwrap(), bound);
+        }
+    }
+
+    fn link_pair(link_id: u64, key: &[u8]) -> (PeerLink, PeerLink) {
+        (PeerLink::new(link_id, key, "alice", "bob").unwrap(), PeerLink::new(link_id, key, "bob", "alice").unwrap())
+    }
+
+    #[test]
+    fn peer_datagrams_are_authenticated() {
+        let (mut alice, mut bob) = link_pair(42, &[1u8; LINK_KEY_LEN]);
+        let data = PeerDatagram::Data { seq: 3, packet: b"packet".to_vec() };
+        let sealed = alice.seal(&data);
+        assert_eq!(peer_link_id(&sealed), Some(42));
+        assert!(!is_rendezvous_datagram(&sealed));
+        let mut tampered = sealed.clone();
+        tampered[PEER_HEADER_LEN] ^= 1;
+        assert!(matches!(bob.open(&tampered), Err(ProtoError::AuthFailed)));
+        assert_eq!(bob.open(&sealed).unwrap(), data);
+        assert_eq!(alice.open(&bob.seal(&PeerDatagram::Probe { nonce: 9 })).unwrap(), PeerDatagram::Probe { nonce: 9 });
+
+        let (_, mut other_key) = link_pair(42, &[2u8; LINK_KEY_LEN]);
+        assert!(matches!(other_key.open(&sealed), Err(ProtoError::AuthFailed)));
+        let (_, mut other_link) = link_pair(43, &[1u8; LINK_KEY_LEN]);
+        assert!(other_link.open(&sealed).is_err());
+        assert!(PeerLink::new(1, &[0u8; 16], "alice", "bob").is_err());
+        assert!(PeerLink::new(1, &[0u8; LINK_KEY_LEN], "alice", "alice").is_err());
+    }
+
+    #[test]
+    fn peer_datagrams_not_replayed_or_reflected() {
+        let (mut alice, mut bob) = link_pair(42, &[1u8; LINK_KEY_LEN]);
+        let first = alice.seal(&PeerDatagram::Probe { nonce: 1 });
+        let second = alice.seal(&PeerDatagram::Probe { nonce: 2 });
+
+        // Reflected back to its sender
+        assert!(matches!(alice.open(&first), Err(ProtoError::AuthFailed)));
+
+        // Out of order is fine, twice is not
+        assert!(bob.open(&second).is_ok());
+        assert!(bob.open(&first).is_ok());
+        assert!(matches!(bob.open(&first), Err(ProtoError::Replay)));
+        assert!(matches!(bob.open(&second), Err(ProtoError::Replay)));
+
+        // Counters of the two directions are independent
+        assert!(alice.open(&bob.seal(&PeerDatagram::ProbeAck { nonce: 2 })).is_ok());
+    }
+}
@@ -0,0 +1,95 @@
+//! Rotating recipient tags
+//!
+//! Besides its node id a client may be addressed by recipient tags: short
+//! values derived from a secret it shares with its contacts and the
+//! current epoch ([`recipient_tag`]). A sender addresses `Send` to the hex
+//! tag, so the relay routes by a value that changes every
+//! [`TAG_ROTATION_SECS`] and cannot be linked to the node id by anyone
+//! without the secret. The client registers its tags with a signed
+//! `Register` ([`crate::auth::register_payload`]); a registration covers
+//! at most [`MAX_TAG_LIFETIME_SECS`].
+
+use crate::ProtoError;
+use sha2::{Digest, Sha256};
+
+/// Length of a recipient tag
+pub const RECIPIENT_TAG_LEN: usize = 16;
+
+/// Recipient tag
+pub type RecipientTag = [u8; RECIPIENT_TAG_LEN];
+
+/// Tags rotate this often
+pub const TAG_ROTATION_SECS: u64 = 3600;
+
+/// Longest registration the relay accepts: the current and the next epoch
+pub const MAX_TAG_LIFETIME_SECS: u64 = 2 * TAG_ROTATION_SECS;
+
+/// Tags one registration may carry
+pub const MAX_TAGS_PER_REGISTRATION: usize = 8;
+
+/// Domain separation for tag derivation
+const TAG_CONTEXT: &[u8] = b"yaok-recipient-tag-v1";
+
+/// Epoch of unix time `unix_secs`
+pub fn tag_epoch(unix_secs: u64) -> u64 {
+    unix_secs / TAG_ROTATION_SECS
+}
+
+/// Tag for `epoch` derived from a secret shared with contacts
+pub fn recipient_tag(secret: &[u8], epoch: u64) -> RecipientTag {
+    let mut hasher = Sha256::new();
+    hasher.update(TAG_CONTEXT);
+    hasher.update((secret.len() as u32).to_be_bytes());
+    hasher.update(secret);
+    hasher.update(epoch.to_be_bytes());
+    let hash = hasher.finalize();
+    let mut tag = [0u8; RECIPIENT_TAG_LEN];
+    tag.copy_from_slice(&hash[..RECIPIENT_TAG_LEN]);
+    tag
+}
+
+/// Parse a `Send` recipient that is a hex tag rather than a node id
+pub fn parse_tag(to: &str) -> Option<RecipientTag> {
+    if to.len() != 2 * RECIPIENT_TAG_LEN {
+        return None;
+    }
+    hex::decode(to).ok()?.try_into().ok()
+}
+
+/// Pack tags into the `Register` wire format (concatenated)
+pub fn encode_tags<'a>(tags: impl IntoIterator<Item = &'a RecipientTag>) -> Vec<u8> {
+    tags.into_iter().flatten().copied().collect()
+}
+
+/// Unpack tags from a `Register` message
+pub fn decode_tags(bytes: &[u8]) -> Result<Vec<RecipientTag>, ProtoError> {
+    if !bytes.len().is_multiple_of(RECIPIENT_TAG_LEN) || bytes.len() > MAX_TAGS_PER_REGISTRATION * RECIPIENT_TAG_LEN {
+        return Err(ProtoError::Malformed(format!("recipient tags of {} bytes", bytes.len())));
+    }
+    Ok(bytes
+        .chunks_exact(RECIPIENT_TAG_LEN)
+        .map(|chunk| chunk.try_into().expect("chunk has tag length"))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_rotate_and_roundtrip() {
+        let epoch = tag_epoch(1_700_000_000);
+        let tag = recipient_tag(b"shared", epoch);
+        assert_eq!(tag, recipient_tag(b"shared", epoch));
+        assert_ne!(tag, recipient_tag(b"shared", epoch + 1));
+        assert_ne!(tag, recipient_tag(b"other", epoch));
+
+        assert_eq!(parse_tag(&hex::encode(tag)), Some(tag));
+        assert_eq!(parse_tag(&hex::encode([1u8; 32])), None);
+
+        let tags = [tag, recipient_tag(b"shared", epoch + 1)];
+        assert_eq!(decode_tags(&encode_tags(&tags)).unwrap(), tags);
+        assert!(decode_tags(&[0u8; RECIPIENT_TAG_LEN - 1]).is_err());
+        assert!(decode_tags(&[0u8; (MAX_TAGS_PER_REGISTRATION + 1) * RECIPIENT_TAG_LEN]).is_err());
+    }
+}
@@ -0,0 +1,554 @@
+//! Admin API: authenticated control of a running relay
+//!
+//! Requests carry `Authorization: Bearer <token>`. Each token has a name
+//! (recorded in the audit log) and a role: `read` may list peers, bans,
+//! rate limits and the audit log; `operator` may also evict peers, ban and
+//! unban address ranges and node ids, change the rate limits and drain the
+//! relay. Every operator request, allowed or refused, is audited.
+//!
+//! `Admin::handle` takes a plain `AdminRequest`, so the HTTP server in
+//! `main.rs` only adapts requests to it.
+//!
+//! | Method   | Path                    | Role     |
+//! |----------|-------------------------|----------|
+//! | `GET`    | `/admin/whoami`         | read     |
+//! | `GET`    | `/admin/peers`          | read     |
+//! | `DELETE` | `/admin/peers/{node}`   | operator |
+//! | `GET`    | `/admin/bans`           | read     |
+//! | `POST`   | `/admin/bans`           | operator |
+//! | `DELETE` | `/admin/bans`           | operator |
+//! | `GET`    | `/admin/rate-limit`     | read     |
+//! | `PUT`    | `/admin/rate-limit`     | operator |
+//! | `POST`   | `/admin/drain`          | operator |
+//! | `GET`    | `/admin/audit`          | read     |
+//!
+//! Ban bodies are `{"ip_range": "10.0.0.0/8"}` or `{"node_id": "..."}`;
+//! the rate limit body sets any of `ip_pps`, `node_pps`, `global_pps`,
+//! `ip_bytes_per_sec`, `node_bytes_per_sec` and `global_bytes_per_sec`
+//! (0 lifts a byte or global limit; see `crate::ratelimit`).
+
+use crate::bans::IpRange;
+use crate::mailbox::unix_now;
+use crate::server::RelayState;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+/// Shortest accepted admin token
+pub const MIN_TOKEN_LEN: usize = 16;
+
+/// Audit entries kept in memory for `/admin/audit`
+const AUDIT_RECENT: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Operator,
+}
+
+struct AdminToken {
+    name: String,
+    role: Role,
+    /// SHA-256 of the token; the token itself is not kept
+    digest: [u8; 32],
+}
+
+/// Accepted admin tokens
+#[derive(Default)]
+pub struct AdminTokens(Vec<AdminToken>);
+
+impl AdminTokens {
+    /// Parse comma-separated `name:role:token` entries (`ADMIN_TOKENS`)
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        for entry in spec.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(role), Some(token)) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(format!("Admin token entry must be name:role:token, got {:?}", name_of(entry)));
+            };
+            let role = match role {
+                "read" => Role::Read,
+                "operator" => Role::Operator,
+                other => return Err(format!("Unknown admin role {:?} for {}", other, name)),
+            };
+            if token.len() < MIN_TOKEN_LEN {
+                return Err(format!("Admin token for {} is shorter than {} characters", name, MIN_TOKEN_LEN));
+            }
+            tokens.push(AdminToken { name: name.to_string(), role, digest: Sha256::digest(token.as_bytes()).into() });
+        }
+        Ok(Self(tokens))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Name and role for an `Authorization` header value
+    fn authenticate(&self, authorization: Option<&str>) -> Option<(&str, Role)> {
+        let token = authorization?.strip_prefix("Bearer ")?.trim();
+        // Digests have a fixed length, so the comparison time says nothing
+        // about the token
+        let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        self.0.iter().find(|t| t.digest == digest).map(|t| (t.name.as_str(), t.role))
+    }
+}
+
+/// Name part of a malformed entry, without the secret
+fn name_of(entry: &str) -> &str {
+    entry.split(':').next().unwrap_or_default()
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    /// Unix seconds
+    pub time: u64,
+    /// Token name, or `-` if unauthenticated
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    /// `ok`, `denied`, `not found` or `invalid`
+    pub outcome: String,
+}
+
+/// Admin actions: the recent ones in memory, all of them appended to an
+/// optional JSON-lines file
+pub struct AuditLog {
+    recent: Mutex<VecDeque<AuditEntry>>,
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self { recent: Mutex::new(VecDeque::new()), file: None }
+    }
+
+    /// Also append every entry to `path`
+    pub fn with_file(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Some(Mutex::new(file)), ..Self::new() })
+    }
+
+    pub fn record(&self, entry: AuditEntry) {
+        info!(
+            "audit: actor={} action={} target={} outcome={}",
+            entry.actor, entry.action, entry.target, entry.outcome
+        );
+        if let Some(file) = &self.file {
+            let line = serde_json::to_string(&entry).unwrap_or_default();
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                warn!("Cannot write admin audit log: {}", e);
+            }
+        }
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == AUDIT_RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(entry);
+    }
+
+    /// Recent entries, newest first
+    pub fn recent(&self) -> Vec<AuditEntry> {
+        self.recent.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct AdminRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    /// `Authorization` header value
+    pub authorization: Option<&'a str>,
+    pub body: &'a [u8],
+}
+
+/// JSON response
+#[derive(Debug)]
+pub struct AdminResponse {
+    pub status: u16,
+    pub body: String,
+}
+
+impl AdminResponse {
+    fn json(status: u16, body: Value) -> Self {
+        Self { status, body: body.to_string() }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+}
+
+enum Route<'a> {
+    WhoAmI,
+    Peers,
+    Evict(&'a str),
+    Bans,
+    Ban,
+    Unban,
+    RateLimit,
+    SetRateLimit,
+    Drain,
+    Audit,
+}
+
+impl<'a> Route<'a> {
+    fn parse(method: &str, path: &'a str) -> Option<Self> {
+        let path = path.strip_prefix("/admin/")?.trim_end_matches('/');
+        Some(match (method, path) {
+            ("GET", "whoami") => Route::WhoAmI,
+            ("GET", "peers") => Route::Peers,
+            ("DELETE", path) => match path.strip_prefix("peers/") {
+                Some(node_id) if !node_id.is_empty() && !node_id.contains('/') => Route::Evict(node_id),
+                _ if path == "bans" => Route::Unban,
+                _ => return None,
+            },
+            ("GET", "bans") => Route::Bans,
+            ("POST", "bans") => Route::Ban,
+            ("GET", "rate-limit") => Route::RateLimit,
+            ("PUT", "rate-limit") => Route::SetRateLimit,
+            ("POST", "drain") => Route::Drain,
+            ("GET", "audit") => Route::Audit,
+            _ => return None,
+        })
+    }
+
+    /// Action name in the audit log; `None` for read-only routes
+    fn action(&self) -> Option<&'static str> {
+        match self {
+            Route::Evict(_) => Some("evict"),
+            Route::Ban => Some("ban"),
+            Route::Unban => Some("unban"),
+            Route::SetRateLimit => Some("set_rate_limit"),
+            Route::Drain => Some("drain"),
+            _ => None,
+        }
+    }
+
+    fn role(&self) -> Role {
+        if self.action().is_some() {
+            Role::Operator
+        } else {
+            Role::Read
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BanRequest {
+    ip_range: Option<String>,
+    node_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitRequest {
+    ip_pps: Option<u32>,
+    node_pps: Option<u32>,
+    global_pps: Option<u32>,
+    ip_bytes_per_sec: Option<u64>,
+    node_bytes_per_sec: Option<u64>,
+    global_bytes_per_sec: Option<u64>,
+}
+
+/// Outcome of an operator action: response plus audit target and outcome
+struct Outcome {
+    response: AdminResponse,
+    target: String,
+}
+
+impl Outcome {
+    fn new(target: impl Into<String>, response: AdminResponse) -> Self {
+        Self { response, target: target.into() }
+    }
+
+    fn invalid(message: &str) -> Self {
+        Self::new("", AdminResponse::error(400, message))
+    }
+}
+
+pub struct Admin {
+    state: Arc<RelayState>,
+    tokens: AdminTokens,
+    audit: AuditLog,
+}
+
+impl Admin {
+    pub fn new(state: Arc<RelayState>, tokens: AdminTokens, audit: AuditLog) -> Self {
+        Self { state, tokens, audit }
+    }
+
+    pub fn state(&self) -> &Arc<RelayState> {
+        &self.state
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub fn handle(&self, request: &AdminRequest<'_>) -> AdminResponse {
+        let Some(route) = Route::parse(request.method, request.path) else {
+            return AdminResponse::error(404, "Unknown admin endpoint");
+        };
+        let identity = self.tokens.authenticate(request.authorization);
+
+        let audit = |actor: &str, target: &str, outcome: &str| {
+            if let Some(action) = route.action() {
+                self.audit.record(AuditEntry {
+                    time: unix_now(),
+                    actor: actor.to_string(),
+                    action: action.to_string(),
+                    target: target.to_string(),
+                    outcome: outcome.to_string(),
+                });
+            }
+        };
+
+        let Some((name, role)) = identity else {
+            audit("-", "", "denied");
+            return AdminResponse::error(401, "Missing or invalid admin token");
+        };
+        if role < route.role() {
+            audit(name, "", "denied");
+            return AdminResponse::error(403, "Operator role required");
+        }
+
+        let outcome = match route {
+            Route::WhoAmI => return AdminResponse::json(200, json!({ "name": name, "role": role })),
+            Route::Peers => return AdminResponse::json(200, json!(self.state.peers())),
+            Route::Bans => return AdminResponse::json(200, json!(self.state.bans())),
+            Route::RateLimit => return self.rate_limit_response(),
+            Route::Audit => return AdminResponse::json(200, json!(self.audit.recent())),
+            Route::Evict(node_id) => {
+                let response = if self.state.evict(node_id) {
+                    AdminResponse::json(200, json!({ "evicted": 1 }))
+                } else {
+                    AdminResponse::error(404, "No session for this node")
+                };
+                Outcome::new(node_id, response)
+            }
+            Route::Ban => self.ban(request.body),
+            Route::Unban => self.unban(request.body),
+            Route::SetRateLimit => self.set_rate_limit(request.body),
+            Route::Drain => {
+                let sessions = self.state.session_count();
+                self.state.close_sessions();
+                Outcome::new("", AdminResponse::json(200, json!({ "draining": true, "sessions": sessions })))
+            }
+        };
+
+        let result = match outcome.response.status {
+            200 => "ok",
+            404 => "not found",
+            _ => "invalid",
+        };
+        audit(name, &outcome.target, result);
+        outcome.response
+    }
+
+    fn rate_limit_response(&self) -> AdminResponse {
+        let limits = self.state.limits();
+        AdminResponse::json(
+            200,
+            json!({
+                "ip_pps": limits.rate_limit_pps,
+                "node_pps": limits.node_rate_limit_pps,
+                "global_pps": limits.global_rate_limit_pps,
+                "ip_bytes_per_sec": limits.rate_limit_bytes_per_sec,
+                "node_bytes_per_sec": limits.node_rate_limit_bytes_per_sec,
+                "global_bytes_per_sec": limits.global_rate_limit_bytes_per_sec,
+            }),
+        )
+    }
+
+    fn ban(&self, body: &[u8]) -> Outcome {
+        match serde_json::from_slice::<BanRequest>(body) {
+            Ok(BanRequest { ip_range: Some(range), node_id: None }) => match range.parse::<IpRange>() {
+                Ok(parsed) => {
+                    let evicted = self.state.ban_ip_range(parsed);
+                    Outcome::new(parsed.to_string(), AdminResponse::json(200, json!({ "evicted": evicted })))
+                }
+                Err(e) => Outcome::new(range, AdminResponse::error(400, &e)),
+            },
+            Ok(BanRequest { ip_range: None, node_id: Some(node_id) }) if !node_id.is_empty() => {
+                let evicted = usize::from(self.state.ban_node(&node_id));
+                Outcome::new(node_id, AdminResponse::json(200, json!({ "evicted": evicted })))
+            }
+            _ => Outcome::invalid("Expected {\"ip_range\": ...} or {\"node_id\": ...}"),
+        }
+    }
+
+    fn unban(&self, body: &[u8]) -> Outcome {
+        let (target, removed) = match serde_json::from_slice::<BanRequest>(body) {
+            Ok(BanRequest { ip_range: Some(range), node_id: None }) => match range.parse::<IpRange>() {
+                Ok(parsed) => (parsed.to_string(), self.state.unban_ip_range(&parsed)),
+                Err(e) => return Outcome::new(range, AdminResponse::error(400, &e)),
+            },
+            Ok(BanRequest { ip_range: None, node_id: Some(node_id) }) => {
+                let removed = self.state.unban_node(&node_id);
+                (node_id, removed)
+            }
+            _ => return Outcome::invalid("Expected {\"ip_range\": ...} or {\"node_id\": ...}"),
+        };
+        let response = if removed {
+            AdminResponse::json(200, json!({ "removed": true }))
+        } else {
+            AdminResponse::error(404, "No such ban")
+        };
+        Outcome::new(target, response)
+    }
+
+    fn set_rate_limit(&self, body: &[u8]) -> Outcome {
+        let Ok(request) = serde_json::from_slice::<RateLimitRequest>(body) else {
+            return Outcome::invalid("Expected {\"ip_pps\": ..., \"node_pps\": ...}");
+        };
+        if request.ip_pps == Some(0) || request.node_pps == Some(0) {
+            return Outcome::invalid("Rate limits must be positive");
+        }
+        let mut limits = self.state.limits();
+        let max_packet = self.state.config().max_packet as u64;
+        for bytes_per_sec in [request.ip_bytes_per_sec, request.node_bytes_per_sec, request.global_bytes_per_sec] {
+            let burst = bytes_per_sec.unwrap_or(0).saturating_mul(u64::from(limits.rate_burst_secs));
+            if bytes_per_sec.is_some_and(|bytes| bytes > 0) && burst < max_packet {
+                return Outcome::invalid("Byte limits must let the largest packet through");
+            }
+        }
+
+        let mut changes = Vec::new();
+        update(&mut changes, "ip_pps", &mut limits.rate_limit_pps, request.ip_pps);
+        update(&mut changes, "node_pps", &mut limits.node_rate_limit_pps, request.node_pps);
+        update(&mut changes, "global_pps", &mut limits.global_rate_limit_pps, request.global_pps);
+        update(&mut changes, "ip_bytes_per_sec", &mut limits.rate_limit_bytes_per_sec, request.ip_bytes_per_sec);
+        update(&mut changes, "node_bytes_per_sec", &mut limits.node_rate_limit_bytes_per_sec, request.node_bytes_per_sec);
+        update(&mut changes, "global_bytes_per_sec", &mut limits.global_rate_limit_bytes_per_sec, request.global_bytes_per_sec);
+        self.state.set_limits(limits);
+        Outcome::new(changes.join(" "), self.rate_limit_response())
+    }
+}
+
+/// Set `field` to `value` if given, noting the change for the audit log
+fn update<T: Copy + std::fmt::Display>(changes: &mut Vec<String>, name: &str, field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+        changes.push(format!("{}={}", name, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::RelayConfig;
+
+    const READ_TOKEN: &str = "read-token-0123456789";
+    const OPERATOR_TOKEN: &str = "operator-token-0123456789";
+
+    fn admin() -> Admin {
+        let tokens = AdminTokens::parse(&format!("grafana:read:{},alice:operator:{}", READ_TOKEN, OPERATOR_TOKEN)).unwrap();
+        Admin::new(Arc::new(RelayState::new(RelayConfig::default())), tokens, AuditLog::new())
+    }
+
+    fn call(admin: &Admin, method: &str, path: &str, token: Option<&str>, body: &str) -> AdminResponse {
+        let authorization = token.map(|token| format!("Bearer {}", token));
+        admin.handle(&AdminRequest { method, path, authorization: authorization.as_deref(), body: body.as_bytes() })
+    }
+
+    #[test]
+    fn tokens_are_parsed_and_checked() {
+        assert!(AdminTokens::parse("alice:operator:short").is_err());
+        assert!(AdminTokens::parse("alice:root:operator-token-0123456789").is_err());
+        assert!(AdminTokens::parse("alice-operator-token-0123456789").is_err());
+        assert!(AdminTokens::parse("").unwrap().is_empty());
+
+        let admin = admin();
+        assert_eq!(call(&admin, "GET", "/admin/peers", None, "").status, 401);
+        assert_eq!(call(&admin, "GET", "/admin/peers", Some("wrong-token-0123456789"), "").status, 401);
+        let whoami = call(&admin, "GET", "/admin/whoami", Some(READ_TOKEN), "");
+        assert_eq!(whoami.status, 200);
+        assert_eq!(serde_json::from_str::<Value>(&whoami.body).unwrap(), json!({ "name": "grafana", "role": "read" }));
+        assert_eq!(call(&admin, "GET", "/admin/nothing", Some(READ_TOKEN), "").status, 404);
+    }
+
+    #[test]
+    fn operator_actions_need_the_operator_role_and_are_audited() {
+        let admin = admin();
+        let body = r#"{"ip_range": "203.0.113.0/24"}"#;
+        assert_eq!(call(&admin, "POST", "/admin/bans", Some(READ_TOKEN), body).status, 403);
+        assert_eq!(call(&admin, "POST", "/admin/bans", None, body).status, 401);
+        assert!(admin.state().bans().ip_ranges.is_empty());
+
+        assert_eq!(call(&admin, "POST", "/admin/bans", Some(OPERATOR_TOKEN), body).status, 200);
+        assert!(admin.state().bans().is_ip_banned("203.0.113.7".parse().unwrap()));
+        assert_eq!(call(&admin, "POST", "/admin/bans", Some(OPERATOR_TOKEN), r#"{"ip_range": "nope"}"#).status, 400);
+        assert_eq!(call(&admin, "DELETE", "/admin/bans", Some(OPERATOR_TOKEN), body).status, 200);
+        assert_eq!(call(&admin, "DELETE", "/admin/bans", Some(OPERATOR_TOKEN), body).status, 404);
+
+        let limits = call(&admin, "PUT", "/admin/rate-limit", Some(OPERATOR_TOKEN), r#"{"node_pps": 7}"#);
+        assert_eq!(limits.status, 200);
+        assert_eq!(admin.state().rate_limits(), (RelayConfig::default().rate_limit_pps, 7));
+        assert_eq!(call(&admin, "PUT", "/admin/rate-limit", Some(OPERATOR_TOKEN), r#"{"ip_pps": 0}"#).status, 400);
+        let body = r#"{"global_pps": 1000, "node_bytes_per_sec": 100}"#;
+        assert_eq!(call(&admin, "PUT", "/admin/rate-limit", Some(OPERATOR_TOKEN), body).status, 400);
+        let body = r#"{"global_pps": 1000, "node_bytes_per_sec": 0}"#;
+        let limits = call(&admin, "PUT", "/admin/rate-limit", Some(OPERATOR_TOKEN), body);
+        assert_eq!(serde_json::from_str::<Value>(&limits.body).unwrap()["global_pps"], 1000);
+        assert_eq!(admin.state().limits().node_rate_limit_bytes_per_sec, 0);
+
+        assert_eq!(call(&admin, "DELETE", "/admin/peers/unknown", Some(OPERATOR_TOKEN), "").status, 404);
+        assert_eq!(call(&admin, "POST", "/admin/drain", Some(OPERATOR_TOKEN), "").status, 200);
+        assert!(admin.state().is_closing());
+
+        // Reads are not audited; every operator request is, newest first
+        let audit: Vec<(String, String, String)> = admin
+            .audit()
+            .recent()
+            .into_iter()
+            .map(|entry| (entry.actor, entry.action, entry.outcome))
+            .collect();
+        let expected = [
+            ("alice", "drain", "ok"),
+            ("alice", "evict", "not found"),
+            ("alice", "set_rate_limit", "ok"),
+            ("alice", "set_rate_limit", "invalid"),
+            ("alice", "set_rate_limit", "invalid"),
+            ("alice", "set_rate_limit", "ok"),
+            ("alice", "unban", "not found"),
+            ("alice", "unban", "ok"),
+            ("alice", "ban", "invalid"),
+            ("alice", "ban", "ok"),
+            ("-", "ban", "denied"),
+            ("grafana", "ban", "denied"),
+        ];
+        let expected: Vec<(String, String, String)> =
+            expected.iter().map(|(a, b, c)| (a.to_string(), b.to_string(), c.to_string())).collect();
+        assert_eq!(audit, expected);
+        assert_eq!(call(&admin, "GET", "/admin/audit", Some(READ_TOKEN), "").status, 200);
+    }
+
+    #[test]
+    fn audit_log_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("yaok-audit-{}.log", rand::random::<u64>()));
+        let tokens = AdminTokens::parse(&format!("alice:operator:{}", OPERATOR_TOKEN)).unwrap();
+        let state = Arc::new(RelayState::new(RelayConfig::default()));
+        let admin = Admin::new(state, tokens, AuditLog::with_file(&path).unwrap());
+        call(&admin, "POST", "/admin/bans", Some(OPERATOR_TOKEN), r#"{"node_id": "node-1"}"#);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let entry: Value = serde_json::from_str(contents.lines().next().unwrap()).unwrap();
+        assert_eq!(entry["actor"], "alice");
+        assert_eq!(entry["action"], "ban");
+        assert_eq!(entry["target"], "node-1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Banned source address ranges and node ids
+//!
+//! Set through the admin API (`crate::admin`). A banned address is refused
+//! before the handshake, a banned node right after it; sessions already
+//! open are evicted when the ban is added.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Address range in CIDR notation; a bare address is a single-host range
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl IpRange {
+    /// The network of `ip` with `ipv4_prefix` or `ipv6_prefix` bits
+    pub fn covering(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        match unmap(ip) {
+            IpAddr::V4(v4) => {
+                let prefix = ipv4_prefix.min(32);
+                let network = network(u128::from(u32::from(v4)), prefix, 32) as u32;
+                Self { network: IpAddr::V4(network.into()), prefix }
+            }
+            IpAddr::V6(v6) => {
+                let prefix = ipv6_prefix.min(128);
+                Self { network: IpAddr::V6(network(u128::from(v6), prefix, 128).into()), prefix }
+            }
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, unmap(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                mask(u128::from(u32::from(network)), self.prefix, 32) == mask(u128::from(u32::from(ip)), self.prefix, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                mask(u128::from(network), self.prefix, 128) == mask(u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 peers (dual-stack sockets) count as IPv4
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+/// Keep the top `prefix` bits of a `bits`-wide address
+fn mask(addr: u128, prefix: u8, bits: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        addr >> (bits - prefix)
+    }
+}
+
+/// `addr` with everything after the top `prefix` bits cleared
+fn network(addr: u128, prefix: u8, bits: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        mask(addr, prefix, bits) << (bits - prefix)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.trim().parse().map_err(|_| format!("Invalid address: {}", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| format!("Invalid prefix: {}", s))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl Serialize for IpRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let range = String::deserialize(deserializer)?;
+        range.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Default, Clone, Debug, Serialize)]
+pub struct Bans {
+    pub ip_ranges: BTreeSet<IpRange>,
+    pub node_ids: BTreeSet<String>,
+}
+
+impl Bans {
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.ip_ranges.iter().any(|range| range.contains(ip))
+    }
+
+    pub fn is_node_banned(&self, node_id: &str) -> bool {
+        self.node_ids.contains(node_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_match_by_prefix() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains("10.1.200.3".parse().unwrap()));
+        assert!(!range.contains("10.2.0.1".parse().unwrap()));
+        assert!(range.contains("::ffff:10.1.0.9".parse().unwrap()));
+        assert_eq!(range.to_string(), "10.1.0.0/16");
+
+        let host: IpRange = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!v6.contains("10.1.0.1".parse().unwrap()));
+
+        let all: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("relay.example".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn covering_network_clears_host_bits() {
+        let covering = |ip: &str| IpRange::covering(ip.parse().unwrap(), 24, 64).to_string();
+        assert_eq!(covering("198.51.100.77"), "198.51.100.0/24");
+        assert_eq!(covering("::ffff:198.51.100.77"), "198.51.100.0/24");
+        assert_eq!(covering("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::/64");
+        assert_eq!(IpRange::covering("192.0.2.1".parse().unwrap(), 32, 128).to_string(), "192.0.2.1/32");
+        assert_eq!(IpRange::covering("192.0.2.1".parse().unwrap(), 0, 0).to_string(), "0.0.0.0/0");
+    }
+}
@@ -0,0 +1,912 @@
+//! Relay configuration: a TOML file with environment overrides
+//!
+//! The file (`--config <path>` or `RELAY_CONFIG`) is optional and every
+//! setting has a default. Each setting can also be set by an environment
+//! variable (`ENV_OVERRIDES`), which wins over the file. Invalid values
+//! are reported all together at startup instead of falling back to
+//! defaults; `--check-config` only loads and validates.
+//!
+//! On reload (SIGHUP) limits, mailbox quotas and TTL, and bans are
+//! applied to the running relay without closing sessions. Listen
+//! addresses, TLS files, the store, rendezvous, push, federation and
+//! admin settings need a restart.
+
+use crate::admin::AdminTokens;
+use crate::bans::IpRange;
+use crate::federation::{FederationConfig, FederationPeer};
+use crate::mailbox::MailboxConfig;
+use crate::net::dual_stack;
+use crate::push::PushConfig;
+use crate::server::{Limits, RelayConfig, RelayState};
+use crate::web::WebConfig;
+use crate::stats::MAX_PEERS;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use yaok_relay_proto::pow::MAX_POW_DIFFICULTY;
+use yaok_relay_proto::{Endpoint, DEFAULT_KEEPALIVE_SECS, MAX_FRAME_LEN};
+
+/// Environment variable for each setting, as `section.key`
+pub const ENV_OVERRIDES: &[(&str, &str)] = &[
+    ("server.port", "RELAY_PORT"),
+    ("server.bind", "RELAY_BIND"),
+    ("server.udp_bind", "RELAY_UDP_BIND"),
+    ("server.max_packet_size", "MAX_PACKET_SIZE"),
+    ("server.keepalive_secs", "KEEPALIVE_SECS"),
+    ("server.static_key", "RELAY_STATIC_KEY"),
+    ("server.static_key_file", "RELAY_STATIC_KEY_FILE"),
+    ("server.fallback_relay", "FALLBACK_RELAY"),
+    ("server.relay_list_file", "RELAY_LIST_FILE"),
+    ("limits.rate_limit_pps", "RATE_LIMIT_PPS"),
+    ("limits.node_rate_limit_pps", "NODE_RATE_LIMIT_PPS"),
+    ("limits.rate_limit_bytes_per_sec", "RATE_LIMIT_BYTES_PER_SEC"),
+    ("limits.node_rate_limit_bytes_per_sec", "NODE_RATE_LIMIT_BYTES_PER_SEC"),
+    ("limits.global_rate_limit_pps", "GLOBAL_RATE_LIMIT_PPS"),
+    ("limits.global_rate_limit_bytes_per_sec", "GLOBAL_RATE_LIMIT_BYTES_PER_SEC"),
+    ("limits.rate_limit_ipv4_prefix", "RATE_LIMIT_IPV4_PREFIX"),
+    ("limits.rate_limit_ipv6_prefix", "RATE_LIMIT_IPV6_PREFIX"),
+    ("limits.rate_burst_secs", "RATE_BURST_SECS"),
+    ("limits.emergency_reserve_percent", "EMERGENCY_RESERVE_PERCENT"),
+    ("limits.max_sessions_per_ip", "MAX_SESSIONS_PER_IP"),
+    ("limits.pow_difficulty", "POW_DIFFICULTY"),
+    ("limits.pow_load_percent", "POW_LOAD_PERCENT"),
+    ("limits.flood_interval_secs", "FLOOD_INTERVAL_SECS"),
+    ("metrics.port", "METRICS_PORT"),
+    ("metrics.interval_secs", "METRICS_INTERVAL_SECS"),
+    ("tls.cert_path", "TLS_CERT_PATH"),
+    ("tls.key_path", "TLS_KEY_PATH"),
+    ("mailbox.dir", "MAILBOX_DIR"),
+    ("mailbox.ttl_secs", "MAILBOX_TTL_SECS"),
+    ("mailbox.max_packets", "MAILBOX_MAX_PACKETS"),
+    ("mailbox.max_bytes", "MAILBOX_MAX_BYTES"),
+    ("store.dir", "RELAY_STORE_DIR"),
+    ("rendezvous.port", "RENDEZVOUS_PORT"),
+    ("push.fcm_url", "PUSH_FCM_URL"),
+    ("push.fcm_authorization", "PUSH_FCM_AUTHORIZATION"),
+    ("push.fcm_service_account", "PUSH_FCM_SERVICE_ACCOUNT"),
+    ("push.min_interval_secs", "PUSH_MIN_INTERVAL_SECS"),
+    ("push.max_per_hour", "PUSH_MAX_PER_HOUR"),
+    ("federation.key", "FEDERATION_KEY"),
+    ("federation.peers", "FEDERATION_PEERS"),
+    ("federation.peers_file", "FEDERATION_PEERS_FILE"),
+    ("admin.tokens", "ADMIN_TOKENS"),
+    ("admin.audit_log", "ADMIN_AUDIT_LOG"),
+    ("web.public_url", "RELAY_PUBLIC_URL"),
+    ("web.play_store_url", "PLAY_STORE_URL"),
+    ("web.app_store_url", "APP_STORE_URL"),
+];
+
+/// Every problem found while loading, one message each
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.join("\n"))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(error: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub limits: LimitsSection,
+    pub metrics: MetricsSection,
+    pub tls: TlsSection,
+    pub mailbox: MailboxSection,
+    pub store: StoreSection,
+    pub rendezvous: RendezvousSection,
+    pub push: PushSection,
+    pub federation: FederationSection,
+    pub admin: AdminSection,
+    pub bans: BansSection,
+    pub web: WebSection,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    pub port: u16,
+    /// Session listener; `[::]:<port>` (IPv6 and IPv4) by default. Use an
+    /// interface address: a DNS name here can fail on some platforms.
+    pub bind: Option<String>,
+    /// Datagram socket; same as `bind` by default
+    pub udp_bind: Option<String>,
+    pub max_packet_size: usize,
+    pub keepalive_secs: u32,
+    /// Datagram link key (hex X25519 secret); clients pin its fingerprint
+    pub static_key: Option<String>,
+    /// File holding the key, created on first start
+    pub static_key_file: Option<PathBuf>,
+    /// Relay that clients may fall back to, shown in `/health`
+    pub fallback_relay: Option<String>,
+    /// Signed relay list served at `/relays`, re-read on every request
+    pub relay_list_file: Option<PathBuf>,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        Self {
+            port: 40100,
+            bind: None,
+            udp_bind: None,
+            max_packet_size: 64_000,
+            keepalive_secs: DEFAULT_KEEPALIVE_SECS,
+            static_key: None,
+            static_key_file: None,
+            fallback_relay: None,
+            relay_list_file: None,
+        }
+    }
+}
+
+impl ServerSection {
+    pub fn bind_addr(&self) -> String {
+        self.bind.clone().unwrap_or_else(|| dual_stack(self.port).to_string())
+    }
+
+    pub fn udp_bind_addr(&self) -> String {
+        self.udp_bind.clone().unwrap_or_else(|| self.bind_addr())
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub rate_limit_pps: u32,
+    pub node_rate_limit_pps: u32,
+    /// 0 means unlimited, as for the other byte and global budgets
+    pub rate_limit_bytes_per_sec: u64,
+    pub node_rate_limit_bytes_per_sec: u64,
+    pub global_rate_limit_pps: u32,
+    pub global_rate_limit_bytes_per_sec: u64,
+    pub rate_limit_ipv4_prefix: u8,
+    pub rate_limit_ipv6_prefix: u8,
+    pub rate_burst_secs: u32,
+    pub emergency_reserve_percent: u8,
+    pub max_sessions_per_ip: usize,
+    /// 0 disables proof of work
+    pub pow_difficulty: u8,
+    pub pow_load_percent: u8,
+    pub flood_interval_secs: u64,
+}
+
+impl Default for LimitsSection {
+    fn default() -> Self {
+        let limits = RelayConfig::default().limits();
+        Self {
+            rate_limit_pps: limits.rate_limit_pps,
+            node_rate_limit_pps: limits.node_rate_limit_pps,
+            rate_limit_bytes_per_sec: limits.rate_limit_bytes_per_sec,
+            node_rate_limit_bytes_per_sec: limits.node_rate_limit_bytes_per_sec,
+            global_rate_limit_pps: limits.global_rate_limit_pps,
+            global_rate_limit_bytes_per_sec: limits.global_rate_limit_bytes_per_sec,
+            rate_limit_ipv4_prefix: limits.rate_limit_ipv4_prefix,
+            rate_limit_ipv6_prefix: limits.rate_limit_ipv6_prefix,
+            rate_burst_secs: limits.rate_burst_secs,
+            emergency_reserve_percent: limits.emergency_reserve_percent,
+            max_sessions_per_ip: limits.max_sessions_per_ip,
+            pow_difficulty: limits.pow_difficulty,
+            pow_load_percent: limits.pow_load_percent,
+            flood_interval_secs: limits.flood_interval.as_secs(),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    pub port: u16,
+    /// How often stats are logged and published
+    pub interval_secs: u64,
+}
+
+impl Default for MetricsSection {
+    fn default() -> Self {
+        Self { port: 9090, interval_secs: 60 }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSection {
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailboxSection {
+    /// Packets are kept in memory only without it
+    pub dir: Option<PathBuf>,
+    pub ttl_secs: u64,
+    pub max_packets: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for MailboxSection {
+    fn default() -> Self {
+        let config = MailboxConfig::default();
+        Self {
+            dir: None,
+            ttl_secs: config.max_ttl.as_secs(),
+            max_packets: config.max_packets,
+            max_bytes: config.max_bytes,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreSection {
+    /// Tag registrations and counters are lost on restart without it
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RendezvousSection {
+    /// UDP port for hole punching (`[::]:<port>`); off without it
+    pub port: Option<u16>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushSection {
+    /// FCM-style endpoint wakeups are posted to; push is off without it
+    pub fcm_url: Option<String>,
+    /// Fixed `Authorization` header sent to `fcm_url` (`Bearer …` or `key=…`)
+    pub fcm_authorization: Option<String>,
+    /// JSON key file of a Google service account; access tokens for
+    /// `fcm_url` are minted and refreshed from it
+    pub fcm_service_account: Option<PathBuf>,
+    /// Minimum time between two wakeups of one recipient
+    pub min_interval_secs: u64,
+    pub max_per_hour: u32,
+}
+
+impl Default for PushSection {
+    fn default() -> Self {
+        let config = PushConfig::default();
+        Self {
+            fcm_url: None,
+            fcm_authorization: None,
+            fcm_service_account: None,
+            min_interval_secs: config.min_interval.as_secs(),
+            max_per_hour: config.max_per_hour,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FederationSection {
+    /// Hex Ed25519 seed of this relay
+    pub key: Option<String>,
+    /// `relay_id@host:port`, or `relay_id` for peers that only dial in
+    pub peers: Vec<String>,
+    /// More peers, one per line, `#` comments
+    pub peers_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    /// Comma-separated `name:role:token`; the admin API is disabled without
+    pub tokens: Option<String>,
+    /// Operator actions are appended here as JSON lines
+    pub audit_log: Option<PathBuf>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSection {
+    /// `https://host` of the invite pages, used in link previews
+    pub public_url: Option<String>,
+    pub play_store_url: String,
+    /// iOS fallback of the invite page; none without it
+    pub app_store_url: Option<String>,
+}
+
+impl Default for WebSection {
+    fn default() -> Self {
+        let config = WebConfig::default();
+        Self { public_url: config.public_url, play_store_url: config.play_store_url, app_store_url: config.app_store_url }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BansSection {
+    pub ip_ranges: Vec<IpRange>,
+    pub node_ids: Vec<String>,
+}
+
+impl Config {
+    /// Read `path` (defaults only without it), apply the environment and
+    /// validate
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e)]))?;
+                Self::from_toml(&text).map_err(|e| ConfigError(vec![format!("{}: {}", path.display(), e)]))?
+            }
+            None => Self::default(),
+        };
+        config.apply_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, ConfigError> {
+        toml::from_str(text).map_err(|e| ConfigError(vec![e.to_string()]))
+    }
+
+    /// Override settings from environment variables (`ENV_OVERRIDES`);
+    /// `var` returns a variable's value
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        for (key, name) in ENV_OVERRIDES {
+            if let Some(value) = var(name) {
+                if let Err(e) = self.set(key, &value) {
+                    errors.push(format!("{}: {}", name, e));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "server.port" => self.server.port = parse(value)?,
+            "server.bind" => self.server.bind = non_empty(value),
+            "server.udp_bind" => self.server.udp_bind = non_empty(value),
+            "server.max_packet_size" => self.server.max_packet_size = parse(value)?,
+            "server.keepalive_secs" => self.server.keepalive_secs = parse(value)?,
+            "server.static_key" => self.server.static_key = non_empty(value),
+            "server.static_key_file" => self.server.static_key_file = non_empty(value).map(Into::into),
+            "server.fallback_relay" => self.server.fallback_relay = non_empty(value),
+            "server.relay_list_file" => self.server.relay_list_file = non_empty(value).map(Into::into),
+            "limits.rate_limit_pps" => self.limits.rate_limit_pps = parse(value)?,
+            "limits.node_rate_limit_pps" => self.limits.node_rate_limit_pps = parse(value)?,
+            "limits.rate_limit_bytes_per_sec" => self.limits.rate_limit_bytes_per_sec = parse(value)?,
+            "limits.node_rate_limit_bytes_per_sec" => self.limits.node_rate_limit_bytes_per_sec = parse(value)?,
+            "limits.global_rate_limit_pps" => self.limits.global_rate_limit_pps = parse(value)?,
+            "limits.global_rate_limit_bytes_per_sec" => self.limits.global_rate_limit_bytes_per_sec = parse(value)?,
+            "limits.rate_limit_ipv4_prefix" => self.limits.rate_limit_ipv4_prefix = parse(value)?,
+            "limits.rate_limit_ipv6_prefix" => self.limits.rate_limit_ipv6_prefix = parse(value)?,
+            "limits.rate_burst_secs" => self.limits.rate_burst_secs = parse(value)?,
+            "limits.emergency_reserve_percent" => self.limits.emergency_reserve_percent = parse(value)?,
+            "limits.max_sessions_per_ip" => self.limits.max_sessions_per_ip = parse(value)?,
+            "limits.pow_difficulty" => self.limits.pow_difficulty = parse(value)?,
+            "limits.pow_load_percent" => self.limits.pow_load_percent = parse(value)?,
+            "limits.flood_interval_secs" => self.limits.flood_interval_secs = parse(value)?,
+            "metrics.port" => self.metrics.port = parse(value)?,
+            "metrics.interval_secs" => self.metrics.interval_secs = parse(value)?,
+            "tls.cert_path" => self.tls.cert_path = non_empty(value).map(Into::into),
+            "tls.key_path" => self.tls.key_path = non_empty(value).map(Into::into),
+            "mailbox.dir" => self.mailbox.dir = non_empty(value).map(Into::into),
+            "mailbox.ttl_secs" => self.mailbox.ttl_secs = parse(value)?,
+            "mailbox.max_packets" => self.mailbox.max_packets = parse(value)?,
+            "mailbox.max_bytes" => self.mailbox.max_bytes = parse(value)?,
+            "store.dir" => self.store.dir = non_empty(value).map(Into::into),
+            "rendezvous.port" => self.rendezvous.port = non_empty(value).map(|port| parse(&port)).transpose()?,
+            "push.fcm_url" => self.push.fcm_url = non_empty(value),
+            "push.fcm_authorization" => self.push.fcm_authorization = non_empty(value),
+            "push.fcm_service_account" => self.push.fcm_service_account = non_empty(value).map(Into::into),
+            "push.min_interval_secs" => self.push.min_interval_secs = parse(value)?,
+            "push.max_per_hour" => self.push.max_per_hour = parse(value)?,
+            "federation.key" => self.federation.key = non_empty(value),
+            "federation.peers" => {
+                self.federation.peers = value.split(',').map(str::trim).filter(|peer| !peer.is_empty()).map(str::to_string).collect()
+            }
+            "federation.peers_file" => self.federation.peers_file = non_empty(value).map(Into::into),
+            "admin.tokens" => self.admin.tokens = non_empty(value),
+            "admin.audit_log" => self.admin.audit_log = non_empty(value).map(Into::into),
+            "web.public_url" => self.web.public_url = non_empty(value),
+            "web.play_store_url" => self.web.play_store_url = value.trim().to_string(),
+            "web.app_store_url" => self.web.app_store_url = non_empty(value),
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Check every setting; all problems are returned together
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        let mut check = |ok: bool, key: &str, problem: &str| {
+            if !ok {
+                errors.push(format!("{}: {}", setting(key), problem));
+            }
+        };
+
+        let server = &self.server;
+        check(server.bind_addr().parse::<SocketAddr>().is_ok(), "server.bind", "must be an IP address and port");
+        check(server.udp_bind_addr().parse::<SocketAddr>().is_ok(), "server.udp_bind", "must be an IP address and port");
+        check(
+            server.fallback_relay.as_deref().is_none_or(|relay| relay.parse::<Endpoint>().is_ok()),
+            "server.fallback_relay",
+            "must be host:port, with an IPv6 address in brackets",
+        );
+        check(
+            server.max_packet_size > 0 && server.max_packet_size < MAX_FRAME_LEN,
+            "server.max_packet_size",
+            &format!("must be between 1 and {}", MAX_FRAME_LEN - 1),
+        );
+        check(server.keepalive_secs > 0, "server.keepalive_secs", "must be positive");
+        check(
+            server.static_key.as_deref().is_none_or(|key| parse_key(key).is_some()),
+            "server.static_key",
+            "must be 32 bytes in hex",
+        );
+
+        let limits = &self.limits;
+        check(limits.rate_limit_pps > 0, "limits.rate_limit_pps", "must be positive");
+        check(limits.node_rate_limit_pps > 0, "limits.node_rate_limit_pps", "must be positive");
+        for (key, bytes_per_sec) in [
+            ("limits.rate_limit_bytes_per_sec", limits.rate_limit_bytes_per_sec),
+            ("limits.node_rate_limit_bytes_per_sec", limits.node_rate_limit_bytes_per_sec),
+            ("limits.global_rate_limit_bytes_per_sec", limits.global_rate_limit_bytes_per_sec),
+        ] {
+            // A full bucket must hold the largest packet, or it never passes
+            let burst = bytes_per_sec.saturating_mul(u64::from(limits.rate_burst_secs));
+            check(
+                bytes_per_sec == 0 || burst >= server.max_packet_size as u64,
+                key,
+                "times rate_burst_secs must be at least server.max_packet_size (or 0 for unlimited)",
+            );
+        }
+        check(limits.rate_limit_ipv4_prefix <= 32, "limits.rate_limit_ipv4_prefix", "must be at most 32");
+        check(limits.rate_limit_ipv6_prefix <= 128, "limits.rate_limit_ipv6_prefix", "must be at most 128");
+        check(limits.rate_burst_secs > 0, "limits.rate_burst_secs", "must be positive");
+        check(limits.emergency_reserve_percent <= 100, "limits.emergency_reserve_percent", "must be at most 100");
+        check(limits.max_sessions_per_ip > 0, "limits.max_sessions_per_ip", "must be positive");
+        check(
+            limits.pow_difficulty <= MAX_POW_DIFFICULTY,
+            "limits.pow_difficulty",
+            &format!("must be at most {} bits", MAX_POW_DIFFICULTY),
+        );
+        check(limits.pow_load_percent <= 100, "limits.pow_load_percent", "must be at most 100");
+
+        check(self.metrics.interval_secs > 0, "metrics.interval_secs", "must be positive");
+        check(self.metrics.port != server.port || server.bind.is_some(), "metrics.port", "must differ from server.port");
+
+        if let Some(port) = self.rendezvous.port {
+            check(port > 0, "rendezvous.port", "must be positive");
+            check(port != server.port || server.udp_bind.is_some(), "rendezvous.port", "must differ from server.port");
+        }
+
+        let push = &self.push;
+        check(push.fcm_url.as_deref().is_none_or(is_web_url), "push.fcm_url", "must be an http(s):// URL");
+        check(
+            push.fcm_authorization.is_none() || push.fcm_url.is_some(),
+            "push.fcm_authorization",
+            "is set without push.fcm_url",
+        );
+        check(
+            push.fcm_service_account.is_none() || push.fcm_url.is_some(),
+            "push.fcm_service_account",
+            "is set without push.fcm_url",
+        );
+        check(
+            push.fcm_service_account.is_none() || push.fcm_authorization.is_none(),
+            "push.fcm_service_account",
+            "cannot be combined with push.fcm_authorization",
+        );
+        check(push.min_interval_secs > 0, "push.min_interval_secs", "must be positive");
+        check(push.max_per_hour > 0, "push.max_per_hour", "must be positive");
+
+        check(
+            self.tls.cert_path.is_some() == self.tls.key_path.is_some(),
+            "tls.key_path",
+            "tls.cert_path and tls.key_path must be set together",
+        );
+
+        let mailbox = &self.mailbox;
+        check(mailbox.ttl_secs > 0, "mailbox.ttl_secs", "must be positive");
+        check(mailbox.max_packets > 0, "mailbox.max_packets", "must be positive");
+        check(mailbox.max_bytes > 0, "mailbox.max_bytes", "must be positive");
+
+        let web = &self.web;
+        for (key, url) in [
+            ("web.public_url", web.public_url.as_deref()),
+            ("web.play_store_url", Some(web.play_store_url.as_str())),
+            ("web.app_store_url", web.app_store_url.as_deref()),
+        ] {
+            check(url.is_none_or(is_web_url), key, "must be an http(s):// URL");
+        }
+
+        match self.federation_config() {
+            // Peer links are authenticated over the TLS exporter
+            Ok(Some(_)) if self.tls.cert_path.is_none() => {
+                errors.push(format!("{}: federation needs tls.cert_path and tls.key_path", setting("federation.peers")));
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e),
+        }
+        if let Err(e) = self.admin_tokens() {
+            errors.push(format!("{}: {}", setting("admin.tokens"), e));
+        }
+        if self.bans.node_ids.iter().any(|node_id| node_id.trim().is_empty()) {
+            errors.push("bans.node_ids: node ids must not be empty".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        let limits = &self.limits;
+        Limits {
+            rate_limit_pps: limits.rate_limit_pps,
+            node_rate_limit_pps: limits.node_rate_limit_pps,
+            rate_limit_bytes_per_sec: limits.rate_limit_bytes_per_sec,
+            node_rate_limit_bytes_per_sec: limits.node_rate_limit_bytes_per_sec,
+            global_rate_limit_pps: limits.global_rate_limit_pps,
+            global_rate_limit_bytes_per_sec: limits.global_rate_limit_bytes_per_sec,
+            rate_limit_ipv4_prefix: limits.rate_limit_ipv4_prefix,
+            rate_limit_ipv6_prefix: limits.rate_limit_ipv6_prefix,
+            rate_burst_secs: limits.rate_burst_secs,
+            emergency_reserve_percent: limits.emergency_reserve_percent,
+            max_sessions_per_ip: limits.max_sessions_per_ip,
+            pow_difficulty: limits.pow_difficulty,
+            pow_load_percent: limits.pow_load_percent,
+            flood_interval: Duration::from_secs(limits.flood_interval_secs),
+        }
+    }
+
+    /// Relay settings; federation peers are read from `peers_file` again
+    pub fn relay_config(&self) -> Result<RelayConfig, ConfigError> {
+        let federation = self.federation_config().map_err(|e| ConfigError(vec![e]))?;
+        let limits = self.limits();
+        Ok(RelayConfig {
+            max_packet: self.server.max_packet_size,
+            rate_limit_pps: limits.rate_limit_pps,
+            node_rate_limit_pps: limits.node_rate_limit_pps,
+            rate_limit_bytes_per_sec: limits.rate_limit_bytes_per_sec,
+            node_rate_limit_bytes_per_sec: limits.node_rate_limit_bytes_per_sec,
+            global_rate_limit_pps: limits.global_rate_limit_pps,
+            global_rate_limit_bytes_per_sec: limits.global_rate_limit_bytes_per_sec,
+            rate_limit_ipv4_prefix: limits.rate_limit_ipv4_prefix,
+            rate_limit_ipv6_prefix: limits.rate_limit_ipv6_prefix,
+            rate_burst_secs: limits.rate_burst_secs,
+            emergency_reserve_percent: limits.emergency_reserve_percent,
+            keepalive_secs: self.server.keepalive_secs,
+            max_sessions: MAX_PEERS,
+            max_sessions_per_ip: limits.max_sessions_per_ip,
+            pow_difficulty: limits.pow_difficulty,
+            pow_load_percent: limits.pow_load_percent,
+            federation,
+            flood_interval: limits.flood_interval,
+        })
+    }
+
+    pub fn mailbox_config(&self) -> MailboxConfig {
+        MailboxConfig {
+            dir: self.mailbox.dir.clone(),
+            max_ttl: Duration::from_secs(self.mailbox.ttl_secs),
+            max_packets: self.mailbox.max_packets,
+            max_bytes: self.mailbox.max_bytes,
+            ..MailboxConfig::default()
+        }
+    }
+
+    pub fn push_config(&self) -> PushConfig {
+        PushConfig { min_interval: Duration::from_secs(self.push.min_interval_secs), max_per_hour: self.push.max_per_hour }
+    }
+
+    pub fn web_config(&self) -> WebConfig {
+        WebConfig {
+            public_url: self.web.public_url.as_deref().map(|url| url.trim_end_matches('/').to_string()),
+            play_store_url: self.web.play_store_url.clone(),
+            app_store_url: self.web.app_store_url.clone(),
+        }
+    }
+
+    /// Datagram link key from `server.static_key`, if set
+    pub fn static_key(&self) -> Option<[u8; 32]> {
+        self.server.static_key.as_deref().and_then(parse_key)
+    }
+
+    pub fn admin_tokens(&self) -> Result<AdminTokens, String> {
+        self.admin.tokens.as_deref().map_or_else(|| Ok(AdminTokens::default()), AdminTokens::parse)
+    }
+
+    /// `None` without peers: the relay runs standalone
+    pub fn federation_config(&self) -> Result<Option<FederationConfig>, String> {
+        let federation = &self.federation;
+        let mut specs = federation.peers.clone();
+        if let Some(path) = &federation.peers_file {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| format!("{}: {}: {}", setting("federation.peers_file"), path.display(), e))?;
+            specs.extend(contents.lines().map(|line| line.split('#').next().unwrap_or("").to_string()));
+        }
+        let peers = specs
+            .iter()
+            .filter(|spec| !spec.trim().is_empty())
+            .map(|spec| spec.parse::<FederationPeer>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: {}", setting("federation.peers"), e))?;
+        if peers.is_empty() {
+            return Ok(None);
+        }
+        let seed = federation.key.as_deref().and_then(parse_key).ok_or_else(|| {
+            format!("{}: missing or invalid while federation peers are set", setting("federation.key"))
+        })?;
+        Ok(Some(FederationConfig::new(ed25519_dalek::SigningKey::from_bytes(&seed), peers)))
+    }
+
+    /// Apply the settings that can change at runtime to `state`: limits
+    /// and mailbox quotas when they differ from `previous`, and the bans
+    /// added or removed since `previous` (all of them without it). Bans
+    /// added through the admin API are left alone. Returns the sections
+    /// that changed but only take effect after a restart.
+    pub fn apply(&self, previous: Option<&Config>, state: &RelayState) -> Vec<&'static str> {
+        if previous.is_none_or(|previous| previous.limits != self.limits) {
+            state.set_limits(self.limits());
+        }
+        if let Some(mailbox) = state.mailbox() {
+            if previous.is_none_or(|previous| previous.mailbox != self.mailbox) {
+                mailbox.set_limits(&self.mailbox_config());
+            }
+        }
+
+        let no_bans = BansSection::default();
+        let old = previous.map_or(&no_bans, |previous| &previous.bans);
+        for range in old.ip_ranges.iter().filter(|range| !self.bans.ip_ranges.contains(range)) {
+            state.unban_ip_range(range);
+        }
+        for node_id in old.node_ids.iter().filter(|node_id| !self.bans.node_ids.contains(node_id)) {
+            state.unban_node(node_id);
+        }
+        for range in self.bans.ip_ranges.iter().filter(|range| !old.ip_ranges.contains(range)) {
+            state.ban_ip_range(*range);
+        }
+        for node_id in self.bans.node_ids.iter().filter(|node_id| !old.node_ids.contains(node_id)) {
+            state.ban_node(node_id);
+        }
+
+        let Some(previous) = previous else { return Vec::new() };
+        let mut restart = Vec::new();
+        if previous.server != self.server {
+            restart.push("server");
+        }
+        if previous.metrics != self.metrics {
+            restart.push("metrics");
+        }
+        if previous.tls != self.tls {
+            restart.push("tls");
+        }
+        if previous.mailbox.dir != self.mailbox.dir {
+            restart.push("mailbox.dir");
+        }
+        if previous.store != self.store {
+            restart.push("store");
+        }
+        if previous.rendezvous != self.rendezvous {
+            restart.push("rendezvous");
+        }
+        if previous.push != self.push {
+            restart.push("push");
+        }
+        if previous.federation != self.federation {
+            restart.push("federation");
+        }
+        if previous.admin != self.admin {
+            restart.push("admin");
+        }
+        if previous.web != self.web {
+            restart.push("web");
+        }
+        restart
+    }
+}
+
+/// `section.key (ENV_VAR)` for messages
+fn setting(key: &str) -> String {
+    match ENV_OVERRIDES.iter().find(|(k, _)| *k == key) {
+        Some((_, name)) => format!("{} ({})", key, name),
+        None => key.to_string(),
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String> {
+    value.trim().parse().map_err(|_| format!("invalid value {:?}", value))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Absolute `http(s)://host...` URL without quotes or spaces
+fn is_web_url(url: &str) -> bool {
+    let rest = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+    rest.is_some_and(|rest| !rest.is_empty() && !rest.starts_with('/') && !url.contains(|c: char| c.is_whitespace() || c == '"' || c == '\''))
+}
+
+fn parse_key(key: &str) -> Option<[u8; 32]> {
+    hex::decode(key.trim()).ok().and_then(|bytes| bytes.try_into().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn errors(result: Result<(), ConfigError>) -> Vec<String> {
+        match result {
+            Ok(()) => Vec::new(),
+            Err(ConfigError(errors)) => errors,
+        }
+    }
+
+    #[test]
+    fn file_values_are_overridden_by_environment() {
+        let mut config = Config::from_toml(
+            r#"
+            [server]
+            port = 41000
+            fallback_relay = "relay-2.example:40100"
+
+            [limits]
+            rate_limit_pps = 100
+            pow_difficulty = 12
+
+            [bans]
+            ip_ranges = ["203.0.113.0/24"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.bind_addr(), "[::]:41000");
+        assert_eq!(config.limits.node_rate_limit_pps, 50);
+        assert_eq!(config.bans.ip_ranges, vec!["203.0.113.0/24".parse().unwrap()]);
+
+        let env: HashMap<&str, &str> =
+            [("RATE_LIMIT_PPS", "300"), ("FALLBACK_RELAY", ""), ("FEDERATION_PEERS", " , "), ("RELAY_PUBLIC_URL", "https://relay.example/")]
+                .into();
+        config.apply_env(|name| env.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(config.limits.rate_limit_pps, 300);
+        assert_eq!(config.web_config().public_url.as_deref(), Some("https://relay.example"));
+        assert_eq!(config.limits.pow_difficulty, 12);
+        assert_eq!(config.server.fallback_relay, None);
+        assert!(config.federation.peers.is_empty());
+        assert!(errors(config.validate()).is_empty());
+
+        let env: HashMap<&str, &str> = [("RATE_LIMIT_PPS", "fast"), ("RELAY_PORT", "70000")].into();
+        let Err(ConfigError(problems)) = config.apply_env(|name| env.get(name).map(|value| value.to_string())) else {
+            panic!("invalid environment accepted");
+        };
+        assert_eq!(problems.len(), 2);
+        assert!(problems.iter().any(|problem| problem.starts_with("RATE_LIMIT_PPS:")));
+    }
+
+    #[test]
+    fn invalid_settings_are_all_reported() {
+        assert!(Config::from_toml("[limits]\nrate_limit = 5").is_err());
+        assert!(Config::from_toml("[bans]\nip_ranges = [\"10.0.0.0/40\"]").is_err());
+
+        let config = Config::from_toml(
+            r#"
+            [server]
+            bind = "relay.example:40100"
+            fallback_relay = "2001:db8::1:40100"
+
+            [limits]
+            rate_limit_pps = 0
+            pow_difficulty = 30
+            node_rate_limit_bytes_per_sec = 1000
+            rate_limit_ipv6_prefix = 129
+
+            [tls]
+            cert_path = "/etc/relay/cert.pem"
+
+            [federation]
+            peers = ["not-a-relay-id"]
+
+            [rendezvous]
+            port = 40100
+
+            [push]
+            fcm_authorization = "Bearer secret"
+            fcm_service_account = "/etc/relay/fcm.json"
+            max_per_hour = 0
+
+            [web]
+            public_url = "javascript:alert(1)"
+            "#,
+        )
+        .unwrap();
+        let problems = errors(config.validate());
+        assert_eq!(problems.len(), 15, "{:?}", problems);
+        assert!(problems.iter().any(|problem| problem.starts_with("limits.pow_difficulty (POW_DIFFICULTY)")));
+        assert!(problems.iter().any(|problem| problem.starts_with("limits.node_rate_limit_bytes_per_sec")));
+        assert!(problems.iter().any(|problem| problem.starts_with("federation.peers")));
+        assert!(problems.iter().any(|problem| problem.starts_with("server.fallback_relay")));
+        assert!(problems.iter().any(|problem| problem.starts_with("web.public_url (RELAY_PUBLIC_URL)")));
+        assert!(problems.iter().any(|problem| problem.starts_with("rendezvous.port (RENDEZVOUS_PORT)")));
+        assert!(problems.iter().any(|problem| problem.starts_with("push.fcm_authorization (PUSH_FCM_AUTHORIZATION)")));
+        assert!(problems.iter().any(|problem| problem.starts_with("push.fcm_service_account (PUSH_FCM_SERVICE_ACCOUNT)")));
+
+        let peer: FederationPeer = format!("{}@[2001:db8::1]", "ab".repeat(32)).parse().unwrap();
+        assert_eq!(peer.addr.as_deref(), Some("[2001:db8::1]:40100"));
+        assert!(format!("{}@2001:db8::1", "ab".repeat(32)).parse::<FederationPeer>().is_err());
+    }
+
+    #[test]
+    fn federation_needs_tls() {
+        let federation = format!("[federation]\nkey = \"{}\"\npeers = [\"{}@relay-b.example:40100\"]\n", "11".repeat(32), "ab".repeat(32));
+        let problems = errors(Config::from_toml(&federation).unwrap().validate());
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("federation.peers"));
+
+        let with_tls = format!("[tls]\ncert_path = \"/etc/relay/cert.pem\"\nkey_path = \"/etc/relay/key.pem\"\n{}", federation);
+        assert!(errors(Config::from_toml(&with_tls).unwrap().validate()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reload_applies_limits_and_bans_without_dropping_sessions() {
+        let initial = Config::from_toml("[bans]\nnode_ids = [\"mallory\"]\n").unwrap();
+        let state = RelayState::new(initial.relay_config().unwrap())
+            .with_mailbox(crate::mailbox::Mailbox::open(initial.mailbox_config()).unwrap());
+        assert!(initial.apply(None, &state).is_empty());
+        assert!(state.bans().is_node_banned("mallory"));
+
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        state.register("alice", "10.0.0.1".parse().unwrap(), crate::metrics::Transport::Tcp, tx).unwrap();
+        state.ban_node("trudy");
+
+        let reloaded = Config::from_toml(
+            r#"
+            [server]
+            port = 41000
+
+            [limits]
+            rate_limit_pps = 20
+            flood_interval_secs = 5
+
+            [mailbox]
+            ttl_secs = 60
+
+            [bans]
+            ip_ranges = ["192.0.2.0/24"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(reloaded.apply(Some(&initial), &state), vec!["server"]);
+        assert_eq!(state.rate_limits(), (20, 50));
+        assert_eq!(state.limits().flood_interval, Duration::from_secs(5));
+        assert!(state.is_registered("alice"));
+
+        let bans = state.bans();
+        assert!(!bans.is_node_banned("mallory"));
+        assert!(bans.is_node_banned("trudy"));
+        assert!(bans.is_ip_banned("192.0.2.7".parse().unwrap()));
+
+        // Mailbox TTL is capped by the new maximum
+        let mailbox = state.mailbox().unwrap();
+        let id = mailbox.store("bob", "alice", vec![1], Some(3600), 1000).await.unwrap();
+        let stored = mailbox.pending("bob", 1000);
+        assert_eq!(stored.iter().find(|packet| packet.id == id).unwrap().expires_at, 1060);
+    }
+}
@@ -0,0 +1,222 @@
+//! UDP endpoint for relay sessions (see `yaok_relay_proto::datagram`)
+//!
+//! Handshakes go to the stateless `Responder`; `Data` datagrams are
+//! demultiplexed by session id, decrypted and fed into the session's
+//! `MessageLink`, so `server::run_session` is the same as for TCP. A
+//! session follows its client when the source address changes.
+//!
+//! A slot is only created for an address that is not banned and holds
+//! fewer than `max_sessions_per_ip` datagram sessions, so a flood of
+//! handshakes from one host cannot fill the table before authentication.
+
+use crate::metrics::Transport;
+use crate::net::peer_ip;
+use crate::server::{run_session, RelayState};
+use crate::stats::MAX_PEERS;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+use yaok_relay_proto::datagram::{
+    data_session_id, datagram_kind, DatagramKind, DatagramReceiver, DatagramSession, Responder, ResponderEvent,
+    MAX_DATAGRAM_LEN, MAX_DATAGRAM_MESSAGE,
+};
+use yaok_relay_proto::{decode_frame, encode_message, is_closing, MessageLink, RelayMessage};
+
+/// Established datagram sessions, authenticated or not yet
+pub const MAX_DATAGRAM_SESSIONS: usize = 2 * MAX_PEERS;
+
+/// How often closed sessions are removed from the table
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Peer {
+    /// Address the session was admitted from, counted in `Sessions::per_ip`
+    ip: IpAddr,
+    receiver: DatagramReceiver,
+    /// Current client address, shared with the session's writer
+    addr: Arc<Mutex<SocketAddr>>,
+    incoming: mpsc::Sender<RelayMessage>,
+}
+
+/// Session table with the number of sessions admitted from each address
+#[derive(Default)]
+struct Sessions {
+    peers: HashMap<u64, Peer>,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+impl Sessions {
+    fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    fn count_for(&self, ip: IpAddr) -> usize {
+        self.per_ip.get(&ip).copied().unwrap_or(0)
+    }
+
+    fn insert(&mut self, session_id: u64, peer: Peer) {
+        *self.per_ip.entry(peer.ip).or_insert(0) += 1;
+        self.peers.insert(session_id, peer);
+    }
+
+    /// Remove sessions whose link has closed
+    fn sweep(&mut self) {
+        let per_ip = &mut self.per_ip;
+        self.peers.retain(|_, peer| {
+            if !peer.incoming.is_closed() {
+                return true;
+            }
+            if let Some(count) = per_ip.get_mut(&peer.ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&peer.ip);
+                }
+            }
+            false
+        });
+    }
+}
+
+/// Serve encrypted relay sessions on `socket` until it fails
+pub async fn serve_datagram(socket: UdpSocket, responder: Responder, state: Arc<RelayState>) -> std::io::Result<()> {
+    info!(
+        "Relay datagram sessions listening on {}, key fingerprint {}",
+        socket.local_addr()?,
+        responder.fingerprint()
+    );
+
+    let socket = Arc::new(socket);
+    let mut sessions = Sessions::default();
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    let mut last_sweep = Instant::now();
+
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let datagram = &buf[..len];
+
+        if last_sweep.elapsed() >= SWEEP_INTERVAL {
+            sessions.sweep();
+            last_sweep = Instant::now();
+        }
+
+        match datagram_kind(datagram) {
+            Some(DatagramKind::Initiation | DatagramKind::Resume) => {
+                let ip = peer_ip(from);
+                if state.is_ip_banned(ip) {
+                    state.record(|stats| stats.rejected_banned += 1);
+                    continue;
+                }
+                let max_per_ip = state.limits().max_sessions_per_ip;
+                if sessions.len() >= MAX_DATAGRAM_SESSIONS || sessions.count_for(ip) >= max_per_ip {
+                    sessions.sweep();
+                    if sessions.len() >= MAX_DATAGRAM_SESSIONS {
+                        state.record(|stats| stats.dropped_peer_limit += 1);
+                        continue;
+                    }
+                    if sessions.count_for(ip) >= max_per_ip {
+                        state.record(|stats| stats.rejected_ip_sessions += 1);
+                        continue;
+                    }
+                }
+
+                let session_id = loop {
+                    let id = rand::random::<u64>();
+                    if !sessions.peers.contains_key(&id) {
+                        break id;
+                    }
+                };
+
+                match responder.accept(datagram, from, unix_now(), session_id) {
+                    Ok(ResponderEvent::Reply(reply)) => {
+                        state.record(|stats| stats.udp_retries += 1);
+                        let _ = socket.send_to(&reply, from).await;
+                    }
+                    Ok(ResponderEvent::Established { session, reply, resumed }) => {
+                        state.record(|stats| {
+                            stats.udp_handshakes += 1;
+                            if resumed {
+                                stats.udp_resumptions += 1;
+                            }
+                        });
+                        let _ = socket.send_to(&reply, from).await;
+                        sessions.insert(session_id, start_session(session, from, socket.clone(), state.clone()));
+                    }
+                    Err(e) => debug!("Dropped handshake from {}: {}", from, e),
+                }
+            }
+            Some(DatagramKind::Data) => {
+                let Some(peer) = data_session_id(datagram).and_then(|id| sessions.peers.get_mut(&id)) else {
+                    continue;
+                };
+                let plaintext = match peer.receiver.open(datagram) {
+                    Ok(plaintext) => plaintext,
+                    Err(e) => {
+                        debug!("Dropped datagram from {}: {}", from, e);
+                        continue;
+                    }
+                };
+
+                // Only authenticated, non-replayed datagrams move the session
+                let mut addr = peer.addr.lock().unwrap();
+                if *addr != from {
+                    debug!("Session {:016x} migrated {} -> {}", peer.receiver.session_id(), *addr, from);
+                    *addr = from;
+                }
+                drop(addr);
+
+                match decode_frame(&plaintext) {
+                    Ok(message) => {
+                        let _ = peer.incoming.try_send(message);
+                    }
+                    Err(e) => debug!("Malformed message from {}: {}", from, e),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn start_session(session: DatagramSession, addr: SocketAddr, socket: Arc<UdpSocket>, state: Arc<RelayState>) -> Peer {
+    let DatagramSession { mut sender, receiver, handshake_hash } = session;
+    let (link, incoming, mut outgoing) = MessageLink::channel();
+    let link = link.with_binding(handshake_hash);
+    let addr = Arc::new(Mutex::new(addr));
+
+    let writer_addr = addr.clone();
+    let writer_state = state.clone();
+    tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let Ok(plaintext) = encode_message(&message) else { continue };
+            // Packets are capped at `MAX_DATAGRAM_PACKET` before they get
+            // here; anything still too large is counted, not sent
+            if plaintext.len() > MAX_DATAGRAM_MESSAGE {
+                debug!("Message too large for a datagram: {} bytes", plaintext.len());
+                writer_state.record(|stats| stats.dropped_datagram_size += 1);
+                continue;
+            }
+            let datagram = sender.seal(&plaintext);
+
+            let target = *writer_addr.lock().unwrap();
+            let _ = socket.send_to(&datagram, target).await;
+            if is_closing(&message) {
+                break;
+            }
+        }
+    });
+
+    let ip = peer_ip(*addr.lock().unwrap());
+    tokio::spawn(async move {
+        if let Err(e) = run_session(link, ip, Transport::Udp, state).await {
+            debug!("Datagram session from {} ended: {}", ip, e);
+        }
+    });
+
+    Peer { ip, receiver, addr, incoming }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
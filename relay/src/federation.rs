@@ -0,0 +1,523 @@
+//! Federation with other relays (see `yaok_relay_proto::federation`)
+//!
+//! A relay keeps links to its configured peers: it dials peers with a
+//! known address and accepts links from peers listed by relay id. Over a
+//! link both sides announce which nodes have a session (as presence
+//! digests), and a `Send` to a node with no local session is forwarded to
+//! a peer that announced it. Forwarded packets carry an id; a relay drops
+//! ids it has already handled, so stale presence cannot create a loop.
+//!
+//! Links run over TLS only: the dialer checks the peer's certificate and
+//! both relays sign the TLS exporter, so frames after the handshake are
+//! bound to the authenticated relays. A `PeerHello` on a plain TCP link is
+//! refused.
+
+use crate::server::{RelayState, HANDSHAKE_TIMEOUT};
+use ed25519_dalek::{Signer, SigningKey};
+use rand::Rng;
+use rustls::pki_types::ServerName;
+use rustls::RootCertStore;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, warn};
+use yaok_relay_proto::dedup::RecentIds;
+use yaok_relay_proto::federation::{
+    decode_digests, encode_digests, forward_id, presence_digest, ForwardId, PresenceDigest, MAX_FORWARD_HOPS,
+};
+use yaok_relay_proto::{
+    node_id_for_key, peer_auth_payload, stream_link, verify_peer_auth, Endpoint, ErrorCode, MessageLink, PeerRole,
+    PeerTranscript, ProtoError, RelayMessage, CHANNEL_BINDING_LEN, NONCE_LEN, PROTOCOL_VERSION, TLS_EXPORTER_LABEL,
+};
+
+/// Forwarded packet ids remembered for loop prevention
+const SEEN_FORWARDS: usize = 16_384;
+
+/// First delay before redialing a peer
+const REDIAL_INITIAL: Duration = Duration::from_secs(1);
+
+/// Maximum delay before redialing a peer
+const REDIAL_MAX: Duration = Duration::from_secs(60);
+
+/// Federation settings
+#[derive(Clone, Debug)]
+pub struct FederationConfig {
+    /// Relay identity; the relay id is the hex-encoded public key
+    pub key: SigningKey,
+    /// Trusted peer relays
+    pub peers: Vec<FederationPeer>,
+    /// Full presence summaries are resent this often (deltas are sent
+    /// as sessions come and go)
+    pub presence_interval: Duration,
+    /// Trust anchors for the certificates of dialed peers (webpki roots
+    /// unless replaced)
+    pub tls_roots: Arc<RootCertStore>,
+}
+
+impl FederationConfig {
+    pub fn new(key: SigningKey, peers: Vec<FederationPeer>) -> Self {
+        Self {
+            key,
+            peers,
+            presence_interval: Duration::from_secs(60),
+            tls_roots: Arc::new(RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() }),
+        }
+    }
+
+    pub fn relay_id(&self) -> String {
+        node_id_for_key(self.key.verifying_key().as_bytes())
+    }
+}
+
+/// Peer relay: `relay_id@host:port` to dial it (IPv6 as `[addr]:port`),
+/// or just `relay_id` to accept its links only
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FederationPeer {
+    pub relay_id: String,
+    pub addr: Option<String>,
+}
+
+impl FromStr for FederationPeer {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let spec = spec.trim();
+        let (relay_id, addr) = match spec.split_once('@') {
+            Some((relay_id, addr)) if !addr.is_empty() => {
+                let endpoint: Endpoint = addr.parse().map_err(|e| format!("federation peer '{}': {}", spec, e))?;
+                (relay_id, Some(endpoint.authority()))
+            }
+            Some(_) => return Err(format!("missing address in federation peer '{}'", spec)),
+            None => (spec, None),
+        };
+        let valid = hex::decode(relay_id).is_ok_and(|key| key.len() == 32);
+        if !valid {
+            return Err(format!("federation peer id must be a hex Ed25519 key: '{}'", relay_id));
+        }
+        Ok(Self { relay_id: relay_id.to_ascii_lowercase(), addr })
+    }
+}
+
+struct PeerLink {
+    relay_id: String,
+    tx: mpsc::Sender<RelayMessage>,
+    /// Nodes the peer announced
+    presence: HashSet<PresenceDigest>,
+}
+
+/// Federation state of one relay
+pub(crate) struct Federation {
+    config: FederationConfig,
+    relay_id: String,
+    links: Mutex<HashMap<u64, PeerLink>>,
+    next_link_id: AtomicU64,
+    seen: Mutex<RecentIds<ForwardId>>,
+}
+
+impl Federation {
+    pub(crate) fn new(config: FederationConfig) -> Self {
+        Self {
+            relay_id: config.relay_id(),
+            config,
+            links: Mutex::new(HashMap::new()),
+            next_link_id: AtomicU64::new(1),
+            seen: Mutex::new(RecentIds::new(SEEN_FORWARDS)),
+        }
+    }
+
+    pub(crate) fn relay_id(&self) -> &str {
+        &self.relay_id
+    }
+
+    fn is_trusted(&self, relay_id: &str) -> bool {
+        relay_id != self.relay_id && self.config.peers.iter().any(|peer| peer.relay_id == relay_id)
+    }
+
+    /// Register a link; `snapshot` is the full presence to send first
+    pub(crate) fn attach(&self, relay_id: &str, tx: mpsc::Sender<RelayMessage>, snapshot: &[PresenceDigest]) -> u64 {
+        let id = self.next_link_id.fetch_add(1, Ordering::Relaxed);
+        let _ = tx.try_send(RelayMessage::Presence {
+            full: true,
+            added: encode_digests(snapshot),
+            removed: Vec::new(),
+        });
+        self.links.lock().unwrap().insert(
+            id,
+            PeerLink { relay_id: relay_id.to_string(), tx, presence: HashSet::new() },
+        );
+        id
+    }
+
+    pub(crate) fn detach(&self, link_id: u64) {
+        self.links.lock().unwrap().remove(&link_id);
+    }
+
+    /// Resend the full presence on one link
+    pub(crate) fn resync(&self, link_id: u64, snapshot: &[PresenceDigest]) {
+        if let Some(link) = self.links.lock().unwrap().get(&link_id) {
+            let _ = link.tx.try_send(RelayMessage::Presence {
+                full: true,
+                added: encode_digests(snapshot),
+                removed: Vec::new(),
+            });
+        }
+    }
+
+    /// Tell all peers that `node_id` got (or lost) its session here
+    pub(crate) fn announce(&self, node_id: &str, present: bool) {
+        let digest = [presence_digest(node_id)];
+        let (added, removed) = if present {
+            (encode_digests(&digest), Vec::new())
+        } else {
+            (Vec::new(), encode_digests(&digest))
+        };
+        for link in self.links.lock().unwrap().values() {
+            let _ = link.tx.try_send(RelayMessage::Presence {
+                full: false,
+                added: added.clone(),
+                removed: removed.clone(),
+            });
+        }
+    }
+
+    fn apply_presence(&self, link_id: u64, full: bool, added: &[PresenceDigest], removed: &[PresenceDigest]) {
+        if let Some(link) = self.links.lock().unwrap().get_mut(&link_id) {
+            if full {
+                link.presence.clear();
+            }
+            for digest in removed {
+                link.presence.remove(digest);
+            }
+            link.presence.extend(added.iter().copied());
+        }
+    }
+
+    /// Record a forwarded packet id; `false` if it was handled before
+    pub(crate) fn mark_seen(&self, id: ForwardId) -> bool {
+        self.seen.lock().unwrap().insert(id)
+    }
+
+    /// Forward a packet from a local sender to a peer that announced `to`
+    pub(crate) fn forward_new(&self, from: &str, to: &str, packet: Vec<u8>, ttl_secs: Option<u32>) -> Result<(), Vec<u8>> {
+        let id: ForwardId = rand::random();
+        self.mark_seen(id);
+        self.forward(id, from, to, MAX_FORWARD_HOPS - 1, packet, ttl_secs, None)
+    }
+
+    /// Hand the packet to the first link (other than `exclude`) whose
+    /// relay announced `to`; the packet is returned if there is none
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn forward(
+        &self,
+        id: ForwardId,
+        from: &str,
+        to: &str,
+        hops: u8,
+        packet: Vec<u8>,
+        ttl_secs: Option<u32>,
+        exclude: Option<u64>,
+    ) -> Result<(), Vec<u8>> {
+        let digest = presence_digest(to);
+        let links = self.links.lock().unwrap();
+        let mut message = RelayMessage::Forward {
+            id: id.to_vec(),
+            from: from.to_string(),
+            to: to.to_string(),
+            hops,
+            packet,
+            ttl_secs,
+        };
+        for (link_id, link) in links.iter() {
+            if Some(*link_id) == exclude || !link.presence.contains(&digest) {
+                continue;
+            }
+            match link.tx.try_send(message) {
+                Ok(()) => return Ok(()),
+                // Queue full or link closing: try another relay
+                Err(mpsc::error::TrySendError::Full(returned) | mpsc::error::TrySendError::Closed(returned)) => {
+                    message = returned;
+                }
+            }
+        }
+        match message {
+            RelayMessage::Forward { packet, .. } => Err(packet),
+            _ => unreachable!("message is a forward"),
+        }
+    }
+
+    /// Whether a peer announced `node_id`
+    pub(crate) fn knows(&self, node_id: &str) -> bool {
+        let digest = presence_digest(node_id);
+        self.links.lock().unwrap().values().any(|link| link.presence.contains(&digest))
+    }
+
+    /// Relay ids with an open link
+    pub(crate) fn connected_peers(&self) -> Vec<String> {
+        let mut peers: Vec<String> = self.links.lock().unwrap().values().map(|l| l.relay_id.clone()).collect();
+        peers.sort();
+        peers.dedup();
+        peers
+    }
+
+    pub(crate) fn link_count(&self) -> usize {
+        self.links.lock().unwrap().len()
+    }
+}
+
+/// Dial every configured peer with an address and keep the links up
+/// (with backoff after failures). Inbound links are accepted by `serve`.
+pub fn start_federation(state: &Arc<RelayState>) -> Vec<JoinHandle<()>> {
+    let Some(federation) = state.federation() else {
+        return Vec::new();
+    };
+    info!("Federation relay id {}", federation.relay_id());
+
+    federation
+        .config
+        .peers
+        .iter()
+        .filter_map(|peer| Some((peer.relay_id.clone(), peer.addr.clone()?)))
+        .map(|(relay_id, addr)| tokio::spawn(dial_loop(relay_id, addr, state.clone())))
+        .collect()
+}
+
+async fn dial_loop(relay_id: String, addr: String, state: Arc<RelayState>) {
+    let mut delay = REDIAL_INITIAL;
+    loop {
+        let started = Instant::now();
+        match dial(&relay_id, &addr, &state).await {
+            Ok(link) => {
+                info!("Federation link to {} ({}) established", relay_id, addr);
+                let _ = run_link(link, relay_id.clone(), state.clone()).await;
+                info!("Federation link to {} closed", relay_id);
+            }
+            Err(e) => warn!("Federation link to {} ({}) failed: {}", relay_id, addr, e),
+        }
+
+        // A link that stayed up for a while resets the backoff
+        if started.elapsed() > REDIAL_MAX {
+            delay = REDIAL_INITIAL;
+        }
+        let jitter = delay.mul_f64(rand::thread_rng().gen_range(0.5..1.0));
+        tokio::time::sleep(jitter).await;
+        delay = (delay * 2).min(REDIAL_MAX);
+    }
+}
+
+/// Open an authenticated link to peer `relay_id` at `addr`
+async fn dial(relay_id: &str, addr: &str, state: &RelayState) -> Result<MessageLink, ProtoError> {
+    let federation = state.federation().ok_or(ProtoError::Closed)?;
+    let mut link = timeout(HANDSHAKE_TIMEOUT, connect_tls(addr, &federation.config.tls_roots))
+        .await
+        .map_err(|_| ProtoError::Unexpected("TLS handshake timeout"))??;
+
+    let handshake = async {
+        let own_id = federation.relay_id();
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        send(&link.outgoing, RelayMessage::PeerHello {
+            version: PROTOCOL_VERSION,
+            relay_id: own_id.to_string(),
+            public_key: federation.config.key.verifying_key().to_bytes().to_vec(),
+            nonce: nonce.to_vec(),
+        })
+        .await?;
+
+        match link.incoming.recv().await.ok_or(ProtoError::Closed)? {
+            RelayMessage::PeerChallenge { relay_id: peer_id, public_key, nonce: peer_nonce, signature } => {
+                // The relay at `addr` must be the configured peer
+                if peer_id != relay_id {
+                    return Err(ProtoError::AuthFailed);
+                }
+                let transcript = PeerTranscript { dialer_nonce: &nonce, acceptor_nonce: &peer_nonce, binding: &link.binding };
+                verify_peer_auth(PeerRole::Acceptor, &transcript, &peer_id, own_id, &public_key, &signature)?;
+                let signature = federation.config.key.sign(&peer_auth_payload(PeerRole::Dialer, &transcript, own_id, &peer_id));
+                send(&link.outgoing, RelayMessage::Auth { signature: signature.to_bytes().to_vec(), pow: None }).await?;
+            }
+            RelayMessage::Error { code, message } => return Err(ProtoError::Rejected(code, message)),
+            other => return Err(ProtoError::Unexpected(other.kind())),
+        }
+
+        match link.incoming.recv().await.ok_or(ProtoError::Closed)? {
+            RelayMessage::Welcome { .. } => Ok(()),
+            RelayMessage::Error { code, message } => Err(ProtoError::Rejected(code, message)),
+            other => Err(ProtoError::Unexpected(other.kind())),
+        }
+    };
+
+    match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(())) => Ok(link),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(ProtoError::Unexpected("handshake timeout")),
+    }
+}
+
+/// TLS link to `addr` with the exporter as its channel binding
+async fn connect_tls(addr: &str, roots: &Arc<RootCertStore>) -> Result<MessageLink, ProtoError> {
+    let invalid = |e: String| ProtoError::Io(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+    let endpoint: Endpoint = addr.parse().map_err(|e| invalid(format!("{}: {}", addr, e)))?;
+    let server_name = ServerName::try_from(endpoint.server_name()).map_err(|e| invalid(e.to_string()))?;
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(e.to_string()))?
+        .with_root_certificates(roots.clone())
+        .with_no_client_auth();
+
+    let stream = TcpStream::connect(addr).await?;
+    let _ = stream.set_nodelay(true);
+    let stream = TlsConnector::from(Arc::new(config)).connect(server_name, stream).await?;
+    let mut binding = [0u8; CHANNEL_BINDING_LEN];
+    stream
+        .get_ref()
+        .1
+        .export_keying_material(&mut binding, TLS_EXPORTER_LABEL, None)
+        .map_err(|e| invalid(e.to_string()))?;
+    Ok(stream_link(stream).with_binding(binding))
+}
+
+/// Accept a link from a peer relay; `hello` is its `PeerHello`
+pub(crate) async fn accept(mut link: MessageLink, hello: RelayMessage, state: Arc<RelayState>) -> Result<(), ProtoError> {
+    let Some(federation) = state.federation() else {
+        reject(&link.outgoing, ErrorCode::ProtocolViolation, "Federation is disabled").await;
+        return Err(ProtoError::Unexpected(hello.kind()));
+    };
+    let RelayMessage::PeerHello { version, relay_id, public_key, nonce } = hello else {
+        return Err(ProtoError::Unexpected(hello.kind()));
+    };
+
+    let handshake = async {
+        if version != PROTOCOL_VERSION {
+            reject(&link.outgoing, ErrorCode::UnsupportedVersion, "Unsupported protocol version").await;
+            return Err(ProtoError::Rejected(ErrorCode::UnsupportedVersion, version.to_string()));
+        }
+        // Plain TCP: nothing would protect the frames after the handshake
+        if link.binding.is_empty() {
+            reject(&link.outgoing, ErrorCode::AuthFailed, "Federation requires TLS").await;
+            return Err(ProtoError::AuthFailed);
+        }
+        if !federation.is_trusted(&relay_id) || relay_id != node_id_for_key(&public_key) || nonce.len() != NONCE_LEN {
+            reject(&link.outgoing, ErrorCode::AuthFailed, "Unknown relay").await;
+            return Err(ProtoError::AuthFailed);
+        }
+
+        let own_id = federation.relay_id();
+        let own_nonce: [u8; NONCE_LEN] = rand::random();
+        let transcript = PeerTranscript { dialer_nonce: &nonce, acceptor_nonce: &own_nonce, binding: &link.binding };
+        let signature = federation.config.key.sign(&peer_auth_payload(PeerRole::Acceptor, &transcript, own_id, &relay_id));
+        send(&link.outgoing, RelayMessage::PeerChallenge {
+            relay_id: own_id.to_string(),
+            public_key: federation.config.key.verifying_key().to_bytes().to_vec(),
+            nonce: own_nonce.to_vec(),
+            signature: signature.to_bytes().to_vec(),
+        })
+        .await?;
+
+        match link.incoming.recv().await.ok_or(ProtoError::Closed)? {
+            RelayMessage::Auth { signature, .. } => {
+                if let Err(e) = verify_peer_auth(PeerRole::Dialer, &transcript, &relay_id, own_id, &public_key, &signature) {
+                    reject(&link.outgoing, ErrorCode::AuthFailed, "Invalid signature").await;
+                    return Err(e);
+                }
+            }
+            other => {
+                reject(&link.outgoing, ErrorCode::ProtocolViolation, "Expected auth").await;
+                return Err(ProtoError::Unexpected(other.kind()));
+            }
+        }
+        send(&link.outgoing, RelayMessage::Welcome { keepalive_secs: state.config().keepalive_secs }).await
+    };
+
+    match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            if matches!(e, ProtoError::AuthFailed) {
+                state.record(|stats| stats.auth_failures += 1);
+            }
+            return Err(e);
+        }
+        Err(_) => return Err(ProtoError::Unexpected("handshake timeout")),
+    }
+
+    info!("Federation link from {} accepted", relay_id);
+    run_link(link, relay_id, state).await
+}
+
+/// Exchange presence and forwarded packets until the link closes
+async fn run_link(mut link: MessageLink, relay_id: String, state: Arc<RelayState>) -> Result<(), ProtoError> {
+    let Some(federation) = state.federation() else {
+        return Err(ProtoError::Closed);
+    };
+    let link_id = state.attach_peer_link(&relay_id, link.outgoing.clone());
+
+    let idle_timeout = state.config().idle_timeout();
+    let mut keepalive = tokio::time::interval(Duration::from_secs(u64::from(state.config().keepalive_secs.max(1))));
+    let mut resync = tokio::time::interval(federation.config.presence_interval);
+    keepalive.tick().await;
+    resync.tick().await;
+    let mut last_seen = Instant::now();
+    let mut ping_seq = 0;
+
+    let result = loop {
+        let message = tokio::select! {
+            message = link.incoming.recv() => message,
+            _ = keepalive.tick() => {
+                if last_seen.elapsed() >= idle_timeout {
+                    debug!("Federation link {} missed keepalives", relay_id);
+                    break Ok(());
+                }
+                ping_seq += 1;
+                if send(&link.outgoing, RelayMessage::Ping { seq: ping_seq }).await.is_err() {
+                    break Ok(());
+                }
+                continue;
+            }
+            _ = resync.tick() => {
+                state.resync_peer_link(link_id);
+                continue;
+            }
+        };
+        let Some(message) = message else { break Ok(()) };
+        last_seen = Instant::now();
+
+        match message {
+            RelayMessage::Ping { seq } => {
+                if send(&link.outgoing, RelayMessage::Pong { seq }).await.is_err() {
+                    break Ok(());
+                }
+            }
+            RelayMessage::Pong { .. } => {}
+            RelayMessage::Presence { full, added, removed } => {
+                match (decode_digests(&added), decode_digests(&removed)) {
+                    (Ok(added), Ok(removed)) => federation.apply_presence(link_id, full, &added, &removed),
+                    (Err(e), _) | (_, Err(e)) => break Err(e),
+                }
+            }
+            RelayMessage::Forward { id, from, to, hops, packet, ttl_secs } => match forward_id(&id) {
+                Ok(id) => state.route_forwarded(link_id, id, &from, &to, hops, packet, ttl_secs).await,
+                Err(e) => break Err(e),
+            },
+            RelayMessage::Bye | RelayMessage::Error { .. } => break Ok(()),
+            other => {
+                reject(&link.outgoing, ErrorCode::ProtocolViolation, &format!("Unexpected {}", other.kind())).await;
+                break Err(ProtoError::Unexpected(other.kind()));
+            }
+        }
+    };
+
+    federation.detach(link_id);
+    result
+}
+
+async fn send(outgoing: &mpsc::Sender<RelayMessage>, message: RelayMessage) -> Result<(), ProtoError> {
+    outgoing.send(message).await.map_err(|_| ProtoError::Closed)
+}
+
+async fn reject(outgoing: &mpsc::Sender<RelayMessage>, code: ErrorCode, message: &str) {
+    let _ = outgoing.send(RelayMessage::Error { code, message: message.to_string() }).await;
+}
@@ -0,0 +1,40 @@
+//! yaok-relay library: session server for the relay protocol
+//! (see `yaok-relay-proto`), run as a [`Relay`], over TCP, TLS or
+//! encrypted UDP. The binary in `main.rs` adds configuration (file and
+//! environment, see [`Config`]), signal handling and the HTTP(S) metrics
+//! endpoint.
+
+pub mod admin;
+pub mod bans;
+pub mod config;
+pub mod datagram;
+pub mod federation;
+pub mod mailbox;
+pub mod metrics;
+pub mod net;
+pub mod push;
+pub mod ratelimit;
+pub mod relay;
+pub mod rendezvous;
+pub mod server;
+pub mod stats;
+pub mod store;
+pub mod tls;
+pub mod web;
+
+pub use admin::{Admin, AdminRequest, AdminResponse, AdminTokens, AuditLog};
+pub use config::{Config, ConfigError};
+pub use datagram::serve_datagram;
+pub use federation::{start_federation, FederationConfig, FederationPeer};
+pub use mailbox::{Mailbox, MailboxConfig};
+pub use push::{
+    start_push, FcmProvider, MockPushProvider, PushAuth, PushConfig, PushGateway, PushProvider, ServiceAccountAuth, StaticAuth,
+};
+pub use ratelimit::{Budget, RateLimiter, RateLimits};
+pub use relay::{Relay, ShutdownHandle};
+pub use rendezvous::{serve_rendezvous, Rendezvous};
+pub use server::{serve, Limits, RelayConfig, RelayState};
+pub use stats::Stats;
+pub use store::Store;
+pub use tls::{serve_tls, TlsCertificates};
+pub use web::WebConfig;
@@ -0,0 +1,457 @@
+//! Store-and-forward mailbox
+//!
+//! Packets for a recipient without a session are kept per recipient tag
+//! (the presence digest of its node id, so node ids are not written to
+//! disk) until they expire or the recipient acknowledges them. Every new
+//! session of the recipient gets all of its unacknowledged packets, so a
+//! packet is delivered at least once.
+//!
+//! With a directory configured each packet is one file,
+//! `<dir>/<tag>/<id>.pkt`, written and synced to disk before the sender is
+//! told `Stored` and removed on `Ack` or expiry; the mailbox is reloaded on
+//! start. The write runs on the blocking pool with no lock held: the id
+//! and the quota are reserved first, the packet is added to its inbox once
+//! the file is on disk.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use yaok_relay_proto::federation::{presence_digest, PresenceDigest};
+
+/// Extension of stored packet files
+const PACKET_EXT: &str = "pkt";
+
+#[derive(Clone, Debug)]
+pub struct MailboxConfig {
+    /// Where packets are persisted; `None` keeps them in memory only
+    pub dir: Option<PathBuf>,
+    /// Longest time a packet is kept, whatever the sender asks for
+    pub max_ttl: Duration,
+    /// Packets kept per recipient
+    pub max_packets: usize,
+    /// Bytes kept per recipient
+    pub max_bytes: usize,
+    /// Bytes kept for all recipients together
+    pub max_total_bytes: usize,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_ttl: Duration::from_secs(7 * 24 * 3600),
+            max_packets: 256,
+            max_bytes: 1024 * 1024,
+            max_total_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// Why a packet was not stored
+#[derive(Debug)]
+pub enum MailboxError {
+    /// Recipient or relay quota reached
+    Full,
+    /// Zero lifetime requested
+    Expired,
+    Io(io::Error),
+}
+
+/// Packet waiting for its recipient
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPacket {
+    pub id: u64,
+    pub from: String,
+    #[serde(with = "serde_bytes")]
+    pub packet: Vec<u8>,
+    /// Unix seconds
+    pub expires_at: u64,
+}
+
+#[derive(Default)]
+struct Inbox {
+    packets: VecDeque<StoredPacket>,
+    /// Bytes of stored packets and of packets being written
+    bytes: usize,
+    /// Packets reserved and still being written
+    writing: usize,
+}
+
+impl Inbox {
+    fn is_empty(&self) -> bool {
+        self.packets.is_empty() && self.writing == 0
+    }
+}
+
+struct Boxes {
+    inboxes: HashMap<PresenceDigest, Inbox>,
+    total_bytes: usize,
+    next_id: u64,
+}
+
+pub struct Mailbox {
+    /// Fixed when the mailbox is opened
+    dir: Option<PathBuf>,
+    /// Quotas and TTL; may change while the relay runs
+    config: RwLock<MailboxConfig>,
+    boxes: Mutex<Boxes>,
+}
+
+/// Current unix time in seconds
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Mailbox {
+    /// Open the mailbox, loading persisted packets that have not expired
+    pub fn open(config: MailboxConfig) -> io::Result<Self> {
+        // Ids start from the clock so an acknowledged id is not reissued
+        // after a restart
+        let first_id = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_micros() as u64);
+        let mut boxes = Boxes { inboxes: HashMap::new(), total_bytes: 0, next_id: first_id };
+        if let Some(dir) = &config.dir {
+            std::fs::create_dir_all(dir)?;
+            let loaded = load_dir(dir, unix_now(), &mut boxes)?;
+            if loaded > 0 {
+                info!("Mailbox: loaded {} packets from {}", loaded, dir.display());
+            }
+        }
+        Ok(Self { dir: config.dir.clone(), config: RwLock::new(config), boxes: Mutex::new(boxes) })
+    }
+
+    /// Keep `packet` for `to` for `ttl_secs` (capped by the configured
+    /// maximum) and return its mailbox id
+    pub async fn store(
+        &self,
+        to: &str,
+        from: &str,
+        packet: Vec<u8>,
+        ttl_secs: Option<u32>,
+        now: u64,
+    ) -> Result<u64, MailboxError> {
+        let tag = presence_digest(to);
+        let stored = self.reserve(tag, from, packet, ttl_secs, now)?;
+
+        if let Some(dir) = &self.dir {
+            let (dir, written) = (dir.clone(), stored.clone());
+            let result = tokio::task::spawn_blocking(move || write_packet(&dir, &tag, &written))
+                .await
+                .unwrap_or_else(|e| Err(io::Error::other(e)));
+            if let Err(e) = result {
+                self.release(&tag, stored.packet.len());
+                return Err(MailboxError::Io(e));
+            }
+        }
+
+        let id = stored.id;
+        let mut boxes = self.boxes.lock().unwrap();
+        let inbox = boxes.inboxes.entry(tag).or_default();
+        inbox.writing -= 1;
+        // Writes finish in any order; the inbox stays sorted by id
+        let index = inbox.packets.partition_point(|packet| packet.id < id);
+        inbox.packets.insert(index, stored);
+        Ok(id)
+    }
+
+    /// Check the quotas and take an id and space for a packet
+    fn reserve(
+        &self,
+        tag: PresenceDigest,
+        from: &str,
+        packet: Vec<u8>,
+        ttl_secs: Option<u32>,
+        now: u64,
+    ) -> Result<StoredPacket, MailboxError> {
+        let config = self.config.read().unwrap();
+        let max_ttl = config.max_ttl.as_secs();
+        let ttl = ttl_secs.map_or(max_ttl, |ttl| u64::from(ttl).min(max_ttl));
+        if ttl == 0 {
+            return Err(MailboxError::Expired);
+        }
+
+        let mut boxes = self.boxes.lock().unwrap();
+        let inbox_full = boxes.inboxes.get(&tag).is_some_and(|inbox| {
+            inbox.packets.len() + inbox.writing >= config.max_packets || inbox.bytes + packet.len() > config.max_bytes
+        });
+        if inbox_full
+            || packet.len() > config.max_bytes
+            || boxes.total_bytes + packet.len() > config.max_total_bytes
+        {
+            return Err(MailboxError::Full);
+        }
+
+        let id = boxes.next_id;
+        boxes.next_id += 1;
+        boxes.total_bytes += packet.len();
+        let inbox = boxes.inboxes.entry(tag).or_default();
+        inbox.bytes += packet.len();
+        inbox.writing += 1;
+        Ok(StoredPacket { id, from: from.to_string(), packet, expires_at: now + ttl })
+    }
+
+    /// Give back the space of a packet that could not be written
+    fn release(&self, tag: &PresenceDigest, len: usize) {
+        let mut boxes = self.boxes.lock().unwrap();
+        boxes.total_bytes -= len;
+        if let Some(inbox) = boxes.inboxes.get_mut(tag) {
+            inbox.bytes -= len;
+            inbox.writing -= 1;
+            if inbox.is_empty() {
+                boxes.inboxes.remove(tag);
+            }
+        }
+    }
+
+    /// Unexpired packets for `node_id`, oldest first
+    pub fn pending(&self, node_id: &str, now: u64) -> Vec<StoredPacket> {
+        let boxes = self.boxes.lock().unwrap();
+        boxes
+            .inboxes
+            .get(&presence_digest(node_id))
+            .map(|inbox| inbox.packets.iter().filter(|p| p.expires_at > now).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Delete packet `id` of `node_id`; only the recipient can acknowledge
+    pub fn ack(&self, node_id: &str, id: u64) -> bool {
+        let tag = presence_digest(node_id);
+        let mut boxes = self.boxes.lock().unwrap();
+        let Some(inbox) = boxes.inboxes.get_mut(&tag) else { return false };
+        let Some(index) = inbox.packets.iter().position(|p| p.id == id) else { return false };
+
+        let removed = inbox.packets.remove(index).expect("index in range");
+        inbox.bytes -= removed.packet.len();
+        if inbox.is_empty() {
+            boxes.inboxes.remove(&tag);
+        }
+        boxes.total_bytes -= removed.packet.len();
+        drop(boxes);
+
+        self.remove_file(&tag, id);
+        true
+    }
+
+    /// Drop expired packets; returns how many
+    pub fn expire(&self, now: u64) -> usize {
+        let mut expired = Vec::new();
+        {
+            let mut boxes = self.boxes.lock().unwrap();
+            let mut freed = 0;
+            boxes.inboxes.retain(|tag, inbox| {
+                inbox.packets.retain(|packet| {
+                    if packet.expires_at > now {
+                        return true;
+                    }
+                    inbox.bytes -= packet.packet.len();
+                    freed += packet.packet.len();
+                    expired.push((*tag, packet.id));
+                    false
+                });
+                !inbox.is_empty()
+            });
+            boxes.total_bytes -= freed;
+        }
+
+        for (tag, id) in &expired {
+            self.remove_file(tag, *id);
+        }
+        expired.len()
+    }
+
+    /// Id the next stored packet gets
+    pub fn next_id(&self) -> u64 {
+        self.boxes.lock().unwrap().next_id
+    }
+
+    /// Never issue ids below `next_id`, even if the clock went back since
+    /// they were issued
+    pub fn reserve_ids(&self, next_id: u64) {
+        let mut boxes = self.boxes.lock().unwrap();
+        boxes.next_id = boxes.next_id.max(next_id);
+    }
+
+    /// Apply new quotas and TTL to packets stored from now on; the
+    /// directory of `config` is ignored
+    pub fn set_limits(&self, config: &MailboxConfig) {
+        let mut current = self.config.write().unwrap();
+        *current = MailboxConfig { dir: current.dir.clone(), ..config.clone() };
+    }
+
+    /// Packets and bytes currently stored
+    pub fn usage(&self) -> (usize, usize) {
+        let boxes = self.boxes.lock().unwrap();
+        let packets = boxes.inboxes.values().map(|inbox| inbox.packets.len()).sum();
+        (packets, boxes.total_bytes)
+    }
+
+    fn remove_file(&self, tag: &PresenceDigest, id: u64) {
+        let Some(dir) = &self.dir else { return };
+        let inbox_dir = dir.join(hex::encode(tag));
+        if let Err(e) = std::fs::remove_file(packet_path(&inbox_dir, id)) {
+            warn!("Mailbox: cannot remove packet {}: {}", id, e);
+        }
+        // Fails while other packets remain
+        let _ = std::fs::remove_dir(&inbox_dir);
+    }
+}
+
+fn packet_path(inbox_dir: &Path, id: u64) -> PathBuf {
+    inbox_dir.join(format!("{:016x}.{}", id, PACKET_EXT))
+}
+
+/// Write via a temporary file so a crash never leaves a partial packet,
+/// and sync the file and the directories so the packet survives a power
+/// loss once the sender is told it was stored
+fn write_packet(dir: &Path, tag: &PresenceDigest, packet: &StoredPacket) -> io::Result<()> {
+    let inbox_dir = dir.join(hex::encode(tag));
+    let created = !inbox_dir.is_dir();
+    std::fs::create_dir_all(&inbox_dir)?;
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(packet, &mut bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let path = packet_path(&inbox_dir, packet.id);
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    File::open(&inbox_dir)?.sync_all()?;
+    if created {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn load_dir(dir: &Path, now: u64, boxes: &mut Boxes) -> io::Result<usize> {
+    let mut loaded = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let inbox_dir = entry?.path();
+        let tag: Option<PresenceDigest> = inbox_dir
+            .file_name()
+            .and_then(|name| hex::decode(name.to_str()?).ok())
+            .and_then(|bytes| bytes.try_into().ok());
+        let Some(tag) = tag.filter(|_| inbox_dir.is_dir()) else { continue };
+
+        let mut packets = Vec::new();
+        for entry in std::fs::read_dir(&inbox_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(PACKET_EXT) {
+                // Leftover of an interrupted write
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+            let stored: Option<StoredPacket> = std::fs::read(&path)
+                .ok()
+                .and_then(|bytes| ciborium::de::from_reader(bytes.as_slice()).ok());
+            match stored {
+                Some(stored) if stored.expires_at > now => packets.push(stored),
+                Some(_) => {
+                    let _ = std::fs::remove_file(&path);
+                }
+                None => warn!("Mailbox: skipping unreadable {}", path.display()),
+            }
+        }
+        if packets.is_empty() {
+            let _ = std::fs::remove_dir(&inbox_dir);
+            continue;
+        }
+
+        packets.sort_by_key(|packet| packet.id);
+        let inbox = boxes.inboxes.entry(tag).or_default();
+        for packet in packets {
+            boxes.next_id = boxes.next_id.max(packet.id + 1);
+            boxes.total_bytes += packet.packet.len();
+            inbox.bytes += packet.packet.len();
+            inbox.packets.push_back(packet);
+            loaded += 1;
+        }
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MailboxConfig {
+        MailboxConfig {
+            max_packets: 2,
+            max_bytes: 100,
+            ..MailboxConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn quota_ttl_and_ack() {
+        let mailbox = Mailbox::open(config()).unwrap();
+        let first = mailbox.store("bob", "alice", vec![1; 10], Some(60), 1_000).await.unwrap();
+        mailbox.store("bob", "alice", vec![2; 10], Some(10), 1_000).await.unwrap();
+        assert!(matches!(mailbox.store("bob", "alice", vec![3; 10], None, 1_000).await, Err(MailboxError::Full)));
+        assert!(matches!(mailbox.store("carol", "alice", vec![0; 101], None, 1_000).await, Err(MailboxError::Full)));
+        assert!(matches!(mailbox.store("carol", "alice", vec![0; 1], Some(0), 1_000).await, Err(MailboxError::Expired)));
+        assert_eq!(mailbox.usage(), (2, 20));
+
+        // Second packet outlives its TTL
+        assert_eq!(mailbox.pending("bob", 1_020).len(), 1);
+        assert_eq!(mailbox.expire(1_020), 1);
+
+        // Only the recipient's own packet can be acknowledged
+        assert!(!mailbox.ack("carol", first));
+        assert!(mailbox.ack("bob", first));
+        assert!(!mailbox.ack("bob", first));
+        assert_eq!(mailbox.usage(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn persisted_packets_survive_reopen() {
+        let dir = std::env::temp_dir().join(format!("yaok-mailbox-{}", rand::random::<u64>()));
+        let config = MailboxConfig { dir: Some(dir.clone()), ..config() };
+        let now = unix_now();
+
+        let mailbox = Mailbox::open(config.clone()).unwrap();
+        let kept = mailbox.store("bob", "alice", b"kept".to_vec(), None, now).await.unwrap();
+        let acked = mailbox.store("bob", "alice", b"acked".to_vec(), None, now).await.unwrap();
+        mailbox.ack("bob", acked);
+        drop(mailbox);
+
+        let reopened = Mailbox::open(config).unwrap();
+        let pending = reopened.pending("bob", now);
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].id, pending[0].packet.as_slice()), (kept, &b"kept"[..]));
+        // New ids continue after the persisted ones
+        assert!(reopened.store("carol", "alice", vec![1], None, now).await.unwrap() > acked);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_share_the_quota() {
+        let dir = std::env::temp_dir().join(format!("yaok-mailbox-{}", rand::random::<u64>()));
+        let config = MailboxConfig { dir: Some(dir.clone()), ..config() };
+        let mailbox = Mailbox::open(config.clone()).unwrap();
+        let now = unix_now();
+
+        // Packets being written count against the quota
+        let results = tokio::join!(
+            mailbox.store("bob", "alice", vec![0; 10], None, now),
+            mailbox.store("bob", "alice", vec![1; 10], None, now),
+            mailbox.store("bob", "alice", vec![2; 10], None, now),
+            mailbox.store("bob", "alice", vec![3; 10], None, now),
+        );
+        let results = [results.0, results.1, results.2, results.3];
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 2);
+        assert_eq!(results.iter().filter(|result| matches!(result, Err(MailboxError::Full))).count(), 2);
+
+        let pending = mailbox.pending("bob", now);
+        assert!(pending.windows(2).all(|pair| pair[0].id < pair[1].id));
+        assert_eq!(Mailbox::open(config).unwrap().pending("bob", now), pending);
+        assert_eq!(mailbox.usage(), (2, 20));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,38 +1,31 @@
-use std::collections::HashMap;
 use std::env;
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use tracing::{info, warn, error, debug};
+use tracing::{info, warn, error};
 use serde::Serialize;
-
-// Security limits to prevent memory exhaustion attacks
-const MAX_PEERS: usize = 10_000;
-const MAX_RATE_ENTRIES: usize = 50_000;
-const CLEANUP_INTERVAL: u32 = 1_000; // Cleanup every N packets
-
-struct RateEntry {
-    window_start: Instant,
-    count: u32,
-}
-
-#[derive(Default, Clone, Serialize)]
-struct Stats {
-    received: u64,
-    forwarded: u64,
-    dropped_rate: u64,
-    dropped_size: u64,
-    dropped_peer_limit: u64,
-    active_peers: usize,
-    rate_entries: usize,
-    uptime_secs: u64,
-}
+use yaok_relay::server::HANDSHAKE_TIMEOUT;
+use yaok_relay::ratelimit::MAX_RATE_ENTRIES;
+use yaok_relay::stats::MAX_PEERS;
+use yaok_relay::admin::AdminRequest;
+use yaok_relay::metrics::{relay_metrics, Format};
+use yaok_relay::{net, web};
+use yaok_relay::mailbox::unix_now;
+use yaok_relay::{
+    Admin, AuditLog, Config, FcmProvider, Mailbox, PushAuth, PushGateway, Relay, RelayState, Rendezvous, ServiceAccountAuth,
+    StaticAuth, Stats, Store, TlsCertificates, WebConfig,
+};
+use yaok_relay_proto::datagram::{Responder, StaticKeypair};
 
 #[derive(Serialize)]
 struct HealthStatus {
     status: String,
     uptime_secs: u64,
     version: String,
+    /// Relay that clients may fall back to (`FALLBACK_RELAY`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback_relay: Option<String>,
 }
 
 #[tokio::main]
@@ -47,312 +40,406 @@ async fn main() -> std::io::Result<()> {
 
     let start_time = Instant::now();
 
-    let port = env::var("RELAY_PORT")
-        .ok()
-        .and_then(|v| v.parse::<u16>().ok())
-        .unwrap_or(40100);
-
-    let max_packet = env::var("MAX_PACKET_SIZE")
-        .ok()
-        .and_then(|v| v.parse::<usize>().ok())
-        .unwrap_or(64_000);
-
-    let rate_limit = env::var("RATE_LIMIT_PPS")
-        .ok()
-        .and_then(|v| v.parse::<u32>().ok())
-        .unwrap_or(200);
-
-    let peer_ttl = env::var("PEER_TTL_SECS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(300);
-
-    let metrics_interval_secs = env::var("METRICS_INTERVAL_SECS")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(60);
-
-    let metrics_port = env::var("METRICS_PORT")
-        .ok()
-        .and_then(|v| v.parse::<u16>().ok())
-        .unwrap_or(9090);
-
-    let fallback_relay = env::var("FALLBACK_RELAY").ok();
-
-    // IMPORTANT:
-    // Bind to a local interface address (0.0.0.0) by default.
-    // Using a DNS name here (e.g. "fly-global-services") can fail on some platforms/providers
-    // and cause crash-restart loops.
-    let bind_addr = env::var("RELAY_BIND").unwrap_or_else(|_| format!("0.0.0.0:{}", port));
-
-    let socket = match UdpSocket::bind(&bind_addr) {
-        Ok(s) => s,
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nUsage: yaok-relay [--config <file.toml>] [--check-config]", e);
+            std::process::exit(2);
+        }
+    };
+    let config = match Config::load(args.config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration:\n{}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.check_config {
+        println!("Configuration OK");
+        return Ok(());
+    }
+    if let Some(path) = &args.config_path {
+        info!("Configuration loaded from {}", path.display());
+    }
+
+    // Both parse: checked by `Config::validate`
+    let bind_addr: SocketAddr = config.server.bind_addr().parse().map_err(std::io::Error::other)?;
+    let udp_bind_addr: SocketAddr = config.server.udp_bind_addr().parse().map_err(std::io::Error::other)?;
+
+    let listener = match net::bind_tcp(bind_addr) {
+        Ok(l) => l,
         Err(err) => {
             error!("Failed to bind relay socket on {}: {}", bind_addr, err);
             return Err(err);
         }
     };
-    socket.set_nonblocking(true)?;
-    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
 
+    let udp_socket = match net::bind_udp(udp_bind_addr) {
+        Ok(s) => s,
+        Err(err) => {
+            error!("Failed to bind relay datagram socket on {}: {}", udp_bind_addr, err);
+            return Err(err);
+        }
+    };
+    let rendezvous_socket = match config.rendezvous.port.map(net::dual_stack) {
+        Some(addr) => match net::bind_udp(addr) {
+            Ok(s) => Some(s),
+            Err(err) => {
+                error!("Failed to bind rendezvous socket on {}: {}", addr, err);
+                return Err(err);
+            }
+        },
+        None => None,
+    };
+    let responder = Responder::new(load_static_key(&config)?);
+    let tls = load_tls(&config)?;
+    let relay_config = config.relay_config()?;
+    let mailbox = Mailbox::open(config.mailbox_config())?;
+    let store = match &config.store.dir {
+        Some(dir) => Some(Store::open(dir, unix_now())?),
+        None => {
+            warn!("No RELAY_STORE_DIR: tag registrations and counters will not survive a restart");
+            None
+        }
+    };
+
+    let push = match &config.push.fcm_url {
+        Some(url) => {
+            let auth: Option<Arc<dyn PushAuth>> = match (&config.push.fcm_service_account, &config.push.fcm_authorization) {
+                (Some(path), _) => Some(Arc::new(ServiceAccountAuth::from_file(path).map_err(std::io::Error::other)?)),
+                (None, Some(authorization)) => Some(Arc::new(StaticAuth::new(authorization.clone()))),
+                (None, None) => None,
+            };
+            let fcm = FcmProvider::new(url, auth).map_err(std::io::Error::other)?;
+            Some(PushGateway::new(config.push_config()).with_provider(Arc::new(fcm)))
+        }
+        None => {
+            info!("No PUSH_FCM_URL: sleeping phones are not woken for stored packets");
+            None
+        }
+    };
+
+    let limits = &config.limits;
     info!(
-        "yaok-relay listening on {}, max_packet={}, rate_limit_pps={}, peer_ttl={}s, metrics_interval={}s",
-        bind_addr, max_packet, rate_limit, peer_ttl, metrics_interval_secs
+        "yaok-relay listening on {}, max_packet={}, rate_limit_pps={}, keepalive={}s, metrics_interval={}s",
+        bind_addr, config.server.max_packet_size, limits.rate_limit_pps, config.server.keepalive_secs, config.metrics.interval_secs
     );
     info!("Security limits: MAX_PEERS={}, MAX_RATE_ENTRIES={}", MAX_PEERS, MAX_RATE_ENTRIES);
-    info!("Metrics HTTP endpoint: http://0.0.0.0:{}", metrics_port);
-    if let Some(ref fallback) = fallback_relay {
+    info!(
+        "Admission: node_rate_limit_pps={}, max_sessions_per_ip={}, pow_difficulty={} above {}% load",
+        limits.node_rate_limit_pps, limits.max_sessions_per_ip, limits.pow_difficulty, limits.pow_load_percent
+    );
+    info!(
+        "Rate budgets: per /{} (IPv4) or /{} (IPv6) {} B/s, per node {} B/s, global {} pps {} B/s, burst {}s, emergency reserve {}%",
+        limits.rate_limit_ipv4_prefix,
+        limits.rate_limit_ipv6_prefix,
+        limits.rate_limit_bytes_per_sec,
+        limits.node_rate_limit_bytes_per_sec,
+        limits.global_rate_limit_pps,
+        limits.global_rate_limit_bytes_per_sec,
+        limits.rate_burst_secs,
+        limits.emergency_reserve_percent
+    );
+    info!("Metrics HTTP endpoint: http://{}", net::dual_stack(config.metrics.port));
+    if let Some(ref fallback) = config.server.fallback_relay {
         info!("Fallback relay configured: {}", fallback);
     }
+    if let Some(ref path) = config.server.relay_list_file {
+        info!("Serving signed relay list from {}", path.display());
+    }
+    match &config.web.public_url {
+        Some(url) => info!("Invite pages at {}/add", url.trim_end_matches('/')),
+        None => warn!("No RELAY_PUBLIC_URL: invite link previews have no image or URL"),
+    }
+    if rendezvous_socket.is_none() {
+        info!("No RENDEZVOUS_PORT: nodes cannot set up direct links");
+    }
+    if let Some(federation) = &relay_config.federation {
+        info!("Federation with {} peer relay(s) as {}", federation.peers.len(), federation.relay_id());
+    }
 
-    let mut peers: HashMap<SocketAddr, Instant> = HashMap::new();
-    let mut rate: HashMap<IpAddr, RateEntry> = HashMap::new();
-    let mut buf = vec![0u8; max_packet];
-    let mut stats = Stats::default();
-    let metrics_interval = Duration::from_secs(metrics_interval_secs);
-    let mut last_metrics = Instant::now();
-    let mut cleanup_counter: u32 = 0;
+    let mut state = RelayState::new(relay_config).with_mailbox(mailbox);
+    if let Some(store) = store {
+        state = state.with_store(store);
+    }
+    if let Some(push) = push {
+        state = state.with_push(push);
+    }
+    if let Some(socket) = &rendezvous_socket {
+        state = state.with_rendezvous(Rendezvous::new(socket.local_addr()?.port()));
+    }
+    let state = Arc::new(state);
+    config.apply(None, &state);
+    let admin = Arc::new(load_admin(&config, state.clone())?);
+    let mut relay = Relay::new(state.clone(), listener)
+        .with_datagram(udp_socket, responder)
+        .with_housekeeping_interval(Duration::from_secs(config.metrics.interval_secs));
+    if let Some(certs) = &tls {
+        relay = relay.with_tls(certs.clone());
+    }
+    if let Some(socket) = rendezvous_socket {
+        relay = relay.with_rendezvous(socket);
+    }
 
     // Shared stats for HTTP endpoint
     let shared_stats = Arc::new(Mutex::new(Stats::default()));
     let stats_clone = shared_stats.clone();
-    let start_clone = start_time;
 
     // Spawn HTTP metrics server
+    let metrics_port = config.metrics.port;
+    let directory = RelayDirectory {
+        fallback_relay: config.server.fallback_relay.clone(),
+        relay_list_file: config.server.relay_list_file.clone(),
+    };
+    let web = config.web_config();
+    let metrics_tls = tls.clone();
     tokio::spawn(async move {
-        if let Err(e) = run_metrics_server(metrics_port, stats_clone, start_clone).await {
+        if let Err(e) = run_metrics_server(metrics_port, stats_clone, start_time, directory, web, metrics_tls, admin).await {
             error!("Metrics server error: {}", e);
         }
     });
 
-    loop {
-        match socket.recv_from(&mut buf) {
-            Ok((len, src)) => {
-                stats.received += 1;
-                if len == 0 || len > max_packet {
-                    stats.dropped_size += 1;
-                    continue;
-                }
+    tokio::spawn(reload_on_sighup(args.config_path, config, state, tls));
 
-                if !allow_packet(&mut rate, src.ip(), rate_limit) {
-                    stats.dropped_rate += 1;
-                    continue;
-                }
-
-                // Check peer limit before adding
-                if peers.len() >= MAX_PEERS && !peers.contains_key(&src) {
-                    stats.dropped_peer_limit += 1;
-                    debug!("Peer limit reached, dropping packet from {}", src);
-                    continue;
-                }
+    // SIGTERM (fly.io stop, docker stop) or Ctrl-C: close sessions and flush stats
+    let shutdown = relay.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received");
+        shutdown.shutdown();
+    });
 
-                peers.insert(src, Instant::now());
+    let stats = relay
+        .run(|stats| {
+            log_stats(stats);
+            // Update shared stats for HTTP endpoint
+            if let Ok(mut shared) = shared_stats.lock() {
+                *shared = stats.clone();
+            }
+        })
+        .await?;
+    info!("Relay stopped after {}s", stats.uptime_secs);
+    Ok(())
+}
 
-                // Periodic cleanup to prevent unbounded growth
-                cleanup_counter += 1;
-                if cleanup_counter >= CLEANUP_INTERVAL {
-                    cleanup_peers(&mut peers, peer_ttl);
-                    cleanup_rate_entries(&mut rate);
-                    cleanup_counter = 0;
-                }
+/// Command line: `--config <file>` (or `RELAY_CONFIG`) and
+/// `--check-config` to validate the configuration and exit
+struct Args {
+    config_path: Option<PathBuf>,
+    check_config: bool,
+}
 
-                for (peer, _) in peers.iter() {
-                    if *peer == src {
-                        continue;
-                    }
-                    if socket.send_to(&buf[..len], peer).is_ok() {
-                        stats.forwarded += 1;
-                    }
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self { config_path: env::var_os("RELAY_CONFIG").map(PathBuf::from), check_config: false };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--check-config" => parsed.check_config = true,
+                "--config" => {
+                    let path = args.next().ok_or("--config needs a file")?;
+                    parsed.config_path = Some(path.into());
                 }
-            }
-            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                cleanup_peers(&mut peers, peer_ttl);
-                cleanup_rate_entries(&mut rate);
-                std::thread::sleep(Duration::from_millis(20));
-                continue;
-            }
-            Err(err) => {
-                error!("recv error: {}", err);
+                other => match other.strip_prefix("--config=") {
+                    Some(path) => parsed.config_path = Some(path.into()),
+                    None => return Err(format!("Unknown argument {}", other)),
+                },
             }
         }
+        Ok(parsed)
+    }
+}
 
-        if last_metrics.elapsed() >= metrics_interval {
-            stats.active_peers = peers.len();
-            stats.rate_entries = rate.len();
-            stats.uptime_secs = start_time.elapsed().as_secs();
-
-            info!(
-                "metrics: received={}, forwarded={}, dropped_rate={}, dropped_size={}, dropped_peer_limit={}, peers={}, rate_entries={}, uptime={}s",
-                stats.received,
-                stats.forwarded,
-                stats.dropped_rate,
-                stats.dropped_size,
-                stats.dropped_peer_limit,
-                stats.active_peers,
-                stats.rate_entries,
-                stats.uptime_secs
-            );
+/// Admin API tokens (`admin.tokens`) and audit log file
+/// (`admin.audit_log`). Without tokens every admin request is refused.
+fn load_admin(config: &Config, state: Arc<RelayState>) -> std::io::Result<Admin> {
+    let tokens = config.admin_tokens().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    if tokens.is_empty() {
+        warn!("No ADMIN_TOKENS: admin API disabled");
+    }
+    let audit = match &config.admin.audit_log {
+        Some(path) => {
+            info!("Admin audit log: {}", path.display());
+            AuditLog::with_file(path)?
+        }
+        None => AuditLog::new(),
+    };
+    Ok(Admin::new(state, tokens, audit))
+}
 
-            // Update shared stats for HTTP endpoint
-            if let Ok(mut shared) = shared_stats.lock() {
-                *shared = stats.clone();
-            }
+/// TLS certificate for the session listener and the metrics server
+///
+/// Enabled by `tls.cert_path` (PEM chain) and `tls.key_path` (PEM key).
+/// Without them both are served in plain text, for local use or behind a
+/// TLS-terminating proxy.
+fn load_tls(config: &Config) -> std::io::Result<Option<Arc<TlsCertificates>>> {
+    match (&config.tls.cert_path, &config.tls.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = TlsCertificates::load(cert_path, key_path)?;
+            info!("TLS enabled with certificate {}", cert_path.display());
+            Ok(Some(Arc::new(certs)))
+        }
+        _ => {
+            warn!("No TLS_CERT_PATH/TLS_KEY_PATH: session stream and metrics are served without TLS");
+            Ok(None)
+        }
+    }
+}
 
-            stats = Stats::default();
-            last_metrics = Instant::now();
+/// On SIGHUP reload the configuration file and the TLS certificate
+/// (renewal files are also picked up by polling, see `Relay`). A file
+/// that fails to load or validate keeps the current configuration.
+async fn reload_on_sighup(
+    config_path: Option<PathBuf>,
+    mut current: Config,
+    state: Arc<RelayState>,
+    certs: Option<Arc<TlsCertificates>>,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            if let Some(certs) = &certs {
+                if let Err(e) = certs.reload() {
+                    warn!("Keeping current TLS certificate, reload failed: {}", e);
+                }
+            }
+            match Config::load(config_path.as_deref()) {
+                Ok(config) => {
+                    for section in config.apply(Some(&current), &state) {
+                        warn!("Configuration of {} changed; it takes effect after a restart", section);
+                    }
+                    info!("Configuration reloaded");
+                    current = config;
+                }
+                Err(e) => warn!("Keeping current configuration, reload failed:\n{}", e),
+            }
         }
     }
+    #[cfg(not(unix))]
+    drop((config_path, current, state, certs));
 }
 
-fn allow_packet(rate: &mut HashMap<IpAddr, RateEntry>, ip: IpAddr, limit: u32) -> bool {
-    let now = Instant::now();
-    let entry = rate.entry(ip).or_insert(RateEntry {
-        window_start: now,
-        count: 0,
-    });
+/// Resolves on SIGTERM or Ctrl-C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Cannot listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
 
-    if now.duration_since(entry.window_start) >= Duration::from_secs(1) {
-        entry.window_start = now;
-        entry.count = 0;
-    }
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    if entry.count >= limit {
-        return false;
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
-
-    entry.count += 1;
-    true
 }
 
-fn cleanup_peers(peers: &mut HashMap<SocketAddr, Instant>, ttl_secs: u64) {
-    let ttl = Duration::from_secs(ttl_secs);
-    let now = Instant::now();
-    peers.retain(|_, last_seen| now.duration_since(*last_seen) <= ttl);
-}
+fn log_stats(stats: &Stats) {
+    info!(
+        "metrics: received={}, forwarded={}, federated_out={}, federated_in={}, dropped_loop={}, dropped_rate={}, dropped_node_rate={}, dropped_global_rate={}, emergency_reserved={}, dropped_size={}, dropped_datagram_size={}, dropped_peer_limit={}, dropped_offline={}, stored={}, mailbox_delivered={}, mailbox_acked={}, mailbox_expired={}, dropped_mailbox_full={}, mailbox_packets={}, flooded={}, flood_deliveries={}, dropped_flood_rate={}, max_fanout={}, tag_routed={}, registered_tags={}, punch_introductions={}, push_sent={}, push_rate_limited={}, auth_failures={}, rejected_pow={}, rejected_ip_sessions={}, rejected_banned={}, evicted={}, peers={}, federation_links={}, rate_entries={}, uptime={}s",
+        stats.received,
+        stats.forwarded,
+        stats.federated_out,
+        stats.federated_in,
+        stats.dropped_loop,
+        stats.dropped_rate,
+        stats.dropped_node_rate,
+        stats.dropped_global_rate,
+        stats.emergency_reserved,
+        stats.dropped_size,
+        stats.dropped_datagram_size,
+        stats.dropped_peer_limit,
+        stats.dropped_offline,
+        stats.stored,
+        stats.mailbox_delivered,
+        stats.mailbox_acked,
+        stats.mailbox_expired,
+        stats.dropped_mailbox_full,
+        stats.mailbox_packets,
+        stats.flooded,
+        stats.flood_deliveries,
+        stats.dropped_flood_rate,
+        stats.max_fanout,
+        stats.tag_routed,
+        stats.registered_tags,
+        stats.punch_introductions,
+        stats.push_sent,
+        stats.push_rate_limited,
+        stats.auth_failures,
+        stats.rejected_pow,
+        stats.rejected_ip_sessions,
+        stats.rejected_banned,
+        stats.evicted,
+        stats.active_peers,
+        stats.federation_links,
+        stats.rate_entries,
+        stats.uptime_secs
+    );}
+
+/// Relay static X25519 key for datagram sessions. Clients pin its
+/// fingerprint, and resumption tickets stay valid only while it is kept.
+///
+/// `server.static_key` (hex) takes precedence; otherwise the key is read
+/// from `server.static_key_file`, which is created on first start.
+fn load_static_key(config: &Config) -> std::io::Result<StaticKeypair> {
+    if let Some(bytes) = config.static_key() {
+        return Ok(StaticKeypair::from_secret_bytes(bytes));
+    }
 
-/// Clean up rate limiting entries older than 60 seconds
-fn cleanup_rate_entries(rate: &mut HashMap<IpAddr, RateEntry>) {
-    let now = Instant::now();
-    rate.retain(|_, entry| now.duration_since(entry.window_start).as_secs() < 60);
-    
-    // If still over MAX_RATE_ENTRIES, remove oldest 10%
-    if rate.len() > MAX_RATE_ENTRIES {
-        let to_remove = rate.len() / 10;
-        let mut entries: Vec<_> = rate.iter().map(|(ip, entry)| (*ip, entry.window_start)).collect();
-        entries.sort_by_key(|(_, time)| *time);
-        
-        for (ip, _) in entries.iter().take(to_remove) {
-            rate.remove(ip);
+    match &config.server.static_key_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(contents) => hex::decode(contents.trim())
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .map(StaticKeypair::from_secret_bytes)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("Invalid relay static key in {}", path.display()),
+                    )
+                }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let key = StaticKeypair::generate();
+                std::fs::write(path, hex::encode(key.secret_bytes()))?;
+                info!("Generated relay static key in {}", path.display());
+                Ok(key)
+            }
+            Err(err) => Err(err),
+        },
+        None => {
+            warn!("No RELAY_STATIC_KEY or RELAY_STATIC_KEY_FILE: using a temporary key, pins and resumption tickets will not survive a restart");
+            Ok(StaticKeypair::generate())
         }
-        warn!("Rate entries cleanup: removed {} oldest entries", to_remove);
     }
 }
 
-fn generate_add_page(query: &str) -> String {
-    // Parse name from query
-    let name = query.split('&')
-        .find(|part| part.starts_with("name="))
-        .and_then(|part| part.strip_prefix("name="))
-        .and_then(|encoded| urlencoding::decode(encoded).ok())
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| "Користувач".to_string());
-    
-    let title = format!("{} запрошує вас в Я ОК!", name);
-    let description = "Швидке повідомлення про безпеку для близьких 🇺🇦";
-    
-    format!(r#"<!DOCTYPE html>
-<html lang="uk">
-<head>
-    <meta charset="UTF-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1.0">
-    <title>{title}</title>
-    
-    <!-- Favicon -->
-    <link rel="icon" href="/favicon.ico" type="image/svg+xml">
-    
-    <!-- Open Graph for WhatsApp/Telegram/Viber -->
-    <meta property="og:title" content="{title}">
-    <meta property="og:description" content="{description}">
-    <meta property="og:image" content="https://i-am-ok-relay.fly.dev/og-image.png">
-    <meta property="og:type" content="website">
-    <meta property="og:url" content="https://i-am-ok-relay.fly.dev/add?{query}">
-    
-    <!-- Twitter Card -->
-    <meta name="twitter:card" content="summary">
-    <meta name="twitter:title" content="{title}">
-    <meta name="twitter:description" content="{description}">
-    
-    <style>
-        body {{
-            margin: 0;
-            padding: 20px;
-            font-family: -apple-system, BlinkMacSystemFont, 'Segoe UI', Roboto, sans-serif;
-            background: linear-gradient(135deg, #0057B7 0%, #34C759 100%);
-            min-height: 100vh;
-            display: flex;
-            align-items: center;
-            justify-content: center;
-            color: white;
-        }}
-        .container {{
-            text-align: center;
-            max-width: 400px;
-        }}
-        .logo {{
-            font-size: 80px;
-            margin-bottom: 20px;
-        }}
-        h1 {{
-            font-size: 28px;
-            margin-bottom: 10px;
-        }}
-        p {{
-            font-size: 16px;
-            opacity: 0.9;
-            margin-bottom: 30px;
-        }}
-        .button {{
-            display: inline-block;
-            background: white;
-            color: #0057B7;
-            padding: 16px 32px;
-            border-radius: 12px;
-            text-decoration: none;
-            font-weight: bold;
-            font-size: 18px;
-            box-shadow: 0 4px 12px rgba(0,0,0,0.2);
-        }}
-        .button:hover {{
-            transform: translateY(-2px);
-            box-shadow: 0 6px 16px rgba(0,0,0,0.3);
-        }}
-    </style>
-    
-    <script>
-        // Auto-redirect to app on mobile
-        const userAgent = navigator.userAgent.toLowerCase();
-        const isMobile = /android|iphone|ipad|ipod/.test(userAgent);
-        if (isMobile) {{
-            const yaokLink = "yaok://add?{query}";
-            window.location.href = yaokLink;
-            // Fallback to store after 2 seconds if app not installed
-            setTimeout(() => {{
-                if (userAgent.includes('android')) {{
-                    window.location.href = "https://play.google.com/store/apps/details?id=app.poruch.ya_ok";
-                }}
-            }}, 2000);
-        }}
-    </script>
-</head>
-<body>
-    <div class="container">
-        <div class="logo">💚</div>
-        <h1>{title}</h1>
-        <p>{description}</p>
-        <a href="yaok://add?{query}" class="button">Відкрити додаток</a>
-    </div>
-</body>
-</html>"#, title=title, description=description, query=query)
+/// Where clients learn about other relays
+struct RelayDirectory {
+    fallback_relay: Option<String>,
+    relay_list_file: Option<PathBuf>,
 }
 
 /// Run HTTP metrics server for monitoring
@@ -360,6 +447,10 @@ async fn run_metrics_server(
     port: u16,
     stats: Arc<Mutex<Stats>>,
     start_time: Instant,
+    directory: RelayDirectory,
+    web: WebConfig,
+    tls: Option<Arc<TlsCertificates>>,
+    admin: Arc<Admin>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     use hyper::server::conn::http1;
     use hyper::service::service_fn;
@@ -367,21 +458,30 @@ async fn run_metrics_server(
     use hyper_util::rt::TokioIo;
     use http_body_util::Full;
     use hyper::body::Bytes;
-    use tokio::net::TcpListener;
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    info!("Metrics server listening on http://{}", addr);
+    let addr = net::dual_stack(port);
+    let listener = net::bind_tcp(addr)?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Metrics server listening on {}://{}", scheme, addr);
+    let directory = Arc::new(directory);
+    let web = Arc::new(web);
 
     loop {
         let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
         let stats = stats.clone();
         let start_time = start_time;
+        let directory = directory.clone();
+        let web = web.clone();
+        let admin = admin.clone();
 
         let service = service_fn(move |req: Request<hyper::body::Incoming>| {
             let stats = stats.clone();
+            let directory = directory.clone();
+            let web = web.clone();
+            let admin = admin.clone();
             async move {
+                if req.uri().path().starts_with("/admin/") {
+                    return Ok::<_, hyper::Error>(admin_response(&admin, req).await);
+                }
                 match (req.method(), req.uri().path()) {
                     (&Method::GET, "/") => {
                         // Admin panel HTML
@@ -394,13 +494,18 @@ async fn run_metrics_server(
                         Ok::<_, hyper::Error>(response)
                     }
                     (&Method::GET, path) if path.starts_with("/add") => {
-                        // Deep link page with favicon
+                        // Invite landing page, opens the app or its store
                         let query = req.uri().query().unwrap_or("");
-                        let html = generate_add_page(query);
-                        let mut response = Response::new(Full::new(Bytes::from(html)));
-                        response.headers_mut().insert(
-                            hyper::header::CONTENT_TYPE,
-                            hyper::header::HeaderValue::from_static("text/html; charset=utf-8")
+                        let accept_language = req.headers().get(hyper::header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok());
+                        let page = web::invite_page(&web, query, accept_language);
+                        let mut response = Response::new(Full::new(Bytes::from(page.html)));
+                        *response.status_mut() = StatusCode::from_u16(page.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                        let headers = response.headers_mut();
+                        headers.insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/html; charset=utf-8"));
+                        headers.insert(hyper::header::VARY, hyper::header::HeaderValue::from_static("Accept-Language"));
+                        headers.insert(
+                            hyper::header::X_CONTENT_TYPE_OPTIONS,
+                            hyper::header::HeaderValue::from_static("nosniff"),
                         );
                         Ok::<_, hyper::Error>(response)
                     }
@@ -442,9 +547,11 @@ async fn run_metrics_server(
                     }
                     (&Method::GET, "/health") => {
                         let health = HealthStatus {
-                            status: "healthy".to_string(),
+                            // Drained relays stay up but take no new sessions
+                            status: if admin.state().is_closing() { "draining" } else { "healthy" }.to_string(),
                             uptime_secs: start_time.elapsed().as_secs(),
                             version: env!("CARGO_PKG_VERSION").to_string(),
+                            fallback_relay: directory.fallback_relay.clone(),
                         };
                         let json = serde_json::to_string(&health).unwrap_or_else(|_| "{}".to_string());
                         let mut response = Response::new(Full::new(Bytes::from(json)));
@@ -454,48 +561,37 @@ async fn run_metrics_server(
                         );
                         Ok::<_, hyper::Error>(response)
                     }
-                    (&Method::GET, "/metrics") => {
-                        let stats_snapshot = stats.lock().unwrap().clone();
-                        
-                        // Prometheus format
-                        let metrics = format!(
-                            "# HELP yaok_relay_received_total Total packets received\\n\
-                             # TYPE yaok_relay_received_total counter\\n\
-                             yaok_relay_received_total {}\\n\
-                             # HELP yaok_relay_forwarded_total Total packets forwarded\\n\
-                             # TYPE yaok_relay_forwarded_total counter\\n\
-                             yaok_relay_forwarded_total {}\\n\
-                             # HELP yaok_relay_dropped_rate_total Packets dropped due to rate limit\\n\
-                             # TYPE yaok_relay_dropped_rate_total counter\\n\
-                             yaok_relay_dropped_rate_total {}\\n\
-                             # HELP yaok_relay_dropped_size_total Packets dropped due to size\\n\
-                             # TYPE yaok_relay_dropped_size_total counter\\n\
-                             yaok_relay_dropped_size_total {}\\n\
-                             # HELP yaok_relay_dropped_peer_limit_total Packets dropped due to peer limit\\n\
-                             # TYPE yaok_relay_dropped_peer_limit_total counter\\n\
-                             yaok_relay_dropped_peer_limit_total {}\\n\
-                             # HELP yaok_relay_active_peers Active peer count\\n\
-                             # TYPE yaok_relay_active_peers gauge\\n\
-                             yaok_relay_active_peers {}\\n\
-                             # HELP yaok_relay_rate_entries Rate limiting entries\\n\
-                             # TYPE yaok_relay_rate_entries gauge\\n\
-                             yaok_relay_rate_entries {}\\n\
-                             # HELP yaok_relay_uptime_seconds Server uptime\\n\
-                             # TYPE yaok_relay_uptime_seconds gauge\\n\
-                             yaok_relay_uptime_seconds {}\\n",
-                            stats_snapshot.received,
-                            stats_snapshot.forwarded,
-                            stats_snapshot.dropped_rate,
-                            stats_snapshot.dropped_size,
-                            stats_snapshot.dropped_peer_limit,
-                            stats_snapshot.active_peers,
-                            stats_snapshot.rate_entries,
-                            stats_snapshot.uptime_secs
+                    (&Method::GET, "/relays") => {
+                        // The list is signed offline by the publisher; the relay only serves it
+                        let list = match directory.relay_list_file.as_deref() {
+                            Some(path) => tokio::fs::read(path).await.ok(),
+                            None => None,
+                        };
+                        let Some(list) = list else {
+                            let mut response = Response::new(Full::new(Bytes::from("Not Found")));
+                            *response.status_mut() = StatusCode::NOT_FOUND;
+                            return Ok::<_, hyper::Error>(response);
+                        };
+                        let mut response = Response::new(Full::new(Bytes::from(list)));
+                        response.headers_mut().insert(
+                            hyper::header::CONTENT_TYPE,
+                            hyper::header::HeaderValue::from_static("application/json")
+                        );
+                        response.headers_mut().insert(
+                            hyper::header::CACHE_CONTROL,
+                            hyper::header::HeaderValue::from_static("public, max-age=300")
                         );
+                        Ok::<_, hyper::Error>(response)
+                    }
+                    (&Method::GET, "/metrics") => {
+                        // Monotonic totals, OpenMetrics if the scraper asks for it
+                        let accept = req.headers().get(hyper::header::ACCEPT).and_then(|v| v.to_str().ok());
+                        let format = Format::from_accept(accept);
+                        let metrics = relay_metrics(admin.state(), start_time.elapsed()).render(format);
                         let mut response = Response::new(Full::new(Bytes::from(metrics)));
                         response.headers_mut().insert(
                             hyper::header::CONTENT_TYPE,
-                            hyper::header::HeaderValue::from_static("text/plain; charset=utf-8")
+                            hyper::header::HeaderValue::from_static(format.content_type())
                         );
                         Ok::<_, hyper::Error>(response)
                     }
@@ -518,10 +614,61 @@ async fn run_metrics_server(
             }
         });
 
+        let tls = tls.clone();
         tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+            let result = match tls {
+                Some(certs) => {
+                    let accepted = tokio::time::timeout(HANDSHAKE_TIMEOUT, certs.acceptor().accept(stream)).await;
+                    match accepted {
+                        Ok(Ok(stream)) => http1::Builder::new().serve_connection(TokioIo::new(stream), service).await,
+                        _ => return,
+                    }
+                }
+                None => http1::Builder::new().serve_connection(TokioIo::new(stream), service).await,
+            };
+            if let Err(err) = result {
                 error!("Metrics connection error: {}", err);
             }
         });
     }
 }
+
+/// Largest admin request body
+const MAX_ADMIN_BODY: usize = 16 * 1024;
+
+/// Adapt an HTTP request to the admin API
+async fn admin_response(
+    admin: &Admin,
+    req: hyper::Request<hyper::body::Incoming>,
+) -> hyper::Response<http_body_util::Full<hyper::body::Bytes>> {
+    use http_body_util::{BodyExt, Full, Limited};
+    use hyper::header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, WWW_AUTHENTICATE};
+    use hyper::{Response, StatusCode};
+
+    let method = req.method().as_str().to_string();
+    let path = req.uri().path().to_string();
+    let authorization = req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let (status, body) = match Limited::new(req.into_body(), MAX_ADMIN_BODY).collect().await {
+        Ok(body) => {
+            let body = body.to_bytes();
+            let result = admin.handle(&AdminRequest {
+                method: &method,
+                path: &path,
+                authorization: authorization.as_deref(),
+                body: &body,
+            });
+            (result.status, result.body)
+        }
+        Err(_) => (413, r#"{"error":"Request body too large"}"#.to_string()),
+    };
+
+    let mut response = Response::new(Full::new(hyper::body::Bytes::from(body)));
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if status == 401 {
+        headers.insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    response
+}
@@ -0,0 +1,696 @@
+//! Metrics registry and exposition
+//!
+//! Counters in `Stats` are per logging interval; the relay folds every
+//! interval into monotonic totals (`RelayState::total_stats`) and
+//! `relay_metrics` turns those, the gauges and the histograms into a
+//! `Registry`. With a store (`crate::store`) the totals continue across
+//! restarts. `/metrics` renders it in the Prometheus text format, or in
+//! OpenMetrics when the scraper asks for it (`Format::from_accept`).
+
+use crate::server::RelayState;
+use crate::stats::Stats;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// How a client session reaches the relay
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Tls,
+    Udp,
+}
+
+impl Transport {
+    pub const ALL: [Transport; 3] = [Transport::Tcp, Transport::Tls, Transport::Udp];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Udp => "udp",
+        }
+    }
+}
+
+/// One value per transport
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PerTransport<T> {
+    pub tcp: T,
+    pub tls: T,
+    pub udp: T,
+}
+
+impl<T> PerTransport<T> {
+    pub fn get(&self, transport: Transport) -> &T {
+        match transport {
+            Transport::Tcp => &self.tcp,
+            Transport::Tls => &self.tls,
+            Transport::Udp => &self.udp,
+        }
+    }
+
+    pub fn get_mut(&mut self, transport: Transport) -> &mut T {
+        match transport {
+            Transport::Tcp => &mut self.tcp,
+            Transport::Tls => &mut self.tls,
+            Transport::Udp => &mut self.udp,
+        }
+    }
+}
+
+/// Handshake and routing time buckets (seconds)
+pub const LATENCY_BUCKETS: &[f64] = &[0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Packet size buckets (bytes)
+pub const SIZE_BUCKETS: &[f64] = &[64.0, 256.0, 1024.0, 4096.0, 16_384.0, 65_536.0];
+
+/// Lock-free histogram; observations are never reset
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// One per bound plus +Inf, not cumulative
+    buckets: Box<[AtomicU64]>,
+    /// f64 bits
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    /// The count is the total of the buckets read here, so the exported
+    /// `+Inf` bucket always equals `_count`
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<u64> = self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+        HistogramSnapshot {
+            bounds: self.bounds,
+            count: buckets.iter().sum(),
+            buckets,
+            sum: f64::from_bits(self.sum.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistogramSnapshot {
+    pub bounds: &'static [f64],
+    /// One per bound plus +Inf, not cumulative
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// Histograms kept by `RelayState`
+pub struct Histograms {
+    /// Hello received to Welcome sent
+    pub handshake: PerTransport<Histogram>,
+    /// Time to route one `Send` (lookup, queueing, mailbox write)
+    pub route: Histogram,
+    /// Size of packets in `Send` and `Flood`
+    pub packet_size: PerTransport<Histogram>,
+}
+
+impl Default for Histograms {
+    fn default() -> Self {
+        let per_transport = |bounds| PerTransport {
+            tcp: Histogram::new(bounds),
+            tls: Histogram::new(bounds),
+            udp: Histogram::new(bounds),
+        };
+        Self {
+            handshake: per_transport(LATENCY_BUCKETS),
+            route: Histogram::new(LATENCY_BUCKETS),
+            packet_size: per_transport(SIZE_BUCKETS),
+        }
+    }
+}
+
+/// Exposition format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Prometheus text format 0.0.4
+    Prometheus,
+    /// OpenMetrics 1.0 text format
+    OpenMetrics,
+}
+
+impl Format {
+    /// OpenMetrics when the `Accept` header lists it
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Format::OpenMetrics,
+            _ => Format::Prometheus,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+enum Value {
+    Number(f64),
+    Histogram(HistogramSnapshot),
+}
+
+struct Sample {
+    labels: Vec<(&'static str, String)>,
+    value: Value,
+}
+
+struct Family {
+    /// Without the `_total` suffix of counters
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    samples: Vec<Sample>,
+}
+
+/// Metric families in registration order; samples with the same name
+/// join one family
+#[derive(Default)]
+pub struct Registry {
+    families: Vec<Family>,
+}
+
+impl Registry {
+    /// `name` without `_total`; the suffix is added when rendering
+    pub fn counter(&mut self, name: &'static str, help: &'static str, labels: &[(&'static str, &str)], value: u64) {
+        self.add(name, help, Kind::Counter, labels, Value::Number(value as f64));
+    }
+
+    pub fn gauge(&mut self, name: &'static str, help: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.add(name, help, Kind::Gauge, labels, Value::Number(value));
+    }
+
+    pub fn histogram(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&'static str, &str)],
+        snapshot: HistogramSnapshot,
+    ) {
+        self.add(name, help, Kind::Histogram, labels, Value::Histogram(snapshot));
+    }
+
+    fn add(&mut self, name: &'static str, help: &'static str, kind: Kind, labels: &[(&'static str, &str)], value: Value) {
+        let labels = labels.iter().map(|(key, value)| (*key, value.to_string())).collect();
+        let sample = Sample { labels, value };
+        match self.families.iter_mut().find(|family| family.name == name) {
+            Some(family) => {
+                debug_assert_eq!(family.kind, kind, "metric {} registered with two types", name);
+                family.samples.push(sample);
+            }
+            None => self.families.push(Family { name, help, kind, samples: vec![sample] }),
+        }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        let mut out = String::new();
+        for family in &self.families {
+            // Prometheus names the counter family after its samples,
+            // OpenMetrics after the metric
+            let family_name = match (family.kind, format) {
+                (Kind::Counter, Format::Prometheus) => format!("{}_total", family.name),
+                _ => family.name.to_string(),
+            };
+            let _ = writeln!(out, "# TYPE {} {}", family_name, family.kind.name());
+            let _ = writeln!(out, "# HELP {} {}", family_name, escape_help(family.help));
+            for sample in &family.samples {
+                match &sample.value {
+                    Value::Number(value) => {
+                        let name = match family.kind {
+                            Kind::Counter => format!("{}_total", family.name),
+                            _ => family.name.to_string(),
+                        };
+                        write_sample(&mut out, &name, &sample.labels, None, *value);
+                    }
+                    Value::Histogram(histogram) => {
+                        let bucket_name = format!("{}_bucket", family.name);
+                        let mut cumulative = 0;
+                        for (i, count) in histogram.buckets.iter().enumerate() {
+                            cumulative += count;
+                            let le = histogram.bounds.get(i).map_or_else(|| "+Inf".to_string(), |bound| format_value(*bound));
+                            write_sample(&mut out, &bucket_name, &sample.labels, Some(&le), cumulative as f64);
+                        }
+                        write_sample(&mut out, &format!("{}_count", family.name), &sample.labels, None, histogram.count as f64);
+                        write_sample(&mut out, &format!("{}_sum", family.name), &sample.labels, None, histogram.sum);
+                    }
+                }
+            }
+        }
+        if format == Format::OpenMetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&'static str, String)], le: Option<&str>, value: f64) {
+    out.push_str(name);
+    let le = le.map(|le| ("le", le.to_string()));
+    let mut labels = labels.iter().chain(le.as_ref()).peekable();
+    if labels.peek().is_some() {
+        out.push('{');
+        for (i, (key, value)) in labels.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{}=\"{}\"", key, escape_label(value));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", format_value(value));
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        // Integers without a fraction, floats in their shortest form
+        format!("{}", value)
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+/// Everything `/metrics` exposes
+pub fn relay_metrics(state: &RelayState, uptime: Duration) -> Registry {
+    let stats = state.total_stats();
+    let histograms = state.histograms();
+    let mut registry = Registry::default();
+
+    for transport in Transport::ALL {
+        let labels = [("transport", transport.label())];
+        registry.counter(
+            "yaok_relay_received",
+            "Packets received from clients",
+            &labels,
+            *stats.received_by.get(transport),
+        );
+    }
+    for transport in Transport::ALL {
+        let labels = [("transport", transport.label())];
+        registry.gauge(
+            "yaok_relay_sessions",
+            "Open client sessions",
+            &labels,
+            *stats.sessions.get(transport) as f64,
+        );
+    }
+    registry.counter("yaok_relay_forwarded", "Packets delivered to a local session", &[], stats.forwarded);
+
+    let Stats {
+        dropped_rate,
+        dropped_node_rate,
+        dropped_global_rate,
+        dropped_size,
+        dropped_datagram_size,
+        dropped_flood_rate,
+        dropped_peer_limit,
+        dropped_offline,
+        dropped_mailbox_full,
+        dropped_loop,
+        ..
+    } = stats;
+    for (reason, value) in [
+        ("ip_rate", dropped_rate),
+        ("node_rate", dropped_node_rate),
+        ("global_rate", dropped_global_rate),
+        ("size", dropped_size),
+        ("datagram_size", dropped_datagram_size),
+        ("flood_rate", dropped_flood_rate),
+        ("offline", dropped_offline),
+        ("mailbox_full", dropped_mailbox_full),
+        ("loop", dropped_loop),
+        ("peer_limit", dropped_peer_limit),
+    ] {
+        registry.counter("yaok_relay_dropped", "Packets dropped, by reason", &[("reason", reason)], value);
+    }
+    for (reason, value) in [
+        ("ip_sessions", stats.rejected_ip_sessions),
+        ("auth", stats.auth_failures),
+        ("pow", stats.rejected_pow),
+        ("banned", stats.rejected_banned),
+    ] {
+        registry.counter("yaok_relay_rejections", "Sessions refused by admission control, by reason", &[("reason", reason)], value);
+    }
+    registry.counter("yaok_relay_evicted", "Sessions closed by an operator", &[], stats.evicted);
+    registry.counter("yaok_relay_pow_challenges", "Handshakes that asked for proof of work", &[], stats.pow_challenges);
+
+    registry.counter("yaok_relay_udp_handshakes", "Datagram sessions established", &[], stats.udp_handshakes);
+    registry.counter("yaok_relay_udp_resumptions", "Datagram sessions resumed from a ticket", &[], stats.udp_resumptions);
+    registry.counter(
+        "yaok_relay_udp_retries",
+        "Stateless handshake replies (cookie retry or ticket reject)",
+        &[],
+        stats.udp_retries,
+    );
+
+    registry.counter("yaok_relay_federated_out", "Packets forwarded to federated relays", &[], stats.federated_out);
+    registry.counter("yaok_relay_federated_in", "Packets received from federated relays", &[], stats.federated_in);
+    registry.gauge("yaok_relay_federation_links", "Open federation links", &[], stats.federation_links as f64);
+
+    registry.counter("yaok_relay_stored", "Packets stored for offline recipients", &[], stats.stored);
+    registry.counter(
+        "yaok_relay_mailbox_delivered",
+        "Stored packets sent to reconnecting recipients",
+        &[],
+        stats.mailbox_delivered,
+    );
+    registry.counter("yaok_relay_mailbox_acked", "Stored packets acknowledged and deleted", &[], stats.mailbox_acked);
+    registry.counter("yaok_relay_mailbox_expired", "Stored packets dropped after their TTL", &[], stats.mailbox_expired);
+    registry.gauge("yaok_relay_mailbox_packets", "Packets waiting in mailboxes", &[], stats.mailbox_packets as f64);
+    registry.gauge("yaok_relay_mailbox_bytes", "Bytes waiting in mailboxes", &[], stats.mailbox_bytes as f64);
+
+    registry.counter("yaok_relay_flooded", "Emergency packets broadcast to all sessions", &[], stats.flooded);
+    registry.counter(
+        "yaok_relay_emergency_reserved",
+        "Emergency packets let through on the rate limit reserve",
+        &[],
+        stats.emergency_reserved,
+    );
+    registry.counter("yaok_relay_flood_deliveries", "Sessions reached by emergency floods", &[], stats.flood_deliveries);
+    registry.gauge("yaok_relay_max_fanout", "Most sessions one packet went to", &[], stats.max_fanout as f64);
+
+    registry.counter("yaok_relay_tag_registrations", "Recipient tag registrations", &[], stats.tag_registrations);
+    registry.counter("yaok_relay_tag_routed", "Packets addressed by recipient tag", &[], stats.tag_routed);
+    registry.gauge("yaok_relay_registered_tags", "Recipient tags currently registered", &[], stats.registered_tags as f64);
+
+    registry.counter("yaok_relay_punch_requests", "Requests for a direct link between nodes", &[], stats.punch_requests);
+    registry.counter("yaok_relay_rendezvous_binds", "Public UDP addresses learned for hole punching", &[], stats.rendezvous_binds);
+    registry.counter("yaok_relay_punch_introductions", "Pairs of nodes introduced for a direct link", &[], stats.punch_introductions);
+
+    registry.counter("yaok_relay_push_registrations", "Push token registrations and removals", &[], stats.push_registrations);
+    for (result, value) in [
+        ("queued", stats.push_queued),
+        ("rate_limited", stats.push_rate_limited),
+        ("sent", stats.push_sent),
+        ("failed", stats.push_failed),
+    ] {
+        registry.counter("yaok_relay_push_wakeups", "Push wakeups of offline recipients, by result", &[("result", result)], value);
+    }
+    registry.gauge("yaok_relay_push_tokens", "Nodes with a push token", &[], stats.push_tokens as f64);
+    registry.gauge("yaok_relay_rate_entries", "Rate limiting entries", &[], stats.rate_entries as f64);
+
+    for transport in Transport::ALL {
+        registry.histogram(
+            "yaok_relay_handshake_duration_seconds",
+            "Time from Hello to Welcome",
+            &[("transport", transport.label())],
+            histograms.handshake.get(transport).snapshot(),
+        );
+    }
+    registry.histogram(
+        "yaok_relay_route_duration_seconds",
+        "Time to route one Send",
+        &[],
+        histograms.route.snapshot(),
+    );
+    for transport in Transport::ALL {
+        registry.histogram(
+            "yaok_relay_packet_size_bytes",
+            "Size of packets sent by clients",
+            &[("transport", transport.label())],
+            histograms.packet_size.get(transport).snapshot(),
+        );
+    }
+
+    registry.gauge("yaok_relay_uptime_seconds", "Server uptime", &[], uptime.as_secs() as f64);
+    registry.counter("yaok_relay_restarts", "Starts restored from the store", &[], state.restarts());
+    registry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::RelayConfig;
+    use std::collections::{HashMap, HashSet};
+
+    /// One parsed sample line
+    #[derive(Debug)]
+    struct Line {
+        name: String,
+        labels: Vec<(String, String)>,
+        value: f64,
+    }
+
+    fn parse_sample(line: &str) -> Line {
+        let (series, value) = line.rsplit_once(' ').unwrap_or_else(|| panic!("no value: {}", line));
+        let value = match value {
+            "+Inf" => f64::INFINITY,
+            "-Inf" => f64::NEG_INFINITY,
+            value => value.parse().unwrap_or_else(|_| panic!("bad value: {}", line)),
+        };
+        let (name, labels) = match series.split_once('{') {
+            Some((name, rest)) => {
+                let body = rest.strip_suffix('}').unwrap_or_else(|| panic!("unclosed labels: {}", line));
+                let mut labels = Vec::new();
+                let mut chars = body.chars().peekable();
+                while chars.peek().is_some() {
+                    let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+                    assert_eq!(chars.next(), Some('"'), "unquoted label value: {}", line);
+                    let mut value = String::new();
+                    loop {
+                        match chars.next().unwrap_or_else(|| panic!("unterminated label value: {}", line)) {
+                            '\\' => match chars.next() {
+                                Some('n') => value.push('\n'),
+                                Some(c @ ('\\' | '"')) => value.push(c),
+                                other => panic!("bad escape {:?}: {}", other, line),
+                            },
+                            '"' => break,
+                            c => value.push(c),
+                        }
+                    }
+                    labels.push((key, value));
+                    match chars.next() {
+                        Some(',') | None => {}
+                        other => panic!("expected , got {:?}: {}", other, line),
+                    }
+                }
+                (name, labels)
+            }
+            None => (series, Vec::new()),
+        };
+        assert!(valid_name(name), "bad metric name: {}", line);
+        for (key, _) in &labels {
+            assert!(valid_name(key) && !key.starts_with("__"), "bad label name: {}", line);
+        }
+        Line { name: name.to_string(), labels, value }
+    }
+
+    fn valid_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == ':')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+    }
+
+    /// Check an OpenMetrics exposition: every sample belongs to the family
+    /// declared just before it, with the suffixes its type allows,
+    /// counters are whole and non-negative, histogram buckets are
+    /// cumulative and end with +Inf equal to the count, and the text ends
+    /// with `# EOF`. Returns family types and samples.
+    fn parse_openmetrics(text: &str) -> (HashMap<String, String>, Vec<Line>) {
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.last(), Some(&"# EOF"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let mut types = HashMap::new();
+        let mut samples = Vec::new();
+        let mut current: Option<(String, String)> = None;
+        let mut finished = HashSet::new();
+        for line in &lines[..lines.len() - 1] {
+            if let Some(rest) = line.strip_prefix("# ") {
+                let mut parts = rest.splitn(3, ' ');
+                let (keyword, name) = (parts.next().unwrap(), parts.next().unwrap());
+                match keyword {
+                    "TYPE" => {
+                        let kind = parts.next().unwrap().to_string();
+                        assert!(["counter", "gauge", "histogram"].contains(&kind.as_str()), "type {}", kind);
+                        assert!(!name.ends_with("_total"), "OpenMetrics counter family with suffix: {}", name);
+                        if let Some((previous, _)) = current.take() {
+                            finished.insert(previous);
+                        }
+                        assert!(!finished.contains(name) && types.insert(name.to_string(), kind.clone()).is_none(), "family {} declared twice", name);
+                        current = Some((name.to_string(), kind));
+                    }
+                    "HELP" => assert_eq!(current.as_ref().map(|(n, _)| n.as_str()), Some(name), "HELP outside its family"),
+                    other => panic!("unexpected comment {}", other),
+                }
+                continue;
+            }
+            let (family, kind) = current.clone().unwrap_or_else(|| panic!("sample before TYPE: {}", line));
+            let sample = parse_sample(line);
+            let suffix = sample.name.strip_prefix(family.as_str()).unwrap_or_else(|| panic!("{} outside family {}", sample.name, family));
+            let allowed: &[&str] = match kind.as_str() {
+                "counter" => &["_total"],
+                "gauge" => &[""],
+                _ => &["_bucket", "_count", "_sum"],
+            };
+            assert!(allowed.contains(&suffix), "{} is not a valid {} sample", sample.name, kind);
+            if kind == "counter" {
+                assert!(sample.value >= 0.0 && sample.value.fract() == 0.0, "counter value: {}", line);
+            }
+            samples.push(sample);
+        }
+
+        // Histogram buckets: cumulative, ending with +Inf == _count
+        for (family, kind) in &types {
+            if kind != "histogram" {
+                continue;
+            }
+            let mut series: HashMap<_, Vec<(f64, f64)>> = HashMap::new();
+            let mut counts = HashMap::new();
+            for sample in samples.iter().filter(|s| s.name.starts_with(family.as_str())) {
+                let mut labels = sample.labels.clone();
+                if sample.name.ends_with("_bucket") {
+                    let le = labels.iter().position(|(key, _)| key == "le").expect("bucket without le");
+                    let (_, le) = labels.remove(le);
+                    let le = if le == "+Inf" { f64::INFINITY } else { le.parse().unwrap() };
+                    series.entry(labels).or_default().push((le, sample.value));
+                } else if sample.name.ends_with("_count") {
+                    counts.insert(labels, sample.value);
+                }
+            }
+            for (labels, buckets) in series {
+                assert!(buckets.windows(2).all(|w| w[0].0 < w[1].0 && w[0].1 <= w[1].1), "{} buckets not cumulative", family);
+                let (le, last) = *buckets.last().unwrap();
+                assert!(le.is_infinite());
+                assert_eq!(Some(&last), counts.get(&labels), "{} +Inf bucket differs from count", family);
+            }
+        }
+        (types, samples)
+    }
+
+    fn value(samples: &[Line], name: &str, labels: &[(&str, &str)]) -> f64 {
+        samples
+            .iter()
+            .find(|s| s.name == name && labels.iter().all(|(k, v)| s.labels.iter().any(|(lk, lv)| lk == k && lv == v)))
+            .unwrap_or_else(|| panic!("no sample {} {:?}", name, labels))
+            .value
+    }
+
+    #[test]
+    fn relay_metrics_are_valid_openmetrics_and_monotonic() {
+        let state = RelayState::new(RelayConfig::default());
+        state.record(|stats| {
+            stats.received += 3;
+            stats.received_by.udp += 3;
+            stats.dropped_rate += 2;
+        });
+        state.histograms().packet_size.udp.observe(100.0);
+        state.histograms().packet_size.udp.observe(70_000.0);
+        state.histograms().handshake.tls.observe_duration(Duration::from_millis(3));
+
+        let text = relay_metrics(&state, Duration::from_secs(5)).render(Format::OpenMetrics);
+        let (types, samples) = parse_openmetrics(&text);
+        assert_eq!(types["yaok_relay_received"], "counter");
+        assert_eq!(types["yaok_relay_packet_size_bytes"], "histogram");
+        assert_eq!(value(&samples, "yaok_relay_received_total", &[("transport", "udp")]), 3.0);
+        assert_eq!(value(&samples, "yaok_relay_dropped_total", &[("reason", "ip_rate")]), 2.0);
+        assert_eq!(value(&samples, "yaok_relay_packet_size_bytes_bucket", &[("transport", "udp"), ("le", "256")]), 1.0);
+        assert_eq!(value(&samples, "yaok_relay_packet_size_bytes_bucket", &[("transport", "udp"), ("le", "+Inf")]), 2.0);
+        assert_eq!(value(&samples, "yaok_relay_packet_size_bytes_sum", &[("transport", "udp")]), 70_100.0);
+        assert_eq!(value(&samples, "yaok_relay_handshake_duration_seconds_count", &[("transport", "tls")]), 1.0);
+
+        // Taking the interval stats for logging does not reset counters
+        let interval = state.take_stats();
+        assert_eq!(interval.received, 3);
+        state.record(|stats| {
+            stats.received += 1;
+            stats.received_by.udp += 1;
+        });
+        let text = relay_metrics(&state, Duration::from_secs(6)).render(Format::OpenMetrics);
+        let (_, samples) = parse_openmetrics(&text);
+        assert_eq!(value(&samples, "yaok_relay_received_total", &[("transport", "udp")]), 4.0);
+        assert_eq!(value(&samples, "yaok_relay_dropped_total", &[("reason", "ip_rate")]), 2.0);
+        assert_eq!(state.take_stats().received, 1);
+    }
+
+    #[test]
+    fn histogram_count_matches_buckets_under_concurrent_observers() {
+        static BOUNDS: &[f64] = &[1.0, 10.0];
+        let histogram = std::sync::Arc::new(Histogram::new(BOUNDS));
+        let observers: Vec<_> = (0..4)
+            .map(|_| {
+                let histogram = histogram.clone();
+                std::thread::spawn(move || (0..10_000).for_each(|i| histogram.observe((i % 20) as f64)))
+            })
+            .collect();
+        while !observers.iter().all(|observer| observer.is_finished()) {
+            let snapshot = histogram.snapshot();
+            assert_eq!(snapshot.count, snapshot.buckets.iter().sum::<u64>());
+        }
+        observers.into_iter().for_each(|observer| observer.join().unwrap());
+        assert_eq!(histogram.snapshot().count, 40_000);
+    }
+
+    #[test]
+    fn prometheus_format_names_counter_families_with_suffix() {
+        let mut registry = Registry::default();
+        registry.counter("requests", "Requests \"served\"\nso far", &[("path", "a\"b\\c")], 7);
+        registry.gauge("temperature_celsius", "Temperature", &[], 21.5);
+        let text = registry.render(Format::Prometheus);
+        assert_eq!(
+            text,
+            "# TYPE requests_total counter\n\
+             # HELP requests_total Requests \"served\"\\nso far\n\
+             requests_total{path=\"a\\\"b\\\\c\"} 7\n\
+             # TYPE temperature_celsius gauge\n\
+             # HELP temperature_celsius Temperature\n\
+             temperature_celsius 21.5\n"
+        );
+        let line = parse_sample(text.lines().nth(2).unwrap());
+        assert_eq!(line.labels, vec![("path".to_string(), "a\"b\\c".to_string())]);
+        assert_eq!(Format::from_accept(Some("application/openmetrics-text;version=1.0.0,text/plain;q=0.5")), Format::OpenMetrics);
+        assert_eq!(Format::from_accept(None), Format::Prometheus);
+    }
+}
@@ -0,0 +1,114 @@
+//! Listening sockets
+//!
+//! The unspecified IPv6 address (`[::]`, the default bind) listens
+//! dual-stack, IPv6 and IPv4 on one socket, whatever `net.ipv6.bindv6only`
+//! says. IPv4 peers then show up as IPv4-mapped addresses; sessions use
+//! [`peer_ip`] so limits, bans and logs see plain IPv4. On hosts without
+//! IPv6 the relay falls back to `0.0.0.0`.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::warn;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Default bind address: dual-stack on `port`
+pub fn dual_stack(port: u16) -> SocketAddr {
+    SocketAddr::from(([0u16; 8], port))
+}
+
+/// Address of a peer as limits and bans see it
+pub fn peer_ip(addr: SocketAddr) -> IpAddr {
+    addr.ip().to_canonical()
+}
+
+pub fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = bind_with_fallback(addr, Type::STREAM, Protocol::TCP)?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = bind_with_fallback(addr, Type::DGRAM, Protocol::UDP)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn bind_with_fallback(addr: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    match bind(addr, kind, protocol) {
+        Err(e) if is_dual_stack(addr) => {
+            let fallback = SocketAddr::from((Ipv4Addr::UNSPECIFIED, addr.port()));
+            warn!("IPv6 unavailable ({}), listening on {} only", e, fallback);
+            bind(fallback, kind, protocol)
+        }
+        result => result,
+    }
+}
+
+fn is_dual_stack(addr: SocketAddr) -> bool {
+    matches!(addr.ip(), IpAddr::V6(ip) if ip.is_unspecified())
+}
+
+fn bind(addr: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, Some(protocol))?;
+    if is_dual_stack(addr) {
+        socket.set_only_v6(false)?;
+    }
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn dual_stack_listener_accepts_ipv4_and_ipv6() {
+        let listener = bind_tcp(dual_stack(0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for target in [format!("[::1]:{}", port), format!("127.0.0.1:{}", port)] {
+            let mut client = TcpStream::connect(&target).await.unwrap();
+            let (mut accepted, addr) = listener.accept().await.unwrap();
+            assert!(peer_ip(addr).is_loopback());
+            assert_eq!(peer_ip(addr).is_ipv4(), target.starts_with("127."), "{} seen as {}", target, addr);
+
+            client.write_all(b"ok").await.unwrap();
+            let mut buf = [0u8; 2];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn dual_stack_datagram_socket_answers_both_families() {
+        let socket = bind_udp(dual_stack(0)).unwrap();
+        let port = socket.local_addr().unwrap().port();
+
+        for (bind, target) in [("[::1]:0", format!("[::1]:{}", port)), ("127.0.0.1:0", format!("127.0.0.1:{}", port))] {
+            let client = UdpSocket::bind(bind).await.unwrap();
+            client.send_to(b"ping", &target).await.unwrap();
+            let mut buf = [0u8; 4];
+            let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(peer_ip(from), client.local_addr().unwrap().ip());
+            socket.send_to(b"pong", from).await.unwrap();
+            client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        }
+    }
+
+    #[tokio::test]
+    async fn specific_address_binds_as_given() {
+        let listener = bind_tcp("[::1]:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_eq!(addr.ip(), IpAddr::V6(std::net::Ipv6Addr::LOCALHOST));
+        assert!(TcpStream::connect(("127.0.0.1", addr.port())).await.is_err());
+    }
+}
@@ -0,0 +1,468 @@
+//! Token-bucket rate limiting of client packets
+//!
+//! Every `Send` and `Flood` costs one packet plus its length in bytes, charged
+//! to three budgets at once: the source network (the address cut to
+//! `ipv4_prefix`/`ipv6_prefix` bits), the authenticated node and the relay as
+//! a whole. A bucket holds `burst_secs` worth of its rate and refills
+//! continuously, so there is no window edge to double a burst across. A
+//! packet refused by one budget is not charged to the others.
+//!
+//! Emergency packets (`Flood`) may still pass once a budget is spent: each
+//! budget keeps a reserve of `emergency_reserve_percent` of its rate that
+//! only they draw from.
+//!
+//! Time comes from a [`Clock`], so tests can move it by hand.
+
+use crate::bans::IpRange;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::warn;
+
+/// Rate limiting entries kept at most, for networks and for nodes each
+pub const MAX_RATE_ENTRIES: usize = 50_000;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Rates of one budget; 0 leaves that dimension unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    pub packets_per_sec: u32,
+    pub bytes_per_sec: u64,
+}
+
+impl Budget {
+    fn is_unlimited(&self) -> bool {
+        self.packets_per_sec == 0 && self.bytes_per_sec == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimits {
+    /// Per source network
+    pub ip: Budget,
+    /// Per authenticated node
+    pub node: Budget,
+    /// Whole relay
+    pub global: Budget,
+    /// Leading address bits that share an `ip` budget
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Seconds of traffic a full bucket lets through at once
+    pub burst_secs: u32,
+    /// Extra share of every budget kept for emergency packets
+    pub emergency_reserve_percent: u8,
+}
+
+impl RateLimits {
+    fn reserve(&self) -> f64 {
+        f64::from(self.emergency_reserve_percent) / 100.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    Normal,
+    /// `Flood`: may use the emergency reserve
+    Emergency,
+}
+
+/// Budget that refused a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Ip,
+    Node,
+    Global,
+}
+
+/// How an admitted packet was paid for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admitted {
+    Budget,
+    /// At least one budget was spent and the emergency reserve paid
+    Reserve,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Pool {
+    /// Dimension is unlimited
+    Free,
+    Normal,
+    Reserve,
+}
+
+/// Tokens of one dimension (packets or bytes)
+#[derive(Clone, Copy)]
+struct Tokens {
+    normal: f64,
+    reserve: f64,
+}
+
+impl Tokens {
+    fn full(rate: f64, limits: &RateLimits) -> Self {
+        let capacity = rate * f64::from(limits.burst_secs);
+        Self { normal: capacity, reserve: capacity * limits.reserve() }
+    }
+
+    fn refill(&mut self, rate: f64, elapsed: f64, limits: &RateLimits) {
+        let full = Self::full(rate, limits);
+        self.normal = (self.normal + rate * elapsed).min(full.normal);
+        self.reserve = (self.reserve + rate * limits.reserve() * elapsed).min(full.reserve);
+    }
+
+    fn is_full(&self, rate: f64, limits: &RateLimits) -> bool {
+        let full = Self::full(rate, limits);
+        self.normal >= full.normal && self.reserve >= full.reserve
+    }
+
+    fn pool(&self, rate: f64, cost: f64, priority: Priority) -> Option<Pool> {
+        if rate == 0.0 {
+            Some(Pool::Free)
+        } else if self.normal >= cost {
+            Some(Pool::Normal)
+        } else if priority == Priority::Emergency && self.reserve >= cost {
+            Some(Pool::Reserve)
+        } else {
+            None
+        }
+    }
+
+    fn take(&mut self, pool: Pool, cost: f64) {
+        match pool {
+            Pool::Free => {}
+            Pool::Normal => self.normal -= cost,
+            Pool::Reserve => self.reserve -= cost,
+        }
+    }
+}
+
+/// Packet and byte buckets of one budget
+struct Buckets {
+    packets: Tokens,
+    bytes: Tokens,
+    updated: Instant,
+}
+
+impl Buckets {
+    fn full(budget: &Budget, limits: &RateLimits, now: Instant) -> Self {
+        let (packets, bytes) = rates(budget);
+        Self { packets: Tokens::full(packets, limits), bytes: Tokens::full(bytes, limits), updated: now }
+    }
+
+    fn refill(&mut self, budget: &Budget, limits: &RateLimits, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let (packets, bytes) = rates(budget);
+        self.packets.refill(packets, elapsed, limits);
+        self.bytes.refill(bytes, elapsed, limits);
+        self.updated = now;
+    }
+
+    fn is_full(&self, budget: &Budget, limits: &RateLimits) -> bool {
+        let (packets, bytes) = rates(budget);
+        self.packets.is_full(packets, limits) && self.bytes.is_full(bytes, limits)
+    }
+
+    /// Pools that would pay for the packet, `None` if the budget is spent
+    fn plan(&self, budget: &Budget, size: f64, priority: Priority) -> Option<(Pool, Pool)> {
+        let (packets, bytes) = rates(budget);
+        Some((self.packets.pool(packets, 1.0, priority)?, self.bytes.pool(bytes, size, priority)?))
+    }
+
+    fn take(&mut self, (packets, bytes): (Pool, Pool), size: f64) {
+        self.packets.take(packets, 1.0);
+        self.bytes.take(bytes, size);
+    }
+}
+
+fn rates(budget: &Budget) -> (f64, f64) {
+    (f64::from(budget.packets_per_sec), budget.bytes_per_sec as f64)
+}
+
+struct Inner {
+    networks: HashMap<IpRange, Buckets>,
+    nodes: HashMap<String, Buckets>,
+    global: Option<Buckets>,
+}
+
+pub struct RateLimiter {
+    clock: Arc<dyn Clock>,
+    inner: Mutex<Inner>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl RateLimiter {
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self { clock, inner: Mutex::new(Inner { networks: HashMap::new(), nodes: HashMap::new(), global: None }) }
+    }
+
+    /// Charge a packet of `size` bytes from `ip`/`node_id` to its budgets
+    pub fn check(
+        &self,
+        limits: &RateLimits,
+        ip: IpAddr,
+        node_id: &str,
+        size: usize,
+        priority: Priority,
+    ) -> Result<Admitted, Scope> {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { networks, nodes, global } = &mut *inner;
+
+        // Unlimited budgets keep no state
+        let mut budgets: Vec<(Scope, &Budget, &mut Buckets)> = Vec::with_capacity(3);
+        if !limits.ip.is_unlimited() {
+            let network = IpRange::covering(ip, limits.ipv4_prefix, limits.ipv6_prefix);
+            let buckets = networks.entry(network).or_insert_with(|| Buckets::full(&limits.ip, limits, now));
+            budgets.push((Scope::Ip, &limits.ip, buckets));
+        }
+        if !limits.node.is_unlimited() {
+            let buckets = nodes.entry(node_id.to_string()).or_insert_with(|| Buckets::full(&limits.node, limits, now));
+            budgets.push((Scope::Node, &limits.node, buckets));
+        }
+        if !limits.global.is_unlimited() {
+            let buckets = global.get_or_insert_with(|| Buckets::full(&limits.global, limits, now));
+            budgets.push((Scope::Global, &limits.global, buckets));
+        }
+
+        let size = size as f64;
+        let mut plans = Vec::with_capacity(budgets.len());
+        for (scope, budget, buckets) in &mut budgets {
+            buckets.refill(budget, limits, now);
+            plans.push(buckets.plan(budget, size, priority).ok_or(*scope)?);
+        }
+
+        let mut admitted = Admitted::Budget;
+        for ((_, _, buckets), plan) in budgets.iter_mut().zip(plans) {
+            if plan.0 == Pool::Reserve || plan.1 == Pool::Reserve {
+                admitted = Admitted::Reserve;
+            }
+            buckets.take(plan, size);
+        }
+        Ok(admitted)
+    }
+
+    /// Networks and nodes with rate limiting state
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.networks.len() + inner.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget buckets that refilled completely: a new one is the same
+    pub fn cleanup(&self, limits: &RateLimits) {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        let Inner { networks, nodes, .. } = &mut *inner;
+        cleanup_buckets(networks, &limits.ip, limits, now);
+        cleanup_buckets(nodes, &limits.node, limits, now);
+    }
+}
+
+fn cleanup_buckets<K: std::hash::Hash + Eq + Clone>(
+    entries: &mut HashMap<K, Buckets>,
+    budget: &Budget,
+    limits: &RateLimits,
+    now: Instant,
+) {
+    entries.retain(|_, buckets| {
+        buckets.refill(budget, limits, now);
+        !buckets.is_full(budget, limits)
+    });
+
+    // If still over MAX_RATE_ENTRIES, remove the 10% that were idle longest
+    if entries.len() > MAX_RATE_ENTRIES {
+        let to_remove = entries.len() / 10;
+        let mut idle: Vec<_> = entries.iter().map(|(key, buckets)| (key.clone(), buckets.packets.normal)).collect();
+        idle.sort_by(|a, b| b.1.total_cmp(&a.1));
+        for (key, _) in idle.iter().take(to_remove) {
+            entries.remove(key);
+        }
+        warn!("Rate entries cleanup: removed {} least active entries", to_remove);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    struct MockClock(Mutex<Instant>);
+
+    impl MockClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn limiter() -> (RateLimiter, Arc<MockClock>) {
+        let clock = Arc::new(MockClock(Mutex::new(Instant::now())));
+        (RateLimiter::with_clock(clock.clone()), clock)
+    }
+
+    fn limits() -> RateLimits {
+        RateLimits {
+            ip: Budget { packets_per_sec: 10, bytes_per_sec: 0 },
+            node: Budget { packets_per_sec: 4, bytes_per_sec: 1000 },
+            global: Budget::default(),
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+            burst_secs: 1,
+            emergency_reserve_percent: 50,
+        }
+    }
+
+    const ALICE: &str = "alice";
+    const BOB: &str = "bob";
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn passed(limiter: &RateLimiter, limits: &RateLimits, ip: IpAddr, node: &str, size: usize, count: usize) -> usize {
+        (0..count).filter(|_| limiter.check(limits, ip, node, size, Priority::Normal).is_ok()).count()
+    }
+
+    #[test]
+    fn bucket_refills_continuously_without_window_edges() {
+        let (limiter, clock) = limiter();
+        let limits = limits();
+        let addr = ip("198.51.100.1");
+
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 10, 10), 4);
+        assert_eq!(limiter.check(&limits, addr, ALICE, 10, Priority::Normal), Err(Scope::Node));
+
+        // A fixed window would let 4 more through right after its edge;
+        // the bucket gives back one packet per 250 ms
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 10, 10), 1);
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 10, 10), 2);
+
+        // A bucket holds no more than burst_secs
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 10, 10), 4);
+    }
+
+    #[test]
+    fn bytes_are_limited_separately_from_packets() {
+        let (limiter, clock) = limiter();
+        let limits = limits();
+        let addr = ip("198.51.100.1");
+
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 400, 4), 2);
+        clock.advance(Duration::from_millis(400));
+        assert_eq!(limiter.check(&limits, addr, ALICE, 400, Priority::Normal), Ok(Admitted::Budget));
+        assert_eq!(limiter.check(&limits, addr, ALICE, 1, Priority::Normal), Ok(Admitted::Budget));
+    }
+
+    #[test]
+    fn nodes_behind_one_address_have_own_budgets_and_share_the_network() {
+        let (limiter, _clock) = limiter();
+        let limits = limits();
+
+        assert_eq!(passed(&limiter, &limits, ip("198.51.100.1"), ALICE, 1, 10), 4);
+        assert_eq!(passed(&limiter, &limits, ip("198.51.100.1"), BOB, 1, 10), 4);
+        // Same /24: 2 packets left in the network budget
+        assert_eq!(passed(&limiter, &limits, ip("198.51.100.200"), "carol", 1, 10), 2);
+        assert_eq!(limiter.check(&limits, ip("198.51.100.7"), "dave", 1, Priority::Normal), Err(Scope::Ip));
+        // Another network is not affected
+        assert_eq!(passed(&limiter, &limits, ip("203.0.113.1"), "dave", 1, 10), 4);
+
+        // IPv6: one budget per /64
+        let mut limits = limits;
+        limits.node = Budget::default();
+        assert_eq!(passed(&limiter, &limits, ip("2001:db8::1"), ALICE, 1, 6), 6);
+        assert_eq!(passed(&limiter, &limits, ip("2001:db8::ffff:1"), BOB, 1, 6), 4);
+        assert_eq!(passed(&limiter, &limits, ip("2001:db8:0:1::1"), BOB, 1, 6), 6);
+    }
+
+    #[test]
+    fn refused_packet_is_not_charged_to_other_budgets() {
+        let (limiter, _clock) = limiter();
+        let limits = limits();
+        let addr = ip("198.51.100.1");
+
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 1, 4), 4);
+        // 20 packets refused for the node leave the network budget alone
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 1, 20), 0);
+        assert_eq!(passed(&limiter, &limits, addr, BOB, 1, 10), 4);
+    }
+
+    #[test]
+    fn global_budget_covers_every_sender() {
+        let (limiter, clock) = limiter();
+        let mut limits = limits();
+        limits.global = Budget { packets_per_sec: 5, bytes_per_sec: 0 };
+
+        assert_eq!(passed(&limiter, &limits, ip("198.51.100.1"), ALICE, 1, 3), 3);
+        assert_eq!(passed(&limiter, &limits, ip("203.0.113.1"), BOB, 1, 3), 2);
+        assert_eq!(limiter.check(&limits, ip("192.0.2.1"), "carol", 1, Priority::Normal), Err(Scope::Global));
+        clock.advance(Duration::from_millis(200));
+        assert!(limiter.check(&limits, ip("192.0.2.1"), "carol", 1, Priority::Normal).is_ok());
+    }
+
+    #[test]
+    fn emergency_packets_use_the_reserve_once_budget_is_spent() {
+        let (limiter, clock) = limiter();
+        let mut limits = limits();
+        limits.global = Budget { packets_per_sec: 4, bytes_per_sec: 0 };
+        let addr = ip("198.51.100.1");
+
+        assert_eq!(limiter.check(&limits, addr, ALICE, 1, Priority::Emergency), Ok(Admitted::Budget));
+        assert_eq!(passed(&limiter, &limits, addr, BOB, 1, 10), 3);
+
+        // The reserve is 50% of 4 packets: two emergency packets over budget
+        assert_eq!(limiter.check(&limits, addr, ALICE, 1, Priority::Emergency), Ok(Admitted::Reserve));
+        assert_eq!(limiter.check(&limits, addr, BOB, 1, Priority::Emergency), Ok(Admitted::Reserve));
+        assert_eq!(limiter.check(&limits, addr, ALICE, 1, Priority::Emergency), Err(Scope::Global));
+        assert_eq!(limiter.check(&limits, addr, BOB, 1, Priority::Normal), Err(Scope::Node));
+
+        // Normal traffic never touches the reserve, and it refills
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(passed(&limiter, &limits, addr, "carol", 1, 10), 4);
+        assert_eq!(limiter.check(&limits, addr, ALICE, 1, Priority::Emergency), Ok(Admitted::Reserve));
+    }
+
+    #[test]
+    fn limits_change_without_losing_state_and_idle_buckets_are_dropped() {
+        let (limiter, clock) = limiter();
+        let mut limits = limits();
+        let addr = ip("198.51.100.1");
+
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 1, 4), 4);
+        assert_eq!(limiter.len(), 2);
+        limits.node.packets_per_sec = 2;
+        assert_eq!(passed(&limiter, &limits, addr, ALICE, 1, 2), 0);
+
+        limiter.cleanup(&limits);
+        assert_eq!(limiter.len(), 2);
+        clock.advance(Duration::from_secs(1));
+        limiter.cleanup(&limits);
+        assert!(limiter.is_empty());
+    }
+}
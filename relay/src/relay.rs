@@ -0,0 +1,259 @@
+//! Relay process: session endpoints and housekeeping in one task
+//!
+//! `Relay` owns the TCP listener (plain or TLS-terminating), the optional
+//! datagram and rendezvous sockets and the shared `RelayState`. `run`
+//! serves the endpoints (and push wakeups, if the state has a gateway),
+//! picks up renewed TLS certificates and, every
+//! housekeeping interval, cleans up state and hands a stats snapshot to
+//! the caller. On shutdown it stops accepting, closes the sessions with
+//! `Bye`, waits for them to end (at most the grace period) and reports
+//! the final stats. Every snapshot is also saved to the store, if the
+//! state has one.
+
+use crate::datagram::serve_datagram;
+use crate::federation::start_federation;
+use crate::push::start_push;
+use crate::rendezvous::serve_rendezvous;
+use crate::server::{serve, RelayConfig, RelayState};
+use crate::stats::Stats;
+use crate::tls::{check_for_renewal, serve_tls, TlsCertificates};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+use yaok_relay_proto::datagram::Responder;
+
+/// Default interval between cleanups and stats snapshots
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the TLS certificate files are checked for renewal
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default time sessions get to close on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Stops a running `Relay`
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<watch::Sender<bool>>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+pub struct Relay {
+    state: Arc<RelayState>,
+    listener: TcpListener,
+    datagram: Option<(UdpSocket, Responder)>,
+    rendezvous: Option<UdpSocket>,
+    tls: Option<Arc<TlsCertificates>>,
+    housekeeping_interval: Duration,
+    shutdown_grace: Duration,
+    shutdown: Arc<watch::Sender<bool>>,
+    started: Instant,
+}
+
+impl Relay {
+    pub fn new(state: Arc<RelayState>, listener: TcpListener) -> Self {
+        Self {
+            state,
+            listener,
+            datagram: None,
+            rendezvous: None,
+            tls: None,
+            housekeeping_interval: HOUSEKEEPING_INTERVAL,
+            shutdown_grace: SHUTDOWN_GRACE,
+            shutdown: Arc::new(watch::Sender::new(false)),
+            started: Instant::now(),
+        }
+    }
+
+    /// Relay with fresh state listening on `addr` (TCP only)
+    pub async fn bind(config: RelayConfig, addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self::new(Arc::new(RelayState::new(config)), listener))
+    }
+
+    /// Also serve encrypted datagram sessions on `socket`
+    pub fn with_datagram(mut self, socket: UdpSocket, responder: Responder) -> Self {
+        self.datagram = Some((socket, responder));
+        self
+    }
+
+    /// Answer rendezvous `Bind`s on `socket`; the state needs
+    /// `RelayState::with_rendezvous` with its port
+    pub fn with_rendezvous(mut self, socket: UdpSocket) -> Self {
+        self.rendezvous = Some(socket);
+        self
+    }
+
+    /// Terminate TLS on the TCP listener with `certs`
+    pub fn with_tls(mut self, certs: Arc<TlsCertificates>) -> Self {
+        self.tls = Some(certs);
+        self
+    }
+
+    pub fn with_housekeeping_interval(mut self, interval: Duration) -> Self {
+        self.housekeeping_interval = interval;
+        self
+    }
+
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    pub fn state(&self) -> Arc<RelayState> {
+        self.state.clone()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn datagram_addr(&self) -> Option<SocketAddr> {
+        self.datagram.as_ref().and_then(|(socket, _)| socket.local_addr().ok())
+    }
+
+    pub fn rendezvous_addr(&self) -> Option<SocketAddr> {
+        self.rendezvous.as_ref().and_then(|socket| socket.local_addr().ok())
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
+    /// Serve until shutdown (or an endpoint fails). `on_stats` gets every
+    /// snapshot, the final one included; the final snapshot is returned.
+    pub async fn run(self, mut on_stats: impl FnMut(&Stats) + Send) -> io::Result<Stats> {
+        let Relay { state, listener, datagram, rendezvous, tls, housekeeping_interval, shutdown_grace, shutdown, started } = self;
+        start_federation(&state);
+        start_push(&state);
+
+        let mut endpoints = JoinSet::new();
+        match &tls {
+            Some(certs) => endpoints.spawn(serve_tls(listener, certs.clone(), state.clone())),
+            None => endpoints.spawn(serve(listener, state.clone())),
+        };
+        if let Some((socket, responder)) = datagram {
+            endpoints.spawn(serve_datagram(socket, responder, state.clone()));
+        }
+        if let Some(socket) = rendezvous {
+            endpoints.spawn(serve_rendezvous(socket, state.clone()));
+        }
+
+        let snapshot = |state: &RelayState| {
+            state.cleanup();
+            let mut stats = state.take_stats();
+            stats.uptime_secs = started.elapsed().as_secs();
+            if let Err(e) = state.save_snapshot() {
+                warn!("Store: snapshot failed: {}", e);
+            }
+            stats
+        };
+
+        let mut stop = shutdown.subscribe();
+        let mut housekeeping = tokio::time::interval(housekeeping_interval.max(Duration::from_millis(1)));
+        housekeeping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        housekeeping.tick().await;
+        let mut cert_check = tokio::time::interval(CERT_CHECK_INTERVAL);
+        cert_check.tick().await;
+
+        let result = loop {
+            tokio::select! {
+                _ = stop.wait_for(|stop| *stop) => break Ok(()),
+                _ = housekeeping.tick() => on_stats(&snapshot(&state)),
+                _ = cert_check.tick(), if tls.is_some() => {
+                    if let Some(certs) = &tls {
+                        check_for_renewal(certs);
+                    }
+                }
+                Some(joined) = endpoints.join_next() => {
+                    // Endpoints only return when their socket fails
+                    break match joined {
+                        Ok(result) => result,
+                        Err(e) => Err(io::Error::other(e)),
+                    };
+                }
+            }
+        };
+
+        info!("Relay shutting down, closing {} sessions", state.session_count());
+        // Sockets are closed once the aborted endpoint tasks are gone
+        endpoints.abort_all();
+        while endpoints.join_next().await.is_some() {}
+        state.close_sessions();
+        let deadline = Instant::now() + shutdown_grace;
+        while state.session_count() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let stats = snapshot(&state);
+        state.sync_store().await;
+        on_stats(&stats);
+        result.map(|()| stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::sync::Mutex;
+    use tokio::net::TcpStream;
+    use yaok_relay_proto::{auth_payload, node_id_for_key, stream_link, MessageLink, RelayMessage, PROTOCOL_VERSION};
+
+    async fn connect(addr: SocketAddr, key: &SigningKey) -> MessageLink {
+        let mut link = stream_link(TcpStream::connect(addr).await.unwrap());
+        let node_id = node_id_for_key(key.verifying_key().as_bytes());
+        let hello = RelayMessage::Hello {
+            version: PROTOCOL_VERSION,
+            node_id: node_id.clone(),
+            public_key: key.verifying_key().to_bytes().to_vec(),
+        };
+        link.outgoing.send(hello).await.unwrap();
+        let Some(RelayMessage::Challenge { nonce, .. }) = link.incoming.recv().await else { panic!("expected challenge") };
+        let signature = key.sign(&auth_payload(&nonce, &link.binding, &node_id)).to_bytes().to_vec();
+        link.outgoing.send(RelayMessage::Auth { signature, pow: None }).await.unwrap();
+        assert!(matches!(link.incoming.recv().await, Some(RelayMessage::Welcome { .. })));
+        link
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_sessions_and_flushes_stats() {
+        let relay = Relay::bind(RelayConfig::default(), "127.0.0.1:0")
+            .await
+            .unwrap()
+            .with_housekeeping_interval(Duration::from_millis(50));
+        let addr = relay.local_addr().unwrap();
+        let state = relay.state();
+        let shutdown = relay.shutdown_handle();
+        let snapshots = Arc::new(Mutex::new(Vec::new()));
+        let recorded = snapshots.clone();
+        let running = tokio::spawn(relay.run(move |stats| recorded.lock().unwrap().push(stats.clone())));
+
+        let mut link = connect(addr, &SigningKey::from_bytes(&[1; 32])).await;
+        link.outgoing.send(RelayMessage::Ping { seq: 1 }).await.unwrap();
+        assert_eq!(link.incoming.recv().await, Some(RelayMessage::Pong { seq: 1 }));
+        assert_eq!(state.session_count(), 1);
+
+        // Housekeeping runs on its own timer
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert!(!snapshots.lock().unwrap().is_empty());
+
+        shutdown.shutdown();
+        assert_eq!(link.incoming.recv().await, Some(RelayMessage::Bye));
+        let stats = tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+        assert_eq!(stats.active_peers, 0);
+        assert_eq!(snapshots.lock().unwrap().last().map(|s| s.active_peers), Some(0));
+
+        // No new sessions once closed
+        assert!(TcpStream::connect(addr).await.is_err());
+        assert!(state.is_closing());
+    }
+}
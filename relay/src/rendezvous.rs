@@ -0,0 +1,230 @@
+//! Rendezvous for direct links (see `yaok_relay_proto::rendezvous`)
+//!
+//! `Punch` requests, bind tokens and the public UDP addresses nodes bound
+//! from are kept for `PUNCH_REQUEST_TTL`. Two nodes are introduced once
+//! each asked for the other and both bound within that time; their
+//! requests are consumed by the introduction. Both must have a session on
+//! this relay: requests are not passed to federated relays.
+//!
+//! The rendezvous socket answers only `Bind` datagrams with a token the
+//! relay handed out, and never with more bytes than it got.
+
+use crate::net::peer_ip;
+use crate::server::RelayState;
+use crate::stats::MAX_PEERS;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::info;
+use yaok_relay_proto::rendezvous::{BindToken, RendezvousDatagram, MIN_BIND_LEN};
+
+/// Lifetime of requests, bind tokens and bound addresses
+pub const PUNCH_REQUEST_TTL: Duration = Duration::from_secs(30);
+
+/// Open requests one node may have
+pub const MAX_PUNCH_REQUESTS_PER_NODE: usize = 16;
+
+/// Open requests and tokens on the relay
+const MAX_PUNCH_REQUESTS: usize = 4 * MAX_PEERS;
+
+/// Two nodes ready to be introduced, with the addresses they bound from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Introduction {
+    pub node: String,
+    pub node_addr: SocketAddr,
+    pub peer: String,
+    pub peer_addr: SocketAddr,
+}
+
+#[derive(Default)]
+struct Tables {
+    /// (from, to): `from` asked for a link with `to`
+    requests: HashMap<(String, String), Instant>,
+    tokens: HashMap<BindToken, (String, Instant)>,
+    /// Last address each node bound from
+    endpoints: HashMap<String, (SocketAddr, Instant)>,
+}
+
+pub struct Rendezvous {
+    /// Rendezvous port announced in `PunchBind`
+    port: u16,
+    tables: Mutex<Tables>,
+}
+
+impl Rendezvous {
+    pub fn new(port: u16) -> Self {
+        Self { port, tables: Mutex::new(Tables::default()) }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// `from` asks for a link with `to`. Returns a bind token and whether
+    /// `to` has not asked for `from` yet (and so must be asked), or `None`
+    /// when `from` has too many open requests.
+    pub fn request(&self, from: &str, to: &str, now: Instant) -> Option<(BindToken, bool)> {
+        let mut tables = self.tables.lock().unwrap();
+        let key = (from.to_string(), to.to_string());
+        if !tables.requests.contains_key(&key) {
+            let open = tables.requests.keys().filter(|(node, _)| node == from).count();
+            if open >= MAX_PUNCH_REQUESTS_PER_NODE || tables.requests.len() >= MAX_PUNCH_REQUESTS {
+                return None;
+            }
+        }
+        if tables.tokens.len() >= MAX_PUNCH_REQUESTS {
+            return None;
+        }
+        tables.requests.insert(key, now);
+
+        // Repeated requests replace the node's oldest token
+        let issued: Vec<(BindToken, Instant)> =
+            tables.tokens.iter().filter(|(_, (node, _))| node == from).map(|(token, (_, at))| (*token, *at)).collect();
+        if issued.len() >= MAX_PUNCH_REQUESTS_PER_NODE {
+            if let Some((oldest, _)) = issued.iter().min_by_key(|(_, at)| *at) {
+                tables.tokens.remove(oldest);
+            }
+        }
+        let token: BindToken = rand::random();
+        tables.tokens.insert(token, (from.to_string(), now));
+        let asked = tables
+            .requests
+            .get(&(to.to_string(), from.to_string()))
+            .is_some_and(|at| now.duration_since(*at) < PUNCH_REQUEST_TTL);
+        Some((token, !asked))
+    }
+
+    /// `addr` bound with `token`; returns the node the token was given to.
+    /// A token binds once.
+    pub fn bind(&self, token: &BindToken, addr: SocketAddr, now: Instant) -> Option<String> {
+        let mut tables = self.tables.lock().unwrap();
+        let (node, issued) = tables.tokens.remove(token)?;
+        if now.duration_since(issued) >= PUNCH_REQUEST_TTL {
+            return None;
+        }
+        tables.endpoints.insert(node.clone(), (addr, now));
+        Some(node)
+    }
+
+    /// Nodes that asked for a link with `node` and were asked by it, both
+    /// bound recently; their requests are consumed
+    pub fn ready(&self, node: &str, now: Instant) -> Vec<Introduction> {
+        let mut tables = self.tables.lock().unwrap();
+        let fresh = |at: &Instant| now.duration_since(*at) < PUNCH_REQUEST_TTL;
+        let Some(node_addr) = tables.endpoints.get(node).filter(|(_, at)| fresh(at)).map(|(addr, _)| *addr) else {
+            return Vec::new();
+        };
+
+        let peers: Vec<String> = tables
+            .requests
+            .iter()
+            .filter(|((from, _), at)| from == node && fresh(at))
+            .map(|((_, to), _)| to.clone())
+            .collect();
+        let mut ready = Vec::new();
+        for peer in peers {
+            let back = (peer.clone(), node.to_string());
+            if !tables.requests.get(&back).is_some_and(fresh) {
+                continue;
+            }
+            let Some(peer_addr) = tables.endpoints.get(&peer).filter(|(_, at)| fresh(at)).map(|(addr, _)| *addr) else {
+                continue;
+            };
+            tables.requests.remove(&back);
+            tables.requests.remove(&(node.to_string(), peer.clone()));
+            ready.push(Introduction { node: node.to_string(), node_addr, peer, peer_addr });
+        }
+        ready
+    }
+
+    /// Drop everything older than `PUNCH_REQUEST_TTL`
+    pub fn cleanup(&self, now: Instant) {
+        let mut tables = self.tables.lock().unwrap();
+        let fresh = |at: &Instant| now.duration_since(*at) < PUNCH_REQUEST_TTL;
+        tables.requests.retain(|_, at| fresh(at));
+        tables.tokens.retain(|_, (_, at)| fresh(at));
+        tables.endpoints.retain(|_, (_, at)| fresh(at));
+    }
+}
+
+/// Answer `Bind` datagrams on `socket` until it fails
+pub async fn serve_rendezvous(socket: UdpSocket, state: Arc<RelayState>) -> std::io::Result<()> {
+    info!("Relay rendezvous listening on {}", socket.local_addr()?);
+
+    let mut buf = [0u8; 2 * MIN_BIND_LEN];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        let Ok(RendezvousDatagram::Bind { token }) = RendezvousDatagram::decode(&buf[..len]) else { continue };
+
+        // Dual-stack sockets see IPv4 clients as mapped addresses
+        let observed = SocketAddr::new(peer_ip(from), from.port());
+        if state.bind_punch_endpoint(&token, observed) {
+            let _ = socket.send_to(&RendezvousDatagram::Bound { token, observed }.encode(), from).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([198, 51, 100, 1], port))
+    }
+
+    #[test]
+    fn introduces_only_nodes_that_asked_for_each_other() {
+        let rendezvous = Rendezvous::new(40101);
+        let now = Instant::now();
+
+        let (alice_token, ask_bob) = rendezvous.request("alice", "bob", now).unwrap();
+        assert!(ask_bob);
+        assert_eq!(rendezvous.bind(&alice_token, addr(1), now), Some("alice".to_string()));
+        // Tokens bind once
+        assert_eq!(rendezvous.bind(&alice_token, addr(9), now), None);
+        assert!(rendezvous.ready("alice", now).is_empty());
+
+        // Mallory bound, but alice never asked for her
+        let (mallory_token, _) = rendezvous.request("mallory", "alice", now).unwrap();
+        rendezvous.bind(&mallory_token, addr(3), now);
+        assert!(rendezvous.ready("mallory", now).is_empty());
+
+        let (bob_token, ask_alice) = rendezvous.request("bob", "alice", now).unwrap();
+        assert!(!ask_alice);
+        assert!(rendezvous.ready("bob", now).is_empty());
+        rendezvous.bind(&bob_token, addr(2), now);
+        let ready = rendezvous.ready("bob", now);
+        assert_eq!(
+            ready,
+            vec![Introduction { node: "bob".to_string(), node_addr: addr(2), peer: "alice".to_string(), peer_addr: addr(1) }]
+        );
+        // Consumed by the introduction
+        assert!(rendezvous.ready("alice", now).is_empty());
+    }
+
+    #[test]
+    fn requests_expire_and_are_capped() {
+        let rendezvous = Rendezvous::new(40101);
+        let now = Instant::now();
+        let (token, _) = rendezvous.request("alice", "bob", now).unwrap();
+        let later = now + PUNCH_REQUEST_TTL;
+        assert_eq!(rendezvous.bind(&token, addr(1), later), None);
+
+        let (alice_token, _) = rendezvous.request("alice", "bob", now).unwrap();
+        let (bob_token, _) = rendezvous.request("bob", "alice", now).unwrap();
+        rendezvous.bind(&alice_token, addr(1), now);
+        rendezvous.bind(&bob_token, addr(2), now);
+        assert!(rendezvous.ready("alice", later).is_empty());
+        rendezvous.cleanup(later);
+        assert!(rendezvous.ready("alice", now).is_empty());
+
+        for peer in 0..MAX_PUNCH_REQUESTS_PER_NODE {
+            assert!(rendezvous.request("carol", &peer.to_string(), now).is_some());
+        }
+        assert!(rendezvous.request("carol", "dave", now).is_none());
+        // Repeating an open request is fine
+        assert!(rendezvous.request("carol", "0", now).is_some());
+    }
+}
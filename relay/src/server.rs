@@ -0,0 +1,1359 @@
+//! Relay session server
+//!
+//! Each client holds one authenticated session (see `yaok-relay-proto`),
+//! registered by node id, over TCP or encrypted UDP (`crate::datagram`).
+//! Clients may also register rotating recipient tags with a signed
+//! `Register`; a tag stays bound to its node until it expires, session or
+//! not. `Send` frames are routed to the recipient's session only. A
+//! recipient without a session here may be reached through a federated
+//! relay (`crate::federation`); otherwise the packet waits in the mailbox
+//! (`crate::mailbox`) and is delivered when the recipient connects.
+//! With a store (`crate::store`) tag registrations and counters survive
+//! restarts. Two local nodes that both send `Punch` for each other are
+//! introduced for a direct UDP link (`crate::rendezvous`). Nodes that left
+//! a push token are woken when a packet for them is stored (`crate::push`).
+//!
+//! The only broadcast is `Flood`, for emergency packets: it reaches every
+//! local session and each node may flood once per `flood_interval`.
+//!
+//! Admission: datagram clients prove their address with a cookie before
+//! a session exists (`yaok_relay_proto::datagram`); every client then
+//! signs the challenge for its node id, and once the relay is loaded it
+//! must also solve proof of work (`yaok_relay_proto::pow`). Connections
+//! per source address are capped, and packets are rate limited both per
+//! source address and per node. Operators may ban addresses and nodes
+//! (`crate::bans`), evict sessions and change the rate limits at runtime
+//! through the admin API (`crate::admin`).
+
+use crate::bans::{Bans, IpRange};
+use crate::federation::{Federation, FederationConfig};
+use crate::mailbox::{unix_now, Mailbox, MailboxError};
+use crate::metrics::{Histograms, Transport};
+use crate::net::peer_ip;
+use crate::push::{PushGateway, PushRecord, Wake, MAX_PUSH_TOKEN_LEN, PUSH_TOKEN_TTL_SECS};
+use crate::ratelimit::{Admitted, Budget, Priority, RateLimiter, RateLimits, Scope};
+use crate::rendezvous::Rendezvous;
+use crate::stats::{Stats, MAX_PEERS};
+use crate::store::{Store, StoreWriter, TagRecord};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::time::timeout;
+use tracing::{debug, info, warn};
+use yaok_relay_proto::datagram::MAX_DATAGRAM_PACKET;
+use yaok_relay_proto::federation::{presence_digest, ForwardId, PresenceDigest};
+use yaok_relay_proto::pow::verify_pow;
+use yaok_relay_proto::rendezvous::{BindToken, LINK_KEY_LEN, MIN_BIND_LEN};
+use yaok_relay_proto::tags::{decode_tags, parse_tag, RecipientTag, MAX_TAG_LIFETIME_SECS};
+use yaok_relay_proto::{
+    stream_link, verify_auth, verify_register, DeliveryStatus, ErrorCode, MessageLink, ProtoError, RelayMessage,
+    DEFAULT_KEEPALIVE_SECS, NONCE_LEN, PROTOCOL_VERSION,
+};
+
+/// Time allowed for Hello/Auth before the connection is dropped
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Maximum packet size accepted in `Send`
+    pub max_packet: usize,
+    /// `Send` frames per second per source network (see `rate_limit_ipv4_prefix`)
+    pub rate_limit_pps: u32,
+    /// `Send` bytes per second per source network; 0 means unlimited
+    pub rate_limit_bytes_per_sec: u64,
+    /// `Send` frames per second per node
+    pub node_rate_limit_pps: u32,
+    /// `Send` bytes per second per node; 0 means unlimited
+    pub node_rate_limit_bytes_per_sec: u64,
+    /// `Send` frames per second for the whole relay; 0 means unlimited
+    pub global_rate_limit_pps: u32,
+    /// `Send` bytes per second for the whole relay; 0 means unlimited
+    pub global_rate_limit_bytes_per_sec: u64,
+    /// Leading bits of an IPv4 source address that share a rate budget
+    pub rate_limit_ipv4_prefix: u8,
+    /// Leading bits of an IPv6 source address that share a rate budget
+    pub rate_limit_ipv6_prefix: u8,
+    /// Seconds of traffic a sender may burst after being idle
+    pub rate_burst_secs: u32,
+    /// Share of every rate budget, in percent, kept on top of it for `Flood`
+    pub emergency_reserve_percent: u8,
+    /// Keepalive interval announced to clients
+    pub keepalive_secs: u32,
+    /// Maximum concurrent sessions
+    pub max_sessions: usize,
+    /// Concurrent connections per source IP (generous: carrier NAT puts
+    /// many phones behind one address)
+    pub max_sessions_per_ip: usize,
+    /// Proof of work (bits) asked from new sessions under load; 0 disables it
+    pub pow_difficulty: u8,
+    /// Sessions, as a percentage of `max_sessions`, from which proof of
+    /// work is required
+    pub pow_load_percent: u8,
+    /// Links to other relays; `None` keeps the relay standalone
+    pub federation: Option<FederationConfig>,
+    /// Minimum time between two `Flood`s of one node
+    pub flood_interval: Duration,
+}
+
+impl RelayConfig {
+    /// A session with no frames for three keepalive intervals is dropped
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(u64::from(self.keepalive_secs.max(1)) * 3)
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            rate_limit_pps: self.rate_limit_pps,
+            rate_limit_bytes_per_sec: self.rate_limit_bytes_per_sec,
+            node_rate_limit_pps: self.node_rate_limit_pps,
+            node_rate_limit_bytes_per_sec: self.node_rate_limit_bytes_per_sec,
+            global_rate_limit_pps: self.global_rate_limit_pps,
+            global_rate_limit_bytes_per_sec: self.global_rate_limit_bytes_per_sec,
+            rate_limit_ipv4_prefix: self.rate_limit_ipv4_prefix,
+            rate_limit_ipv6_prefix: self.rate_limit_ipv6_prefix,
+            rate_burst_secs: self.rate_burst_secs,
+            emergency_reserve_percent: self.emergency_reserve_percent,
+            max_sessions_per_ip: self.max_sessions_per_ip,
+            pow_difficulty: self.pow_difficulty,
+            pow_load_percent: self.pow_load_percent,
+            flood_interval: self.flood_interval,
+        }
+    }
+}
+
+/// The part of `RelayConfig` that can change while the relay runs (admin
+/// API, configuration reload); open sessions are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub rate_limit_pps: u32,
+    pub rate_limit_bytes_per_sec: u64,
+    pub node_rate_limit_pps: u32,
+    pub node_rate_limit_bytes_per_sec: u64,
+    pub global_rate_limit_pps: u32,
+    pub global_rate_limit_bytes_per_sec: u64,
+    pub rate_limit_ipv4_prefix: u8,
+    pub rate_limit_ipv6_prefix: u8,
+    pub rate_burst_secs: u32,
+    pub emergency_reserve_percent: u8,
+    pub max_sessions_per_ip: usize,
+    pub pow_difficulty: u8,
+    pub pow_load_percent: u8,
+    pub flood_interval: Duration,
+}
+
+impl Limits {
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            ip: Budget { packets_per_sec: self.rate_limit_pps, bytes_per_sec: self.rate_limit_bytes_per_sec },
+            node: Budget { packets_per_sec: self.node_rate_limit_pps, bytes_per_sec: self.node_rate_limit_bytes_per_sec },
+            global: Budget {
+                packets_per_sec: self.global_rate_limit_pps,
+                bytes_per_sec: self.global_rate_limit_bytes_per_sec,
+            },
+            ipv4_prefix: self.rate_limit_ipv4_prefix,
+            ipv6_prefix: self.rate_limit_ipv6_prefix,
+            burst_secs: self.rate_burst_secs,
+            emergency_reserve_percent: self.emergency_reserve_percent,
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            max_packet: 64_000,
+            rate_limit_pps: 200,
+            rate_limit_bytes_per_sec: 4_000_000,
+            node_rate_limit_pps: 50,
+            node_rate_limit_bytes_per_sec: 1_000_000,
+            global_rate_limit_pps: 0,
+            global_rate_limit_bytes_per_sec: 0,
+            rate_limit_ipv4_prefix: 32,
+            rate_limit_ipv6_prefix: 64,
+            rate_burst_secs: 1,
+            emergency_reserve_percent: 20,
+            keepalive_secs: DEFAULT_KEEPALIVE_SECS,
+            max_sessions: MAX_PEERS,
+            max_sessions_per_ip: 256,
+            pow_difficulty: 16,
+            pow_load_percent: 80,
+            federation: None,
+            flood_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+struct Session {
+    id: u64,
+    ip: IpAddr,
+    transport: Transport,
+    connected_at: Instant,
+    tx: mpsc::Sender<RelayMessage>,
+    /// Ends the session's read loop when it is evicted
+    kick: Arc<Notify>,
+}
+
+/// A local session as listed by the admin API
+#[derive(Clone, Debug, Serialize)]
+pub struct PeerInfo {
+    pub node_id: String,
+    pub ip: IpAddr,
+    pub transport: Transport,
+    pub connected_secs: u64,
+}
+
+/// Node a recipient tag is registered to
+struct TagOwner {
+    node_id: String,
+    /// Unix seconds
+    expires_at: u64,
+}
+
+/// Sender of a `Send` or `Flood`
+#[derive(Clone, Copy)]
+struct Client<'a> {
+    ip: IpAddr,
+    node_id: &'a str,
+    transport: Transport,
+}
+
+/// Shared relay state: registered sessions, rate limits and counters
+pub struct RelayState {
+    config: RelayConfig,
+    sessions: Mutex<HashMap<String, Session>>,
+    tags: Mutex<HashMap<RecipientTag, TagOwner>>,
+    /// Last `Flood` per node
+    floods: Mutex<HashMap<String, Instant>>,
+    rate: RateLimiter,
+    /// Current limits; start from the config
+    limits: RwLock<Limits>,
+    bans: Mutex<Bans>,
+    /// Open client connections per source IP
+    connections: Mutex<HashMap<IpAddr, usize>>,
+    /// Counters of the current interval
+    stats: Mutex<Stats>,
+    /// Counters of all previous intervals
+    totals: Mutex<Stats>,
+    histograms: Histograms,
+    next_session_id: AtomicU64,
+    federation: Option<Federation>,
+    mailbox: Option<Mailbox>,
+    store: Option<Arc<Store>>,
+    /// Applies tag and push token changes to `store` in order
+    store_writer: Option<StoreWriter>,
+    rendezvous: Option<Rendezvous>,
+    push: Option<PushGateway>,
+    /// Set on shutdown: sessions send `Bye` and end
+    closing: watch::Sender<bool>,
+}
+
+impl RelayState {
+    pub fn new(config: RelayConfig) -> Self {
+        Self {
+            limits: RwLock::new(config.limits()),
+            federation: config.federation.clone().map(Federation::new),
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            tags: Mutex::new(HashMap::new()),
+            floods: Mutex::new(HashMap::new()),
+            rate: RateLimiter::default(),
+            bans: Mutex::new(Bans::default()),
+            connections: Mutex::new(HashMap::new()),
+            stats: Mutex::new(Stats::default()),
+            totals: Mutex::new(Stats::default()),
+            histograms: Histograms::default(),
+            next_session_id: AtomicU64::new(1),
+            mailbox: None,
+            store: None,
+            store_writer: None,
+            rendezvous: None,
+            push: None,
+            closing: watch::Sender::new(false),
+        }
+    }
+
+    /// Keep packets for offline recipients in `mailbox`
+    pub fn with_mailbox(mut self, mailbox: Mailbox) -> Self {
+        self.mailbox = Some(mailbox);
+        self
+    }
+
+    /// Persist tag registrations and counters in `store`, restoring
+    /// those of the previous run. Call after `with_mailbox`, so mailbox
+    /// ids continue where they stopped. Needs a Tokio runtime for the
+    /// store writer.
+    pub fn with_store(mut self, store: Store) -> Self {
+        let now = unix_now();
+        if let Some(snapshot) = store.restored() {
+            *self.totals.get_mut().unwrap() = snapshot.totals.clone();
+            if let Some(mailbox) = &self.mailbox {
+                mailbox.reserve_ids(snapshot.mailbox_next_id);
+            }
+        }
+        let tags = store.tags(now);
+        info!("Store: restored {} tag registrations, restart {}", tags.len(), store.restarts());
+        self.tags.get_mut().unwrap().extend(
+            tags.into_iter().map(|(tag, record)| (tag, TagOwner { node_id: record.node_id, expires_at: record.expires_at })),
+        );
+        let store = Arc::new(store);
+        self.store_writer = Some(StoreWriter::start(store.clone()));
+        self.store = Some(store);
+        // Counts this start even if the relay dies before the next snapshot
+        if let Err(e) = self.save_snapshot() {
+            warn!("Store: snapshot failed: {}", e);
+        }
+        self
+    }
+
+    /// Introduce nodes for direct links; `rendezvous.port()` is where
+    /// `serve_rendezvous` listens
+    pub fn with_rendezvous(mut self, rendezvous: Rendezvous) -> Self {
+        self.rendezvous = Some(rendezvous);
+        self
+    }
+
+    /// Wake nodes that left a push token when packets for them are
+    /// stored; `crate::push::start_push` sends the wakeups. Call after
+    /// `with_store`, so saved tokens are restored.
+    pub fn with_push(mut self, push: PushGateway) -> Self {
+        if let Some(store) = &self.store {
+            let tokens = store.push_tokens();
+            info!("Store: restored {} push tokens", tokens.len());
+            push.restore(tokens);
+        }
+        self.push = Some(push);
+        self
+    }
+
+    /// Starts before this one with the same store; 0 without a store
+    pub fn restarts(&self) -> u64 {
+        self.store.as_deref().map_or(0, Store::restarts)
+    }
+
+    /// Save counters and the mailbox id to the store, if there is one
+    pub fn save_snapshot(&self) -> std::io::Result<()> {
+        let Some(store) = &self.store else { return Ok(()) };
+        let totals = {
+            let current = self.stats.lock().unwrap();
+            let mut totals = self.totals.lock().unwrap().clone();
+            totals.add(&current);
+            totals
+        };
+        let mailbox_next_id = self.mailbox.as_ref().map_or(0, Mailbox::next_id);
+        store.save_snapshot(&totals, mailbox_next_id)
+    }
+
+    /// Wait until tag and push token changes made so far are on disk
+    pub async fn sync_store(&self) {
+        if let Some(writer) = &self.store_writer {
+            writer.sync().await;
+        }
+    }
+
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
+    /// Close all sessions (each gets `Bye`) and refuse new ones
+    pub fn close_sessions(&self) {
+        self.closing.send_replace(true);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+
+    /// Number of registered sessions
+    pub fn session_count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Whether `node_id` currently has a session
+    pub fn is_registered(&self, node_id: &str) -> bool {
+        self.sessions.lock().unwrap().contains_key(node_id)
+    }
+
+    /// Local sessions, ordered by node id
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut peers: Vec<PeerInfo> = sessions
+            .iter()
+            .map(|(node_id, session)| PeerInfo {
+                node_id: node_id.clone(),
+                ip: session.ip,
+                transport: session.transport,
+                connected_secs: session.connected_at.elapsed().as_secs(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        peers
+    }
+
+    /// Close the session of `node_id` with `Bye`; the client may reconnect
+    pub fn evict(&self, node_id: &str) -> bool {
+        self.evict_where(RelayMessage::Bye, |id, _| id == node_id) > 0
+    }
+
+    /// Remove matching sessions, tell them why with `message` and end them
+    fn evict_where(&self, message: RelayMessage, matches: impl Fn(&str, &Session) -> bool) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let evicted: Vec<String> =
+            sessions.iter().filter(|(node_id, session)| matches(node_id, session)).map(|(node_id, _)| node_id.clone()).collect();
+        for node_id in &evicted {
+            let Some(session) = sessions.remove(node_id) else { continue };
+            let _ = session.tx.try_send(message.clone());
+            session.kick.notify_one();
+            if let Some(federation) = &self.federation {
+                federation.announce(node_id, false);
+            }
+        }
+        drop(sessions);
+        self.record(|stats| stats.evicted += evicted.len() as u64);
+        evicted.len()
+    }
+
+    pub fn bans(&self) -> Bans {
+        self.bans.lock().unwrap().clone()
+    }
+
+    pub(crate) fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.bans.lock().unwrap().is_ip_banned(ip)
+    }
+
+    /// Ban an address range and evict its sessions; returns how many
+    pub fn ban_ip_range(&self, range: IpRange) -> usize {
+        self.bans.lock().unwrap().ip_ranges.insert(range);
+        self.evict_where(banned(), |_, session| range.contains(session.ip))
+    }
+
+    /// Ban a node and evict its session; returns whether it had one
+    pub fn ban_node(&self, node_id: &str) -> bool {
+        self.bans.lock().unwrap().node_ids.insert(node_id.to_string());
+        self.evict_where(banned(), |id, _| id == node_id) > 0
+    }
+
+    pub fn unban_ip_range(&self, range: &IpRange) -> bool {
+        self.bans.lock().unwrap().ip_ranges.remove(range)
+    }
+
+    pub fn unban_node(&self, node_id: &str) -> bool {
+        self.bans.lock().unwrap().node_ids.remove(node_id)
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Apply new limits to new packets and connections
+    pub fn set_limits(&self, limits: Limits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Current `Send` limits: per source IP and per node (packets/second)
+    pub fn rate_limits(&self) -> (u32, u32) {
+        let limits = self.limits();
+        (limits.rate_limit_pps, limits.node_rate_limit_pps)
+    }
+
+    pub fn set_rate_limits(&self, ip_pps: u32, node_pps: u32) {
+        let mut limits = self.limits.write().unwrap();
+        limits.rate_limit_pps = ip_pps;
+        limits.node_rate_limit_pps = node_pps;
+    }
+
+    /// Proof of work (bits) a new session must solve now
+    pub fn pow_difficulty(&self) -> u8 {
+        let limits = self.limits();
+        let loaded = self.session_count() * 100 >= self.config.max_sessions * usize::from(limits.pow_load_percent);
+        if loaded {
+            limits.pow_difficulty
+        } else {
+            0
+        }
+    }
+
+    /// Node the recipient tag `tag` is currently registered to
+    pub fn tag_owner(&self, tag: &RecipientTag) -> Option<String> {
+        let tags = self.tags.lock().unwrap();
+        tags.get(tag).filter(|owner| owner.expires_at > unix_now()).map(|owner| owner.node_id.clone())
+    }
+
+    /// Whether `node_id` has a session here or on a federated relay
+    pub fn is_reachable(&self, node_id: &str) -> bool {
+        self.is_registered(node_id) || self.federation.as_ref().is_some_and(|f| f.knows(node_id))
+    }
+
+    pub fn mailbox(&self) -> Option<&Mailbox> {
+        self.mailbox.as_ref()
+    }
+
+    pub fn push(&self) -> Option<&PushGateway> {
+        self.push.as_ref()
+    }
+
+    /// Federation id of this relay, if federation is configured
+    pub fn relay_id(&self) -> Option<&str> {
+        self.federation.as_ref().map(|f| f.relay_id())
+    }
+
+    /// Ids of peer relays with an open federation link
+    pub fn federation_peers(&self) -> Vec<String> {
+        self.federation.as_ref().map(|f| f.connected_peers()).unwrap_or_default()
+    }
+
+    /// Counters accumulated since the previous call (reset afterwards)
+    pub fn take_stats(&self) -> Stats {
+        let mut stats = {
+            let mut current = self.stats.lock().unwrap();
+            self.totals.lock().unwrap().add(&current);
+            std::mem::take(&mut *current)
+        };
+        self.fill_gauges(&mut stats);
+        stats
+    }
+
+    /// Counters since the relay started, unaffected by `take_stats`
+    pub fn total_stats(&self) -> Stats {
+        let mut stats = {
+            let current = self.stats.lock().unwrap();
+            let mut totals = self.totals.lock().unwrap().clone();
+            totals.add(&current);
+            totals
+        };
+        self.fill_gauges(&mut stats);
+        stats
+    }
+
+    fn fill_gauges(&self, stats: &mut Stats) {
+        {
+            let sessions = self.sessions.lock().unwrap();
+            stats.active_peers = sessions.len();
+            for session in sessions.values() {
+                *stats.sessions.get_mut(session.transport) += 1;
+            }
+        }
+        stats.rate_entries = self.rate.len();
+        stats.registered_tags = self.tags.lock().unwrap().len();
+        stats.federation_links = self.federation.as_ref().map_or(0, |f| f.link_count());
+        (stats.mailbox_packets, stats.mailbox_bytes) = self.mailbox.as_ref().map_or((0, 0), |m| m.usage());
+        stats.push_tokens = self.push.as_ref().map_or(0, PushGateway::token_count);
+    }
+
+    pub fn histograms(&self) -> &Histograms {
+        &self.histograms
+    }
+
+    pub(crate) fn federation(&self) -> Option<&Federation> {
+        self.federation.as_ref()
+    }
+
+    pub(crate) fn record(&self, update: impl FnOnce(&mut Stats)) {
+        update(&mut self.stats.lock().unwrap());
+    }
+
+    /// Periodic housekeeping
+    pub fn cleanup(&self) {
+        self.rate.cleanup(&self.limits().rate_limits());
+        let now = unix_now();
+        self.tags.lock().unwrap().retain(|_, owner| owner.expires_at > now);
+        if let Some(writer) = &self.store_writer {
+            writer.queue("expiring tags", move |store| store.expire_tags(now).map(drop));
+        }
+        let flood_interval = self.limits().flood_interval;
+        self.floods.lock().unwrap().retain(|_, last| last.elapsed() < flood_interval);
+        if let Some(mailbox) = &self.mailbox {
+            let expired = mailbox.expire(unix_now());
+            self.stats.lock().unwrap().mailbox_expired += expired as u64;
+        }
+        if let Some(rendezvous) = &self.rendezvous {
+            rendezvous.cleanup(Instant::now());
+        }
+        if let Some(push) = &self.push {
+            let expired = push.cleanup(Instant::now(), now.saturating_sub(PUSH_TOKEN_TTL_SECS));
+            if let (Some(writer), false) = (&self.store_writer, expired.is_empty()) {
+                writer.queue("expiring push tokens", move |store| store.remove_push_tokens(&expired));
+            }
+        }
+    }
+
+    /// Packets waiting in the mailbox for `node_id`
+    pub fn mailbox_pending(&self, node_id: &str) -> usize {
+        self.mailbox.as_ref().map_or(0, |mailbox| mailbox.pending(node_id, unix_now()).len())
+    }
+
+    pub(crate) fn register(
+        &self,
+        node_id: &str,
+        ip: IpAddr,
+        transport: Transport,
+        tx: mpsc::Sender<RelayMessage>,
+    ) -> Result<(u64, Arc<Notify>), ErrorCode> {
+        if self.is_closing() {
+            return Err(ErrorCode::Overloaded);
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.config.max_sessions && !sessions.contains_key(node_id) {
+            // Stats are locked before sessions elsewhere
+            drop(sessions);
+            self.record(|stats| stats.dropped_peer_limit += 1);
+            return Err(ErrorCode::Overloaded);
+        }
+
+        let id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        let kick = Arc::new(Notify::new());
+        let session = Session { id, ip, transport, connected_at: Instant::now(), tx, kick: kick.clone() };
+        match sessions.insert(node_id.to_string(), session) {
+            Some(previous) => {
+                // Same node reconnected (e.g. network change): the old session is closed
+                let _ = previous.tx.try_send(RelayMessage::Error {
+                    code: ErrorCode::Replaced,
+                    message: "Session replaced by a newer connection".to_string(),
+                });
+            }
+            None => {
+                if let Some(federation) = &self.federation {
+                    federation.announce(node_id, true);
+                }
+            }
+        }
+        Ok((id, kick))
+    }
+
+    fn unregister(&self, node_id: &str, session_id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(node_id).map(|s| s.id) == Some(session_id) {
+            sessions.remove(node_id);
+            if let Some(federation) = &self.federation {
+                federation.announce(node_id, false);
+            }
+        }
+    }
+
+    /// Presence digests of all local sessions
+    fn presence_snapshot(sessions: &HashMap<String, Session>) -> Vec<PresenceDigest> {
+        sessions.keys().map(|node_id| presence_digest(node_id)).collect()
+    }
+
+    /// Register a federation link. The snapshot is taken under the
+    /// sessions lock, so no presence change is lost in between.
+    pub(crate) fn attach_peer_link(&self, relay_id: &str, tx: mpsc::Sender<RelayMessage>) -> u64 {
+        let sessions = self.sessions.lock().unwrap();
+        let federation = self.federation.as_ref().expect("federation link without federation config");
+        federation.attach(relay_id, tx, &Self::presence_snapshot(&sessions))
+    }
+
+    pub(crate) fn resync_peer_link(&self, link_id: u64) {
+        let sessions = self.sessions.lock().unwrap();
+        if let Some(federation) = &self.federation {
+            federation.resync(link_id, &Self::presence_snapshot(&sessions));
+        }
+    }
+
+    fn is_current(&self, node_id: &str, session_id: u64) -> bool {
+        self.sessions.lock().unwrap().get(node_id).map(|s| s.id) == Some(session_id)
+    }
+
+    /// Bind recipient tags to `node_id`, replacing its previous ones. The
+    /// lifetime is capped; tags another node holds are skipped.
+    fn register_tags(&self, node_id: &str, tags: &[u8], expires_at: u64, signature: &[u8]) -> Result<RelayMessage, ErrorCode> {
+        if verify_register(node_id, tags, expires_at, signature).is_err() {
+            self.record(|stats| stats.auth_failures += 1);
+            return Err(ErrorCode::AuthFailed);
+        }
+        let tags = decode_tags(tags).map_err(|_| ErrorCode::ProtocolViolation)?;
+
+        let now = unix_now();
+        let expires_at = expires_at.min(now + MAX_TAG_LIFETIME_SECS);
+        let mut registered = self.tags.lock().unwrap();
+        let mut removed = Vec::new();
+        registered.retain(|tag, owner| {
+            let keep = owner.node_id != node_id;
+            if !keep {
+                removed.push(*tag);
+            }
+            keep
+        });
+        let mut added = Vec::new();
+        if expires_at > now {
+            for tag in tags {
+                let taken = registered.get(&tag).is_some_and(|owner| owner.expires_at > now);
+                if !taken {
+                    registered.insert(tag, TagOwner { node_id: node_id.to_string(), expires_at });
+                    added.push((tag, TagRecord { node_id: node_id.to_string(), expires_at }));
+                }
+            }
+        }
+        let accepted = added.len() as u32;
+        // Queued under the lock so the store sees registrations in order
+        if let Some(writer) = &self.store_writer {
+            writer.queue("saving tag registrations", move |store| store.update_tags(&removed, &added));
+        }
+        drop(registered);
+
+        self.record(|stats| stats.tag_registrations += 1);
+        Ok(RelayMessage::Registered { accepted, expires_at })
+    }
+
+    /// Keep the push token of `node_id`, or remove it when `token` is empty
+    fn register_push(&self, node_id: &str, provider: &str, token: &[u8]) -> Result<RelayMessage, ErrorCode> {
+        if token.len() > MAX_PUSH_TOKEN_LEN || provider.len() > MAX_PUSH_TOKEN_LEN {
+            return Err(ErrorCode::ProtocolViolation);
+        }
+        let Some(push) = &self.push else { return Ok(RelayMessage::PushRegistered { enabled: false }) };
+        self.record(|stats| stats.push_registrations += 1);
+
+        let record = PushRecord { provider: provider.to_string(), token: token.to_vec(), registered_at: unix_now() };
+        let enabled = !token.is_empty() && push.register(node_id, record.clone());
+        if !enabled {
+            push.unregister(node_id, None);
+        }
+        if let Some(writer) = &self.store_writer {
+            let node_id = node_id.to_string();
+            writer.queue("saving push token", move |store| match enabled {
+                true => store.save_push_token(&node_id, &record),
+                false => store.remove_push_tokens(&[node_id]),
+            });
+        }
+        Ok(RelayMessage::PushRegistered { enabled })
+    }
+
+    /// Forget `token` of `node_id` after its provider refused it
+    pub(crate) fn forget_push_token(&self, node_id: &str, token: &[u8]) {
+        let Some(push) = &self.push else { return };
+        if push.unregister(node_id, Some(token)) {
+            if let Some(writer) = &self.store_writer {
+                let node_id = node_id.to_string();
+                writer.queue("removing push token", move |store| store.remove_push_tokens(&[node_id]));
+            }
+        }
+    }
+
+    /// Count a client connection from `ip`; `None` if the address has
+    /// too many already
+    fn open_connection(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let max_sessions_per_ip = self.limits().max_sessions_per_ip;
+        let mut connections = self.connections.lock().unwrap();
+        let count = connections.entry(ip).or_insert(0);
+        if *count >= max_sessions_per_ip {
+            drop(connections);
+            self.record(|stats| stats.rejected_ip_sessions += 1);
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard { state: self.clone(), ip })
+    }
+
+    /// Largest packet a session on `transport` can carry: one message per
+    /// datagram on datagram sessions
+    pub(crate) fn packet_limit(&self, transport: Transport) -> usize {
+        match transport {
+            Transport::Udp => self.config.max_packet.min(MAX_DATAGRAM_PACKET),
+            Transport::Tcp | Transport::Tls => self.config.max_packet,
+        }
+    }
+
+    /// Size and rate checks shared by `Send` and `Flood`
+    fn admit(
+        &self,
+        stats: &mut Stats,
+        client: &Client<'_>,
+        packet: &[u8],
+        priority: Priority,
+    ) -> Result<(), DeliveryStatus> {
+        let Client { ip, node_id: from, transport } = *client;
+        stats.received += 1;
+        *stats.received_by.get_mut(transport) += 1;
+        self.histograms.packet_size.get(transport).observe(packet.len() as f64);
+
+        if packet.is_empty() || packet.len() > self.config.max_packet {
+            stats.dropped_size += 1;
+            return Err(DeliveryStatus::TooLarge);
+        }
+        if packet.len() > self.packet_limit(transport) {
+            stats.dropped_datagram_size += 1;
+            return Err(DeliveryStatus::TooLarge);
+        }
+
+        // Per node as well as per address: many nodes may share an address
+        // (carrier NAT), and one node may hop between addresses
+        match self.rate.check(&self.limits().rate_limits(), ip, from, packet.len(), priority) {
+            Ok(Admitted::Budget) => Ok(()),
+            Ok(Admitted::Reserve) => {
+                stats.emergency_reserved += 1;
+                Ok(())
+            }
+            Err(scope) => {
+                match scope {
+                    Scope::Ip => stats.dropped_rate += 1,
+                    Scope::Node => stats.dropped_node_rate += 1,
+                    Scope::Global => stats.dropped_global_rate += 1,
+                }
+                Err(DeliveryStatus::RateLimited)
+            }
+        }
+    }
+
+    /// `Punch` from `client` for `peer`: hand out a bind token, ask `peer`
+    /// unless it asked first, and introduce the two if both are ready.
+    /// Ignored without rendezvous, for peers without a local session and
+    /// over the rate limit.
+    fn punch(&self, client: &Client<'_>, peer: &str) -> Option<RelayMessage> {
+        let rendezvous = self.rendezvous.as_ref()?;
+        let from = client.node_id;
+        if peer == from || !self.is_registered(peer) {
+            return None;
+        }
+        if let Err(scope) = self.rate.check(&self.limits().rate_limits(), client.ip, from, MIN_BIND_LEN, Priority::Normal) {
+            self.record(|stats| match scope {
+                Scope::Ip => stats.dropped_rate += 1,
+                Scope::Node => stats.dropped_node_rate += 1,
+                Scope::Global => stats.dropped_global_rate += 1,
+            });
+            return None;
+        }
+
+        let (token, ask) = rendezvous.request(from, peer, Instant::now())?;
+        self.record(|stats| stats.punch_requests += 1);
+        if ask {
+            if let Some(session) = self.sessions.lock().unwrap().get(peer) {
+                let _ = session.tx.try_send(RelayMessage::Punch { peer: from.to_string() });
+            }
+        }
+        self.introduce(from);
+        Some(RelayMessage::PunchBind { peer: peer.to_string(), token: token.to_vec(), port: rendezvous.port() })
+    }
+
+    /// `Bind` with `token` from `addr` on the rendezvous socket; true if the
+    /// token was valid
+    pub(crate) fn bind_punch_endpoint(&self, token: &BindToken, addr: SocketAddr) -> bool {
+        let Some(rendezvous) = &self.rendezvous else { return false };
+        let Some(node) = rendezvous.bind(token, addr, Instant::now()) else { return false };
+        self.record(|stats| stats.rendezvous_binds += 1);
+        self.introduce(&node);
+        true
+    }
+
+    /// Send `Introduce` to both ends of every pair with `node` that is ready
+    fn introduce(&self, node: &str) {
+        let Some(rendezvous) = &self.rendezvous else { return };
+        for pair in rendezvous.ready(node, Instant::now()) {
+            let link_id: u64 = rand::random();
+            let key: [u8; LINK_KEY_LEN] = rand::random();
+            let sessions = self.sessions.lock().unwrap();
+            for (to, peer, addr) in [(&pair.node, &pair.peer, pair.peer_addr), (&pair.peer, &pair.node, pair.node_addr)] {
+                if let Some(session) = sessions.get(to) {
+                    let _ = session.tx.try_send(RelayMessage::Introduce {
+                        peer: peer.clone(),
+                        addr: addr.to_string(),
+                        link_id,
+                        key: key.to_vec(),
+                    });
+                }
+            }
+            drop(sessions);
+            self.record(|stats| stats.punch_introductions += 1);
+            debug!("Introduced {} and {}", pair.node, pair.peer);
+        }
+    }
+
+    /// Deliver an emergency packet to every other local session
+    fn flood(&self, client: &Client<'_>, packet: Vec<u8>) -> DeliveryStatus {
+        let mut counted = Stats::default();
+        let status = self.flood_counted(&mut counted, client, packet);
+        self.record(|stats| stats.add(&counted));
+        status
+    }
+
+    fn flood_counted(&self, stats: &mut Stats, client: &Client<'_>, packet: Vec<u8>) -> DeliveryStatus {
+        let from = client.node_id;
+        if let Err(status) = self.admit(stats, client, &packet, Priority::Emergency) {
+            return status;
+        }
+
+        {
+            let flood_interval = self.limits().flood_interval;
+            let mut floods = self.floods.lock().unwrap();
+            if floods.get(from).is_some_and(|last| last.elapsed() < flood_interval) {
+                stats.dropped_flood_rate += 1;
+                return DeliveryStatus::RateLimited;
+            }
+            floods.insert(from.to_string(), Instant::now());
+        }
+
+        let recipients: Vec<_> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(node_id, _)| node_id.as_str() != from)
+            .map(|(_, session)| (session.tx.clone(), session.transport))
+            .collect();
+        let mut fanout = 0;
+        for (tx, transport) in recipients {
+            if packet.len() > self.packet_limit(transport) {
+                stats.dropped_datagram_size += 1;
+                continue;
+            }
+            let deliver = RelayMessage::Deliver { from: from.to_string(), packet: packet.clone(), mailbox_id: None };
+            if tx.try_send(deliver).is_ok() {
+                fanout += 1;
+            }
+        }
+
+        stats.flooded += 1;
+        stats.flood_deliveries += fanout;
+        stats.max_fanout = stats.max_fanout.max(fanout);
+        if fanout > 0 {
+            DeliveryStatus::Delivered
+        } else {
+            DeliveryStatus::Offline
+        }
+    }
+
+    /// Route a packet to `to`. Counted into a local `Stats` and added to
+    /// the totals afterwards, so the stats lock is not held over mailbox
+    /// writes or federation forwarding.
+    async fn route(&self, client: &Client<'_>, to: &str, packet: Vec<u8>, ttl_secs: Option<u32>) -> DeliveryStatus {
+        let mut counted = Stats::default();
+        let status = self.route_counted(&mut counted, client, to, packet, ttl_secs).await;
+        self.record(|stats| stats.add(&counted));
+        status
+    }
+
+    async fn route_counted(
+        &self,
+        stats: &mut Stats,
+        client: &Client<'_>,
+        to: &str,
+        packet: Vec<u8>,
+        ttl_secs: Option<u32>,
+    ) -> DeliveryStatus {
+        let from = client.node_id;
+        if let Err(status) = self.admit(stats, client, &packet, Priority::Normal) {
+            return status;
+        }
+
+        // A recipient tag stands for the node that registered it
+        let owner;
+        let to = match parse_tag(to) {
+            Some(tag) => match self.tag_owner(&tag) {
+                Some(node_id) => {
+                    stats.tag_routed += 1;
+                    owner = node_id;
+                    owner.as_str()
+                }
+                None => {
+                    stats.dropped_offline += 1;
+                    return DeliveryStatus::Offline;
+                }
+            },
+            None => to,
+        };
+
+        let session = self.sessions.lock().unwrap().get(to).map(|session| (session.tx.clone(), session.transport));
+        let undelivered = match session {
+            // The recipient's datagram session cannot carry it
+            Some((_, transport)) if packet.len() > self.packet_limit(transport) => {
+                stats.dropped_datagram_size += 1;
+                return DeliveryStatus::TooLarge;
+            }
+            Some((tx, _)) => match tx.try_send(RelayMessage::Deliver { from: from.to_string(), packet, mailbox_id: None }) {
+                Ok(()) => {
+                    stats.forwarded += 1;
+                    stats.max_fanout = stats.max_fanout.max(1);
+                    return DeliveryStatus::Delivered;
+                }
+                // Queue full (recipient not reading) or session closing
+                Err(mpsc::error::TrySendError::Full(message) | mpsc::error::TrySendError::Closed(message)) => {
+                    let RelayMessage::Deliver { packet, .. } = message else { unreachable!() };
+                    packet
+                }
+            },
+            // Recipient may be homed on a federated relay
+            None => match &self.federation {
+                Some(federation) => match federation.forward_new(from, to, packet, ttl_secs) {
+                    Ok(()) => {
+                        stats.federated_out += 1;
+                        return DeliveryStatus::Delivered;
+                    }
+                    Err(packet) => packet,
+                },
+                None => packet,
+            },
+        };
+
+        self.store(stats, from, to, undelivered, ttl_secs).await
+    }
+
+    /// Keep a packet for an offline recipient, if the mailbox is enabled
+    async fn store(&self, stats: &mut Stats, from: &str, to: &str, packet: Vec<u8>, ttl_secs: Option<u32>) -> DeliveryStatus {
+        let Some(mailbox) = &self.mailbox else {
+            stats.dropped_offline += 1;
+            return DeliveryStatus::Offline;
+        };
+
+        match mailbox.store(to, from, packet, ttl_secs, unix_now()).await {
+            Ok(_) => {
+                stats.stored += 1;
+                // The recipient may be asleep: wake it to fetch the packet
+                if let Some(push) = &self.push {
+                    match push.wake(to, Instant::now()) {
+                        Wake::Queued => stats.push_queued += 1,
+                        Wake::Limited => stats.push_rate_limited += 1,
+                        Wake::NoToken => {}
+                    }
+                }
+                DeliveryStatus::Stored
+            }
+            Err(MailboxError::Full) => {
+                stats.dropped_mailbox_full += 1;
+                DeliveryStatus::MailboxFull
+            }
+            Err(MailboxError::Expired) => {
+                stats.dropped_offline += 1;
+                DeliveryStatus::Offline
+            }
+            Err(MailboxError::Io(e)) => {
+                warn!("Mailbox write failed: {}", e);
+                stats.dropped_offline += 1;
+                DeliveryStatus::Offline
+            }
+        }
+    }
+
+    /// Route a packet forwarded by the peer relay on `link_id`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn route_forwarded(
+        &self,
+        link_id: u64,
+        id: ForwardId,
+        from: &str,
+        to: &str,
+        hops: u8,
+        packet: Vec<u8>,
+        ttl_secs: Option<u32>,
+    ) {
+        let Some(federation) = &self.federation else { return };
+        let mut counted = Stats::default();
+        self.route_forwarded_counted(&mut counted, federation, link_id, id, from, to, hops, packet, ttl_secs).await;
+        self.record(|stats| stats.add(&counted));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn route_forwarded_counted(
+        &self,
+        stats: &mut Stats,
+        federation: &Federation,
+        link_id: u64,
+        id: ForwardId,
+        from: &str,
+        to: &str,
+        hops: u8,
+        packet: Vec<u8>,
+        ttl_secs: Option<u32>,
+    ) {
+        stats.federated_in += 1;
+
+        if packet.is_empty() || packet.len() > self.config.max_packet {
+            stats.dropped_size += 1;
+            return;
+        }
+        // Already handled: the packet went around a loop
+        if !federation.mark_seen(id) {
+            stats.dropped_loop += 1;
+            return;
+        }
+
+        let session = self.sessions.lock().unwrap().get(to).map(|session| (session.tx.clone(), session.transport));
+        let undelivered = match session {
+            Some((_, transport)) if packet.len() > self.packet_limit(transport) => {
+                stats.dropped_datagram_size += 1;
+                return;
+            }
+            Some((tx, _)) => match tx.try_send(RelayMessage::Deliver { from: from.to_string(), packet, mailbox_id: None }) {
+                Ok(()) => {
+                    stats.forwarded += 1;
+                    return;
+                }
+                Err(mpsc::error::TrySendError::Full(message) | mpsc::error::TrySendError::Closed(message)) => {
+                    let RelayMessage::Deliver { packet, .. } = message else { unreachable!() };
+                    packet
+                }
+            },
+            // Recipient moved on: pass it on while hops remain
+            None if hops == 0 => {
+                stats.dropped_loop += 1;
+                return;
+            }
+            None => match federation.forward(id, from, to, hops - 1, packet, ttl_secs, Some(link_id)) {
+                Ok(()) => {
+                    stats.federated_out += 1;
+                    return;
+                }
+                Err(packet) => packet,
+            },
+        };
+
+        // Nobody has a session for the recipient: keep it here
+        self.store(stats, from, to, undelivered, ttl_secs).await;
+    }
+}
+
+/// Reason sent to banned clients
+const BANNED: &str = "Banned by relay operator";
+
+fn banned() -> RelayMessage {
+    RelayMessage::Error { code: ErrorCode::AuthFailed, message: BANNED.to_string() }
+}
+
+/// Holds one of the connections counted per source IP
+struct ConnectionGuard {
+    state: Arc<RelayState>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.state.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Accept connections until the listener fails
+pub async fn serve(listener: TcpListener, state: Arc<RelayState>) -> std::io::Result<()> {
+    info!("Relay sessions listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let _ = stream.set_nodelay(true);
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run_session(stream_link(stream), peer_ip(addr), Transport::Tcp, state).await {
+                debug!("Session from {} ended: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Run one client session over `link` (stream or datagram) until it closes
+pub async fn run_session(
+    mut link: MessageLink,
+    ip: IpAddr,
+    transport: Transport,
+    state: Arc<RelayState>,
+) -> Result<(), ProtoError> {
+    let first = match timeout(HANDSHAKE_TIMEOUT, link.incoming.recv()).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return Err(ProtoError::Closed),
+        Err(_) => return Err(ProtoError::Unexpected("handshake timeout")),
+    };
+    if matches!(first, RelayMessage::PeerHello { .. }) {
+        return crate::federation::accept(link, first, state).await;
+    }
+
+    if state.is_ip_banned(ip) {
+        state.record(|stats| stats.rejected_banned += 1);
+        reject(&link.outgoing, ErrorCode::AuthFailed, BANNED).await;
+        return Ok(());
+    }
+    let Some(_connection) = state.open_connection(ip) else {
+        reject(&link.outgoing, ErrorCode::Overloaded, "Too many sessions from this address").await;
+        return Ok(());
+    };
+
+    let started = Instant::now();
+    let node_id = match timeout(HANDSHAKE_TIMEOUT, handshake(&mut link, first, &state)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(ProtoError::Unexpected("handshake timeout")),
+    };
+
+    if state.bans.lock().unwrap().is_node_banned(&node_id) {
+        state.record(|stats| stats.rejected_banned += 1);
+        reject(&link.outgoing, ErrorCode::AuthFailed, BANNED).await;
+        return Ok(());
+    }
+
+    let (session_id, kick) = match state.register(&node_id, ip, transport, link.outgoing.clone()) {
+        Ok(registered) => registered,
+        Err(code) => {
+            reject(&link.outgoing, code, "Relay is full").await;
+            return Ok(());
+        }
+    };
+    send(&link.outgoing, RelayMessage::Welcome { keepalive_secs: state.config.keepalive_secs }).await?;
+    state.histograms.handshake.get(transport).observe_duration(started.elapsed());
+    debug!("Session registered: {}", node_id);
+
+    // Drain the mailbox; packets stay stored until acknowledged
+    if let Some(mailbox) = &state.mailbox {
+        // Packets a datagram session cannot carry wait for a stream session
+        let limit = state.packet_limit(transport);
+        let pending: Vec<_> = mailbox
+            .pending(&node_id, unix_now())
+            .into_iter()
+            .filter(|stored| stored.packet.len() <= limit)
+            .collect();
+        state.record(|stats| stats.mailbox_delivered += pending.len() as u64);
+        for stored in pending {
+            let deliver = RelayMessage::Deliver { from: stored.from, packet: stored.packet, mailbox_id: Some(stored.id) };
+            send(&link.outgoing, deliver).await?;
+        }
+    }
+
+    let client = Client { ip, node_id: &node_id, transport };
+    let result = read_loop(&mut link, client, session_id, &state, &kick).await;
+
+    state.unregister(&node_id, session_id);
+    debug!("Session closed: {}", node_id);
+    result
+}
+
+async fn handshake(link: &mut MessageLink, hello: RelayMessage, state: &RelayState) -> Result<String, ProtoError> {
+    let (node_id, public_key) = match hello {
+        RelayMessage::Hello { version, node_id, public_key } => {
+            if version != PROTOCOL_VERSION {
+                reject(&link.outgoing, ErrorCode::UnsupportedVersion, "Unsupported protocol version").await;
+                return Err(ProtoError::Rejected(ErrorCode::UnsupportedVersion, version.to_string()));
+            }
+            (node_id, public_key)
+        }
+        other => {
+            reject(&link.outgoing, ErrorCode::ProtocolViolation, "Expected hello").await;
+            return Err(ProtoError::Unexpected(other.kind()));
+        }
+    };
+
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let pow_difficulty = state.pow_difficulty();
+    if pow_difficulty > 0 {
+        state.record(|stats| stats.pow_challenges += 1);
+    }
+    send(&link.outgoing, RelayMessage::Challenge { nonce: nonce.to_vec(), pow_difficulty }).await?;
+
+    match link.incoming.recv().await.ok_or(ProtoError::Closed)? {
+        RelayMessage::Auth { signature, pow } => {
+            // Proof of work first: it is cheaper to check than the signature
+            let solved = pow.is_some_and(|pow| verify_pow(&nonce, &node_id, pow, pow_difficulty));
+            if pow_difficulty > 0 && !solved {
+                state.record(|stats| stats.rejected_pow += 1);
+                reject(&link.outgoing, ErrorCode::AuthFailed, "Invalid proof of work").await;
+                return Err(ProtoError::AuthFailed);
+            }
+            if let Err(e) = verify_auth(&node_id, &public_key, &nonce, &link.binding, &signature) {
+                state.record(|stats| stats.auth_failures += 1);
+                reject(&link.outgoing, ErrorCode::AuthFailed, "Invalid signature").await;
+                return Err(e);
+            }
+        }
+        other => {
+            reject(&link.outgoing, ErrorCode::ProtocolViolation, "Expected auth").await;
+            return Err(ProtoError::Unexpected(other.kind()));
+        }
+    }
+
+    Ok(node_id)
+}
+
+async fn read_loop(
+    link: &mut MessageLink,
+    client: Client<'_>,
+    session_id: u64,
+    state: &RelayState,
+    kick: &Notify,
+) -> Result<(), ProtoError> {
+    let node_id = client.node_id;
+    let idle_timeout = state.config.idle_timeout();
+    let mut closing = state.closing.subscribe();
+
+    loop {
+        // `None`: the relay is shutting down
+        let received = tokio::select! {
+            _ = closing.wait_for(|closing| *closing) => None,
+            // Evicted: the reason is already queued
+            _ = kick.notified() => return Ok(()),
+            received = timeout(idle_timeout, link.incoming.recv()) => Some(received),
+        };
+        let message = match received {
+            None => {
+                let _ = send(&link.outgoing, RelayMessage::Bye).await;
+                return Ok(());
+            }
+            Some(Err(_)) => {
+                debug!("Session {} missed keepalives", node_id);
+                return Ok(());
+            }
+            Some(Ok(None)) => return Ok(()),
+            Some(Ok(Some(message))) => message,
+        };
+
+        if !state.is_current(node_id, session_id) {
+            return Ok(());
+        }
+
+        let reply = match message {
+            RelayMessage::Ping { seq } => RelayMessage::Pong { seq },
+            RelayMessage::Pong { .. } => continue,
+            RelayMessage::Send { seq, to, packet, ttl_secs } => {
+                let started = Instant::now();
+                let status = state.route(&client, &to, packet, ttl_secs).await;
+                state.histograms.route.observe_duration(started.elapsed());
+                RelayMessage::SendResult { seq, status }
+            }
+            RelayMessage::Flood { seq, packet } => RelayMessage::SendResult {
+                seq,
+                status: state.flood(&client, packet),
+            },
+            RelayMessage::Register { tags, expires_at, signature } => {
+                match state.register_tags(node_id, &tags, expires_at, &signature) {
+                    Ok(reply) => reply,
+                    Err(code) => {
+                        reject(&link.outgoing, code, "Invalid registration").await;
+                        return Err(ProtoError::Rejected(code, "register".to_string()));
+                    }
+                }
+            }
+            RelayMessage::RegisterPush { provider, token } => match state.register_push(node_id, &provider, &token) {
+                Ok(reply) => reply,
+                Err(code) => {
+                    reject(&link.outgoing, code, "Invalid push token").await;
+                    return Err(ProtoError::Rejected(code, "register_push".to_string()));
+                }
+            },
+            RelayMessage::Punch { peer } => match state.punch(&client, &peer) {
+                Some(reply) => reply,
+                None => continue,
+            },
+            RelayMessage::Ack { mailbox_id } => {
+                if state.mailbox.as_ref().is_some_and(|mailbox| mailbox.ack(node_id, mailbox_id)) {
+                    state.record(|stats| stats.mailbox_acked += 1);
+                }
+                continue;
+            }
+            RelayMessage::Bye => return Ok(()),
+            other => {
+                reject(&link.outgoing, ErrorCode::ProtocolViolation, &format!("Unexpected {}", other.kind())).await;
+                return Err(ProtoError::Unexpected(other.kind()));
+            }
+        };
+
+        send(&link.outgoing, reply).await?;
+    }
+}
+
+async fn send(outgoing: &mpsc::Sender<RelayMessage>, message: RelayMessage) -> Result<(), ProtoError> {
+    outgoing.send(message).await.map_err(|_| ProtoError::Closed)
+}
+
+async fn reject(outgoing: &mpsc::Sender<RelayMessage>, code: ErrorCode, message: &str) {
+    let _ = outgoing.send(RelayMessage::Error { code, message: message.to_string() }).await;
+}
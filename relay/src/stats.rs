@@ -0,0 +1,197 @@
+use crate::metrics::PerTransport;
+use serde::{Deserialize, Serialize};
+
+// Security limits to prevent memory exhaustion attacks
+pub const MAX_PEERS: usize = 10_000;
+
+/// Counters for one logging interval (see `RelayState::take_stats`) and
+/// gauges at its end
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Stats {
+    pub received: u64,
+    /// `received` split by the sender's transport
+    pub received_by: PerTransport<u64>,
+    pub forwarded: u64,
+    /// Packets over the budget of their source network
+    pub dropped_rate: u64,
+    /// Packets over the budget of their node
+    pub dropped_node_rate: u64,
+    /// Packets over the relay-wide budget
+    pub dropped_global_rate: u64,
+    /// Emergency packets let through on the emergency reserve
+    pub emergency_reserved: u64,
+    pub dropped_size: u64,
+    /// Packets or messages too large for a datagram session
+    pub dropped_datagram_size: u64,
+    pub dropped_peer_limit: u64,
+    pub dropped_offline: u64,
+    pub auth_failures: u64,
+    /// Connections refused: too many sessions from one address
+    pub rejected_ip_sessions: u64,
+    /// Handshakes with missing or wrong proof of work
+    pub rejected_pow: u64,
+    /// Connections refused: banned address or node
+    pub rejected_banned: u64,
+    /// Sessions closed by an operator (evict or ban)
+    pub evicted: u64,
+    /// Challenges that asked for proof of work (relay under load)
+    pub pow_challenges: u64,
+    pub udp_handshakes: u64,
+    pub udp_resumptions: u64,
+    pub udp_retries: u64,
+    /// Packets handed to federated relays
+    pub federated_out: u64,
+    /// Packets received from federated relays
+    pub federated_in: u64,
+    /// Forwarded packets dropped as repeats or out of hops
+    pub dropped_loop: u64,
+    pub federation_links: usize,
+    /// Packets kept in the mailbox for offline recipients
+    pub stored: u64,
+    /// Mailbox packets sent to reconnecting recipients (redeliveries included)
+    pub mailbox_delivered: u64,
+    pub mailbox_acked: u64,
+    pub mailbox_expired: u64,
+    /// Packets refused because the recipient's mailbox was full
+    pub dropped_mailbox_full: u64,
+    pub mailbox_packets: usize,
+    pub mailbox_bytes: usize,
+    /// Emergency packets broadcast with `Flood`
+    pub flooded: u64,
+    /// Sessions reached by those floods
+    pub flood_deliveries: u64,
+    /// Floods refused because the node flooded recently
+    pub dropped_flood_rate: u64,
+    /// Most sessions a single packet went to (1 for addressed delivery)
+    pub max_fanout: u64,
+    pub tag_registrations: u64,
+    /// Packets addressed by recipient tag
+    pub tag_routed: u64,
+    pub registered_tags: usize,
+    /// `Punch` requests accepted for rendezvous
+    pub punch_requests: u64,
+    /// Public UDP addresses learned on the rendezvous socket
+    pub rendezvous_binds: u64,
+    /// Pairs of nodes introduced for a direct link
+    pub punch_introductions: u64,
+    pub push_registrations: u64,
+    /// Wakeups queued for recipients with a push token
+    pub push_queued: u64,
+    /// Wakeups skipped: the recipient was woken recently
+    pub push_rate_limited: u64,
+    /// Wakeups the push provider accepted
+    pub push_sent: u64,
+    pub push_failed: u64,
+    /// Nodes with a push token
+    pub push_tokens: usize,
+    pub active_peers: usize,
+    /// `active_peers` split by transport
+    pub sessions: PerTransport<usize>,
+    pub rate_entries: usize,
+    pub uptime_secs: u64,
+}
+
+impl Stats {
+    /// Add the counters of `interval`; gauges are left as they are
+    pub fn add(&mut self, interval: &Stats) {
+        // Destructured so a new field cannot be forgotten here
+        let Stats {
+            received,
+            received_by,
+            forwarded,
+            dropped_rate,
+            dropped_node_rate,
+            dropped_global_rate,
+            emergency_reserved,
+            dropped_size,
+            dropped_datagram_size,
+            dropped_peer_limit,
+            dropped_offline,
+            auth_failures,
+            rejected_ip_sessions,
+            rejected_pow,
+            rejected_banned,
+            evicted,
+            pow_challenges,
+            udp_handshakes,
+            udp_resumptions,
+            udp_retries,
+            federated_out,
+            federated_in,
+            dropped_loop,
+            federation_links: _,
+            stored,
+            mailbox_delivered,
+            mailbox_acked,
+            mailbox_expired,
+            dropped_mailbox_full,
+            mailbox_packets: _,
+            mailbox_bytes: _,
+            flooded,
+            flood_deliveries,
+            dropped_flood_rate,
+            max_fanout,
+            tag_registrations,
+            tag_routed,
+            registered_tags: _,
+            punch_requests,
+            rendezvous_binds,
+            punch_introductions,
+            push_registrations,
+            push_queued,
+            push_rate_limited,
+            push_sent,
+            push_failed,
+            push_tokens: _,
+            active_peers: _,
+            sessions: _,
+            rate_entries: _,
+            uptime_secs: _,
+        } = interval;
+        self.received += received;
+        self.received_by.tcp += received_by.tcp;
+        self.received_by.tls += received_by.tls;
+        self.received_by.udp += received_by.udp;
+        self.forwarded += forwarded;
+        self.dropped_rate += dropped_rate;
+        self.dropped_node_rate += dropped_node_rate;
+        self.dropped_global_rate += dropped_global_rate;
+        self.emergency_reserved += emergency_reserved;
+        self.dropped_size += dropped_size;
+        self.dropped_datagram_size += dropped_datagram_size;
+        self.dropped_peer_limit += dropped_peer_limit;
+        self.dropped_offline += dropped_offline;
+        self.auth_failures += auth_failures;
+        self.rejected_ip_sessions += rejected_ip_sessions;
+        self.rejected_pow += rejected_pow;
+        self.rejected_banned += rejected_banned;
+        self.evicted += evicted;
+        self.pow_challenges += pow_challenges;
+        self.udp_handshakes += udp_handshakes;
+        self.udp_resumptions += udp_resumptions;
+        self.udp_retries += udp_retries;
+        self.federated_out += federated_out;
+        self.federated_in += federated_in;
+        self.dropped_loop += dropped_loop;
+        self.stored += stored;
+        self.mailbox_delivered += mailbox_delivered;
+        self.mailbox_acked += mailbox_acked;
+        self.mailbox_expired += mailbox_expired;
+        self.dropped_mailbox_full += dropped_mailbox_full;
+        self.flooded += flooded;
+        self.flood_deliveries += flood_deliveries;
+        self.dropped_flood_rate += dropped_flood_rate;
+        self.max_fanout = self.max_fanout.max(*max_fanout);
+        self.tag_registrations += tag_registrations;
+        self.tag_routed += tag_routed;
+        self.punch_requests += punch_requests;
+        self.rendezvous_binds += rendezvous_binds;
+        self.punch_introductions += punch_introductions;
+        self.push_registrations += push_registrations;
+        self.push_queued += push_queued;
+        self.push_rate_limited += push_rate_limited;
+        self.push_sent += push_sent;
+        self.push_failed += push_failed;
+    }
+}
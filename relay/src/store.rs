@@ -0,0 +1,364 @@
+//! Relay state that survives restarts
+//!
+//! An embedded sled database (`store.dir`) keeps what a restart would
+//! otherwise lose: recipient tag registrations, the cumulative counters
+//! and the next mailbox id (the packets themselves stay in the mailbox
+//! directory). Tag registrations are written as they change; counters and
+//! the mailbox id are saved as a snapshot every housekeeping interval and
+//! on shutdown, so a crash loses at most one interval of counts. Every
+//! write is atomic in sled's log; records that do not decode are skipped
+//! on load.
+//!
+//! Push tokens (`crate::push`) are kept too, written as they change.
+//!
+//! The relay writes both through a `StoreWriter`: callers queue writes
+//! under their own locks, and one task applies them in that order on the
+//! blocking pool, so no flush runs under a lock or on a runtime thread.
+//!
+//! Tag registrations and push tokens hold node ids, unlike the mailbox
+//! directory.
+
+use crate::push::PushRecord;
+use crate::stats::Stats;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+use yaok_relay_proto::tags::{RecipientTag, RECIPIENT_TAG_LEN};
+
+/// Key of the snapshot in the default tree
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+
+/// Tree of tag registrations, keyed by tag
+const TAGS_TREE: &str = "tags";
+
+/// Tree of push tokens, keyed by node id
+const PUSH_TREE: &str = "push";
+
+/// What the relay restores on start
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+    /// Counters of every run so far
+    pub totals: Stats,
+    /// Lowest mailbox id not issued yet
+    pub mailbox_next_id: u64,
+    /// Unix seconds of the first start with this store
+    pub first_started_at: u64,
+    /// Starts after the first one
+    pub restarts: u64,
+}
+
+/// Node a tag was registered to, until `expires_at` (unix seconds)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagRecord {
+    pub node_id: String,
+    pub expires_at: u64,
+}
+
+pub struct Store {
+    db: sled::Db,
+    tags: sled::Tree,
+    push: sled::Tree,
+    /// Snapshot found when the store was opened
+    restored: Option<Snapshot>,
+    first_started_at: u64,
+    restarts: u64,
+}
+
+impl Store {
+    /// Open or create the store in `dir` and count this start
+    pub fn open(dir: &Path, now: u64) -> io::Result<Self> {
+        // Every write flushes itself, no background flusher is needed
+        let db = sled::Config::new().path(dir).flush_every_ms(None).open().map_err(to_io)?;
+        let tags = db.open_tree(TAGS_TREE).map_err(to_io)?;
+        let push = db.open_tree(PUSH_TREE).map_err(to_io)?;
+        let restored = match db.get(SNAPSHOT_KEY).map_err(to_io)? {
+            Some(bytes) => match ciborium::de::from_reader::<Snapshot, _>(bytes.as_ref()) {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    warn!("Store: ignoring unreadable snapshot in {}: {}", dir.display(), e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let (first_started_at, restarts) = match &restored {
+            Some(snapshot) => (snapshot.first_started_at, snapshot.restarts + 1),
+            None => (now, 0),
+        };
+        Ok(Self { db, tags, push, restored, first_started_at, restarts })
+    }
+
+    /// Snapshot of the previous run, if there was one
+    pub fn restored(&self) -> Option<&Snapshot> {
+        self.restored.as_ref()
+    }
+
+    pub fn first_started_at(&self) -> u64 {
+        self.first_started_at
+    }
+
+    pub fn restarts(&self) -> u64 {
+        self.restarts
+    }
+
+    /// Save counters and the mailbox id; durable when this returns
+    pub fn save_snapshot(&self, totals: &Stats, mailbox_next_id: u64) -> io::Result<()> {
+        let snapshot = Snapshot {
+            totals: totals.clone(),
+            mailbox_next_id,
+            first_started_at: self.first_started_at,
+            restarts: self.restarts,
+        };
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&snapshot, &mut bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.db.insert(SNAPSHOT_KEY, bytes).map_err(to_io)?;
+        self.flush()
+    }
+
+    /// Tag registrations that have not expired by `now`
+    pub fn tags(&self, now: u64) -> Vec<(RecipientTag, TagRecord)> {
+        let mut tags = Vec::new();
+        for entry in self.tags.iter() {
+            let Ok((key, value)) = entry else { continue };
+            let tag: Option<RecipientTag> = key.as_ref().try_into().ok();
+            let record = ciborium::de::from_reader::<TagRecord, _>(value.as_ref()).ok();
+            match (tag, record) {
+                (Some(tag), Some(record)) if record.expires_at > now => tags.push((tag, record)),
+                (Some(_), Some(_)) => {}
+                _ => warn!("Store: skipping unreadable tag registration"),
+            }
+        }
+        tags
+    }
+
+    /// Replace tag registrations in one atomic batch: drop `removed`, add
+    /// `added`
+    pub fn update_tags(&self, removed: &[RecipientTag], added: &[(RecipientTag, TagRecord)]) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        for tag in removed {
+            batch.remove(tag.as_slice());
+        }
+        for (tag, record) in added {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(record, &mut bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            batch.insert(tag.as_slice(), bytes);
+        }
+        self.tags.apply_batch(batch).map_err(to_io)?;
+        self.flush()
+    }
+
+    /// Drop registrations expired by `now`; returns how many
+    pub fn expire_tags(&self, now: u64) -> io::Result<usize> {
+        let expired: Vec<RecipientTag> = self
+            .tags
+            .iter()
+            .filter_map(Result::ok)
+            .filter(|(key, value)| {
+                key.len() == RECIPIENT_TAG_LEN
+                    && ciborium::de::from_reader::<TagRecord, _>(value.as_ref()).is_ok_and(|record| record.expires_at <= now)
+            })
+            .filter_map(|(key, _)| key.as_ref().try_into().ok())
+            .collect();
+        if !expired.is_empty() {
+            self.update_tags(&expired, &[])?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Saved push tokens by node id
+    pub fn push_tokens(&self) -> Vec<(String, PushRecord)> {
+        let mut tokens = Vec::new();
+        for entry in self.push.iter() {
+            let Ok((key, value)) = entry else { continue };
+            let node_id = String::from_utf8(key.to_vec()).ok();
+            let record = ciborium::de::from_reader::<PushRecord, _>(value.as_ref()).ok();
+            match (node_id, record) {
+                (Some(node_id), Some(record)) => tokens.push((node_id, record)),
+                _ => warn!("Store: skipping unreadable push token"),
+            }
+        }
+        tokens
+    }
+
+    /// Save the push token of `node_id`, replacing its previous one
+    pub fn save_push_token(&self, node_id: &str, record: &PushRecord) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(record, &mut bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.push.insert(node_id.as_bytes(), bytes).map_err(to_io)?;
+        self.flush()
+    }
+
+    pub fn remove_push_tokens(&self, node_ids: &[String]) -> io::Result<()> {
+        let mut batch = sled::Batch::default();
+        for node_id in node_ids {
+            batch.remove(node_id.as_bytes());
+        }
+        self.push.apply_batch(batch).map_err(to_io)?;
+        self.flush()
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.db.flush().map(|_| ()).map_err(to_io)
+    }
+}
+
+type ApplyWrite = Box<dyn FnOnce(&Store) -> io::Result<()> + Send>;
+
+/// Queued store write; `what` names it in the warning if it fails
+struct Write {
+    what: &'static str,
+    apply: ApplyWrite,
+}
+
+/// Applies queued writes to a store one at a time, in queue order
+#[derive(Clone)]
+pub struct StoreWriter {
+    tx: mpsc::UnboundedSender<Write>,
+}
+
+impl StoreWriter {
+    /// Spawn the writer task; it ends, releasing `store`, once every
+    /// writer is dropped and the queue is empty. Needs a Tokio runtime.
+    pub fn start(store: Arc<Store>) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Write>();
+        tokio::spawn(async move {
+            while let Some(write) = rx.recv().await {
+                let store = store.clone();
+                let what = write.what;
+                match tokio::task::spawn_blocking(move || (write.apply)(&store)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Store: {} failed: {}", what, e),
+                    Err(e) => warn!("Store: {} failed: {}", what, e),
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Queue `apply` behind the writes queued before it. Never blocks,
+    /// so it may be called under a lock to keep the order of changes.
+    pub fn queue(&self, what: &'static str, apply: impl FnOnce(&Store) -> io::Result<()> + Send + 'static) {
+        let _ = self.tx.send(Write { what, apply: Box::new(apply) });
+    }
+
+    /// Wait until every write queued so far is on disk
+    pub async fn sync(&self) {
+        let (done, synced) = oneshot::channel();
+        self.queue("sync", move |_| {
+            let _ = done.send(());
+            Ok(())
+        });
+        let _ = synced.await;
+    }
+}
+
+fn to_io(error: sled::Error) -> io::Error {
+    match error {
+        sled::Error::Io(e) => e,
+        other => io::Error::other(other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("yaok-store-{}", rand::random::<u64>()))
+    }
+
+    /// sled drops its file lock from a background thread, so a quick reopen may have to wait
+    fn reopen(dir: &Path, now: u64) -> Store {
+        for _ in 0..50 {
+            if let Ok(store) = Store::open(dir, now) {
+                return store;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        Store::open(dir, now).unwrap()
+    }
+
+    #[test]
+    fn snapshot_and_tags_survive_reopen() {
+        let dir = temp_dir();
+        {
+            let store = Store::open(&dir, 1_000).unwrap();
+            assert!(store.restored().is_none());
+            assert_eq!((store.first_started_at(), store.restarts()), (1_000, 0));
+
+            let totals = Stats { received: 7, forwarded: 5, ..Stats::default() };
+            store.save_snapshot(&totals, 42).unwrap();
+            let record = |expires_at| TagRecord { node_id: "alice".to_string(), expires_at };
+            store.update_tags(&[], &[([1; RECIPIENT_TAG_LEN], record(2_000)), ([2; RECIPIENT_TAG_LEN], record(1_500))]).unwrap();
+            store.update_tags(&[[1; RECIPIENT_TAG_LEN]], &[([3; RECIPIENT_TAG_LEN], record(2_000))]).unwrap();
+        }
+
+        let store = reopen(&dir, 5_000);
+        let restored = store.restored().unwrap();
+        assert_eq!((restored.totals.received, restored.totals.forwarded, restored.mailbox_next_id), (7, 5, 42));
+        assert_eq!((store.first_started_at(), store.restarts()), (1_000, 1));
+
+        let tags = store.tags(1_600);
+        assert_eq!(tags.iter().map(|(tag, _)| tag[0]).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(store.expire_tags(1_600).unwrap(), 1);
+        assert_eq!(store.tags(0).len(), 1);
+        drop(store);
+
+        let record = PushRecord { provider: "fcm".to_string(), token: b"token".to_vec(), registered_at: 5_000 };
+        {
+            let store = reopen(&dir, 6_000);
+            store.save_push_token("alice", &record).unwrap();
+            store.save_push_token("bob", &record).unwrap();
+            store.remove_push_tokens(&["bob".to_string()]).unwrap();
+        }
+        let store = reopen(&dir, 7_000);
+        assert_eq!(store.push_tokens(), vec![("alice".to_string(), record)]);
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_applies_writes_in_queue_order() {
+        let dir = temp_dir();
+        let store = Arc::new(Store::open(&dir, 1).unwrap());
+        let writer = StoreWriter::start(store.clone());
+        let record = |registered_at| PushRecord { provider: "fcm".to_string(), token: b"token".to_vec(), registered_at };
+        for round in 0..50 {
+            let (old, new) = (record(round), record(round + 1));
+            writer.queue("saving push token", move |store| store.save_push_token("alice", &old));
+            writer.queue("expiring push tokens", |store| store.remove_push_tokens(&["alice".to_string()]));
+            writer.queue("saving push token", move |store| store.save_push_token("alice", &new));
+        }
+        writer.sync().await;
+        assert_eq!(store.push_tokens(), vec![("alice".to_string(), record(50))]);
+        drop((writer, store));
+
+        // The last writer gone, the task releases the store
+        let reopened = dir.clone();
+        let store = tokio::task::spawn_blocking(move || reopen(&reopened, 2)).await.unwrap();
+        assert_eq!(store.push_tokens().len(), 1);
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_records_are_skipped() {
+        let dir = temp_dir();
+        {
+            let store = Store::open(&dir, 1).unwrap();
+            store.db.insert(SNAPSHOT_KEY, b"not cbor".to_vec()).unwrap();
+            store.tags.insert(b"short", b"junk".to_vec()).unwrap();
+            store.flush().unwrap();
+        }
+        let store = reopen(&dir, 9);
+        assert!(store.restored().is_none());
+        assert_eq!(store.restarts(), 0);
+        assert!(store.tags(0).is_empty());
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+//! TLS termination for the session stream and the HTTP server
+//!
+//! `TlsCertificates` holds the rustls server config built from a PEM
+//! certificate chain and private key. It is rebuilt when the files change
+//! (`reload_if_changed`, polled by `Relay`) or on demand (`reload`, on
+//! SIGHUP in `main.rs`); handshakes already running keep the old config,
+//! new connections get the new one. A failed reload keeps the previous
+//! certificate, so a half-written renewal never takes the relay down.
+
+use crate::metrics::Transport;
+use crate::net::peer_ip;
+use crate::server::{run_session, RelayState, HANDSHAKE_TIMEOUT};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use yaok_relay_proto::{stream_link, CHANNEL_BINDING_LEN, TLS_EXPORTER_LABEL};
+
+/// Certificate and key files with the server config loaded from them
+pub struct TlsCertificates {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Loaded>,
+}
+
+struct Loaded {
+    config: Arc<ServerConfig>,
+    /// Modification times the config was loaded at
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl TlsCertificates {
+    /// Load `cert_path` (PEM chain, leaf first) and `key_path` (PEM key)
+    pub fn load(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> io::Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let loaded = Loaded::read(&cert_path, &key_path)?;
+        Ok(Self { cert_path, key_path, current: RwLock::new(loaded) })
+    }
+
+    /// Server config for a new connection
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.current.read().unwrap().config.clone()
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config())
+    }
+
+    /// Re-read both files; on error the current certificate stays
+    pub fn reload(&self) -> io::Result<()> {
+        let loaded = Loaded::read(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = loaded;
+        info!("TLS certificate reloaded from {}", self.cert_path.display());
+        Ok(())
+    }
+
+    /// Reload if either file was modified since the last load
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let modified = modification_times(&self.cert_path, &self.key_path);
+        if modified == self.current.read().unwrap().modified {
+            return Ok(false);
+        }
+        self.reload().map(|()| true)
+    }
+}
+
+impl Loaded {
+    fn read(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let modified = modification_times(cert_path, key_path);
+        let config = load_server_config(cert_path, key_path)?;
+        Ok(Self { config: Arc::new(config), modified })
+    }
+}
+
+fn modification_times(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert_path), modified(key_path))
+}
+
+/// Build a rustls server config from PEM files
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> io::Result<ServerConfig> {
+    let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+
+    let certs: Vec<CertificateDer<'static>> =
+        rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?)).collect::<Result<_, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("No certificate in {}", cert_path.display())));
+    }
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| invalid(format!("No private key in {}", key_path.display())))?;
+
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| invalid(format!("Invalid TLS certificate {}: {}", cert_path.display(), e)))
+}
+
+/// Accept TLS connections until the listener fails; each one carries a
+/// session stream as in `serve`
+pub async fn serve_tls(listener: TcpListener, certs: Arc<TlsCertificates>, state: Arc<RelayState>) -> io::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let acceptor = certs.acceptor();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", addr);
+                    return;
+                }
+            };
+            let mut binding = [0u8; CHANNEL_BINDING_LEN];
+            if let Err(e) = stream.get_ref().1.export_keying_material(&mut binding, TLS_EXPORTER_LABEL, None) {
+                debug!("TLS exporter for {} failed: {}", addr, e);
+                return;
+            }
+            let link = stream_link(stream).with_binding(binding);
+            if let Err(e) = run_session(link, peer_ip(addr), Transport::Tls, state).await {
+                debug!("Session from {} ended: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Log and keep the current certificate if a reload fails
+pub(crate) fn check_for_renewal(certs: &TlsCertificates) {
+    if let Err(e) = certs.reload_if_changed() {
+        warn!("Keeping current TLS certificate, reload failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &Path) {
+        let cert = rcgen::generate_simple_self_signed(vec!["relay.test".to_string()]).unwrap();
+        std::fs::write(dir.join("relay-cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("relay-key.pem"), cert.key_pair.serialize_pem()).unwrap();
+    }
+
+    #[test]
+    fn reload_keeps_current_certificate_on_error() {
+        let dir = std::env::temp_dir().join(format!("yaok-tls-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_cert(&dir);
+        let certs = TlsCertificates::load(dir.join("relay-cert.pem"), dir.join("relay-key.pem")).unwrap();
+        let first = certs.server_config();
+        assert!(!certs.reload_if_changed().unwrap());
+
+        // Half-written renewal: the old config stays
+        std::fs::write(dir.join("relay-key.pem"), "not a key").unwrap();
+        assert!(certs.reload_if_changed().is_err());
+        assert!(Arc::ptr_eq(&first, &certs.server_config()));
+
+        write_cert(&dir);
+        assert!(certs.reload_if_changed().unwrap());
+        assert!(!Arc::ptr_eq(&first, &certs.server_config()));
+
+        assert!(TlsCertificates::load(dir.join("missing.pem"), dir.join("relay-key.pem")).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
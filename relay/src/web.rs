@@ -0,0 +1,241 @@
+//! Public web pages served next to the metrics endpoint
+//!
+//! The invite landing page (`/add?<invite>`, see
+//! [`yaok_relay_proto::invite`]) is rendered from the templates in
+//! `relay/templates`, which escape every value. The inviter's name is
+//! shown only when the invite signature verifies; malformed or forged
+//! invites get an error page. Text follows `Accept-Language`.
+
+use askama::Template;
+use yaok_relay_proto::{Invite, InviteError};
+
+pub const DEFAULT_PLAY_STORE_URL: &str = "https://play.google.com/store/apps/details?id=app.poruch.ya_ok";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebConfig {
+    /// `https://host` the pages are reached at, for link previews
+    pub public_url: Option<String>,
+    pub play_store_url: String,
+    /// iOS devices without the app are sent here, if set
+    pub app_store_url: Option<String>,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        Self { public_url: None, play_store_url: DEFAULT_PLAY_STORE_URL.to_string(), app_store_url: None }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Locale {
+    Uk,
+    En,
+}
+
+/// Page text for one locale
+pub struct Strings {
+    pub lang: &'static str,
+    /// `{name}` is replaced before escaping
+    pub invite_title: &'static str,
+    pub invite_title_unnamed: &'static str,
+    pub description: &'static str,
+    pub open_app: &'static str,
+    pub no_app: &'static str,
+    pub invalid_title: &'static str,
+    pub invalid_text: &'static str,
+}
+
+const UK: Strings = Strings {
+    lang: "uk",
+    invite_title: "{name} запрошує вас в Я ОК!",
+    invite_title_unnamed: "Вас запрошують в Я ОК!",
+    description: "Швидке повідомлення про безпеку для близьких 🇺🇦",
+    open_app: "Відкрити додаток",
+    no_app: "Немає додатка?",
+    invalid_title: "Запрошення недійсне",
+    invalid_text: "Посилання пошкоджене або підроблене. Попросіть надіслати його ще раз.",
+};
+
+const EN: Strings = Strings {
+    lang: "en",
+    invite_title: "{name} invites you to Ya OK!",
+    invite_title_unnamed: "You are invited to Ya OK!",
+    description: "Let the people close to you know you are safe, fast 🇺🇦",
+    open_app: "Open the app",
+    no_app: "No app yet?",
+    invalid_title: "Invalid invite",
+    invalid_text: "The link is damaged or forged. Ask for a new one.",
+};
+
+impl Locale {
+    /// Ukrainian unless the browser prefers a language we have
+    pub const DEFAULT: Locale = Locale::Uk;
+
+    fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next().unwrap_or("").trim();
+        match primary.to_ascii_lowercase().as_str() {
+            "uk" => Some(Locale::Uk),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+
+    /// Best supported locale for an `Accept-Language` header, by q-value
+    /// and then by order
+    pub fn from_accept_language(header: Option<&str>) -> Self {
+        let mut best: Option<(Locale, f32)> = None;
+        for item in header.unwrap_or("").split(',') {
+            let mut parts = item.split(';');
+            let Some(locale) = parts.next().and_then(Self::from_tag) else { continue };
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((locale, q));
+            }
+        }
+        best.map_or(Self::DEFAULT, |(locale, _)| locale)
+    }
+
+    pub fn strings(self) -> &'static Strings {
+        match self {
+            Locale::Uk => &UK,
+            Locale::En => &EN,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "invite.html")]
+struct InviteTemplate<'a> {
+    strings: &'static Strings,
+    title: String,
+    /// Canonical query, re-encoded from the parsed invite
+    query: String,
+    public_url: Option<&'a str>,
+    play_store_url: &'a str,
+    app_store_url: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "invalid_invite.html")]
+struct InvalidInviteTemplate {
+    strings: &'static Strings,
+    title: &'static str,
+}
+
+/// Rendered page and its HTTP status
+pub struct Page {
+    pub status: u16,
+    pub html: String,
+}
+
+/// `/add` landing page for the invite in `query`
+pub fn invite_page(config: &WebConfig, query: &str, accept_language: Option<&str>) -> Page {
+    let strings = Locale::from_accept_language(accept_language).strings();
+    // Unsigned invites still open the app, only without a name
+    let invite = Invite::from_query(query).and_then(|invite| match invite.verify() {
+        Ok(()) => Ok((invite, true)),
+        Err(InviteError::Unsigned) => Ok((invite, false)),
+        Err(e) => Err(e),
+    });
+    let rendered = match invite {
+        Ok((invite, signed)) => {
+            let title = match invite.name.as_deref().filter(|_| signed) {
+                Some(name) => strings.invite_title.replace("{name}", name),
+                None => strings.invite_title_unnamed.to_string(),
+            };
+            let page = InviteTemplate {
+                strings,
+                title,
+                query: invite.to_query(),
+                public_url: config.public_url.as_deref(),
+                play_store_url: &config.play_store_url,
+                app_store_url: config.app_store_url.as_deref(),
+            };
+            page.render().map(|html| Page { status: 200, html })
+        }
+        Err(_) => InvalidInviteTemplate { strings, title: strings.invalid_title }
+            .render()
+            .map(|html| Page { status: 400, html }),
+    };
+    rendered.unwrap_or_else(|e| Page { status: 500, html: format!("Template error: {}", e) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    const HOSTILE: &str = r#""><script>alert(1)</script><img src=x onerror='alert(2)'>"#;
+
+    fn config() -> WebConfig {
+        WebConfig {
+            public_url: Some("https://relay.example".to_string()),
+            app_store_url: Some("https://apps.apple.com/app/id1".to_string()),
+            ..WebConfig::default()
+        }
+    }
+
+    fn signed(name: &str) -> String {
+        Invite::sign(&SigningKey::from_bytes(&[3u8; 32]), Some(&[4u8; 32]), Some(name)).unwrap().to_query()
+    }
+
+    /// Raw markup from the name must not appear anywhere in the page
+    fn assert_escaped(html: &str) {
+        assert!(!html.contains("<script>alert"), "{}", html);
+        assert!(!html.contains("<img"), "{}", html);
+        assert!(!html.contains("\"><"), "{}", html);
+        assert!(!html.contains("onerror='"), "{}", html);
+        assert!(html.matches("<script>").count() <= 1);
+    }
+
+    #[test]
+    fn hostile_signed_name_is_escaped_everywhere() {
+        let page = invite_page(&config(), &signed(HOSTILE), None);
+        assert_eq!(page.status, 200);
+        assert_escaped(&page.html);
+        assert!(page.html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"), "{}", page.html);
+        assert!(page.html.contains("запрошує вас"));
+        assert!(page.html.contains(r#"<meta property="og:url" content="https://relay.example/add?id="#));
+        assert!(page.html.contains("data-app-store=\"https://apps.apple.com"));
+    }
+
+    #[test]
+    fn unsigned_names_are_not_shown() {
+        let id = hex::encode(SigningKey::from_bytes(&[3u8; 32]).verifying_key().as_bytes());
+        let query = format!("id={}&name={}", id, urlencoding::encode(HOSTILE));
+        let page = invite_page(&WebConfig::default(), &query, Some("en-US,en;q=0.9"));
+        assert_eq!(page.status, 200);
+        assert_escaped(&page.html);
+        assert!(page.html.contains("<h1>You are invited to Ya OK!</h1>"));
+        assert!(!page.html.contains("og:url"));
+        assert!(!page.html.contains("App Store"));
+    }
+
+    #[test]
+    fn forged_and_malformed_invites_get_an_error_page() {
+        let forged = signed("Alice").replace("name=Alice", "name=Bank");
+        for query in [forged.as_str(), "", "id=zz", "name=%3Cscript%3E"] {
+            let page = invite_page(&config(), query, Some("en"));
+            assert_eq!(page.status, 400, "{}", query);
+            assert!(page.html.contains("Invalid invite"));
+            assert!(!page.html.contains("yaok://"));
+        }
+        // A raw payload that is not even a valid invite is never echoed
+        let page = invite_page(&config(), &format!("id=%22%3E{}", HOSTILE), None);
+        assert_eq!(page.status, 400);
+        assert_escaped(&page.html);
+    }
+
+    #[test]
+    fn locale_follows_accept_language() {
+        assert_eq!(Locale::from_accept_language(None), Locale::Uk);
+        assert_eq!(Locale::from_accept_language(Some("de-DE,fr;q=0.8")), Locale::Uk);
+        assert_eq!(Locale::from_accept_language(Some("en-GB")), Locale::En);
+        assert_eq!(Locale::from_accept_language(Some("en;q=0.5, uk-UA;q=0.8")), Locale::Uk);
+        assert_eq!(Locale::from_accept_language(Some("uk;q=0, EN")), Locale::En);
+        assert_eq!(Locale::from_accept_language(Some("de, en;q=0.3, uk;q=0.3")), Locale::En);
+    }
+}
@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion, BenchmarkId};
-use ya_ok_core::core::{Crypto, SymmetricKey};
+use ya_ok_core::core::{Crypto, Identity, Message, Packet, PacketOptions, StatusType, SymmetricKey};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 fn benchmark_keypair_generation(c: &mut Criterion) {
@@ -75,13 +75,54 @@ fn benchmark_e2e_encryption(c: &mut Criterion) {
     });
 }
 
+fn benchmark_packet_padding(c: &mut Criterion) {
+    let sender = Identity::new();
+    let receiver = Identity::new();
+    let receiver_key = receiver.x25519_public_bytes().unwrap();
+
+    let messages = [
+        ("status", Message::status(sender.id.clone(), StatusType::Ok)),
+        ("text", Message::text(sender.id.clone(), "Все добре, чекаю".to_string()).unwrap()),
+        ("voice", Message::voice(sender.id.clone(), vec![0u8; 5_000]).unwrap()),
+    ];
+
+    let mut group = c.benchmark_group("packet_padding");
+
+    for (name, message) in messages.iter() {
+        for (label, options) in [
+            ("unpadded", PacketOptions::default()),
+            ("padded", PacketOptions { pad_to_buckets: true, hide_priority: true }),
+        ] {
+            // Размер шифротекста в ID - стоимость дополнения в байтах
+            let size = Packet::from_message_with_options(message, &sender, &receiver_key, options)
+                .unwrap()
+                .encrypted_payload
+                .ciphertext
+                .len();
+
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}/{}", name, label), format!("{}B", size)),
+                message,
+                |b, message| {
+                    b.iter(|| {
+                        Packet::from_message_with_options(black_box(message), &sender, &receiver_key, options)
+                    });
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
 criterion_group!(
     benches,
     benchmark_keypair_generation,
     benchmark_shared_secret,
     benchmark_symmetric_encryption,
     benchmark_symmetric_decryption,
-    benchmark_e2e_encryption
+    benchmark_e2e_encryption,
+    benchmark_packet_padding
 );
 
 criterion_main!(benches);
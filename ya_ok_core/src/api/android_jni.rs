@@ -192,6 +192,25 @@ pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_stopListening(
     ya_ok_stop_listening() as jint
 }
 
+#[no_mangle]
+pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_applyRelayList(
+    mut env: JNIEnv,
+    _class: JClass,
+    signed_json: JString,
+) -> jint {
+    let json: String = match env.get_string(&signed_json) {
+        Ok(s) => s.into(),
+        Err(_) => return -8,
+    };
+
+    let c_json = match CString::new(json) {
+        Ok(s) => s,
+        Err(_) => return -8,
+    };
+
+    unsafe { ya_ok_apply_relay_list(c_json.as_ptr()) as jint }
+}
+
 #[no_mangle]
 pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_setPolicy(
     _env: JNIEnv,
@@ -270,6 +289,43 @@ pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_getIdentityX25519PublicKe
     java_str.into_raw()
 }
 
+#[no_mangle]
+pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_createInvite(
+    mut env: JNIEnv,
+    _class: JClass,
+    name: JString,
+) -> jstring {
+    let c_name = if name.is_null() {
+        None
+    } else {
+        let name: String = match env.get_string(&name) {
+            Ok(s) => s.into(),
+            Err(_) => return std::ptr::null_mut(),
+        };
+        match CString::new(name) {
+            Ok(s) => Some(s),
+            Err(_) => return std::ptr::null_mut(),
+        }
+    };
+
+    let ptr = unsafe { ya_ok_create_invite(c_name.as_ref().map_or(std::ptr::null(), |s| s.as_ptr())) };
+    if ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let c_str = unsafe { CStr::from_ptr(ptr) };
+    let java_str = match env.new_string(c_str.to_string_lossy().as_ref()) {
+        Ok(s) => s,
+        Err(_) => {
+            ya_ok_free_string(ptr);
+            return std::ptr::null_mut();
+        }
+    };
+
+    ya_ok_free_string(ptr);
+    java_str.into_raw()
+}
+
 #[no_mangle]
 pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_addPeer(
     mut env: JNIEnv,
@@ -477,6 +533,32 @@ pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_exportPendingPackets(
     java_str.into_raw()
 }
 
+#[no_mangle]
+pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_exportPendingFrames(
+    env: JNIEnv,
+    _class: JClass,
+    limit: jint,
+    transport_type: jint,
+    mtu: jint,
+) -> jstring {
+    let ptr = ya_ok_export_pending_frames(limit as i32, transport_type as i32, mtu as i32);
+    if ptr.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let c_str = unsafe { CStr::from_ptr(ptr) };
+    let java_str = match env.new_string(c_str.to_string_lossy().as_ref()) {
+        Ok(s) => s,
+        Err(_) => {
+            ya_ok_free_string(ptr);
+            return std::ptr::null_mut();
+        }
+    };
+
+    ya_ok_free_string(ptr);
+    java_str.into_raw()
+}
+
 #[no_mangle]
 pub extern "system" fn Java_app_poruch_ya_1ok_YaOkCore_importMessages(
     mut env: JNIEnv,
@@ -0,0 +1,162 @@
+//! Tests for the export/import path used by the platform transports
+
+#[cfg(test)]
+mod tests {
+    use crate::api::{export_pending_frames, get_runtime, handle_incoming_packet_internal, CoreState};
+    use crate::core::{Identity, Message};
+    use crate::transport::ble::BLE_MTU;
+    use crate::transport::fragmentation::{is_fragment, is_nack};
+    use crate::transport::{Peer, TransportType};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("yaok-api-{}", rand::random::<u64>()))
+    }
+
+    fn node(dir: &PathBuf) -> Arc<CoreState> {
+        let state = Arc::new(CoreState::new_with_base(dir).unwrap());
+        *state.identity.try_write().unwrap() = Some(Identity::new());
+        state
+    }
+
+    fn identity(state: &CoreState) -> Identity {
+        state.identity.try_read().unwrap().clone().unwrap()
+    }
+
+    /// Добавить `other` в известные пиры `state` (как `ya_ok_add_peer`)
+    fn introduce(state: &CoreState, other: &CoreState) {
+        let other = identity(other);
+        let peer = Peer {
+            id: other.id.clone(),
+            transport_type: TransportType::Ble,
+            address: String::new(),
+            last_seen: chrono::Utc::now(),
+            signal_strength: None,
+            ed25519_public_key: Some(other.public_key_bytes().to_vec()),
+            x25519_public_key: Some(other.x25519_public_bytes().unwrap().to_vec()),
+        };
+        get_runtime().unwrap().block_on(state.router.update_peers(vec![peer]));
+    }
+
+    fn voice_from(state: &CoreState) -> Message {
+        let message = Message::voice(identity(state).id, vec![0x5a; 3_000]).unwrap();
+        state.storage.lock().unwrap().store_message(&message).unwrap();
+        message
+    }
+
+    #[test]
+    fn test_voice_exported_as_ble_frames() {
+        let (sender_dir, receiver_dir) = (temp_dir(), temp_dir());
+        let sender = node(&sender_dir);
+        let receiver = node(&receiver_dir);
+        introduce(&sender, &receiver);
+        let message = voice_from(&sender);
+
+        let frames = export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).unwrap();
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|frame| frame.len() <= BLE_MTU && is_fragment(frame)));
+
+        let peer = || Some((TransportType::Ble, "ble-sender".to_string()));
+        let (last, rest) = frames.split_last().unwrap();
+        for frame in rest {
+            assert_eq!(handle_incoming_packet_internal(&receiver, frame, peer()), 1);
+        }
+        assert_eq!(handle_incoming_packet_internal(&receiver, last, peer()), 0);
+
+        assert!(receiver.storage.lock().unwrap().is_message_seen(&message.id).unwrap());
+
+        let _ = std::fs::remove_dir_all(sender_dir);
+        let _ = std::fs::remove_dir_all(receiver_dir);
+    }
+
+    #[test]
+    fn test_no_packets_while_policy_changes() {
+        let (sender_dir, receiver_dir) = (temp_dir(), temp_dir());
+        let sender = node(&sender_dir);
+        let receiver = node(&receiver_dir);
+        introduce(&sender, &receiver);
+        voice_from(&sender);
+
+        // Пока политика меняется, пакеты не создаются (а не уходят без дополнения)
+        let changing = sender.policy_manager.try_write().unwrap();
+        assert!(export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).is_none());
+        drop(changing);
+        assert!(!export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).unwrap().is_empty());
+
+        let _ = std::fs::remove_dir_all(sender_dir);
+        let _ = std::fs::remove_dir_all(receiver_dir);
+    }
+
+    #[test]
+    fn test_voice_completes_across_encounters_and_restart() {
+        let (sender_dir, receiver_dir) = (temp_dir(), temp_dir());
+        let sender = node(&sender_dir);
+        let receiver = node(&receiver_dir);
+        introduce(&sender, &receiver);
+        let receiver_id = identity(&receiver);
+        let message = voice_from(&sender);
+        let peer = || Some((TransportType::Ble, "ble-sender".to_string()));
+
+        // Первая встреча обрывается на середине
+        let frames = export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).unwrap();
+        let half = frames.len() / 2;
+        for frame in &frames[..half] {
+            assert_eq!(handle_incoming_packet_internal(&receiver, frame, peer()), 1);
+        }
+
+        // Получатель перезапускается (как после ya_ok_stop_listening)
+        receiver.transport_manager.save_partial_fragments(&receiver.fragments_path).unwrap();
+        drop(receiver);
+        let receiver = Arc::new(CoreState::new_with_base(&receiver_dir).unwrap());
+        *receiver.identity.try_write().unwrap() = Some(receiver_id);
+
+        // Следующая выгрузка дает те же кадры: недостающей половины хватает
+        let again = export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).unwrap();
+        assert_eq!(again, frames);
+        let (last, rest) = again[half..].split_last().unwrap();
+        for frame in rest {
+            assert_eq!(handle_incoming_packet_internal(&receiver, frame, peer()), 1);
+        }
+        assert_eq!(handle_incoming_packet_internal(&receiver, last, peer()), 0);
+        assert!(receiver.storage.lock().unwrap().is_message_seen(&message.id).unwrap());
+
+        let _ = std::fs::remove_dir_all(sender_dir);
+        let _ = std::fs::remove_dir_all(receiver_dir);
+    }
+
+    #[test]
+    fn test_nack_answered_with_next_frame_export() {
+        let (sender_dir, receiver_dir) = (temp_dir(), temp_dir());
+        let sender = node(&sender_dir);
+        let receiver = node(&receiver_dir);
+        introduce(&sender, &receiver);
+        voice_from(&sender);
+
+        let frames = export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).unwrap();
+        let missing = frames[1].clone();
+        for (index, frame) in frames.iter().enumerate() {
+            if index != 1 {
+                let from_sender = Some((TransportType::Ble, "ble-sender".to_string()));
+                assert_eq!(handle_incoming_packet_internal(&receiver, frame, from_sender), 1);
+            }
+        }
+
+        // Выгрузка получателя несет NACK на недостающий фрагмент
+        let nacks = export_pending_frames(&receiver, 50, &TransportType::Ble, BLE_MTU).unwrap();
+        assert_eq!(nacks.len(), 1);
+        assert!(is_nack(&nacks[0]));
+        let from_receiver = Some((TransportType::Ble, "ble-receiver".to_string()));
+        assert_eq!(handle_incoming_packet_internal(&sender, &nacks[0], from_receiver), 2);
+
+        // Отправитель досылает его первым кадром следующей выгрузки
+        let resent = export_pending_frames(&sender, 50, &TransportType::Ble, BLE_MTU).unwrap();
+        assert_eq!(resent[0], missing);
+        assert_eq!(resent.len(), frames.len() + 1);
+        let from_sender = Some((TransportType::Ble, "ble-sender".to_string()));
+        assert_eq!(handle_incoming_packet_internal(&receiver, &resent[0], from_sender), 0);
+
+        let _ = std::fs::remove_dir_all(sender_dir);
+        let _ = std::fs::remove_dir_all(receiver_dir);
+    }
+}
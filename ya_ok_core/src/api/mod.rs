@@ -4,9 +4,11 @@
 //! - Kotlin (Android)
 //! - Swift (iOS)
 
-use crate::core::{Identity, Message, StatusType, MessageType, MessagePayload, load_identity, save_identity, Packet};
+use crate::core::{Identity, Message, StatusType, MessageType, MessagePayload, load_identity, save_identity, Packet, PacketOptions};
 use crate::storage::Storage;
-use crate::transport::{TransportManager, TransportType, Peer};
+use crate::transport::{Transport, TransportError, TransportManager, TransportType, Peer, IncomingFrame};
+use crate::transport::relay_pool::{RelayEndpoint, RelayListError, RelayPool, RelayPoolStats, RelaySlot, SignedRelayList};
+use crate::transport::chunking::ChunkingMode;
 use crate::routing::{DtnRouter, Router};
 use crate::policy::{PolicyManager, Policy};
 use crate::sync::{Gossip, GossipProtocol};
@@ -23,6 +25,13 @@ use std::fs;
 
 const IDENTITY_FILENAME: &str = "ya_ok_identity.json";
 const DB_FILENAME: &str = "ya_ok.db";
+const FRAGMENTS_FILENAME: &str = "ya_ok_fragments.cbor";
+
+/// Размер кадра UDP по умолчанию (байты до base64)
+const UDP_FRAME_MTU: usize = 1024;
+
+/// Сколько запрошенных NACK фрагментов ждёт выгрузки для одного транспорта
+const MAX_QUEUED_RETRANSMIT_FRAMES: usize = 256;
 
 // Error codes for FFI
 const ERR_OK: c_int = 0;
@@ -39,10 +48,15 @@ const ERR_SERIALIZE_ERROR: c_int = -6;
 const ERR_NULL_POINTER: c_int = -7;
 const ERR_UTF8_ERROR: c_int = -8;
 const ERR_RUNTIME_UNAVAILABLE: c_int = -9;
+const ERR_SIGNATURE_INVALID: c_int = -11;
+const ERR_STALE_LIST: c_int = -12;
 
 #[cfg(target_os = "android")]
 mod android_jni;
 
+#[cfg(test)]
+mod api_tests;
+
 /// --- Peer-store FFI: add/list/remove -------------------------------------
 #[no_mangle]
 pub extern "C" fn ya_ok_peer_store_add(public_key_hex: *const c_char, meta: *const c_char) -> c_int {
@@ -173,16 +187,29 @@ fn get_runtime() -> Result<&'static Runtime, ApiError> {
 pub struct CoreState {
     identity: Arc<RwLock<Option<Identity>>>,
     storage: Arc<Mutex<Storage>>,  // Wrapped in Mutex for thread safety (rusqlite::Connection is not Sync)
-    #[allow(dead_code)] // Will be used when transport layer is fully integrated
+    /// Транспортный слой (сборка входящих фрагментов)
     transport_manager: TransportManager,
     router: DtnRouter,
     policy_manager: RwLock<PolicyManager>,
     gossip: Gossip,
     identity_path: PathBuf,
+    /// Файл незавершённых сборок фрагментов
+    fragments_path: PathBuf,
     /// Кэш identity известных пиров (по sender_id)
     peer_identities: RwLock<std::collections::HashMap<String, Identity>>,
+    /// Пул relay (создается при первом использовании, нужна identity);
+    /// через него же отправляет маршрутизатор
+    relay: RelaySlot,
+    /// Сериализованные пакеты ожидающих сообщений по (message id, X25519 ключ
+    /// получателя): повторная выгрузка даёт те же байты и те же фрагменты
+    outbox: Mutex<std::collections::HashMap<OutboxKey, Vec<u8>>>,
+    /// Фрагменты, запрошенные NACK, до следующей выгрузки кадров транспорта
+    retransmit_frames: Mutex<std::collections::HashMap<TransportType, Vec<Vec<u8>>>>,
 }
 
+/// Ключ кэша исходящих пакетов: message id и X25519 ключ получателя
+type OutboxKey = (String, Vec<u8>);
+
 impl CoreState {
     fn new() -> Result<Self, ApiError> {
         Self::new_with_paths(resolve_paths(None))
@@ -196,7 +223,13 @@ impl CoreState {
     fn new_with_paths(paths: CorePaths) -> Result<Self, ApiError> {
         let storage = Arc::new(Mutex::new(Storage::new(&paths.storage_db)?));
         let transport_manager = TransportManager::new();
-        let router = DtnRouter::new(storage.clone(), TransportManager::new());
+        // Досборка фрагментов, начатая при прошлой встрече
+        let _ = transport_manager.load_partial_fragments(&paths.fragments_file);
+        // Исходящие пакеты маршрутизатор отправляет через пул relay
+        let relay = RelaySlot::new();
+        let mut router_transports = TransportManager::new();
+        router_transports.add_transport(Box::new(relay.clone()));
+        let router = DtnRouter::new(storage.clone(), router_transports);
         let identity = load_identity(&paths.identity_file).ok().flatten();
         let identity = Arc::new(RwLock::new(identity));
         let gossip = Gossip::new(storage.clone(), TransportManager::new(), identity.clone());
@@ -209,7 +242,11 @@ impl CoreState {
             policy_manager: RwLock::new(PolicyManager::new()),
             gossip,
             identity_path: paths.identity_file,
+            fragments_path: paths.fragments_file,
             peer_identities: RwLock::new(std::collections::HashMap::new()),
+            relay,
+            outbox: Mutex::new(std::collections::HashMap::new()),
+            retransmit_frames: Mutex::new(std::collections::HashMap::new()),
         })
     }
 }
@@ -217,6 +254,7 @@ impl CoreState {
 struct CorePaths {
     storage_db: PathBuf,
     identity_file: PathBuf,
+    fragments_file: PathBuf,
 }
 
 fn resolve_paths(base_dir: Option<&Path>) -> CorePaths {
@@ -224,10 +262,12 @@ fn resolve_paths(base_dir: Option<&Path>) -> CorePaths {
         Some(dir) => CorePaths {
             storage_db: dir.join(DB_FILENAME),
             identity_file: dir.join(IDENTITY_FILENAME),
+            fragments_file: dir.join(FRAGMENTS_FILENAME),
         },
         None => CorePaths {
             storage_db: PathBuf::from(DB_FILENAME),
             identity_file: PathBuf::from(IDENTITY_FILENAME),
+            fragments_file: PathBuf::from(FRAGMENTS_FILENAME),
         },
     }
 }
@@ -289,6 +329,44 @@ pub extern "C" fn ya_ok_get_identity_x25519_public_key_hex() -> *mut c_char {
     c_string.into_raw()
 }
 
+/// Подписанное приглашение текущей идентичности: query для `yaok://add?...`
+/// и `https://<relay>/add?...` (`id`, `x`, `name`, `sig`).
+/// NULL при ошибке (нет идентичности или недопустимое имя).
+///
+/// # Safety
+/// `name` - NULL или NUL-терминированная строка, действительная на время вызова.
+#[no_mangle]
+pub unsafe extern "C" fn ya_ok_create_invite(name: *const c_char) -> *mut c_char {
+    let state = match get_core_state() {
+        Ok(state) => state,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let name = if name.is_null() {
+        None
+    } else {
+        match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(s) => Some(s.trim()).filter(|s| !s.is_empty()),
+            Err(_) => return std::ptr::null_mut(),
+        }
+    };
+
+    let identity_lock = state.identity.try_read().unwrap();
+    let invite = match &*identity_lock {
+        Some(identity) => match identity.invite(name) {
+            Ok(invite) => invite,
+            Err(e) => {
+                println!("❌ ya_ok_create_invite: {}", e);
+                return std::ptr::null_mut();
+            }
+        },
+        None => return std::ptr::null_mut(),
+    };
+
+    let c_string = CString::new(invite.to_query()).unwrap_or_else(|_| CString::new("").unwrap());
+    c_string.into_raw()
+}
+
 /// Добавить (зарегистрировать) известного пира по его ID (hex ed25519 pubkey) и X25519 pubkey (hex).
 ///
 /// Это нужно для первичного обмена ключами (например через QR) до того, как пир пришлёт первый пакет.
@@ -429,7 +507,7 @@ fn get_core_state() -> Result<&'static Arc<CoreState>, ApiError> {
 pub extern "C" fn ya_ok_wipe_local_data() -> c_int {
     // Сначала вычисляем пути, потом сбрасываем состояние (чтобы закрыть файлы),
     // и только затем удаляем файлы с диска.
-    let (identity_path, db_path) = {
+    let (identity_path, db_path, fragments_path) = {
         let state = match get_core_state() {
             Ok(state) => state,
             Err(_) => return -1,
@@ -439,7 +517,7 @@ pub extern "C" fn ya_ok_wipe_local_data() -> c_int {
             .parent()
             .map(Path::to_path_buf)
             .unwrap_or_else(|| PathBuf::from("."));
-        (state.identity_path.clone(), base.join(DB_FILENAME))
+        (state.identity_path.clone(), base.join(DB_FILENAME), state.fragments_path.clone())
     };
 
     // Note: CORE_STATE (OnceLock) cannot be reset. Application should restart after wipe.
@@ -448,6 +526,7 @@ pub extern "C" fn ya_ok_wipe_local_data() -> c_int {
     // Best-effort deletes
     let _ = fs::remove_file(&identity_path);
     let _ = fs::remove_file(&db_path);
+    let _ = fs::remove_file(&fragments_path);
     let _ = fs::remove_file(PathBuf::from(format!("{}-wal", db_path.to_string_lossy())));
     let _ = fs::remove_file(PathBuf::from(format!("{}-shm", db_path.to_string_lossy())));
 
@@ -507,6 +586,16 @@ pub extern "C" fn ya_ok_free_string(s: *mut c_char) {
     }
 }
 
+/// Параметры создания пакетов по текущей политике (дополнение, скрытый приоритет).
+/// Если политика сейчас меняется, пакет не создаётся: без неё он ушёл бы
+/// без дополнения и с настоящим приоритетом.
+fn packet_options(state: &CoreState) -> Result<PacketOptions, ApiError> {
+    state.policy_manager
+        .try_read()
+        .map(|policy| policy.get_policy().packet_options())
+        .map_err(|_| ApiError::PolicyUnavailable)
+}
+
 /// Вспомогательная функция для создания и отправки Packet
 fn create_and_send_packet(
     state: &Arc<CoreState>,
@@ -517,6 +606,8 @@ fn create_and_send_packet(
 
     // Сохраняем сообщение
     state.storage.lock().unwrap().store_message(&message)?;
+    // Маршрутизатор отправляет через пул relay - создаём его, если ещё нет
+    let _ = relay_pool(state);
     
     // Получаем список известных пиров через router
     let router = &state.router;
@@ -534,6 +625,7 @@ fn create_and_send_packet(
     }
     
     // Для каждого пира с X25519 ключом создаем отдельный Packet
+    let options = packet_options(state)?;
     let mut packets_created = 0;
     for (peer_id, peer) in known_peers.iter() {
         if let Some(x25519_key_bytes) = &peer.x25519_public_key {
@@ -541,7 +633,7 @@ fn create_and_send_packet(
                 let mut receiver_key = [0u8; 32];
                 receiver_key.copy_from_slice(x25519_key_bytes);
                 
-                if let Ok(packet) = Packet::from_message(&message, identity, &receiver_key) {
+                if let Ok(packet) = Packet::from_message_with_options(&message, identity, &receiver_key, options) {
                     // Отправляем пакет конкретному пиру
                     let _ = handle.block_on(async {
                         router.send_to(&packet, peer_id).await
@@ -558,7 +650,7 @@ fn create_and_send_packet(
         let sender_x25519_public = identity.x25519_public_bytes()
             .ok_or(ApiError::InvalidParameters)?;
         
-        if let Ok(packet) = Packet::from_message(&message, identity, &sender_x25519_public) {
+        if let Ok(packet) = Packet::from_message_with_options(&message, identity, &sender_x25519_public, options) {
             let _ = handle.block_on(async {
                 router.flood_packet(packet).await
             });
@@ -837,6 +929,8 @@ fn create_and_send_packet_to(
 
     // Сохраняем сообщение
     state.storage.lock().unwrap().store_message(&message)?;
+    // Маршрутизатор отправляет через пул relay - создаём его, если ещё нет
+    let _ = relay_pool(state);
     
     // Получаем информацию о конкретном пире
     let router = &state.router;
@@ -858,7 +952,7 @@ fn create_and_send_packet_to(
                 let mut receiver_key = [0u8; 32];
                 receiver_key.copy_from_slice(x25519_key_bytes);
                 
-                if let Ok(packet) = Packet::from_message(&message, identity, &receiver_key) {
+                if let Ok(packet) = Packet::from_message_with_options(&message, identity, &receiver_key, packet_options(state)?) {
                     println!("✅ Created encrypted packet, sending...");
                     let _ = handle.block_on(async {
                         router.send_to(&packet, recipient_id).await
@@ -889,6 +983,31 @@ fn handle_incoming_packet_internal(
     peer_info: Option<(TransportType, String)>,
 ) -> c_int {
     println!("📥 handle_incoming_packet_internal: bytes={}, peer_info={:?}", bytes.len(), peer_info);
+
+    // Собираем фрагменты (BLE и другие транспорты с малым MTU)
+    let sender_address = peer_info.as_ref().map(|(_, address)| address.as_str()).unwrap_or("");
+    let reassembled = match state.transport_manager.handle_incoming_frame(sender_address, bytes) {
+        Ok(IncomingFrame::Packet(packet_bytes)) => packet_bytes,
+        Ok(IncomingFrame::Pending) => return 1, // FRAGMENT_BUFFERED
+        Ok(IncomingFrame::Retransmit(frames)) => {
+            // NACK от получателя: недостающие фрагменты уходят со следующей
+            // выгрузкой кадров того же транспорта (ya_ok_export_pending_frames)
+            if let Some((transport_type, _)) = &peer_info {
+                let mut queued = state.retransmit_frames.lock().unwrap();
+                let queue = queued.entry(transport_type.clone()).or_default();
+                queue.extend(frames);
+                let excess = queue.len().saturating_sub(MAX_QUEUED_RETRANSMIT_FRAMES);
+                queue.drain(..excess);
+            }
+            return 2; // NACK_HANDLED
+        }
+        Err(e) => {
+            println!("❌ Fragment reassembly error: {:?}", e);
+            return -9; // DESERIALIZATION_ERROR
+        }
+    };
+    let bytes = &reassembled[..];
+
     // Десериализуем Packet из CBOR
     let packet = match Packet::from_bytes(bytes) {
         Ok(p) => {
@@ -1001,7 +1120,8 @@ fn handle_incoming_packet_internal(
     }
 }
 
-/// Обработать входящий пакет (CBOR байты)
+/// Обработать входящий пакет (CBOR байты) или фрагмент пакета.
+/// Возвращает 1, если фрагмент принят и пакет ещё не собран.
 #[no_mangle]
 pub extern "C" fn ya_ok_handle_incoming_packet(packet_bytes: *const u8, len: c_int) -> c_int {
     let state = match get_core_state() {
@@ -1017,9 +1137,14 @@ pub extern "C" fn ya_ok_handle_incoming_packet(packet_bytes: *const u8, len: c_i
     handle_incoming_packet_internal(&state, bytes, None)
 }
 
-/// Обработать входящий пакет с информацией о пиру
+/// Обработать входящий пакет (или фрагмент) с информацией о пиру.
+/// Фрагменты собираются отдельно для каждого адреса отправителя.
+///
+/// # Safety
+/// `packet_bytes` указывает на `len` байт, `address` - NUL-терминированная строка
+/// (оба указателя действительны на время вызова).
 #[no_mangle]
-pub extern "C" fn ya_ok_handle_incoming_packet_with_peer(
+pub unsafe extern "C" fn ya_ok_handle_incoming_packet_with_peer(
     packet_bytes: *const u8,
     len: c_int,
     transport_type: c_int,
@@ -1050,30 +1175,211 @@ pub extern "C" fn ya_ok_handle_incoming_packet_with_peer(
     handle_incoming_packet_internal(&state, bytes, Some((transport, addr_str)))
 }
 
+/// Получить кадры-NACK для незавершённых сборок от пира (base64, через запятую).
+/// Платформа отправляет их пиру при встрече, чтобы он дослал только недостающие фрагменты.
+///
+/// # Safety
+/// `address` - NUL-терминированная строка, действительная на время вызова.
+#[no_mangle]
+pub unsafe extern "C" fn ya_ok_get_fragment_nacks(address: *const c_char) -> *mut c_char {
+    let state = match get_core_state() {
+        Ok(state) => state,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let addr = unsafe {
+        if address.is_null() {
+            return std::ptr::null_mut();
+        }
+        CStr::from_ptr(address)
+    };
+    let addr_str = match addr.to_str() {
+        Ok(s) => s,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let nacks_base64 = state.transport_manager
+        .pending_nacks(addr_str)
+        .iter()
+        .map(|frame| BASE64.encode(frame))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let c_string = CString::new(nacks_base64).unwrap_or_else(|_| CString::new("").unwrap());
+    c_string.into_raw()
+}
+
 /// Начать прослушивание входящих сообщений
 #[no_mangle]
 pub extern "C" fn ya_ok_start_listening() -> c_int {
-    let _state = match get_core_state() {
+    let state = match get_core_state() {
         Ok(state) => state,
         Err(_) => return -1,
     };
 
-    // Фоновые задачи для прослушивания запускаются на стороне Android/iOS
-    // Rust core предоставляет функцию ya_ok_handle_incoming_packet для обработки входящих пакетов
-    // TODO: в будущем можно добавить встроенное прослушивание через transport_manager
-
-    0 // SUCCESS
+    // BLE/Wi-Fi Direct слушает платформа и передает пакеты в
+    // ya_ok_handle_incoming_packet; соединение с relay держит ядро
+    let runtime = match get_runtime() {
+        Ok(rt) => rt,
+        Err(_) => return ERR_RUNTIME_UNAVAILABLE,
+    };
+    let relay = match relay_pool(state) {
+        Ok(relay) => relay,
+        Err(code) => return code,
+    };
+
+    // Обработка пакета блокирует поток (block_on), поэтому не в задаче сессии
+    let handle = runtime.handle().clone();
+    let callback_state = state.clone();
+    let result = runtime.block_on(relay.start_listening(Box::new(move |packet| {
+        let Ok(bytes) = packet.to_bytes() else { return };
+        let state = callback_state.clone();
+        let peer_info = Some((TransportType::Udp, packet.sender_id.clone()));
+        handle.spawn_blocking(move || handle_incoming_packet_internal(&state, &bytes, peer_info));
+    })));
+
+    match result {
+        Ok(()) | Err(TransportError::AlreadyListening) => {
+            let _guard = runtime.enter();
+            relay.start_probing();
+            ERR_OK
+        }
+        Err(_) => ERR_INTERNAL_ERROR,
+    }
 }
 
 /// Остановить прослушивание
 #[no_mangle]
 pub extern "C" fn ya_ok_stop_listening() -> c_int {
-    let _state = match get_core_state() {
+    let state = match get_core_state() {
+        Ok(state) => state,
+        Err(_) => return -1,
+    };
+
+    let relay = state.relay.get();
+    if let (Some(relay), Ok(runtime)) = (relay, get_runtime()) {
+        relay.stop_probing();
+        let _ = runtime.block_on(relay.stop_listening());
+    }
+
+    // Сохраняем незавершённые сборки, чтобы дособрать их при следующей встрече
+    let _ = state.transport_manager.save_partial_fragments(&state.fragments_path);
+
+    0 // SUCCESS
+}
+
+/// Пул relay ядра; создается с relay по умолчанию
+fn relay_pool(state: &CoreState) -> Result<Arc<RelayPool>, c_int> {
+    state.relay.get_or_try_insert(|| {
+        let identity = match state.identity.try_read() {
+            Ok(lock) => match &*lock {
+                Some(identity) => identity.clone(),
+                None => return Err(-2), // NO_IDENTITY
+            },
+            Err(_) => return Err(ERR_INTERNAL_ERROR),
+        };
+        Ok(RelayPool::with_default_relay(identity))
+    })
+}
+
+/// Задать список relay: JSON-массив
+/// `[{"url": "host:port", "pin": "...", "backup_pins": [...], "link": "stream"|"datagram"}]`
+///
+/// # Safety
+/// `relays_json` - NUL-терминированная строка, действительная на время вызова.
+#[no_mangle]
+pub unsafe extern "C" fn ya_ok_set_relays(relays_json: *const c_char) -> c_int {
+    let state = match get_core_state() {
+        Ok(state) => state,
+        Err(_) => return -1,
+    };
+    if relays_json.is_null() {
+        return ERR_NULL_POINTER;
+    }
+    let json = match unsafe { CStr::from_ptr(relays_json) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ERR_UTF8_ERROR,
+    };
+    let relays: Vec<RelayEndpoint> = match serde_json::from_str(json) {
+        Ok(relays) => relays,
+        Err(_) => return ERR_SERIALIZE_ERROR,
+    };
+    if relays.is_empty() {
+        return ERR_INVALID_ARGUMENT;
+    }
+
+    let runtime = match get_runtime() {
+        Ok(rt) => rt,
+        Err(_) => return ERR_RUNTIME_UNAVAILABLE,
+    };
+    let relay = match relay_pool(state) {
+        Ok(relay) => relay,
+        Err(code) => return code,
+    };
+    match runtime.block_on(relay.set_relays(relays)) {
+        Ok(()) => ERR_OK,
+        Err(_) => ERR_INVALID_ARGUMENT,
+    }
+}
+
+/// Применить подписанный список relay (`{"payload": ..., "signature": ...}`).
+/// Подпись проверяется ключом издателя, заданным при сборке
+/// (`YAOK_RELAY_LIST_PUBLISHER`); без ключа списки не применяются
+/// (`ERR_SIGNATURE_INVALID`). Список с версией не новее текущей
+/// отклоняется (`ERR_STALE_LIST`).
+///
+/// # Safety
+/// `signed_json` - NUL-терминированная строка, действительная на время вызова.
+#[no_mangle]
+pub unsafe extern "C" fn ya_ok_apply_relay_list(signed_json: *const c_char) -> c_int {
+    let state = match get_core_state() {
+        Ok(state) => state,
+        Err(_) => return -1,
+    };
+    if signed_json.is_null() {
+        return ERR_NULL_POINTER;
+    }
+    let json = match unsafe { CStr::from_ptr(signed_json) }.to_str() {
+        Ok(s) => s,
+        Err(_) => return ERR_UTF8_ERROR,
+    };
+    let signed: SignedRelayList = match serde_json::from_str(json) {
+        Ok(signed) => signed,
+        Err(_) => return ERR_SERIALIZE_ERROR,
+    };
+
+    let runtime = match get_runtime() {
+        Ok(rt) => rt,
+        Err(_) => return ERR_RUNTIME_UNAVAILABLE,
+    };
+    let relay = match relay_pool(state) {
+        Ok(relay) => relay,
+        Err(code) => return code,
+    };
+    let now = chrono::Utc::now().timestamp().max(0) as u64;
+    match runtime.block_on(relay.apply_signed_list(&signed, now)) {
+        Ok(_) => ERR_OK,
+        Err(RelayListError::BadSignature) | Err(RelayListError::NoPublisher) => ERR_SIGNATURE_INVALID,
+        Err(RelayListError::Rollback { .. }) | Err(RelayListError::Expired) => ERR_STALE_LIST,
+        Err(_) => ERR_INVALID_ARGUMENT,
+    }
+}
+
+/// Включить FEC для транспорта: `overhead_percent` > 0 - доля избыточных
+/// фрагментов (%), 0 - обычная фрагментация с NACK
+#[no_mangle]
+pub extern "C" fn ya_ok_set_transport_fec(transport_type: c_int, overhead_percent: c_int) -> c_int {
+    let state = match get_core_state() {
         Ok(state) => state,
         Err(_) => return -1,
     };
 
-    // TODO: остановить фоновые задачи
+    let mode = match overhead_percent {
+        0 => ChunkingMode::Plain,
+        1..=1000 => ChunkingMode::Fec { overhead_percent: overhead_percent as u16 },
+        _ => return ERR_INVALID_ARGUMENT,
+    };
+    state.transport_manager.set_chunking_mode(parse_transport_type(transport_type), mode);
 
     0 // SUCCESS
 }
@@ -1113,10 +1419,13 @@ pub extern "C" fn ya_ok_get_stats() -> *mut c_char {
     let routing_stats = crate::routing::RoutingStats::default();
     let sync_stats = crate::sync::GossipStats::default();
 
+    let relay_stats = state.relay.get().map(|relay| relay.stats()).unwrap_or_default();
+
     let stats = CoreStats {
         storage: storage_stats,
         routing: routing_stats,
         sync: sync_stats,
+        relay: relay_stats,
     };
 
     let json = serde_json::to_string(&stats).unwrap_or_default();
@@ -1133,6 +1442,63 @@ pub extern "C" fn ya_ok_export_pending_packets(limit: c_int) -> *mut c_char {
         Err(_) => return std::ptr::null_mut(),
     };
 
+    let Some(packets) = pending_packets(state, limit) else {
+        return std::ptr::null_mut();
+    };
+    frames_to_c_string(&packets)
+}
+
+/// Экспортировать ожидающие отправки пакеты кадрами для транспорта
+/// `transport_type`: пакеты больше `mtu` (байты кадра до base64; 0 - по
+/// умолчанию для транспорта) режутся на фрагменты в режиме транспорта.
+/// Вместе с ними выгружаются фрагменты, запрошенные NACK через этот
+/// транспорт, и NACK для своих незавершённых сборок.
+/// Возвращает base64 кадров через запятую; каждый кадр отправляется
+/// отдельным сообщением.
+#[no_mangle]
+pub extern "C" fn ya_ok_export_pending_frames(limit: c_int, transport_type: c_int, mtu: c_int) -> *mut c_char {
+    let state = match get_core_state() {
+        Ok(state) => state,
+        Err(_) => return std::ptr::null_mut(),
+    };
+
+    let transport = parse_transport_type(transport_type);
+    let mtu = if mtu <= 0 { default_frame_mtu(&transport) } else { mtu as usize };
+    match export_pending_frames(state, limit, &transport, mtu) {
+        Some(frames) => frames_to_c_string(&frames),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Размер кадра по умолчанию: MTU транспорта; для UDP и Wi-Fi Direct
+/// base64 кадра помещается в датаграмму без IP-фрагментации
+fn default_frame_mtu(transport_type: &TransportType) -> usize {
+    match transport_type {
+        TransportType::Ble => crate::transport::ble::BLE_MTU,
+        TransportType::Satellite => crate::transport::satellite::SATELLITE_MTU,
+        TransportType::Udp | TransportType::WifiDirect => UDP_FRAME_MTU,
+    }
+}
+
+fn export_pending_frames(state: &Arc<CoreState>, limit: c_int, transport_type: &TransportType, mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let packets = pending_packets(state, limit)?;
+
+    // Сначала досылка по NACK и свои NACK: они короче и важнее полного повтора
+    let mut frames = state.retransmit_frames.lock().unwrap().remove(transport_type).unwrap_or_default();
+    frames.extend(state.transport_manager.all_pending_nacks());
+    for packet_bytes in packets {
+        match state.transport_manager.frame_packet(transport_type, &packet_bytes, mtu) {
+            Ok(Some(packet_frames)) => frames.extend(packet_frames),
+            Ok(None) => frames.push(packet_bytes),
+            Err(e) => println!("❌ Cannot fragment packet for {:?}: {}", transport_type, e),
+        }
+    }
+    Some(frames)
+}
+
+/// Пакеты ожидающих сообщений для всех известных пиров; `None` без identity
+/// или пока меняется политика
+fn pending_packets(state: &Arc<CoreState>, limit: c_int) -> Option<Vec<Vec<u8>>> {
     let limit = if limit <= 0 { 50 } else { limit as usize };
     let pending = match state.storage.lock().unwrap().get_pending_messages() {
         Ok(messages) => messages,
@@ -1140,20 +1506,21 @@ pub extern "C" fn ya_ok_export_pending_packets(limit: c_int) -> *mut c_char {
     };
 
     let identity_lock = state.identity.try_read().unwrap();
-    let identity = match &*identity_lock {
-        Some(id) => id,
-        None => return std::ptr::null_mut(),
-    };
+    let identity = identity_lock.as_ref()?;
 
     // Создаем пакеты для каждого сообщения и каждого известного пира с X25519 ключом.
     // Если список пиров пуст (или без X25519), то мы НЕ создаём пакеты, т.к. получатели не смогут их расшифровать.
-    let runtime = match get_runtime() {
-        Ok(rt) => rt,
-        Err(_) => return std::ptr::null_mut(),
-    };
+    let runtime = get_runtime().ok()?;
     let handle = runtime.handle();
     let known_peers = handle.block_on(async { state.router.known_peers().read().await.clone() });
 
+    // Пакет создаётся один раз: тот же шифротекст при каждой встрече
+    // позволяет получателю дособрать фрагменты, полученные ранее
+    let options = packet_options(state).ok()?;
+    let mut outbox = state.outbox.lock().unwrap();
+    let pending_ids = pending.iter().map(|stored| stored.message_id.clone()).collect::<std::collections::HashSet<_>>();
+    outbox.retain(|(message_id, _), _| pending_ids.contains(message_id));
+
     let mut packets: Vec<Vec<u8>> = Vec::new();
     for stored in pending.into_iter().take(limit) {
         let Ok(message) = serde_json::from_slice::<Message>(&stored.message_data) else {
@@ -1167,21 +1534,30 @@ pub extern "C" fn ya_ok_export_pending_packets(limit: c_int) -> *mut c_char {
             if x_key.len() != 32 {
                 continue;
             }
-            if let Ok(packet) = Packet::from_message(&message, identity, x_key) {
+            let key = (stored.message_id.clone(), x_key.clone());
+            if let Some(packet_bytes) = outbox.get(&key) {
+                packets.push(packet_bytes.clone());
+                continue;
+            }
+            if let Ok(packet) = Packet::from_message_with_options(&message, identity, x_key, options) {
                 if let Ok(packet_bytes) = packet.to_bytes() {
+                    outbox.insert(key, packet_bytes.clone());
                     packets.push(packet_bytes);
                 }
             }
         }
     }
+    Some(packets)
+}
 
-    // Сериализуем пакеты в base64 (для передачи через транспорт)
-    let packets_base64 = packets.iter()
-        .map(|p| BASE64.encode(p))
+/// Кадры в base64 через запятую (для передачи через транспорт)
+fn frames_to_c_string(frames: &[Vec<u8>]) -> *mut c_char {
+    let frames_base64 = frames.iter()
+        .map(|frame| BASE64.encode(frame))
         .collect::<Vec<_>>()
         .join(",");
-    
-    let c_string = CString::new(packets_base64).unwrap_or_else(|_| CString::new("").unwrap());
+
+    let c_string = CString::new(frames_base64).unwrap_or_else(|_| CString::new("").unwrap());
     c_string.into_raw()
 }
 
@@ -1224,7 +1600,9 @@ pub extern "C" fn ya_ok_import_packets(packets_base64: *const c_char) -> c_int {
             Err(_) => continue,
         };
 
-        if Packet::from_bytes(&packet_bytes).is_err() {
+        if !crate::transport::fragmentation::is_fragment(&packet_bytes)
+            && Packet::from_bytes(&packet_bytes).is_err()
+        {
             continue;
         }
 
@@ -1466,6 +1844,7 @@ struct CoreStats {
     storage: crate::storage::StorageStats,
     routing: crate::routing::RoutingStats,
     sync: crate::sync::GossipStats,
+    relay: RelayPoolStats,
 }
 
 #[derive(serde::Serialize)]
@@ -1587,6 +1966,9 @@ pub enum ApiError {
     #[error("Invalid parameters")]
     InvalidParameters,
 
+    #[error("Policy is being changed")]
+    PolicyUnavailable,
+
     #[error("Packet error: {0}")]
     PacketError(#[from] crate::core::PacketError),
 }
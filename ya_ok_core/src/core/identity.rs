@@ -20,6 +20,7 @@ use x25519_dalek::{StaticSecret, PublicKey as X25519PublicKey};
 use rand::rngs::OsRng;
 use rand::RngCore;
 use std::fmt;
+use yaok_relay_proto::Invite;
 
 /// Уникальная идентичность пользователя
 #[derive(Clone)]
@@ -152,6 +153,15 @@ impl Identity {
         self.x25519_public = Some(public);
     }
 
+    /// Подписанное приглашение для ссылки `yaok://add?...` (см.
+    /// `yaok_relay_proto::invite`); имя показывается на странице relay,
+    /// только если подпись верна
+    pub fn invite(&self, name: Option<&str>) -> Result<Invite, IdentityError> {
+        let key = self.signing_key.as_ref().ok_or(IdentityError::NoPrivateKey)?;
+        Invite::sign(key, self.x25519_public.as_ref().map(X25519PublicKey::as_bytes), name)
+            .map_err(|_| IdentityError::InvalidName)
+    }
+
     /// Восстановить из байтов (для загрузки из хранилища)
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, IdentityError> {
         if bytes.len() != 32 {
@@ -187,4 +197,7 @@ pub enum IdentityError {
 
     #[error("Invalid public key")]
     InvalidPublicKey,
+
+    #[error("Invalid display name")]
+    InvalidName,
 }
\ No newline at end of file
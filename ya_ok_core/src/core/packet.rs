@@ -4,12 +4,60 @@
 //! - Зашифрованное сообщение
 //! - Метаданные для маршрутизации
 //! - TTL и hops для предотвращения зацикливания
+//!
+//! # Возраст пакета (DTN age)
+//! Часы на офлайн-устройствах часто сбиты, поэтому TTL не сравнивается
+//! с `timestamp` отправителя. Вместо этого каждый узел прибавляет к полю `age`
+//! время, которое пакет провёл у него (по локальным монотонным часам),
+//! и истечение TTL определяется только по накопленному возрасту.
+//! Пребывание округляется вверх до целых секунд, и каждый узел добавляет
+//! не меньше секунды: иначе пакет, проводящий на узлах доли секунды,
+//! не старел бы вовсе и быстрые ретрансляторы продлевали бы его TTL.
+//! Нулевой `age` не сериализуется, поэтому подписанные данные (с `age` = 0)
+//! совпадают с раскладкой пакетов узлов, не знающих этого поля.
+//!
+//! # Защита от анализа трафика
+//! Длина шифротекста выдаёт тип сообщения, а `priority` виден ретрансляторам.
+//! По `PacketOptions` открытый текст дополняется нулями до размера из
+//! [`PADDING_BUCKETS`] (CBOR самоограничен, нули после сообщения отбрасываются
+//! при расшифровке), а приоритет заменяется на [`HIDDEN_PRIORITY`].
 
 use crate::core::{Message, Crypto, EncryptedPayload};
 use ciborium::{de, ser};
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::time::Instant;
+
+/// Максимально допустимое опережение `timestamp` отправителя относительно
+/// локальных часов. Пакеты «из будущего» сверх этой границы отбрасываются.
+pub const MAX_FUTURE_SKEW_SECS: i64 = 24 * 3600;
+
+/// Размеры (байты), до которых дополняется открытый текст перед шифрованием.
+/// Статус и любой текст попадают в первую корзину и неотличимы по длине.
+pub const PADDING_BUCKETS: [usize; 5] = [512, 4096, 16384, 32768, 65000];
+
+/// Длина идентификатора содержимого пакета ([`Packet::content_id`]), байты
+pub const CONTENT_ID_LEN: usize = 16;
+
+/// Приоритет, который видят ретрансляторы, если настоящий скрыт
+pub const HIDDEN_PRIORITY: Priority = Priority::Medium;
+
+/// Размер открытого текста после дополнения до корзины.
+/// Сообщения больше последней корзины не дополняются.
+pub fn padded_len(len: usize) -> usize {
+    PADDING_BUCKETS.iter().copied().find(|bucket| *bucket >= len).unwrap_or(len)
+}
+
+/// Параметры создания пакета (задаются политикой)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PacketOptions {
+    /// Дополнять открытый текст до размера из [`PADDING_BUCKETS`]
+    pub pad_to_buckets: bool,
+    /// Не раскрывать приоритет: пакет помечается [`HIDDEN_PRIORITY`]
+    pub hide_priority: bool,
+}
 
 /// Приоритет пакета
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, PartialOrd, Ord, Eq, Hash)]
@@ -40,6 +88,14 @@ pub struct Packet {
     pub hops: u32,
     /// Максимальное количество hops
     pub max_hops: u32,
+    /// Накопленный возраст пакета в секундах (сумма времени пребывания на узлах).
+    /// Изменяемый заголовок: не входит в подпись.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub age: u32,
+    /// Момент получения (или создания) пакета этим узлом, по монотонным часам.
+    /// Не передаётся по сети.
+    #[serde(skip)]
+    pub received_at: Option<Instant>,
     /// Приоритет пакета
     pub priority: Priority,
     /// Зашифрованный payload
@@ -54,12 +110,26 @@ impl Packet {
         message: &Message,
         sender_identity: &crate::core::Identity,
         receiver_public_key: &[u8],
+    ) -> Result<Self, PacketError> {
+        Self::from_message_with_options(message, sender_identity, receiver_public_key, PacketOptions::default())
+    }
+
+    /// Создать пакет из сообщения с дополнением и/или скрытым приоритетом
+    pub fn from_message_with_options(
+        message: &Message,
+        sender_identity: &crate::core::Identity,
+        receiver_public_key: &[u8],
+        options: PacketOptions,
     ) -> Result<Self, PacketError> {
         // Сериализуем сообщение в CBOR
         let mut message_bytes = Vec::new();
         ser::into_writer(&message, &mut message_bytes)
             .map_err(|_| PacketError::SerializationFailed)?;
 
+        if options.pad_to_buckets {
+            message_bytes.resize(padded_len(message_bytes.len()), 0);
+        }
+
         // Генерируем ключи для шифрования
         let (sender_private, sender_public) = Crypto::generate_ephemeral_keypair();
 
@@ -81,6 +151,7 @@ impl Packet {
 
         // Определяем приоритет
         let priority = match message.message_type {
+            _ if options.hide_priority => HIDDEN_PRIORITY,
             crate::core::MessageType::Status => Priority::High,
             crate::core::MessageType::Text => Priority::Medium,
             crate::core::MessageType::Voice => Priority::Low,
@@ -99,6 +170,8 @@ impl Packet {
             ttl: 3600, // 1 час по умолчанию
             hops: 0,
             max_hops: 10, // Максимум 10 прыжков
+            age: 0,
+            received_at: Some(Instant::now()),
             priority,
             encrypted_payload: encrypted,
             signature: Vec::new(),
@@ -152,17 +225,25 @@ impl Packet {
         )?;
 
         // Десериализуем сообщение
-        let message: Message = de::from_reader(&decrypted_bytes[..])
+        let mut reader = &decrypted_bytes[..];
+        let message: Message = de::from_reader(&mut reader)
             .map_err(|_| PacketError::DeserializationFailed)?;
 
+        // После сообщения допустимо только дополнение нулями
+        if reader.iter().any(|byte| *byte != 0) {
+            return Err(PacketError::DeserializationFailed);
+        }
+
         Ok(message)
     }
 
     /// Получить данные для подписи (без самой подписи)
     fn get_signing_data(&self) -> Result<Vec<u8>, PacketError> {
-        // Создаем копию пакета без подписи для подписи
+        // Создаем копию пакета без подписи и без изменяемого возраста
+        // (нулевой `age` не попадает в CBOR)
         let mut packet_copy = self.clone();
         packet_copy.signature = Vec::new();
+        packet_copy.age = 0;
 
         let mut data = Vec::new();
         ser::into_writer(&packet_copy, &mut data)
@@ -171,10 +252,59 @@ impl Packet {
         Ok(data)
     }
 
+    /// Идентификатор содержимого: первые [`CONTENT_ID_LEN`] байт SHA-256
+    /// подписанных данных и подписи. Не зависит от `age`, поэтому одинаков
+    /// при каждой сериализации одного пакета.
+    pub fn content_id(&self) -> Result<[u8; CONTENT_ID_LEN], PacketError> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.get_signing_data()?);
+        hasher.update(&self.signature);
+        let digest = hasher.finalize();
+
+        let mut id = [0u8; CONTENT_ID_LEN];
+        id.copy_from_slice(&digest[..CONTENT_ID_LEN]);
+        Ok(id)
+    }
+
+    /// Текущий возраст пакета: накопленный `age` плюс время пребывания на этом узле
+    pub fn current_age(&self) -> u32 {
+        self.current_age_at(Instant::now())
+    }
+
+    /// Возраст пакета на момент `now` (по локальным монотонным часам)
+    pub fn current_age_at(&self, now: Instant) -> u32 {
+        self.age.saturating_add(self.residence_at(now))
+    }
+
+    /// Время пребывания на этом узле в секундах, с округлением вверх
+    fn residence_at(&self, now: Instant) -> u32 {
+        let residence = self.received_at.map(|at| now.saturating_duration_since(at)).unwrap_or_default();
+        let secs = residence.as_secs() + u64::from(residence.subsec_nanos() > 0);
+        u32::try_from(secs).unwrap_or(u32::MAX)
+    }
+
+    /// Зафиксировать время пребывания в `age` (не меньше секунды) перед
+    /// отправкой следующему узлу. Отсчёт пребывания начинается заново.
+    pub fn stamp_age(&mut self) {
+        let now = Instant::now();
+        self.age = self.age.saturating_add(self.residence_at(now).max(1));
+        self.received_at = Some(now);
+    }
+
+    /// Отметить момент получения пакета этим узлом
+    pub fn mark_received(&mut self) {
+        self.received_at = Some(Instant::now());
+    }
+
+    /// Проверить, что `timestamp` отправителя не уходит в будущее дальше
+    /// [`MAX_FUTURE_SKEW_SECS`] относительно `now`
+    pub fn has_sane_timestamp(&self, now: DateTime<Utc>) -> bool {
+        self.timestamp.signed_duration_since(now).num_seconds() <= MAX_FUTURE_SKEW_SECS
+    }
+
     /// Проверить, не истек ли TTL
     pub fn is_expired(&self) -> bool {
-        let elapsed = Utc::now().signed_duration_since(self.timestamp);
-        elapsed.num_seconds() as u32 >= self.ttl
+        self.current_age() >= self.ttl
     }
 
     /// Проверить, не превышен ли лимит hops
@@ -191,12 +321,15 @@ impl Packet {
     /// **НЕ ИСПОЛЬЗУЙТЕ** `!is_expired() && can_forward()` - это создает TOCTOU!
     /// Между двумя проверками время может измениться и пакет станет expired.
     pub fn can_be_forwarded(&self) -> bool {
-        let now = Utc::now();
-        let elapsed = now.signed_duration_since(self.timestamp);
-        let is_not_expired = (elapsed.num_seconds() as u32) < self.ttl;
+        self.can_be_forwarded_at(Instant::now(), Utc::now())
+    }
+
+    /// То же, что [`Packet::can_be_forwarded`], но с явно заданными часами
+    pub fn can_be_forwarded_at(&self, now: Instant, wall_now: DateTime<Utc>) -> bool {
+        let is_not_expired = self.current_age_at(now) < self.ttl;
         let has_hops_remaining = self.hops < self.max_hops;
-        
-        is_not_expired && has_hops_remaining
+
+        is_not_expired && has_hops_remaining && self.has_sane_timestamp(wall_now)
     }
 
     /// Увеличить счетчик hops
@@ -204,7 +337,7 @@ impl Packet {
         self.hops += 1;
     }
 
-    /// Сериализовать пакет в CBOR
+    /// Сериализовать пакет в CBOR как есть: одни и те же байты при каждом вызове
     pub fn to_bytes(&self) -> Result<Vec<u8>, PacketError> {
         let mut bytes = Vec::new();
         ser::into_writer(self, &mut bytes)
@@ -212,6 +345,14 @@ impl Packet {
         Ok(bytes)
     }
 
+    /// Сериализовать пакет для отправки следующему узлу: с временем
+    /// пребывания на этом узле в `age`
+    pub fn to_wire_bytes(&self) -> Result<Vec<u8>, PacketError> {
+        let mut stamped = self.clone();
+        stamped.stamp_age();
+        stamped.to_bytes()
+    }
+
     /// Десериализовать пакет из CBOR
     /// 
     /// # Security Note
@@ -226,8 +367,9 @@ impl Packet {
             return Err(PacketError::PacketTooLarge(bytes.len()));
         }
         
-        let packet: Packet = de::from_reader(bytes)
+        let mut packet: Packet = de::from_reader(bytes)
             .map_err(|_| PacketError::DeserializationFailed)?;
+        packet.mark_received();
         
         // Security: Validate packet fields after deserialization
         const MAX_ENCRYPTED_PAYLOAD: usize = 64 * 1024; // 64 KB
@@ -247,11 +389,20 @@ impl Packet {
         if !packet.sender_x25519_public_key.is_empty() && packet.sender_x25519_public_key.len() != 32 {
             return Err(PacketError::InvalidSenderKey);
         }
+
+        // Sanity bound: reject timestamps far in the future (broken sender clock)
+        if !packet.has_sane_timestamp(Utc::now()) {
+            return Err(PacketError::TimestampInFuture);
+        }
         
         Ok(packet)
     }
 }
 
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
 impl fmt::Display for Packet {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -287,6 +438,9 @@ pub enum PacketError {
     #[error("Invalid receiver key")]
     InvalidReceiverKey,
 
+    #[error("Timestamp too far in the future")]
+    TimestampInFuture,
+
     #[error("Identity error: {0}")]
     IdentityError(#[from] crate::core::IdentityError),
 
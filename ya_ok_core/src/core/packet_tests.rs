@@ -0,0 +1,232 @@
+//! Tests for Packet TTL / age accounting and traffic-analysis padding
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{
+        Identity, Message, MessagePayload, Packet, PacketError, PacketOptions, Priority, StatusType,
+        HIDDEN_PRIORITY, MAX_FUTURE_SKEW_SECS, PADDING_BUCKETS,
+    };
+    use crate::policy::Policy;
+    use chrono::{Duration as ChronoDuration, Utc};
+    use std::time::{Duration, Instant};
+
+    fn create_packet() -> (Packet, Identity) {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let message = Message::status(sender.id.clone(), StatusType::Ok);
+        let packet = Packet::from_message(
+            &message,
+            &sender,
+            &receiver.x25519_public_bytes().unwrap(),
+        ).unwrap();
+        (packet, receiver)
+    }
+
+    #[test]
+    fn test_sender_clock_behind_is_not_expired() {
+        // Sender's clock is two days behind ours: wall-clock TTL would drop it
+        let (mut packet, _) = create_packet();
+        packet.timestamp = Utc::now() - ChronoDuration::days(2);
+
+        assert!(!packet.is_expired());
+        assert!(packet.can_be_forwarded());
+    }
+
+    #[test]
+    fn test_sender_clock_ahead_does_not_wrap() {
+        // Sender's clock is an hour ahead: negative elapsed time must not wrap around
+        let (mut packet, _) = create_packet();
+        packet.timestamp = Utc::now() + ChronoDuration::hours(1);
+
+        assert!(!packet.is_expired());
+        assert!(packet.can_be_forwarded());
+    }
+
+    #[test]
+    fn test_far_future_timestamp_rejected() {
+        let (mut packet, _) = create_packet();
+        packet.timestamp = Utc::now() + ChronoDuration::seconds(MAX_FUTURE_SKEW_SECS + 60);
+
+        assert!(!packet.has_sane_timestamp(Utc::now()));
+        assert!(!packet.can_be_forwarded());
+
+        let bytes = packet.to_bytes().unwrap();
+        assert!(matches!(Packet::from_bytes(&bytes), Err(PacketError::TimestampInFuture)));
+    }
+
+    #[test]
+    fn test_expired_by_age_regardless_of_timestamp() {
+        let (mut packet, _) = create_packet();
+        packet.age = packet.ttl;
+
+        assert!(packet.is_expired());
+        assert!(!packet.can_be_forwarded());
+    }
+
+    #[test]
+    fn test_residence_time_accumulates() {
+        let (mut packet, _) = create_packet();
+        let received = Instant::now();
+        packet.age = 100;
+        packet.received_at = Some(received);
+
+        let later = received + Duration::from_secs(250);
+        assert_eq!(packet.current_age_at(later), 350);
+        assert!(packet.can_be_forwarded_at(later, Utc::now()));
+
+        let much_later = received + Duration::from_secs(u64::from(packet.ttl));
+        assert!(!packet.can_be_forwarded_at(much_later, Utc::now()));
+    }
+
+    #[test]
+    fn test_fast_hops_still_age() {
+        let (mut packet, _) = create_packet();
+        let received = Instant::now();
+        packet.received_at = Some(received);
+
+        // Доли секунды пребывания считаются целой секундой
+        assert_eq!(packet.current_age_at(received + Duration::from_millis(10)), 1);
+
+        // Каждая пересылка добавляет хотя бы секунду
+        for hop in 1..=3 {
+            packet.stamp_age();
+            assert!(packet.age >= hop);
+        }
+    }
+
+    #[test]
+    fn test_age_survives_serialization() {
+        let (mut packet, _) = create_packet();
+        packet.age = 42;
+
+        let restored = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert!(restored.age >= 42);
+        assert!(restored.received_at.is_some());
+    }
+
+    #[test]
+    fn test_age_not_covered_by_signature() {
+        let (mut packet, receiver) = create_packet();
+        packet.age = 1234;
+        packet.stamp_age();
+
+        let restored = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert!(restored.decrypt(&receiver).is_ok());
+    }
+
+    #[test]
+    fn test_to_bytes_is_stable() {
+        let (mut packet, receiver) = create_packet();
+        packet.age = 3;
+        packet.received_at = Some(Instant::now() - Duration::from_secs(5));
+
+        // Повторная сериализация не меняет байты и идентификатор
+        let bytes = packet.to_bytes().unwrap();
+        assert_eq!(packet.to_bytes().unwrap(), bytes);
+        let restored = Packet::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.age, 3);
+
+        // Время пребывания добавляется только при отправке дальше
+        let forwarded = Packet::from_bytes(&packet.to_wire_bytes().unwrap()).unwrap();
+        assert!(forwarded.age >= 8);
+        assert_eq!(forwarded.content_id().unwrap(), packet.content_id().unwrap());
+        assert!(forwarded.decrypt(&receiver).is_ok());
+    }
+
+    #[test]
+    fn test_signature_compatible_with_layout_without_age() {
+        use ciborium::Value;
+
+        // Узел без поля `age` подписывает и отправляет CBOR без этого ключа
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let message = Message::status(sender.id.clone(), StatusType::Ok);
+        let packet = create_packet_with(&message, &sender, &receiver, PacketOptions::default());
+
+        let encode = |entries: &Vec<(Value, Value)>| {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(&Value::Map(entries.clone()), &mut bytes).unwrap();
+            bytes
+        };
+        let value: Value = ciborium::de::from_reader(&packet.to_bytes().unwrap()[..]).unwrap();
+        let Value::Map(mut entries) = value else { panic!("packet is not a CBOR map") };
+        entries.retain(|(key, _)| key.as_text() != Some("age"));
+        let signature_at = entries.iter().position(|(key, _)| key.as_text() == Some("signature")).unwrap();
+
+        entries[signature_at].1 = Value::Array(Vec::new());
+        let signature = sender.sign(&encode(&entries)).unwrap().to_bytes();
+        entries[signature_at].1 = Value::Array(signature.iter().map(|byte| Value::Integer((*byte).into())).collect());
+        let legacy_bytes = encode(&entries);
+
+        // Старый пакет проверяется новым узлом, в том числе после пересылки с `age`
+        let mut legacy = Packet::from_bytes(&legacy_bytes).unwrap();
+        assert!(legacy.decrypt(&receiver).is_ok());
+        legacy.age = 7;
+        assert!(Packet::from_bytes(&legacy.to_bytes().unwrap()).unwrap().decrypt(&receiver).is_ok());
+
+        // Новый пакет с нулевым `age` сериализуется в старой раскладке
+        let mut current = packet.clone();
+        current.signature = signature.to_vec();
+        assert_eq!(current.to_bytes().unwrap(), legacy_bytes);
+    }
+
+    fn create_packet_with(message: &Message, sender: &Identity, receiver: &Identity, options: PacketOptions) -> Packet {
+        Packet::from_message_with_options(message, sender, &receiver.x25519_public_bytes().unwrap(), options).unwrap()
+    }
+
+    #[test]
+    fn test_padding_hides_status_vs_text() {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let options = PacketOptions { pad_to_buckets: true, hide_priority: false };
+
+        let status = Message::status(sender.id.clone(), StatusType::Ok);
+        let short_text = Message::text(sender.id.clone(), "ok".to_string()).unwrap();
+        let long_text = Message::text(sender.id.clone(), "Я".repeat(128)).unwrap();
+
+        let lengths: Vec<usize> = [&status, &short_text, &long_text]
+            .iter()
+            .map(|m| create_packet_with(m, &sender, &receiver, options).encrypted_payload.ciphertext.len())
+            .collect();
+        assert!(lengths.iter().all(|len| *len == lengths[0]));
+
+        // Without padding the lengths differ
+        let unpadded_status = create_packet_with(&status, &sender, &receiver, PacketOptions::default());
+        let unpadded_text = create_packet_with(&long_text, &sender, &receiver, PacketOptions::default());
+        assert_ne!(
+            unpadded_status.encrypted_payload.ciphertext.len(),
+            unpadded_text.encrypted_payload.ciphertext.len()
+        );
+    }
+
+    #[test]
+    fn test_padded_voice_uses_bucket_and_decrypts() {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let options = PacketOptions { pad_to_buckets: true, hide_priority: false };
+        let voice = Message::voice(sender.id.clone(), vec![0x42; 3_000]).unwrap();
+
+        let packet = create_packet_with(&voice, &sender, &receiver, options);
+        let plaintext_len = packet.encrypted_payload.ciphertext.len() - 16; // Poly1305 tag
+        assert!(PADDING_BUCKETS.contains(&plaintext_len));
+
+        let restored = Packet::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        let decrypted = restored.decrypt(&receiver).unwrap();
+        assert!(matches!(decrypted.payload, MessagePayload::Voice(ref data) if data.len() == 3_000));
+    }
+
+    #[test]
+    fn test_hidden_priority() {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let status = Message::status(sender.id.clone(), StatusType::Ok);
+
+        let visible = create_packet_with(&status, &sender, &receiver, PacketOptions::default());
+        assert_eq!(visible.priority, Priority::High);
+
+        let options = Policy::military().packet_options();
+        let hidden = create_packet_with(&status, &sender, &receiver, options);
+        assert_eq!(hidden.priority, HIDDEN_PRIORITY);
+        assert!(hidden.decrypt(&receiver).is_ok());
+    }
+}
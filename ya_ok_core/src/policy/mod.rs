@@ -3,6 +3,7 @@
 //! Policy определяет ограничения, которые ядро учитывает в зависимости от среды.
 //! НЕ является "режимом" - это адаптация к условиям.
 
+use crate::core::PacketOptions;
 use crate::transport::TransportType;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
@@ -36,6 +37,15 @@ pub struct Policy {
 
     /// Включена ли автоматическая очистка
     pub enable_auto_cleanup: bool,
+
+    /// Дополнять сообщения до фиксированных размеров перед шифрованием
+    /// (скрывает тип сообщения от наблюдателя, ценой трафика)
+    #[serde(default)]
+    pub pad_to_buckets: bool,
+
+    /// Скрывать приоритет пакета от ретрансляторов
+    #[serde(default)]
+    pub hide_priority: bool,
 }
 
 impl Policy {
@@ -56,6 +66,8 @@ impl Policy {
             max_hops: 10,
             enable_compression: true,
             enable_auto_cleanup: true,
+            pad_to_buckets: false,
+            hide_priority: false,
         }
     }
 
@@ -74,6 +86,8 @@ impl Policy {
             max_hops: 5,
             enable_compression: true,
             enable_auto_cleanup: true,
+            pad_to_buckets: true,
+            hide_priority: true,
         }
     }
 
@@ -91,6 +105,8 @@ impl Policy {
             max_hops: 3,
             enable_compression: false, // экономим батарею
             enable_auto_cleanup: true,
+            pad_to_buckets: false,
+            hide_priority: false,
         }
     }
 
@@ -129,6 +145,14 @@ impl Policy {
         current_count > self.max_stored_messages
     }
 
+    /// Параметры создания пакетов по этой политике
+    pub fn packet_options(&self) -> PacketOptions {
+        PacketOptions {
+            pad_to_buckets: self.pad_to_buckets,
+            hide_priority: self.hide_priority,
+        }
+    }
+
     /// Получить приоритет для типа сообщения
     pub fn get_message_priority(&self, message_type: &crate::core::MessageType) -> u8 {
         match message_type {
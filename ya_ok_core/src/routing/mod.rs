@@ -214,8 +214,9 @@ impl Router for DtnRouter {
         let known_peers = self.known_peers.read().await;
 
         if let Some(peer) = known_peers.get(destination) {
-            // Отправляем напрямую получателю
-            self.transport_manager.send_packet(packet, &peer.address).await?;
+            // Отправляем напрямую получателю: relay адресует узлы по id,
+            // адрес пира - это адрес транспорта, через который он пришёл
+            self.transport_manager.send_packet(packet, &peer.id).await?;
         } else {
             // Не знаем получателя - делаем flooding
             self.flood_packet(packet.clone()).await?;
@@ -237,7 +238,7 @@ impl Router for DtnRouter {
         // Отправляем всем известным пирам
         let mut success_count = 0;
         for peer in known_peers.values() {
-            if let Ok(_) = self.transport_manager.send_packet(&packet, &peer.address).await {
+            if let Ok(_) = self.transport_manager.send_packet(&packet, &peer.id).await {
                 success_count += 1;
             }
         }
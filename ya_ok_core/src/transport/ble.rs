@@ -16,6 +16,9 @@ const YAOK_SERVICE_UUID: &str = "0000CAFE-0000-1000-8000-00805f9b34fb";
 #[allow(dead_code)]
 const YAOK_PACKET_CHAR_UUID: &str = "0000BEEF-0000-1000-8000-00805f9b34fb";
 
+/// BLE GATT MTU (типичное значение после MTU negotiation)
+pub const BLE_MTU: usize = 512;
+
 /// Platform-specific BLE interface (FFI)
 #[repr(C)]
 pub struct BleNativeInterface {
@@ -84,6 +87,25 @@ impl BleTransport {
         }
     }
     
+    /// Send raw bytes via GATT characteristic
+    fn send_gatt(&self, data: &[u8], destination: &str) -> Result<(), TransportError> {
+        // Convert destination address to bytes
+        let dest_bytes = destination.as_bytes();
+        
+        let result = (self.native_interface.send_gatt_packet)(
+            dest_bytes.as_ptr(),
+            dest_bytes.len(),
+            data.as_ptr(),
+            data.len(),
+        );
+        
+        if result < 0 {
+            return Err(TransportError::SendFailed("BLE GATT send failed".to_string()));
+        }
+        
+        Ok(())
+    }
+    
     /// Get platform-specific interface (implemented on Android/iOS)
     #[cfg(any(target_os = "android", target_os = "ios"))]
     fn get_platform_interface() -> BleNativeInterface {
@@ -112,31 +134,27 @@ impl Transport for BleTransport {
             .map_err(|e| TransportError::SendFailed(format!("Serialization failed: {}", e)))?;
         
         // BLE GATT has MTU limit (typically 512 bytes)
-        // For larger packets, we need chunking (handled by transport::chunking module)
-        const BLE_MTU: usize = 512;
+        // Larger packets are fragmented by TransportManager (transport::fragmentation)
         if packet_bytes.len() > BLE_MTU {
             return Err(TransportError::SendFailed(
-                format!("Packet too large for BLE: {} bytes (max {}). Use chunking.", 
+                format!("Packet too large for BLE: {} bytes (max {}). Send via TransportManager to fragment.", 
                     packet_bytes.len(), BLE_MTU)
             ));
         }
         
-        // Convert destination address to bytes
-        let dest_bytes = destination.as_bytes();
-        
-        // Send via GATT characteristic
-        let result = (self.native_interface.send_gatt_packet)(
-            dest_bytes.as_ptr(),
-            dest_bytes.len(),
-            packet_bytes.as_ptr(),
-            packet_bytes.len(),
-        );
-        
-        if result < 0 {
-            return Err(TransportError::SendFailed("BLE GATT send failed".to_string()));
+        self.send_gatt(&packet_bytes, destination)
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        Some(BLE_MTU)
+    }
+
+    async fn send_frame(&self, frame: &[u8], destination: &str) -> Result<(), TransportError> {
+        if frame.len() > BLE_MTU {
+            return Err(TransportError::MtuExceeded);
         }
-        
-        Ok(())
+
+        self.send_gatt(frame, destination)
     }
 
     async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError> {
@@ -2,7 +2,19 @@
 //!
 //! Разбивает большие payloads на chunks для передачи через UDP
 //! и собирает их обратно, обрабатывая потерю пакетов и таймауты.
+//!
+//! Selective repeat: получатель сообщает битовую карту полученных chunks
+//! (`ChunkNack`), отправитель повторяет только недостающие (`RetransmitWindow`).
+//! Незавершённые сборки можно сохранить (`ReassemblySnapshot`) и продолжить
+//! при следующей встрече с отправителем.
+//!
+//! FEC для односторонних каналов (спутник, BLE advertising) без обратного канала:
+//! систематический Reed-Solomon (`FecShard`), payload собирается из любых k
+//! из n shards (`FecReassembler`). Режим выбирается для каждого транспорта (`ChunkingMode`).
 
+use crate::transport::TransportType;
+use chrono::{DateTime, Utc};
+use reed_solomon_erasure::galois_8::ReedSolomon;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
@@ -10,9 +22,49 @@ use std::time::{Duration, Instant};
 /// Максимальный размер chunk для UDP (учитываем MTU ~1400 байт минус заголовки)
 pub const MAX_CHUNK_SIZE: usize = 1200;
 
-/// Таймаут для сборки chunks (если за это время не получены все части, сброс)
+/// Таймаут для сборки chunks (если за это время не пришло ни одной новой части, сброс)
 pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// Сколько хранить отправленные chunks для повторной передачи
+pub const RETRANSMIT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Максимум сообщений в буфере повторной передачи
+pub const MAX_RETRANSMIT_MESSAGES: usize = 16;
+
+/// Максимум chunks, повторяемых в ответ на один NACK
+pub const MAX_RETRANSMIT_PER_NACK: usize = 64;
+
+/// Максимум раундов повторной передачи одного сообщения
+pub const MAX_RETRANSMIT_ROUNDS: u8 = 5;
+
+/// Максимум shards (data + parity) в одном FEC-сообщении (ограничение GF(2^8))
+pub const MAX_FEC_SHARDS: usize = 256;
+
+/// Избыточность FEC по умолчанию (parity shards в % от data shards)
+pub const DEFAULT_FEC_OVERHEAD_PERCENT: u16 = 50;
+
+/// Режим разбиения пакетов на фрагменты для транспорта
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkingMode {
+    /// Обычные chunks, потери восполняются через NACK
+    Plain,
+    /// Erasure coding: payload собирается из любых k из n shards.
+    /// `overhead_percent` - количество parity shards в % от data shards.
+    Fec { overhead_percent: u16 },
+}
+
+impl ChunkingMode {
+    /// Режим по умолчанию: FEC для каналов без обратной связи
+    pub fn default_for(transport_type: &TransportType) -> Self {
+        match transport_type {
+            TransportType::Satellite => ChunkingMode::Fec {
+                overhead_percent: DEFAULT_FEC_OVERHEAD_PERCENT,
+            },
+            _ => ChunkingMode::Plain,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChunkedMessage {
     /// Уникальный ID сообщения
@@ -30,6 +82,12 @@ pub struct ChunkedMessage {
 impl ChunkedMessage {
     /// Разбить payload на chunks
     pub fn chunk_payload(message_id: String, payload: &[u8]) -> Vec<Self> {
+        Self::chunk_payload_sized(message_id, payload, MAX_CHUNK_SIZE)
+    }
+
+    /// Разбить payload на chunks заданного размера (например, под MTU транспорта)
+    pub fn chunk_payload_sized(message_id: String, payload: &[u8], chunk_size: usize) -> Vec<Self> {
+        let chunk_size = chunk_size.max(1);
         if payload.is_empty() {
             return vec![ChunkedMessage {
                 message_id,
@@ -40,10 +98,10 @@ impl ChunkedMessage {
             }];
         }
 
-        let total_chunks = ((payload.len() + MAX_CHUNK_SIZE - 1) / MAX_CHUNK_SIZE) as u16;
+        let total_chunks = payload.len().div_ceil(chunk_size) as u16;
         let mut chunks = Vec::new();
 
-        for (i, chunk_data) in payload.chunks(MAX_CHUNK_SIZE).enumerate() {
+        for (i, chunk_data) in payload.chunks(chunk_size).enumerate() {
             chunks.push(ChunkedMessage {
                 message_id: message_id.clone(),
                 chunk_index: i as u16,
@@ -65,10 +123,59 @@ impl ChunkedMessage {
     }
 }
 
+/// Обратная связь получателя: какие chunks сообщения уже получены
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkNack {
+    /// ID сообщения
+    pub message_id: String,
+    /// Общее количество chunks
+    pub total_chunks: u16,
+    /// Битовая карта полученных chunks (бит i = chunk i получен, LSB first)
+    pub received_bitmap: Vec<u8>,
+}
+
+impl ChunkNack {
+    /// Построить NACK по списку полученных индексов
+    pub fn from_received(message_id: String, total_chunks: u16, received: impl IntoIterator<Item = u16>) -> Self {
+        let mut received_bitmap = vec![0u8; (total_chunks as usize).div_ceil(8)];
+        for index in received {
+            if index < total_chunks {
+                received_bitmap[index as usize / 8] |= 1 << (index % 8);
+            }
+        }
+
+        Self {
+            message_id,
+            total_chunks,
+            received_bitmap,
+        }
+    }
+
+    /// Получен ли chunk с данным индексом
+    pub fn is_received(&self, index: u16) -> bool {
+        self.received_bitmap
+            .get(index as usize / 8)
+            .map(|byte| byte & (1 << (index % 8)) != 0)
+            .unwrap_or(false)
+    }
+
+    /// Индексы недостающих chunks
+    pub fn missing_indexes(&self) -> Vec<u16> {
+        (0..self.total_chunks).filter(|i| !self.is_received(*i)).collect()
+    }
+
+    /// Все ли chunks получены
+    pub fn is_complete(&self) -> bool {
+        (0..self.total_chunks).all(|i| self.is_received(i))
+    }
+}
+
 /// Менеджер сборки chunks
 pub struct ChunkReassembler {
     /// Карта: message_id -> ReassemblyState
     pending: HashMap<String, ReassemblyState>,
+    /// Таймаут бездействия сборки
+    timeout: Duration,
 }
 
 struct ReassemblyState {
@@ -76,14 +183,40 @@ struct ReassemblyState {
     chunks: HashMap<u16, Vec<u8>>,
     /// Ожидаемое общее количество chunks
     total_chunks: u16,
-    /// Время начала сборки
-    started_at: Instant,
+    /// Время получения последнего нового chunk
+    last_activity: Instant,
+}
+
+/// Сохранённое состояние незавершённых сборок
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReassemblySnapshot {
+    /// Время сохранения (локальные часы)
+    pub saved_at: Option<DateTime<Utc>>,
+    /// Незавершённые сообщения
+    pub messages: Vec<PendingMessageSnapshot>,
+}
+
+/// Сохранённая незавершённая сборка одного сообщения
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingMessageSnapshot {
+    pub message_id: String,
+    pub total_chunks: u16,
+    /// Полученные chunks (индекс, данные)
+    pub chunks: Vec<(u16, Vec<u8>)>,
+    /// Сколько секунд сборка простаивала на момент сохранения
+    pub idle_secs: u64,
 }
 
 impl ChunkReassembler {
     pub fn new() -> Self {
+        Self::with_timeout(REASSEMBLY_TIMEOUT)
+    }
+
+    /// Сборщик с заданным таймаутом бездействия
+    pub fn with_timeout(timeout: Duration) -> Self {
         Self {
             pending: HashMap::new(),
+            timeout,
         }
     }
 
@@ -106,7 +239,7 @@ impl ChunkReassembler {
             ReassemblyState {
                 chunks: HashMap::new(),
                 total_chunks: chunk.total_chunks,
-                started_at: Instant::now(),
+                last_activity: Instant::now(),
             }
         });
 
@@ -116,8 +249,16 @@ impl ChunkReassembler {
             return Err(ReassemblyError::InconsistentMetadata);
         }
 
-        // Добавляем chunk
-        state.chunks.insert(chunk.chunk_index, chunk.data);
+        // Добавляем chunk. Повтор уже полученного не продлевает сборку,
+        // а chunk с тем же индексом, но другими данными отвергается
+        match state.chunks.get(&chunk.chunk_index) {
+            Some(data) if *data != chunk.data => return Err(ReassemblyError::ConflictingChunk),
+            Some(_) => {}
+            None => {
+                state.chunks.insert(chunk.chunk_index, chunk.data);
+                state.last_activity = Instant::now();
+            }
+        }
 
         // Проверяем, все ли chunks получены
         if state.chunks.len() == chunk.total_chunks as usize {
@@ -146,9 +287,10 @@ impl ChunkReassembler {
     pub fn cleanup_expired(&mut self) -> usize {
         let now = Instant::now();
         let before = self.pending.len();
+        let timeout = self.timeout;
         
         self.pending.retain(|_, state| {
-            now.duration_since(state.started_at) < REASSEMBLY_TIMEOUT
+            now.duration_since(state.last_activity) < timeout
         });
 
         before - self.pending.len()
@@ -158,6 +300,429 @@ impl ChunkReassembler {
     pub fn pending_count(&self) -> usize {
         self.pending.len()
     }
+
+    /// Идёт ли сборка сообщения с данным ID
+    pub fn is_pending(&self, message_id: &str) -> bool {
+        self.pending.contains_key(message_id)
+    }
+
+    /// Объём данных (байты) в незавершённых сборках
+    pub fn buffered_bytes(&self) -> usize {
+        self.pending
+            .values()
+            .flat_map(|state| state.chunks.values())
+            .map(|data| data.len())
+            .sum()
+    }
+
+    /// NACK для незавершённой сборки сообщения
+    pub fn nack(&self, message_id: &str) -> Option<ChunkNack> {
+        self.pending.get(message_id).map(|state| {
+            ChunkNack::from_received(
+                message_id.to_string(),
+                state.total_chunks,
+                state.chunks.keys().copied(),
+            )
+        })
+    }
+
+    /// NACK для всех незавершённых сборок
+    pub fn nacks(&self) -> Vec<ChunkNack> {
+        self.pending
+            .keys()
+            .filter_map(|message_id| self.nack(message_id))
+            .collect()
+    }
+
+    /// Снимок незавершённых сборок для сохранения
+    pub fn snapshot(&self) -> ReassemblySnapshot {
+        let now = Instant::now();
+        let messages = self
+            .pending
+            .iter()
+            .map(|(message_id, state)| {
+                let mut chunks: Vec<(u16, Vec<u8>)> = state
+                    .chunks
+                    .iter()
+                    .map(|(index, data)| (*index, data.clone()))
+                    .collect();
+                chunks.sort_by_key(|(index, _)| *index);
+
+                PendingMessageSnapshot {
+                    message_id: message_id.clone(),
+                    total_chunks: state.total_chunks,
+                    chunks,
+                    idle_secs: now.duration_since(state.last_activity).as_secs(),
+                }
+            })
+            .collect();
+
+        ReassemblySnapshot {
+            saved_at: Some(Utc::now()),
+            messages,
+        }
+    }
+
+    /// Восстановить сборки из снимка. Истекшие и некорректные записи пропускаются.
+    /// Возвращает количество восстановленных сборок.
+    pub fn restore(&mut self, snapshot: ReassemblySnapshot) -> usize {
+        let now = Instant::now();
+        let offline_secs = snapshot
+            .saved_at
+            .map(|saved_at| Utc::now().signed_duration_since(saved_at).num_seconds().max(0) as u64)
+            .unwrap_or(0);
+        let mut restored = 0;
+
+        for message in snapshot.messages {
+            let idle = Duration::from_secs(message.idle_secs.saturating_add(offline_secs));
+            if idle >= self.timeout || self.pending.contains_key(&message.message_id) {
+                continue;
+            }
+            if message.chunks.iter().any(|(index, _)| *index >= message.total_chunks) {
+                continue;
+            }
+
+            let last_activity = now.checked_sub(idle).unwrap_or(now);
+            self.pending.insert(message.message_id, ReassemblyState {
+                chunks: message.chunks.into_iter().collect(),
+                total_chunks: message.total_chunks,
+                last_activity,
+            });
+            restored += 1;
+        }
+
+        restored
+    }
+}
+
+/// Буфер отправленных chunks для selective repeat
+pub struct RetransmitWindow {
+    /// Карта: message_id -> отправленное сообщение
+    sent: HashMap<String, SentMessage>,
+}
+
+struct SentMessage {
+    chunks: Vec<ChunkedMessage>,
+    sent_at: Instant,
+    rounds: u8,
+}
+
+impl RetransmitWindow {
+    pub fn new() -> Self {
+        Self {
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Запомнить отправленные chunks сообщения
+    pub fn record(&mut self, chunks: Vec<ChunkedMessage>) {
+        let Some(message_id) = chunks.first().map(|c| c.message_id.clone()) else {
+            return;
+        };
+
+        if !self.sent.contains_key(&message_id) && self.sent.len() >= MAX_RETRANSMIT_MESSAGES {
+            // Вытесняем самое старое сообщение
+            if let Some(oldest) = self
+                .sent
+                .iter()
+                .min_by_key(|(_, sent)| sent.sent_at)
+                .map(|(id, _)| id.clone())
+            {
+                self.sent.remove(&oldest);
+            }
+        }
+
+        self.sent.insert(message_id, SentMessage {
+            chunks,
+            sent_at: Instant::now(),
+            rounds: 0,
+        });
+    }
+
+    /// Обработать NACK: вернуть только недостающие chunks (не больше
+    /// `MAX_RETRANSMIT_PER_NACK`). Полный NACK освобождает буфер.
+    pub fn handle_nack(&mut self, nack: &ChunkNack) -> Vec<ChunkedMessage> {
+        let Some(sent) = self.sent.get_mut(&nack.message_id) else {
+            return Vec::new();
+        };
+
+        let total_matches = sent.chunks.first().map(|c| c.total_chunks) == Some(nack.total_chunks);
+        if !total_matches || nack.is_complete() || sent.rounds >= MAX_RETRANSMIT_ROUNDS {
+            self.sent.remove(&nack.message_id);
+            return Vec::new();
+        }
+
+        sent.rounds += 1;
+        sent.chunks
+            .iter()
+            .filter(|chunk| !nack.is_received(chunk.chunk_index))
+            .take(MAX_RETRANSMIT_PER_NACK)
+            .cloned()
+            .collect()
+    }
+
+    /// Удалить сообщения старше `RETRANSMIT_WINDOW`
+    pub fn cleanup_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.sent.len();
+        self.sent.retain(|_, sent| now.duration_since(sent.sent_at) < RETRANSMIT_WINDOW);
+        before - self.sent.len()
+    }
+
+    /// Количество сообщений в буфере
+    pub fn len(&self) -> usize {
+        self.sent.len()
+    }
+
+    /// Пуст ли буфер
+    pub fn is_empty(&self) -> bool {
+        self.sent.is_empty()
+    }
+}
+
+impl Default for RetransmitWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Shard сообщения, закодированного Reed-Solomon
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FecShard {
+    /// ID сообщения
+    pub message_id: String,
+    /// Номер shard (0-based): сначала data shards, затем parity
+    pub shard_index: u16,
+    /// Количество data shards (k)
+    pub data_shards: u16,
+    /// Общее количество shards (n)
+    pub total_shards: u16,
+    /// Длина исходного payload (последний data shard дополнен нулями)
+    pub payload_len: u32,
+    /// Данные shard
+    pub data: Vec<u8>,
+    /// Checksum данных shard
+    pub checksum: Option<u32>,
+}
+
+impl FecShard {
+    /// Закодировать payload в shards размером не больше `max_shard_size`
+    /// с избыточностью `overhead_percent`
+    pub fn encode_payload(
+        message_id: String,
+        payload: &[u8],
+        max_shard_size: usize,
+        overhead_percent: u16,
+    ) -> Result<Vec<Self>, FecError> {
+        let payload_len = u32::try_from(payload.len()).map_err(|_| FecError::PayloadTooLarge)?;
+        let data_shards = payload.len().div_ceil(max_shard_size.max(1)).max(1);
+        let parity_shards = (data_shards * overhead_percent as usize).div_ceil(100);
+        let total_shards = data_shards + parity_shards;
+        if total_shards > MAX_FEC_SHARDS {
+            return Err(FecError::TooManyShards(total_shards));
+        }
+
+        // Shards одинакового размера, минимально достаточного для payload
+        let shard_size = payload.len().div_ceil(data_shards).max(1);
+        let mut shards: Vec<Vec<u8>> = (0..total_shards)
+            .map(|i| {
+                let mut shard = vec![0u8; shard_size];
+                if i < data_shards {
+                    let start = (i * shard_size).min(payload.len());
+                    let end = (start + shard_size).min(payload.len());
+                    shard[..end - start].copy_from_slice(&payload[start..end]);
+                }
+                shard
+            })
+            .collect();
+
+        if parity_shards > 0 {
+            ReedSolomon::new(data_shards, parity_shards)
+                .and_then(|codec| codec.encode(&mut shards))
+                .map_err(|e| FecError::Codec(format!("{:?}", e)))?;
+        }
+
+        Ok(shards
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| FecShard {
+                message_id: message_id.clone(),
+                shard_index: i as u16,
+                data_shards: data_shards as u16,
+                total_shards: total_shards as u16,
+                payload_len,
+                checksum: Some(crc32fast::hash(&data)),
+                data,
+            })
+            .collect())
+    }
+
+    /// Проверить checksum shard
+    pub fn verify_checksum(&self) -> bool {
+        match self.checksum {
+            Some(expected) => crc32fast::hash(&self.data) == expected,
+            None => true,
+        }
+    }
+}
+
+/// Менеджер сборки FEC-сообщений
+pub struct FecReassembler {
+    /// Карта: message_id -> FecState
+    pending: HashMap<String, FecState>,
+    /// Недавно собранные сообщения: лишние shards после сборки игнорируются
+    completed: HashMap<String, Instant>,
+    /// Таймаут бездействия сборки
+    timeout: Duration,
+}
+
+struct FecState {
+    /// Полученные shards (индекс -> данные)
+    shards: Vec<Option<Vec<u8>>>,
+    /// Количество полученных shards
+    received: usize,
+    data_shards: u16,
+    payload_len: u32,
+    shard_size: usize,
+    /// Время получения последнего нового shard
+    last_activity: Instant,
+}
+
+impl FecReassembler {
+    pub fn new() -> Self {
+        Self::with_timeout(REASSEMBLY_TIMEOUT)
+    }
+
+    /// Сборщик с заданным таймаутом бездействия
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Добавить shard. Возвращает payload, как только получены любые k shards.
+    /// Сборка при этом удаляется; собранным сообщение считается только после
+    /// [`FecReassembler::mark_completed`], когда вызывающий проверил payload.
+    pub fn add_shard(&mut self, shard: FecShard) -> Result<Option<Vec<u8>>, ReassemblyError> {
+        if !shard.verify_checksum() {
+            return Err(ReassemblyError::ChecksumMismatch);
+        }
+        if shard.shard_index >= shard.total_shards {
+            return Err(ReassemblyError::InvalidChunkIndex);
+        }
+        let data_shards = shard.data_shards as usize;
+        let total_shards = shard.total_shards as usize;
+        if data_shards == 0
+            || total_shards > MAX_FEC_SHARDS
+            || shard.data.is_empty()
+            || shard.payload_len as usize > data_shards * shard.data.len()
+        {
+            return Err(ReassemblyError::InconsistentMetadata);
+        }
+
+        if self.completed.contains_key(&shard.message_id) {
+            return Ok(None);
+        }
+
+        let message_id = shard.message_id.clone();
+        let state = self.pending.entry(message_id.clone()).or_insert_with(|| FecState {
+            shards: vec![None; total_shards],
+            received: 0,
+            data_shards: shard.data_shards,
+            payload_len: shard.payload_len,
+            shard_size: shard.data.len(),
+            last_activity: Instant::now(),
+        });
+
+        if state.shards.len() != total_shards
+            || state.data_shards != shard.data_shards
+            || state.payload_len != shard.payload_len
+            || state.shard_size != shard.data.len()
+        {
+            self.pending.remove(&message_id);
+            return Err(ReassemblyError::InconsistentMetadata);
+        }
+
+        let slot = &mut state.shards[shard.shard_index as usize];
+        if slot.is_none() {
+            *slot = Some(shard.data);
+            state.received += 1;
+            state.last_activity = Instant::now();
+        }
+
+        if state.received < data_shards {
+            return Ok(None);
+        }
+
+        let Some(mut state) = self.pending.remove(&message_id) else {
+            return Err(ReassemblyError::MissingChunks);
+        };
+        if total_shards > data_shards {
+            ReedSolomon::new(data_shards, total_shards - data_shards)
+                .and_then(|codec| codec.reconstruct_data(&mut state.shards))
+                .map_err(|e| FecError::Codec(format!("{:?}", e)))?;
+        }
+
+        let mut payload = Vec::with_capacity(data_shards * state.shard_size);
+        for shard in state.shards.iter().take(data_shards) {
+            match shard {
+                Some(data) => payload.extend_from_slice(data),
+                None => return Err(ReassemblyError::MissingChunks),
+            }
+        }
+        payload.truncate(state.payload_len as usize);
+
+        Ok(Some(payload))
+    }
+
+    /// Отметить сообщение собранным: его лишние shards больше не начинают сборку
+    pub fn mark_completed(&mut self, message_id: &str) {
+        self.completed.insert(message_id.to_string(), Instant::now());
+    }
+
+    /// Очистить истекшие сборки и записи о собранных сообщениях
+    pub fn cleanup_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.pending.len();
+        let timeout = self.timeout;
+
+        self.pending.retain(|_, state| now.duration_since(state.last_activity) < timeout);
+        self.completed.retain(|_, done_at| now.duration_since(*done_at) < timeout);
+
+        before - self.pending.len()
+    }
+
+    /// Получить количество незавершённых сборок
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Идёт ли сборка сообщения с данным ID
+    pub fn is_pending(&self, message_id: &str) -> bool {
+        self.pending.contains_key(message_id)
+    }
+
+    /// Объём данных (байты) в незавершённых сборках
+    pub fn buffered_bytes(&self) -> usize {
+        self.pending
+            .values()
+            .map(|state| state.received * state.shard_size)
+            .sum()
+    }
+}
+
+impl Default for FecReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for ChunkReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -173,11 +738,31 @@ pub enum ReassemblyError {
     
     #[error("Missing chunks")]
     MissingChunks,
+
+    #[error("Chunk conflicts with one already received")]
+    ConflictingChunk,
+
+    #[error("FEC error: {0}")]
+    Fec(#[from] FecError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FecError {
+    #[error("Too many FEC shards: {0}")]
+    TooManyShards(usize),
+
+    #[error("Payload too large for FEC")]
+    PayloadTooLarge,
+
+    #[error("Erasure codec error: {0}")]
+    Codec(String),
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
 
     #[test]
     fn chunk_and_reassemble_small_payload() {
@@ -251,4 +836,181 @@ mod tests {
         // Сразу после добавления не должно быть истекших
         assert_eq!(expired, 0);
     }
+
+    #[test]
+    fn nack_bitmap_reports_missing_chunks() {
+        let nack = ChunkNack::from_received("msg5".into(), 10, [0, 1, 3, 9]);
+
+        assert_eq!(nack.received_bitmap.len(), 2);
+        assert!(nack.is_received(3));
+        assert!(!nack.is_received(2));
+        assert_eq!(nack.missing_indexes(), vec![2, 4, 5, 6, 7, 8]);
+        assert!(!nack.is_complete());
+    }
+
+    #[test]
+    fn selective_repeat_retransmits_only_missing() {
+        let payload = vec![9u8; MAX_CHUNK_SIZE * 5];
+        let chunks = ChunkedMessage::chunk_payload("msg6".into(), &payload);
+
+        let mut window = RetransmitWindow::new();
+        window.record(chunks.clone());
+
+        // Chunks 1 и 3 потеряны
+        let mut reassembler = ChunkReassembler::new();
+        for i in [0usize, 2, 4] {
+            assert_eq!(reassembler.add_chunk(chunks[i].clone()).unwrap(), None);
+        }
+
+        let nack = reassembler.nack("msg6").unwrap();
+        assert_eq!(nack.missing_indexes(), vec![1, 3]);
+
+        let resent = window.handle_nack(&nack);
+        let resent_indexes: Vec<u16> = resent.iter().map(|c| c.chunk_index).collect();
+        assert_eq!(resent_indexes, vec![1, 3]);
+
+        let mut result = None;
+        for chunk in resent {
+            result = reassembler.add_chunk(chunk).unwrap();
+        }
+        assert_eq!(result, Some(payload));
+
+        // Полный NACK освобождает буфер отправителя
+        let done = ChunkNack::from_received("msg6".into(), 5, 0..5);
+        assert!(window.handle_nack(&done).is_empty());
+        assert!(window.is_empty());
+    }
+
+    #[test]
+    fn retransmit_rounds_are_bounded() {
+        let chunks = ChunkedMessage::chunk_payload("msg7".into(), &vec![1u8; MAX_CHUNK_SIZE * 2]);
+        let mut window = RetransmitWindow::new();
+        window.record(chunks);
+
+        let nack = ChunkNack::from_received("msg7".into(), 2, [0]);
+        for _ in 0..MAX_RETRANSMIT_ROUNDS {
+            assert_eq!(window.handle_nack(&nack).len(), 1);
+        }
+        assert!(window.handle_nack(&nack).is_empty());
+        assert!(window.is_empty());
+    }
+
+    #[test]
+    fn partial_reassembly_survives_snapshot() {
+        let payload: Vec<u8> = (0..MAX_CHUNK_SIZE * 4).map(|i| i as u8).collect();
+        let chunks = ChunkedMessage::chunk_payload("voice".into(), &payload);
+
+        // Первая встреча: получены только chunks 0 и 1
+        let mut first = ChunkReassembler::new();
+        first.add_chunk(chunks[0].clone()).unwrap();
+        first.add_chunk(chunks[1].clone()).unwrap();
+        let snapshot = first.snapshot();
+        drop(first);
+
+        // Вторая встреча: состояние восстановлено, дослать нужно только 2 и 3
+        let mut second = ChunkReassembler::new();
+        assert_eq!(second.restore(snapshot), 1);
+        assert_eq!(second.nack("voice").unwrap().missing_indexes(), vec![2, 3]);
+
+        assert_eq!(second.add_chunk(chunks[2].clone()).unwrap(), None);
+        assert_eq!(second.add_chunk(chunks[3].clone()).unwrap(), Some(payload));
+    }
+
+    #[test]
+    fn restore_skips_expired_snapshot_entries() {
+        let mut snapshot = ReassemblySnapshot::default();
+        snapshot.messages.push(PendingMessageSnapshot {
+            message_id: "stale".into(),
+            total_chunks: 2,
+            chunks: vec![(0, vec![1, 2, 3])],
+            idle_secs: REASSEMBLY_TIMEOUT.as_secs() + 1,
+        });
+
+        let mut reassembler = ChunkReassembler::new();
+        assert_eq!(reassembler.restore(snapshot), 0);
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn fec_reconstructs_from_parity_shards() {
+        let payload: Vec<u8> = (0..5_000u32).map(|i| (i * 7) as u8).collect();
+        let shards = FecShard::encode_payload("fec1".into(), &payload, 1_000, 100).unwrap();
+        assert_eq!(shards[0].data_shards, 5);
+        assert_eq!(shards.len(), 10);
+
+        // Все data shards потеряны, остались только parity
+        let mut reassembler = FecReassembler::new();
+        let mut result = None;
+        for shard in shards.iter().skip(5) {
+            result = reassembler.add_shard(shard.clone()).unwrap();
+        }
+        assert_eq!(result, Some(payload));
+
+        // Лишние shards после сборки не начинают новую сборку
+        reassembler.mark_completed("fec1");
+        assert_eq!(reassembler.add_shard(shards[0].clone()).unwrap(), None);
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn fec_rejects_too_many_shards() {
+        let result = FecShard::encode_payload("fec2".into(), &[0u8; 10_000], 50, 50);
+        assert!(matches!(result, Err(FecError::TooManyShards(300))));
+    }
+
+    #[test]
+    fn default_chunking_mode_uses_fec_without_back_channel() {
+        assert!(matches!(ChunkingMode::default_for(&TransportType::Satellite), ChunkingMode::Fec { .. }));
+        assert_eq!(ChunkingMode::default_for(&TransportType::Ble), ChunkingMode::Plain);
+    }
+
+    /// Payload, его shards и случайный порядок доставки
+    fn fec_transfer() -> impl Strategy<Value = (Vec<u8>, Vec<FecShard>, Vec<usize>)> {
+        (vec(any::<u8>(), 0..16_000), 256usize..1_200, 1u16..=150)
+            .prop_map(|(payload, shard_size, overhead)| {
+                let shards = FecShard::encode_payload("prop".into(), &payload, shard_size, overhead).unwrap();
+                (payload, shards)
+            })
+            .prop_flat_map(|(payload, shards)| {
+                let order = Just((0..shards.len()).collect::<Vec<_>>()).prop_shuffle();
+                (Just(payload), Just(shards), order)
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn fec_recovers_from_any_k_of_n((payload, shards, order) in fec_transfer(), loss in 0.0f64..1.0) {
+            let k = shards[0].data_shards as usize;
+            let n = shards.len();
+            let delivered = n - ((n as f64 * loss) as usize);
+
+            let mut reassembler = FecReassembler::new();
+            let mut recovered_at = None;
+            for (step, index) in order.iter().take(delivered).enumerate() {
+                if let Some(result) = reassembler.add_shard(shards[*index].clone()).unwrap() {
+                    prop_assert!(recovered_at.is_none());
+                    prop_assert_eq!(&result, &payload);
+                    reassembler.mark_completed(&shards[0].message_id);
+                    recovered_at = Some(step + 1);
+                }
+            }
+
+            // Собирается ровно на k-м полученном shard, при потере больше n-k - нет
+            if delivered >= k {
+                prop_assert_eq!(recovered_at, Some(k));
+            } else {
+                prop_assert_eq!(recovered_at, None);
+                prop_assert_eq!(reassembler.pending_count(), 1);
+            }
+        }
+
+        #[test]
+        fn fec_detects_corrupted_shard((_, shards, order) in fec_transfer(), flip in any::<u8>().prop_filter("non-zero", |b| *b != 0)) {
+            let mut shard = shards[order[0]].clone();
+            shard.data[0] ^= flip;
+
+            let mut reassembler = FecReassembler::new();
+            prop_assert!(matches!(reassembler.add_shard(shard), Err(ReassemblyError::ChecksumMismatch)));
+        }
+    }
 }
@@ -0,0 +1,644 @@
+//! Прямые UDP-каналы между узлами (hole punching через rendezvous relay)
+//!
+//! `DirectTransport` оборачивает `UdpTransport`: пакет узлу, с которым
+//! пробит прямой канал, уходит по нему, остальные - через relay. Первый
+//! пакет новому узлу идет через relay, а канал пробивается в фоне: узел
+//! просит relay о `Punch`, сообщает ему свой публичный адрес (`Bind` на
+//! порт rendezvous) и после `Introduce` шлет пробы на адрес собеседника,
+//! пока не получит ответ (см. `yaok_relay_proto::rendezvous`).
+//!
+//! Relay знакомит узлы, только если оба попросили, поэтому предложения
+//! принимаются лишь от разрешенных узлов (`allow_peer`) и тех, кому этот
+//! узел сам отправлял пакеты. Пакет по прямому каналу подтверждается
+//! (`DataAck`); без подтверждения канал закрывается, а пакет уходит через
+//! relay. После неудачного пробития новая попытка к тому же узлу - не
+//! раньше `retry_after`.
+//!
+//! Каналы пробиваются по IPv4: у IPv6 обычно нет NAT, и там хватает relay.
+//! Сокет задается через `PunchSocket`, что позволяет эмулировать NAT в тестах.
+
+use crate::core::Packet;
+use crate::transport::relay::{RelaySession, RendezvousSignal};
+use crate::transport::relay_connection::Backoff;
+use crate::transport::udp::UdpTransport;
+use crate::transport::{Peer, Transport, TransportError, TransportType};
+use async_trait::async_trait;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use yaok_relay_proto::rendezvous::{
+    is_rendezvous_datagram, peer_link_id, BindToken, PeerDatagram, PeerLink, RendezvousDatagram,
+};
+use yaok_relay_proto::Endpoint;
+
+/// Наибольшая датаграмма прямого канала
+const MAX_DIRECT_DATAGRAM: usize = 65_507;
+
+/// Как часто проверять, не закрылась ли сессия с relay
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+type PacketCallback = Arc<dyn Fn(Packet) + Send + Sync>;
+
+/// UDP-сокет для прямых каналов
+#[async_trait]
+pub trait PunchSocket: Send + Sync {
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> io::Result<()>;
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+#[async_trait]
+impl PunchSocket for UdpSocket {
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> io::Result<()> {
+        UdpSocket::send_to(self, datagram, target).await.map(|_| ())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// Настройки прямых каналов
+#[derive(Clone, Debug)]
+pub struct DirectConfig {
+    /// Сколько пробивать канал до отказа
+    pub punch_timeout: Duration,
+    /// Интервал повторов `Bind` и проб
+    pub probe_interval: Duration,
+    /// Сколько ждать подтверждения пакета (пакет отправляется дважды)
+    pub ack_timeout: Duration,
+    /// Keepalive канала: держит открытыми отображения NAT
+    pub keepalive_interval: Duration,
+    /// Канал без входящих датаграмм дольше этого закрывается
+    pub idle_timeout: Duration,
+    /// Пауза перед новой попыткой к узлу после неудачи
+    pub retry_after: Duration,
+}
+
+impl Default for DirectConfig {
+    fn default() -> Self {
+        Self {
+            punch_timeout: Duration::from_secs(5),
+            probe_interval: Duration::from_millis(200),
+            ack_timeout: Duration::from_secs(1),
+            keepalive_interval: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(60),
+            retry_after: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Счетчики прямых каналов
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct DirectStats {
+    /// Открытые прямые каналы
+    pub links: usize,
+    /// Пробитые каналы
+    pub punches: u64,
+    /// Неудачные попытки пробития
+    pub punch_failures: u64,
+    pub direct_sent: u64,
+    pub direct_received: u64,
+    /// Пакеты, отправленные через relay
+    pub relayed: u64,
+    /// Пакеты, ушедшие через relay после сбоя прямого канала
+    pub fallbacks: u64,
+}
+
+struct DirectLink {
+    link: PeerLink,
+    addr: SocketAddr,
+    /// Получен ответ на пробу: канал работает в обе стороны
+    established: bool,
+    /// Последняя проверенная входящая датаграмма
+    last_seen: Instant,
+    last_probe: Instant,
+}
+
+/// Ожидающий `Bound` токен: куда повторять `Bind` и с какого момента
+struct PendingBind {
+    rendezvous: SocketAddr,
+    since: Instant,
+}
+
+struct Inner {
+    config: DirectConfig,
+    socket: Arc<dyn PunchSocket>,
+    links: Mutex<HashMap<String, DirectLink>>,
+    /// Узлы, с которыми канал пробивается сейчас, и начало попытки
+    punching: Mutex<HashMap<String, Instant>>,
+    /// Время последней неудачи с узлом
+    failed: Mutex<HashMap<String, Instant>>,
+    /// Узлы, чьи предложения канала принимаются
+    allowed: Mutex<HashSet<String>>,
+    binds: Mutex<HashMap<BindToken, PendingBind>>,
+    acks: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    /// Уведомления об открытии и закрытии каналов
+    changed: watch::Sender<()>,
+    next_seq: AtomicU64,
+    callback: Mutex<Option<PacketCallback>>,
+    stats: Mutex<DirectStats>,
+}
+
+/// Фоновые задачи транспорта
+struct Running {
+    /// Узлы, которым нужно запросить `Punch` у relay
+    punch_requests: mpsc::UnboundedSender<String>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+/// Транспорт с прямыми каналами поверх relay
+pub struct DirectTransport {
+    relay: Arc<UdpTransport>,
+    inner: Arc<Inner>,
+    running: Mutex<Option<Running>>,
+}
+
+impl DirectTransport {
+    /// Прямые каналы через новый IPv4-сокет на случайном порту
+    pub async fn bind(relay: Arc<UdpTransport>, config: DirectConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        Ok(Self::with_socket(relay, config, Arc::new(socket)))
+    }
+
+    pub fn with_socket(relay: Arc<UdpTransport>, config: DirectConfig, socket: Arc<dyn PunchSocket>) -> Self {
+        let inner = Arc::new(Inner {
+            config,
+            socket,
+            links: Mutex::new(HashMap::new()),
+            punching: Mutex::new(HashMap::new()),
+            failed: Mutex::new(HashMap::new()),
+            allowed: Mutex::new(HashSet::new()),
+            binds: Mutex::new(HashMap::new()),
+            acks: Mutex::new(HashMap::new()),
+            changed: watch::channel(()).0,
+            next_seq: AtomicU64::new(1),
+            callback: Mutex::new(None),
+            stats: Mutex::new(DirectStats::default()),
+        });
+        Self { relay, inner, running: Mutex::new(None) }
+    }
+
+    /// Принимать предложения прямого канала от `node_id`
+    pub fn allow_peer(&self, node_id: &str) {
+        self.inner.allowed.lock().unwrap().insert(node_id.to_string());
+    }
+
+    /// Канал с `node_id` пробит и работает
+    pub fn is_direct(&self, node_id: &str) -> bool {
+        self.inner.links.lock().unwrap().get(node_id).is_some_and(|link| link.established)
+    }
+
+    pub fn stats(&self) -> DirectStats {
+        let mut stats = self.inner.stats.lock().unwrap().clone();
+        stats.links = self.inner.links.lock().unwrap().values().filter(|link| link.established).count();
+        stats
+    }
+
+    /// Пробить канал с `node_id` и дождаться результата. Недавняя неудача
+    /// не мешает явной попытке.
+    pub async fn connect_direct(&self, node_id: &str) -> Result<(), TransportError> {
+        self.allow_peer(node_id);
+        self.inner.failed.lock().unwrap().remove(node_id);
+        let mut changed = self.inner.changed.subscribe();
+        if self.is_direct(node_id) {
+            return Ok(());
+        }
+        self.request_punch(node_id)?;
+
+        let wait = async {
+            loop {
+                if self.is_direct(node_id) {
+                    return Ok(());
+                }
+                if !self.inner.punching.lock().unwrap().contains_key(node_id) {
+                    return Err(TransportError::SendFailed(format!("Punching to {} failed", node_id)));
+                }
+                if changed.changed().await.is_err() {
+                    return Err(TransportError::NotAvailable);
+                }
+            }
+        };
+        timeout(self.inner.config.punch_timeout + self.inner.config.probe_interval, wait)
+            .await
+            .map_err(|_| TransportError::Timeout)?
+    }
+
+    /// Запустить фоновые задачи, если они еще не запущены
+    fn ensure_started(&self) -> Result<mpsc::UnboundedSender<String>, TransportError> {
+        let mut running = self.running.lock().unwrap();
+        if let Some(running) = running.as_ref() {
+            return Ok(running.punch_requests.clone());
+        }
+        // Без relay каналы не пробить: проверяем настройки заранее
+        self.relay.config.endpoint()?;
+
+        let (punch_requests, requests) = mpsc::unbounded_channel();
+        let tasks = vec![
+            tokio::spawn(self.inner.clone().read_loop()),
+            tokio::spawn(self.inner.clone().signal_loop(self.relay.clone(), requests)),
+        ];
+        *running = Some(Running { punch_requests: punch_requests.clone(), tasks });
+        Ok(punch_requests)
+    }
+
+    /// Начать пробитие канала с `node_id`, если оно еще не идет
+    fn request_punch(&self, node_id: &str) -> Result<(), TransportError> {
+        let requests = self.ensure_started()?;
+        if self.inner.start_punch(node_id, Instant::now()) {
+            let _ = requests.send(node_id.to_string());
+        }
+        Ok(())
+    }
+
+    /// Отправить пакет по установленному каналу. `None` - канала нет.
+    async fn send_direct(&self, destination: &str, packet: Vec<u8>) -> Option<Result<(), TransportError>> {
+        let seq = self.inner.next_seq.fetch_add(1, Ordering::Relaxed);
+        let (link_id, addr, datagram) = {
+            let mut links = self.inner.links.lock().unwrap();
+            let link = links.get_mut(destination).filter(|link| link.established)?;
+            (link.link.link_id, link.addr, link.link.seal(&PeerDatagram::Data { seq, packet }))
+        };
+        let (tx, mut rx) = oneshot::channel();
+        self.inner.acks.lock().unwrap().insert(seq, tx);
+
+        for _ in 0..2 {
+            if self.inner.socket.send_to(&datagram, addr).await.is_err() {
+                break;
+            }
+            match timeout(self.inner.config.ack_timeout, &mut rx).await {
+                Ok(Ok(())) => {
+                    self.inner.stats.lock().unwrap().direct_sent += 1;
+                    return Some(Ok(()));
+                }
+                Ok(Err(_)) => break,
+                Err(_) => continue,
+            }
+        }
+
+        // Канал больше не отвечает: закрываем, пакет уйдет через relay
+        self.inner.acks.lock().unwrap().remove(&seq);
+        self.inner.close_link(destination, link_id);
+        Some(Err(TransportError::Timeout))
+    }
+
+    fn stop_tasks(&self) {
+        if let Some(running) = self.running.lock().unwrap().take() {
+            for task in running.tasks {
+                task.abort();
+            }
+        }
+        self.inner.links.lock().unwrap().clear();
+        self.inner.punching.lock().unwrap().clear();
+        self.inner.binds.lock().unwrap().clear();
+        self.inner.changed.send_replace(());
+    }
+}
+
+impl Drop for DirectTransport {
+    fn drop(&mut self) {
+        if let Some(running) = self.running.get_mut().unwrap().take() {
+            for task in running.tasks {
+                task.abort();
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// Отметить начало пробития; `false`, если канал уже есть, пробивается
+    /// или недавно не удался
+    fn start_punch(&self, peer: &str, now: Instant) -> bool {
+        if self.links.lock().unwrap().get(peer).is_some_and(|link| link.established) {
+            return false;
+        }
+        let recently_failed = self
+            .failed
+            .lock()
+            .unwrap()
+            .get(peer)
+            .is_some_and(|at| now.duration_since(*at) < self.config.retry_after);
+        if recently_failed {
+            return false;
+        }
+        let mut punching = self.punching.lock().unwrap();
+        if punching.contains_key(peer) {
+            return false;
+        }
+        punching.insert(peer.to_string(), now);
+        true
+    }
+
+    fn punch_failed(&self, peer: &str, now: Instant) {
+        if self.punching.lock().unwrap().remove(peer).is_none() {
+            return;
+        }
+        let mut links = self.links.lock().unwrap();
+        if !links.get(peer).is_some_and(|link| link.established) {
+            links.remove(peer);
+        }
+        drop(links);
+        self.failed.lock().unwrap().insert(peer.to_string(), now);
+        self.stats.lock().unwrap().punch_failures += 1;
+        tracing::debug!("Punching to {} failed", peer);
+        self.changed.send_replace(());
+    }
+
+    fn close_link(&self, peer: &str, link_id: u64) {
+        let mut links = self.links.lock().unwrap();
+        if links.get(peer).is_some_and(|link| link.link.link_id == link_id) {
+            links.remove(peer);
+            drop(links);
+            tracing::debug!("Direct link to {} closed", peer);
+            self.changed.send_replace(());
+        }
+    }
+
+    /// Датаграммы сокета и периодические пробы, keepalive и тайм-ауты
+    async fn read_loop(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_DIRECT_DATAGRAM];
+        let mut ticks = tokio::time::interval(self.config.probe_interval);
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((len, from)) => self.handle_datagram(&buf[..len], from).await,
+                    // Например, ICMP port unreachable на прошлую пробу
+                    Err(e) => tracing::debug!("Direct socket receive failed: {}", e),
+                },
+                _ = ticks.tick() => self.tick(Instant::now()).await,
+            }
+        }
+    }
+
+    async fn handle_datagram(&self, datagram: &[u8], from: SocketAddr) {
+        if is_rendezvous_datagram(datagram) {
+            if let Ok(RendezvousDatagram::Bound { token, observed }) = RendezvousDatagram::decode(datagram) {
+                if self.binds.lock().unwrap().remove(&token).is_some() {
+                    tracing::debug!("Rendezvous sees this node at {}", observed);
+                }
+            }
+            return;
+        }
+
+        let Some(link_id) = peer_link_id(datagram) else { return };
+
+        // Только свежие проверенные датаграммы двигают канал: NAT
+        // собеседника мог сменить порт, а повтор чужой записи - нет
+        let now = Instant::now();
+        let mut punched = false;
+        let (peer, opened, reply) = {
+            let mut links = self.links.lock().unwrap();
+            let Some((peer, entry)) = links.iter_mut().find(|(_, link)| link.link.link_id == link_id) else {
+                return;
+            };
+            let opened = match entry.link.open(datagram) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    tracing::debug!("Dropping datagram from {}: {}", from, e);
+                    return;
+                }
+            };
+            entry.addr = from;
+            entry.last_seen = now;
+            if matches!(opened, PeerDatagram::ProbeAck { .. }) && !entry.established {
+                entry.established = true;
+                punched = true;
+            }
+            let reply = match &opened {
+                PeerDatagram::Probe { nonce } => Some(entry.link.seal(&PeerDatagram::ProbeAck { nonce: *nonce })),
+                PeerDatagram::Data { seq, .. } => Some(entry.link.seal(&PeerDatagram::DataAck { seq: *seq })),
+                PeerDatagram::ProbeAck { .. } | PeerDatagram::DataAck { .. } => None,
+            };
+            (peer.clone(), opened, reply)
+        };
+        if punched {
+            self.punching.lock().unwrap().remove(&peer);
+            self.failed.lock().unwrap().remove(&peer);
+            self.stats.lock().unwrap().punches += 1;
+            tracing::info!("Direct link to {} at {}", peer, from);
+            self.changed.send_replace(());
+        }
+
+        if let Some(reply) = reply {
+            let _ = self.socket.send_to(&reply, from).await;
+        }
+        match opened {
+            PeerDatagram::Probe { .. } | PeerDatagram::ProbeAck { .. } => {}
+            PeerDatagram::Data { packet, .. } => {
+                self.stats.lock().unwrap().direct_received += 1;
+                let callback = self.callback.lock().unwrap().clone();
+                match (Packet::from_bytes(&packet), callback) {
+                    (Ok(packet), Some(callback)) => callback(packet),
+                    (Ok(_), None) => {}
+                    (Err(e), _) => tracing::warn!("Failed to deserialize packet from {}: {}", peer, e),
+                }
+            }
+            PeerDatagram::DataAck { seq } => {
+                if let Some(waiter) = self.acks.lock().unwrap().remove(&seq) {
+                    let _ = waiter.send(());
+                }
+            }
+        }
+    }
+
+    async fn tick(&self, now: Instant) {
+        let mut outgoing = Vec::new();
+
+        self.binds.lock().unwrap().retain(|token, bind| {
+            let pending = now.duration_since(bind.since) < self.config.punch_timeout;
+            if pending {
+                outgoing.push((RendezvousDatagram::Bind { token: *token }.encode(), bind.rendezvous));
+            }
+            pending
+        });
+
+        let expired: Vec<String> = self
+            .punching
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) >= self.config.punch_timeout)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in expired {
+            self.punch_failed(&peer, now);
+        }
+
+        let mut idle = Vec::new();
+        for (peer, link) in self.links.lock().unwrap().iter_mut() {
+            if link.established && now.duration_since(link.last_seen) >= self.config.idle_timeout {
+                idle.push((peer.clone(), link.link.link_id));
+                continue;
+            }
+            if !link.established || now.duration_since(link.last_probe) >= self.config.keepalive_interval {
+                link.last_probe = now;
+                outgoing.push((link.link.seal(&PeerDatagram::Probe { nonce: rand::random() }), link.addr));
+            }
+        }
+        for (peer, link_id) in idle {
+            self.close_link(&peer, link_id);
+        }
+
+        for (datagram, target) in outgoing {
+            let _ = self.socket.send_to(&datagram, target).await;
+        }
+    }
+
+    /// Сигналы rendezvous текущей сессии с relay и запросы на пробитие
+    async fn signal_loop(self: Arc<Self>, relay: Arc<UdpTransport>, mut requests: mpsc::UnboundedReceiver<String>) {
+        let mut backoff = Backoff::default();
+        loop {
+            let session = match relay.session().await {
+                Ok(session) => {
+                    backoff.reset();
+                    session
+                }
+                Err(e) => {
+                    tracing::debug!("Rendezvous waits for relay session: {}", e);
+                    tokio::time::sleep(backoff.next_delay()).await;
+                    continue;
+                }
+            };
+
+            // Запросы отправляются только после подписки: ответ не потеряется
+            let mut signals = session.rendezvous_signals();
+            loop {
+                tokio::select! {
+                    signal = signals.recv() => match signal {
+                        Ok(signal) => self.handle_signal(&session, &relay, signal).await,
+                        Err(RecvError::Lagged(_)) => {}
+                        Err(RecvError::Closed) => break,
+                    },
+                    peer = requests.recv() => {
+                        let Some(peer) = peer else { return };
+                        if session.punch(&peer).await.is_err() {
+                            self.punch_failed(&peer, Instant::now());
+                            break;
+                        }
+                    }
+                    _ = tokio::time::sleep(SESSION_CHECK_INTERVAL) => {
+                        if session.is_closed() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_signal(&self, session: &RelaySession, relay: &UdpTransport, signal: RendezvousSignal) {
+        let now = Instant::now();
+        match signal {
+            RendezvousSignal::Offer { peer } => {
+                if !self.allowed.lock().unwrap().contains(&peer) {
+                    tracing::debug!("Ignoring direct link offer from {}", peer);
+                    return;
+                }
+                self.failed.lock().unwrap().remove(&peer);
+                if self.start_punch(&peer, now) && session.punch(&peer).await.is_err() {
+                    self.punch_failed(&peer, now);
+                }
+            }
+            RendezvousSignal::Bind { peer, token, port } => {
+                if !self.punching.lock().unwrap().contains_key(&peer) {
+                    return;
+                }
+                let Some(rendezvous) = self.rendezvous_addr(relay, port).await else {
+                    self.punch_failed(&peer, now);
+                    return;
+                };
+                self.binds.lock().unwrap().insert(token, PendingBind { rendezvous, since: now });
+                let _ = self.socket.send_to(&RendezvousDatagram::Bind { token }.encode(), rendezvous).await;
+            }
+            RendezvousSignal::Introduce { peer, addr, mut link } => {
+                if !self.punching.lock().unwrap().contains_key(&peer) {
+                    return;
+                }
+                let probe = link.seal(&PeerDatagram::Probe { nonce: rand::random() });
+                self.links.lock().unwrap().insert(
+                    peer,
+                    DirectLink { link, addr, established: false, last_seen: now, last_probe: now },
+                );
+                let _ = self.socket.send_to(&probe, addr).await;
+            }
+        }
+    }
+
+    /// Адрес rendezvous: хост relay, порт из `PunchBind`, семейство сокета
+    async fn rendezvous_addr(&self, relay: &UdpTransport, port: u16) -> Option<SocketAddr> {
+        let local = self.socket.local_addr().ok()?;
+        let endpoint = relay.config.endpoint().ok()?;
+        let authority = Endpoint::new(endpoint.host, port).authority();
+        lookup_host(authority).await.ok()?.find(|addr| addr.is_ipv4() == local.is_ipv4())
+    }
+}
+
+/// Node id (а не метка получателя): с ним можно пробивать канал
+fn is_node_id(destination: &str) -> bool {
+    destination.len() == 64 && destination.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[async_trait]
+impl Transport for DirectTransport {
+    fn transport_type(&self) -> TransportType {
+        TransportType::Udp
+    }
+
+    async fn is_available(&self) -> bool {
+        self.relay.is_available().await
+    }
+
+    async fn send_packet(&self, packet: &Packet, destination: &str) -> Result<(), TransportError> {
+        let packet_bytes = packet
+            .to_wire_bytes()
+            .map_err(|e| TransportError::SendFailed(format!("Serialization failed: {}", e)))?;
+
+        match self.send_direct(destination, packet_bytes).await {
+            Some(Ok(())) => return Ok(()),
+            Some(Err(e)) => {
+                tracing::debug!("Direct send to {} failed, using relay: {}", destination, e);
+                self.inner.stats.lock().unwrap().fallbacks += 1;
+            }
+            None if is_node_id(destination) => {
+                // Собеседнику отвечаем напрямую: его предложение примем
+                self.allow_peer(destination);
+                self.request_punch(destination)?;
+            }
+            None => {}
+        }
+
+        self.relay.send_packet(packet, destination).await?;
+        self.inner.stats.lock().unwrap().relayed += 1;
+        Ok(())
+    }
+
+    async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError> {
+        self.relay.discover_peers().await
+    }
+
+    async fn start_listening(&self, callback: Box<dyn Fn(Packet) + Send + Sync>) -> Result<(), TransportError> {
+        let callback: PacketCallback = Arc::from(callback);
+        *self.inner.callback.lock().unwrap() = Some(callback.clone());
+        self.ensure_started()?;
+        self.relay.start_listening(Box::new(move |packet| callback(packet))).await
+    }
+
+    async fn stop_listening(&self) -> Result<(), TransportError> {
+        self.inner.callback.lock().unwrap().take();
+        self.stop_tasks();
+        self.relay.stop_listening().await
+    }
+}
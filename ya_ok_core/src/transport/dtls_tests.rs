@@ -2,7 +2,7 @@
 
 #[cfg(test)]
 mod tests {
-    use crate::transport::udp::{UdpTransport, UdpTransportConfig};
+    use crate::transport::udp::{RelayLink, UdpTransport, UdpTransportConfig};
     use crate::transport::Transport;
     use crate::core::{Message, StatusType, Identity};
 
@@ -52,7 +52,10 @@ mod tests {
         let config = UdpTransportConfig {
             relay_url: "example.com:443".to_string(),
             pinned_cert_fingerprint: Some(pinned_fingerprint.clone()),
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
         };
         
         let transport = UdpTransport::with_config(config);
@@ -76,3 +79,295 @@ mod tests {
         assert_eq!(MAX_PACKET_SIZE, 131072);
     }
 }
+
+/// Проверка сертификатов relay на локально выпущенных CA (Ed25519)
+#[cfg(test)]
+mod cert_validation {
+    use crate::transport::dtls::{tls_config_for, CertVerifyError, PinSet, PinnedCertVerifier};
+    use ed25519_dalek::{Signer, SigningKey};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use rustls::{RootCertStore, ServerConfig};
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    const ED25519_ALGORITHM: [u8; 7] = [0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70];
+    const DAY: i64 = 24 * 3600;
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = contents.len();
+        if len < 0x80 {
+            out.push(len as u8);
+        } else if len < 0x100 {
+            out.extend_from_slice(&[0x81, len as u8]);
+        } else {
+            out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+        }
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn seq(parts: &[Vec<u8>]) -> Vec<u8> {
+        der(0x30, &parts.concat())
+    }
+
+    fn name(common_name: &str) -> Vec<u8> {
+        let attribute = seq(&[der(0x06, &[0x55, 0x04, 0x03]), der(0x0c, common_name.as_bytes())]);
+        seq(&[der(0x31, &attribute)])
+    }
+
+    fn time(offset_secs: i64) -> Vec<u8> {
+        let at = chrono::Utc::now() + chrono::Duration::seconds(offset_secs);
+        der(0x18, at.format("%Y%m%d%H%M%SZ").to_string().as_bytes())
+    }
+
+    struct TestCert {
+        der: CertificateDer<'static>,
+        key: SigningKey,
+        common_name: String,
+    }
+
+    impl TestCert {
+        fn ca(common_name: &str) -> Self {
+            issue(common_name, None, true, None, 365 * DAY)
+        }
+
+        fn spki_pin(&self) -> String {
+            PinnedCertVerifier::spki_fingerprint(&self.der).unwrap()
+        }
+
+        fn pkcs8(&self) -> PrivateKeyDer<'static> {
+            let mut pkcs8 = vec![
+                0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+            ];
+            pkcs8.extend_from_slice(&self.key.to_bytes());
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pkcs8))
+        }
+    }
+
+    /// Выпустить сертификат; без `issuer` - самоподписанный
+    fn issue(common_name: &str, dns_name: Option<&str>, is_ca: bool, issuer: Option<&TestCert>, valid_for: i64) -> TestCert {
+        let key = SigningKey::from_bytes(&rand::random());
+        let mut public_key = vec![0x00];
+        public_key.extend_from_slice(key.verifying_key().as_bytes());
+
+        let mut extensions = Vec::new();
+        if is_ca {
+            let constraints = seq(&[der(0x01, &[0xff])]);
+            extensions.push(seq(&[der(0x06, &[0x55, 0x1d, 0x13]), der(0x01, &[0xff]), der(0x04, &constraints)]));
+        }
+        if let Some(dns_name) = dns_name {
+            let names = seq(&[der(0x82, dns_name.as_bytes())]);
+            extensions.push(seq(&[der(0x06, &[0x55, 0x1d, 0x11]), der(0x04, &names)]));
+        }
+
+        let mut serial = vec![0x01];
+        serial.extend_from_slice(&rand::random::<[u8; 8]>());
+
+        let mut tbs_parts = vec![
+            der(0xa0, &der(0x02, &[0x02])),
+            der(0x02, &serial),
+            ED25519_ALGORITHM.to_vec(),
+            name(issuer.map_or(common_name, |issuer| issuer.common_name.as_str())),
+            seq(&[time(-DAY), time(valid_for)]),
+            name(common_name),
+            seq(&[ED25519_ALGORITHM.to_vec(), der(0x03, &public_key)]),
+        ];
+        if !extensions.is_empty() {
+            tbs_parts.push(der(0xa3, &seq(&extensions)));
+        }
+        let tbs = seq(&tbs_parts);
+
+        let signer = issuer.map_or(&key, |issuer| &issuer.key);
+        let mut signature = vec![0x00];
+        signature.extend_from_slice(&signer.sign(&tbs).to_bytes());
+        let cert = seq(&[tbs, ED25519_ALGORITHM.to_vec(), der(0x03, &signature)]);
+
+        TestCert { der: CertificateDer::from(cert), key, common_name: common_name.to_string() }
+    }
+
+    fn leaf(dns_name: &str, issuer: &TestCert) -> TestCert {
+        issue(dns_name, Some(dns_name), false, Some(issuer), 90 * DAY)
+    }
+
+    fn verifier(root: &TestCert, pins: Option<PinSet>) -> PinnedCertVerifier {
+        let mut roots = RootCertStore::empty();
+        roots.add(root.der.clone()).unwrap();
+        PinnedCertVerifier::with_roots(roots, pins).unwrap()
+    }
+
+    fn relay_name() -> ServerName<'static> {
+        ServerName::try_from("relay.test").unwrap()
+    }
+
+    fn unix_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn test_chain_from_local_ca_accepted() {
+        let root = TestCert::ca("Test Root");
+        let intermediate = issue("Test Intermediate", None, true, Some(&root), 180 * DAY);
+        let cert = leaf("relay.test", &intermediate);
+
+        let result = verifier(&root, None).verify_chain(&cert.der, &[intermediate.der.clone()], &relay_name(), UnixTime::now());
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn test_unknown_ca_rejected() {
+        let root = TestCert::ca("Test Root");
+        let rogue = TestCert::ca("Rogue Root");
+        let cert = leaf("relay.test", &rogue);
+
+        let result = verifier(&root, None).verify_chain(&cert.der, &[], &relay_name(), UnixTime::now());
+        assert!(matches!(result, Err(CertVerifyError::UntrustedChain(_))));
+    }
+
+    #[test]
+    fn test_wrong_hostname_rejected() {
+        let root = TestCert::ca("Test Root");
+        let cert = leaf("other.test", &root);
+
+        let result = verifier(&root, None).verify_chain(&cert.der, &[], &relay_name(), UnixTime::now());
+        assert_eq!(result, Err(CertVerifyError::NameMismatch("relay.test".to_string())));
+    }
+
+    #[test]
+    fn test_expired_certificate_rejected() {
+        let root = TestCert::ca("Test Root");
+        let cert = issue("relay.test", Some("relay.test"), false, Some(&root), -3600);
+
+        let result = verifier(&root, None).verify_chain(&cert.der, &[], &relay_name(), UnixTime::now());
+        assert_eq!(result, Err(CertVerifyError::Expired));
+    }
+
+    #[test]
+    fn test_spki_pins_with_backups() {
+        let root = TestCert::ca("Test Root");
+        let intermediate = issue("Test Intermediate", None, true, Some(&root), 180 * DAY);
+        let cert = leaf("relay.test", &intermediate);
+        let chain = [intermediate.der.clone()];
+        let unrelated = TestCert::ca("Unrelated").spki_pin();
+
+        // Любой ключ на проверенном пути: лист, промежуточный или корневой CA
+        for pinned in [cert.spki_pin(), intermediate.spki_pin(), root.spki_pin().to_lowercase().replace(':', "")] {
+            let pins = PinSet::new(vec![unrelated.clone(), pinned]);
+            let result = verifier(&root, Some(pins)).verify_chain(&cert.der, &chain, &relay_name(), UnixTime::now());
+            assert_eq!(result, Ok(()));
+        }
+
+        let pins = PinSet::new(vec![unrelated.clone()]);
+        let result = verifier(&root, Some(pins)).verify_chain(&cert.der, &chain, &relay_name(), UnixTime::now());
+        match result {
+            Err(CertVerifyError::PinMismatch { chain }) => {
+                assert_eq!(chain, vec![cert.spki_pin(), intermediate.spki_pin(), root.spki_pin()]);
+            }
+            other => panic!("expected pin mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expired_pins_not_enforced() {
+        let root = TestCert::ca("Test Root");
+        let cert = leaf("relay.test", &root);
+        let unrelated = TestCert::ca("Unrelated").spki_pin();
+
+        let stale = PinSet::new(vec![unrelated.clone()]).with_expiry(unix_now() - 60);
+        assert!(!stale.is_enforced(unix_now()));
+        let result = verifier(&root, Some(stale)).verify_chain(&cert.der, &[], &relay_name(), UnixTime::now());
+        assert_eq!(result, Ok(()));
+
+        let current = PinSet::new(vec![unrelated]).with_expiry(unix_now() + 3600);
+        let result = verifier(&root, Some(current)).verify_chain(&cert.der, &[], &relay_name(), UnixTime::now());
+        assert!(matches!(result, Err(CertVerifyError::PinMismatch { .. })));
+    }
+
+    #[test]
+    fn test_pins_do_not_bypass_chain_validation() {
+        let root = TestCert::ca("Test Root");
+        let rogue = TestCert::ca("Rogue Root");
+        let cert = leaf("relay.test", &rogue);
+
+        let pins = PinSet::new(vec![cert.spki_pin()]);
+        let result = verifier(&root, Some(pins)).verify_chain(&cert.der, &[], &relay_name(), UnixTime::now());
+        assert!(matches!(result, Err(CertVerifyError::UntrustedChain(_))));
+    }
+
+    /// Цепочка и ключ сервера без проверки их соответствия
+    #[derive(Debug)]
+    struct FixedCert(Arc<CertifiedKey>);
+
+    impl ResolvesServerCert for FixedCert {
+        fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+            Some(self.0.clone())
+        }
+    }
+
+    async fn handshake(
+        client_verifier: PinnedCertVerifier,
+        chain: Vec<CertificateDer<'static>>,
+        key: &TestCert,
+        versions: &[&'static rustls::SupportedProtocolVersion],
+    ) -> Result<(), std::io::Error> {
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let signing_key = provider.key_provider.load_private_key(key.pkcs8()).unwrap();
+        let server_config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(FixedCert(Arc::new(CertifiedKey::new(chain, signing_key)))));
+
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let connector = TlsConnector::from(tls_config_for(client_verifier).unwrap());
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let (client, _server) = tokio::join!(
+            connector.connect(relay_name(), client_io),
+            acceptor.accept(server_io),
+        );
+        client.map(|_| ())
+    }
+
+    fn rejection(error: &std::io::Error) -> Option<CertVerifyError> {
+        let inner = error.get_ref()?.downcast_ref::<rustls::Error>()?;
+        CertVerifyError::from_tls_error(inner)
+    }
+
+    #[tokio::test]
+    async fn test_tls_handshake_with_local_ca() {
+        let root = TestCert::ca("Test Root");
+        let cert = leaf("relay.test", &root);
+
+        for versions in [&[&rustls::version::TLS13][..], &[&rustls::version::TLS12][..]] {
+            let pins = PinSet::new(vec![root.spki_pin()]);
+            let result = handshake(verifier(&root, Some(pins)), vec![cert.der.clone()], &cert, versions).await;
+            assert!(result.is_ok(), "{:?}", result);
+        }
+
+        let pins = PinSet::new(vec![TestCert::ca("Unrelated").spki_pin()]);
+        let error = handshake(verifier(&root, Some(pins)), vec![cert.der.clone()], &cert, &[&rustls::version::TLS13])
+            .await
+            .unwrap_err();
+        assert!(matches!(rejection(&error), Some(CertVerifyError::PinMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_signature_verified() {
+        let root = TestCert::ca("Test Root");
+        let cert = leaf("relay.test", &root);
+        // Чужой ключ с подлинной цепочкой relay: подпись рукопожатия не сходится
+        let impostor = TestCert::ca("Impostor");
+
+        for versions in [&[&rustls::version::TLS13][..], &[&rustls::version::TLS12][..]] {
+            let pins = PinSet::new(vec![cert.spki_pin()]);
+            let error = handshake(verifier(&root, Some(pins)), vec![cert.der.clone()], &impostor, versions)
+                .await
+                .unwrap_err();
+            assert_eq!(rejection(&error), Some(CertVerifyError::BadSignature));
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! Fragmentation - фрагментация пакетов под MTU транспорта
+//!
+//! Слой между `TransportManager` и конкретными транспортами:
+//! - пакет больше MTU разбивается на кадры-фрагменты (`ChunkedMessage`),
+//!   идентифицируемые содержимым пакета (`fragment_id`): повторная
+//!   сериализация того же пакета даёт те же фрагменты, и сборку можно
+//!   закончить при следующей встрече;
+//! - на приёме фрагменты собираются отдельно для каждого отправителя
+//!   (сборка определяется парой отправитель + `fragment_id` и к другому
+//!   отправителю не переходит) с ограничением памяти, незавершённые сборки
+//!   удаляются по таймауту.
+//!
+//! Формат кадра-фрагмента (big-endian):
+//! ```text
+//! [magic "YF1" (3)][fragment id (16)][index (2)][total (2)][crc32 (4)][data...]
+//! ```
+//! Целый пакет всегда начинается с CBOR map, поэтому кадры не путаются.
+//!
+//! Кадр-NACK (selective repeat, получатель -> отправитель):
+//! ```text
+//! [magic "YN1" (3)][fragment id (16)][total (2)][received bitmap...]
+//! ```
+//!
+//! Кадр FEC-shard (односторонние каналы, пакет собирается из любых k из n):
+//! ```text
+//! [magic "YE1" (3)][fragment id (16)][index (2)][k (2)][n (2)][packet len (4)][crc32 (4)][data...]
+//! ```
+//! FEC-сборки не привязаны к адресу отправителя: на односторонних каналах
+//! адрес (BLE advertising) ротируется, поэтому лимиты памяти общие.
+
+use crate::core::Packet;
+use crate::transport::chunking::{
+    ChunkNack, ChunkReassembler, ChunkedMessage, FecError, FecReassembler, FecShard, ReassemblyError,
+    ReassemblySnapshot,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Префикс кадра-фрагмента
+pub const FRAGMENT_MAGIC: &[u8; 3] = b"YF1";
+
+/// Префикс кадра-NACK
+pub const NACK_MAGIC: &[u8; 3] = b"YN1";
+
+/// Префикс кадра FEC-shard
+pub const FEC_MAGIC: &[u8; 3] = b"YE1";
+
+/// Сколько хранить незавершённую сборку без новых фрагментов.
+/// Достаточно, чтобы голосовое сообщение досылалось при следующей встрече.
+pub const PARTIAL_RETENTION: Duration = Duration::from_secs(30 * 60);
+
+/// Длина хеша пакета в заголовке фрагмента (байты)
+pub const PACKET_HASH_LEN: usize = 16;
+
+/// Размер заголовка фрагмента
+pub const FRAGMENT_HEADER_LEN: usize = FRAGMENT_MAGIC.len() + PACKET_HASH_LEN + 2 + 2 + 4;
+
+/// Размер заголовка FEC-shard
+pub const FEC_HEADER_LEN: usize = FEC_MAGIC.len() + PACKET_HASH_LEN + 2 + 2 + 2 + 4 + 4;
+
+/// Максимальный объём незавершённых сборок от одного отправителя
+pub const MAX_BUFFERED_BYTES_PER_SENDER: usize = 256 * 1024;
+
+/// Максимальное количество одновременных сборок от одного отправителя
+pub const MAX_PENDING_PER_SENDER: usize = 8;
+
+/// Максимальное количество отправителей с незавершёнными сборками
+pub const MAX_SENDERS: usize = 64;
+
+/// Максимальное количество одновременных FEC-сборок
+pub const MAX_FEC_PENDING: usize = 32;
+
+/// Максимальный объём незавершённых FEC-сборок
+pub const MAX_FEC_BUFFERED_BYTES: usize = 1024 * 1024;
+
+/// Хеш пакета для заголовка фрагмента (первые 16 байт SHA-256, hex)
+pub fn packet_hash(packet_bytes: &[u8]) -> String {
+    let digest = Sha256::digest(packet_bytes);
+    hex::encode(&digest[..PACKET_HASH_LEN])
+}
+
+/// Идентификатор фрагментов: `Packet::content_id` (не зависит от `age`),
+/// для данных, которые не являются пакетом, - [`packet_hash`].
+/// Им же проверяется собранный пакет.
+pub fn fragment_id(packet_bytes: &[u8]) -> String {
+    match Packet::from_bytes(packet_bytes).ok().and_then(|packet| packet.content_id().ok()) {
+        Some(id) => hex::encode(id),
+        None => packet_hash(packet_bytes),
+    }
+}
+
+/// Является ли кадр фрагментом пакета
+pub fn is_fragment(frame: &[u8]) -> bool {
+    frame.starts_with(FRAGMENT_MAGIC)
+}
+
+/// Является ли кадр NACK-ом
+pub fn is_nack(frame: &[u8]) -> bool {
+    frame.starts_with(NACK_MAGIC)
+}
+
+/// Является ли кадр FEC-shard
+pub fn is_fec_fragment(frame: &[u8]) -> bool {
+    frame.starts_with(FEC_MAGIC)
+}
+
+/// Разбить сериализованный пакет на кадры, каждый не больше `mtu`
+pub fn fragment_packet(packet_bytes: &[u8], mtu: usize) -> Result<Vec<Vec<u8>>, FragmentationError> {
+    fragment_chunks(packet_bytes, mtu)?.iter().map(encode_fragment).collect()
+}
+
+/// Разбить сериализованный пакет на фрагменты под `mtu` (без кодирования в кадры)
+pub fn fragment_chunks(packet_bytes: &[u8], mtu: usize) -> Result<Vec<ChunkedMessage>, FragmentationError> {
+    if mtu <= FRAGMENT_HEADER_LEN {
+        return Err(FragmentationError::MtuTooSmall(mtu));
+    }
+
+    let chunk_size = mtu - FRAGMENT_HEADER_LEN;
+    if packet_bytes.len().div_ceil(chunk_size) > u16::MAX as usize {
+        return Err(FragmentationError::TooManyFragments);
+    }
+
+    Ok(ChunkedMessage::chunk_payload_sized(fragment_id(packet_bytes), packet_bytes, chunk_size))
+}
+
+/// Закодировать фрагмент в кадр
+pub fn encode_fragment(chunk: &ChunkedMessage) -> Result<Vec<u8>, FragmentationError> {
+    let hash = hex::decode(&chunk.message_id).map_err(|_| FragmentationError::Malformed)?;
+    if hash.len() != PACKET_HASH_LEN {
+        return Err(FragmentationError::Malformed);
+    }
+
+    let mut frame = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.data.len());
+    frame.extend_from_slice(FRAGMENT_MAGIC);
+    frame.extend_from_slice(&hash);
+    frame.extend_from_slice(&chunk.chunk_index.to_be_bytes());
+    frame.extend_from_slice(&chunk.total_chunks.to_be_bytes());
+    frame.extend_from_slice(&chunk.checksum.unwrap_or_else(|| crc32fast::hash(&chunk.data)).to_be_bytes());
+    frame.extend_from_slice(&chunk.data);
+    Ok(frame)
+}
+
+/// Декодировать кадр во фрагмент
+pub fn decode_fragment(frame: &[u8]) -> Result<ChunkedMessage, FragmentationError> {
+    if !is_fragment(frame) || frame.len() < FRAGMENT_HEADER_LEN {
+        return Err(FragmentationError::Malformed);
+    }
+
+    let (hash, rest) = frame[FRAGMENT_MAGIC.len()..].split_at(PACKET_HASH_LEN);
+    let chunk_index = u16::from_be_bytes([rest[0], rest[1]]);
+    let total_chunks = u16::from_be_bytes([rest[2], rest[3]]);
+    let checksum = u32::from_be_bytes([rest[4], rest[5], rest[6], rest[7]]);
+
+    Ok(ChunkedMessage {
+        message_id: hex::encode(hash),
+        chunk_index,
+        total_chunks,
+        data: rest[8..].to_vec(),
+        checksum: Some(checksum),
+    })
+}
+
+/// Закодировать пакет в FEC-кадры под `mtu` с избыточностью `overhead_percent`
+pub fn fec_fragment_packet(packet_bytes: &[u8], mtu: usize, overhead_percent: u16) -> Result<Vec<Vec<u8>>, FragmentationError> {
+    if mtu <= FEC_HEADER_LEN {
+        return Err(FragmentationError::MtuTooSmall(mtu));
+    }
+
+    let shards = FecShard::encode_payload(fragment_id(packet_bytes), packet_bytes, mtu - FEC_HEADER_LEN, overhead_percent)?;
+    shards.iter().map(encode_fec_fragment).collect()
+}
+
+/// Закодировать FEC-shard в кадр
+pub fn encode_fec_fragment(shard: &FecShard) -> Result<Vec<u8>, FragmentationError> {
+    let hash = hex::decode(&shard.message_id).map_err(|_| FragmentationError::Malformed)?;
+    if hash.len() != PACKET_HASH_LEN {
+        return Err(FragmentationError::Malformed);
+    }
+
+    let mut frame = Vec::with_capacity(FEC_HEADER_LEN + shard.data.len());
+    frame.extend_from_slice(FEC_MAGIC);
+    frame.extend_from_slice(&hash);
+    frame.extend_from_slice(&shard.shard_index.to_be_bytes());
+    frame.extend_from_slice(&shard.data_shards.to_be_bytes());
+    frame.extend_from_slice(&shard.total_shards.to_be_bytes());
+    frame.extend_from_slice(&shard.payload_len.to_be_bytes());
+    frame.extend_from_slice(&shard.checksum.unwrap_or_else(|| crc32fast::hash(&shard.data)).to_be_bytes());
+    frame.extend_from_slice(&shard.data);
+    Ok(frame)
+}
+
+/// Декодировать кадр в FEC-shard
+pub fn decode_fec_fragment(frame: &[u8]) -> Result<FecShard, FragmentationError> {
+    if !is_fec_fragment(frame) || frame.len() < FEC_HEADER_LEN {
+        return Err(FragmentationError::Malformed);
+    }
+
+    let (hash, rest) = frame[FEC_MAGIC.len()..].split_at(PACKET_HASH_LEN);
+    Ok(FecShard {
+        message_id: hex::encode(hash),
+        shard_index: u16::from_be_bytes([rest[0], rest[1]]),
+        data_shards: u16::from_be_bytes([rest[2], rest[3]]),
+        total_shards: u16::from_be_bytes([rest[4], rest[5]]),
+        payload_len: u32::from_be_bytes([rest[6], rest[7], rest[8], rest[9]]),
+        checksum: Some(u32::from_be_bytes([rest[10], rest[11], rest[12], rest[13]])),
+        data: rest[14..].to_vec(),
+    })
+}
+
+/// Закодировать NACK в кадр
+pub fn encode_nack(nack: &ChunkNack) -> Result<Vec<u8>, FragmentationError> {
+    let hash = hex::decode(&nack.message_id).map_err(|_| FragmentationError::Malformed)?;
+    if hash.len() != PACKET_HASH_LEN {
+        return Err(FragmentationError::Malformed);
+    }
+
+    let mut frame = Vec::with_capacity(NACK_MAGIC.len() + PACKET_HASH_LEN + 2 + nack.received_bitmap.len());
+    frame.extend_from_slice(NACK_MAGIC);
+    frame.extend_from_slice(&hash);
+    frame.extend_from_slice(&nack.total_chunks.to_be_bytes());
+    frame.extend_from_slice(&nack.received_bitmap);
+    Ok(frame)
+}
+
+/// Декодировать кадр-NACK
+pub fn decode_nack(frame: &[u8]) -> Result<ChunkNack, FragmentationError> {
+    let header_len = NACK_MAGIC.len() + PACKET_HASH_LEN + 2;
+    if !is_nack(frame) || frame.len() < header_len {
+        return Err(FragmentationError::Malformed);
+    }
+
+    let (hash, rest) = frame[NACK_MAGIC.len()..].split_at(PACKET_HASH_LEN);
+    let total_chunks = u16::from_be_bytes([rest[0], rest[1]]);
+    let received_bitmap = rest[2..].to_vec();
+    if received_bitmap.len() != (total_chunks as usize).div_ceil(8) {
+        return Err(FragmentationError::Malformed);
+    }
+
+    Ok(ChunkNack {
+        message_id: hex::encode(hash),
+        total_chunks,
+        received_bitmap,
+    })
+}
+
+/// Сборщик фрагментов с раздельным учётом памяти по отправителям
+pub struct FragmentReassembler {
+    /// Карта: адрес отправителя -> его сборки
+    senders: HashMap<String, ChunkReassembler>,
+    /// FEC-сборки (общие для всех отправителей)
+    fec: FecReassembler,
+}
+
+impl FragmentReassembler {
+    pub fn new() -> Self {
+        Self {
+            senders: HashMap::new(),
+            fec: FecReassembler::new(),
+        }
+    }
+
+    /// Добавить кадр-фрагмент от отправителя.
+    /// Возвращает байты пакета, когда собраны все фрагменты.
+    pub fn add_fragment(&mut self, sender: &str, frame: &[u8]) -> Result<Option<Vec<u8>>, FragmentationError> {
+        let chunk = decode_fragment(frame)?;
+
+        if !self.senders.contains_key(sender) && self.senders.len() >= MAX_SENDERS {
+            self.cleanup_expired();
+            if self.senders.len() >= MAX_SENDERS {
+                return Err(FragmentationError::TooManySenders);
+            }
+        }
+
+        let reassembler = self.senders
+            .entry(sender.to_string())
+            .or_insert_with(|| ChunkReassembler::with_timeout(PARTIAL_RETENTION));
+
+        // Лимиты памяти на отправителя
+        if !reassembler.is_pending(&chunk.message_id)
+            && reassembler.pending_count() >= MAX_PENDING_PER_SENDER
+        {
+            return Err(FragmentationError::SenderQuotaExceeded);
+        }
+        if reassembler.buffered_bytes() + chunk.data.len() > MAX_BUFFERED_BYTES_PER_SENDER {
+            return Err(FragmentationError::SenderQuotaExceeded);
+        }
+
+        let expected_hash = chunk.message_id.clone();
+        let result = reassembler.add_chunk(chunk)?;
+
+        if reassembler.pending_count() == 0 {
+            self.senders.remove(sender);
+        }
+
+        match result {
+            Some(packet_bytes) => {
+                if fragment_id(&packet_bytes) != expected_hash {
+                    return Err(FragmentationError::HashMismatch);
+                }
+                Ok(Some(packet_bytes))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Добавить кадр FEC-shard. Возвращает байты пакета, как только
+    /// получены любые k из n shards.
+    pub fn add_fec_fragment(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, FragmentationError> {
+        let shard = decode_fec_fragment(frame)?;
+
+        if !self.fec.is_pending(&shard.message_id) && self.fec.pending_count() >= MAX_FEC_PENDING {
+            self.fec.cleanup_expired();
+            if self.fec.pending_count() >= MAX_FEC_PENDING {
+                return Err(FragmentationError::SenderQuotaExceeded);
+            }
+        }
+        if self.fec.buffered_bytes() + shard.data.len() > MAX_FEC_BUFFERED_BYTES {
+            return Err(FragmentationError::SenderQuotaExceeded);
+        }
+
+        // Сборка с неверным хешем уже удалена: подлинные shards начнут её заново
+        let expected_hash = shard.message_id.clone();
+        match self.fec.add_shard(shard)? {
+            Some(packet_bytes) => {
+                if fragment_id(&packet_bytes) != expected_hash {
+                    return Err(FragmentationError::HashMismatch);
+                }
+                self.fec.mark_completed(&expected_hash);
+                Ok(Some(packet_bytes))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Кадры-NACK для всех незавершённых сборок отправителя
+    pub fn nack_frames(&self, sender: &str) -> Vec<Vec<u8>> {
+        self.senders
+            .get(sender)
+            .map(|reassembler| {
+                reassembler
+                    .nacks()
+                    .iter()
+                    .filter_map(|nack| encode_nack(nack).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Кадры-NACK для незавершённых сборок всех отправителей
+    pub fn all_nack_frames(&self) -> Vec<Vec<u8>> {
+        let mut senders = self.senders.keys().collect::<Vec<_>>();
+        senders.sort();
+        senders.into_iter().flat_map(|sender| self.nack_frames(sender)).collect()
+    }
+
+    /// Сохранить незавершённые сборки в файл (CBOR)
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), FragmentationError> {
+        let snapshot: HashMap<String, ReassemblySnapshot> = self.senders
+            .iter()
+            .map(|(sender, reassembler)| (sender.clone(), reassembler.snapshot()))
+            .collect();
+
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&snapshot, &mut bytes)
+            .map_err(|e| FragmentationError::Persistence(e.to_string()))?;
+        std::fs::write(path, bytes).map_err(|e| FragmentationError::Persistence(e.to_string()))
+    }
+
+    /// Загрузить незавершённые сборки из файла. Отсутствующий файл - не ошибка.
+    pub fn load_from_file<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, FragmentationError> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(FragmentationError::Persistence(e.to_string())),
+        };
+
+        let snapshot: HashMap<String, ReassemblySnapshot> = ciborium::de::from_reader(&bytes[..])
+            .map_err(|e| FragmentationError::Persistence(e.to_string()))?;
+
+        let mut restored = 0;
+        for (sender, sender_snapshot) in snapshot.into_iter().take(MAX_SENDERS) {
+            let reassembler = self.senders
+                .entry(sender)
+                .or_insert_with(|| ChunkReassembler::with_timeout(PARTIAL_RETENTION));
+            restored += reassembler.restore(sender_snapshot);
+        }
+        self.senders.retain(|_, reassembler| reassembler.pending_count() > 0);
+
+        Ok(restored)
+    }
+
+    /// Удалить истекшие сборки у всех отправителей
+    pub fn cleanup_expired(&mut self) -> usize {
+        let mut removed = 0;
+        for reassembler in self.senders.values_mut() {
+            removed += reassembler.cleanup_expired();
+        }
+        self.senders.retain(|_, reassembler| reassembler.pending_count() > 0);
+        removed + self.fec.cleanup_expired()
+    }
+
+    /// Количество незавершённых сборок (по всем отправителям, включая FEC)
+    pub fn pending_count(&self) -> usize {
+        self.senders.values().map(|r| r.pending_count()).sum::<usize>() + self.fec.pending_count()
+    }
+}
+
+impl Default for FragmentReassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ошибки фрагментации
+#[derive(Debug, thiserror::Error)]
+pub enum FragmentationError {
+    #[error("MTU too small for fragmentation: {0} bytes")]
+    MtuTooSmall(usize),
+
+    #[error("Too many fragments")]
+    TooManyFragments,
+
+    #[error("Malformed fragment")]
+    Malformed,
+
+    #[error("Reassembled packet hash mismatch")]
+    HashMismatch,
+
+    #[error("Sender reassembly quota exceeded")]
+    SenderQuotaExceeded,
+
+    #[error("Too many senders with pending reassembly")]
+    TooManySenders,
+
+    #[error("Reassembly error: {0}")]
+    Reassembly(#[from] ReassemblyError),
+
+    #[error("FEC error: {0}")]
+    Fec(#[from] FecError),
+
+    #[error("Persistence error: {0}")]
+    Persistence(String),
+}
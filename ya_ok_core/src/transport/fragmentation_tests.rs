@@ -0,0 +1,348 @@
+//! Tests for packet fragmentation between TransportManager and transports
+
+#[cfg(test)]
+mod tests {
+    use crate::transport::ble::{BleTransport, BleNativeInterface, BLE_MTU};
+    use crate::transport::fragmentation::{
+        self, FragmentReassembler, FragmentationError, MAX_PENDING_PER_SENDER,
+    };
+    use crate::transport::chunking::{ChunkingMode, ReassemblyError};
+    use crate::transport::{IncomingFrame, TransportManager, TransportType};
+    use crate::core::{Identity, Message, MessagePayload, Packet, StatusType};
+    use std::sync::Mutex;
+
+    // Frames captured by the mock GATT layer
+    static SENT_FRAMES: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+    extern "C" fn mock_start_advertising(_data: *const u8, _len: usize) -> i32 { 0 }
+    extern "C" fn mock_stop_advertising() -> i32 { 0 }
+    extern "C" fn mock_start_scanning() -> i32 { 0 }
+    extern "C" fn mock_stop_scanning() -> i32 { 0 }
+    extern "C" fn mock_is_ble_available() -> i32 { 1 }
+    extern "C" fn mock_capture_gatt_packet(_addr: *const u8, _addr_len: usize, data: *const u8, data_len: usize) -> i32 {
+        let frame = unsafe { std::slice::from_raw_parts(data, data_len) }.to_vec();
+        SENT_FRAMES.lock().unwrap().push(frame);
+        0
+    }
+
+    fn create_capturing_interface() -> BleNativeInterface {
+        BleNativeInterface {
+            start_advertising: mock_start_advertising,
+            stop_advertising: mock_stop_advertising,
+            start_scanning: mock_start_scanning,
+            stop_scanning: mock_stop_scanning,
+            send_gatt_packet: mock_capture_gatt_packet,
+            is_ble_available: mock_is_ble_available,
+        }
+    }
+
+    fn create_packet(message: &Message, sender: &Identity, receiver: &Identity) -> Packet {
+        Packet::from_message(message, sender, &receiver.x25519_public_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_fragments_fit_mtu_and_reassemble() {
+        let payload: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        let frames = fragmentation::fragment_packet(&payload, BLE_MTU).unwrap();
+
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|f| f.len() <= BLE_MTU));
+        assert!(frames.iter().all(|f| fragmentation::is_fragment(f)));
+
+        let mut reassembler = FragmentReassembler::new();
+        let mut result = None;
+        // Reverse order
+        for frame in frames.iter().rev() {
+            result = reassembler.add_fragment("AA:BB", frame).unwrap();
+        }
+
+        assert_eq!(result, Some(payload));
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_senders_are_reassembled_separately() {
+        let payload_a = vec![1u8; 2_000];
+        let payload_b = vec![2u8; 2_000];
+        let frames_a = fragmentation::fragment_packet(&payload_a, BLE_MTU).unwrap();
+        let frames_b = fragmentation::fragment_packet(&payload_b, BLE_MTU).unwrap();
+
+        let mut reassembler = FragmentReassembler::new();
+        for frame in &frames_a[..frames_a.len() - 1] {
+            assert!(reassembler.add_fragment("peer-a", frame).unwrap().is_none());
+        }
+        for frame in &frames_b[..frames_b.len() - 1] {
+            assert!(reassembler.add_fragment("peer-b", frame).unwrap().is_none());
+        }
+        assert_eq!(reassembler.pending_count(), 2);
+
+        let done_a = reassembler.add_fragment("peer-a", frames_a.last().unwrap()).unwrap();
+        assert_eq!(done_a, Some(payload_a));
+        let done_b = reassembler.add_fragment("peer-b", frames_b.last().unwrap()).unwrap();
+        assert_eq!(done_b, Some(payload_b));
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_assembly_not_taken_over_by_other_sender() {
+        let payload = vec![3u8; 2_000];
+        let frames = fragmentation::fragment_packet(&payload, BLE_MTU).unwrap();
+
+        let mut reassembler = FragmentReassembler::new();
+        for frame in &frames[..frames.len() - 1] {
+            assert!(reassembler.add_fragment("AA:AA", frame).unwrap().is_none());
+        }
+        // A fragment with the same id from another address starts its own assembly
+        assert!(reassembler.add_fragment("BB:BB", frames.last().unwrap()).unwrap().is_none());
+        assert_eq!(reassembler.pending_count(), 2);
+
+        let done = reassembler.add_fragment("AA:AA", frames.last().unwrap()).unwrap();
+        assert_eq!(done, Some(payload));
+        assert_eq!(reassembler.pending_count(), 1);
+    }
+
+    #[test]
+    fn test_conflicting_duplicate_fragment_rejected() {
+        let payload = vec![5u8; 2_000];
+        let frames = fragmentation::fragment_packet(&payload, BLE_MTU).unwrap();
+
+        let mut reassembler = FragmentReassembler::new();
+        assert!(reassembler.add_fragment("peer", &frames[0]).unwrap().is_none());
+        // Identical repeat is fine
+        assert!(reassembler.add_fragment("peer", &frames[0]).unwrap().is_none());
+
+        let mut chunk = fragmentation::decode_fragment(&frames[0]).unwrap();
+        chunk.data[0] ^= 0xFF;
+        chunk.checksum = Some(crc32fast::hash(&chunk.data));
+        let forged = fragmentation::encode_fragment(&chunk).unwrap();
+        assert!(matches!(
+            reassembler.add_fragment("peer", &forged),
+            Err(FragmentationError::Reassembly(ReassemblyError::ConflictingChunk))
+        ));
+
+        // The original data is kept and the packet still completes
+        let mut result = None;
+        for frame in &frames[1..] {
+            result = reassembler.add_fragment("peer", frame).unwrap();
+        }
+        assert_eq!(result, Some(payload));
+    }
+
+    #[test]
+    fn test_hash_mismatch_rejected() {
+        let payload = vec![7u8; 1_000];
+        let frames = fragmentation::fragment_packet(&payload, BLE_MTU).unwrap();
+
+        let mut reassembler = FragmentReassembler::new();
+        let mut result = Ok(None);
+        for frame in &frames {
+            let mut chunk = fragmentation::decode_fragment(frame).unwrap();
+            chunk.data[0] ^= 0xFF;
+            chunk.checksum = Some(crc32fast::hash(&chunk.data));
+            let tampered = fragmentation::encode_fragment(&chunk).unwrap();
+            result = reassembler.add_fragment("peer", &tampered);
+        }
+
+        assert!(matches!(result, Err(FragmentationError::HashMismatch)));
+    }
+
+    #[test]
+    fn test_per_sender_pending_cap() {
+        let mut reassembler = FragmentReassembler::new();
+
+        for i in 0..MAX_PENDING_PER_SENDER {
+            let payload = vec![i as u8; 1_000];
+            let frames = fragmentation::fragment_packet(&payload, BLE_MTU).unwrap();
+            reassembler.add_fragment("greedy", &frames[0]).unwrap();
+        }
+
+        let frames = fragmentation::fragment_packet(&[0xEE; 1_000], BLE_MTU).unwrap();
+        let result = reassembler.add_fragment("greedy", &frames[0]);
+        assert!(matches!(result, Err(FragmentationError::SenderQuotaExceeded)));
+
+        // Other senders are not affected
+        assert!(reassembler.add_fragment("polite", &frames[0]).is_ok());
+    }
+
+    #[test]
+    fn test_mtu_too_small() {
+        let result = fragmentation::fragment_packet(&[0u8; 100], 16);
+        assert!(matches!(result, Err(FragmentationError::MtuTooSmall(16))));
+    }
+
+    #[test]
+    fn test_whole_packet_passes_through() {
+        let manager = TransportManager::new();
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let packet = create_packet(&Message::status(sender.id.clone(), StatusType::Ok), &sender, &receiver);
+        let bytes = packet.to_bytes().unwrap();
+
+        let result = manager.handle_incoming_frame("peer", &bytes).unwrap();
+        assert_eq!(result, IncomingFrame::Packet(bytes));
+    }
+
+    #[tokio::test]
+    async fn test_voice_and_text_over_ble_end_to_end() {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+
+        let mut sending = TransportManager::new();
+        sending.add_transport(Box::new(BleTransport::with_interface(create_capturing_interface())));
+        let receiving = TransportManager::new();
+
+        let voice = Message::voice(sender.id.clone(), vec![0x55; 12_000]).unwrap();
+        let text = Message::text(sender.id.clone(), "Я в укритті, все добре".to_string()).unwrap();
+
+        for message in [voice, text] {
+            SENT_FRAMES.lock().unwrap().clear();
+            let packet = create_packet(&message, &sender, &receiver);
+            sending.send_packet(&packet, "AA:BB:CC:DD:EE:FF").await.unwrap();
+
+            let frames = std::mem::take(&mut *SENT_FRAMES.lock().unwrap());
+            assert!(frames.iter().all(|f| f.len() <= BLE_MTU));
+
+            let mut reassembled = IncomingFrame::Pending;
+            for frame in &frames {
+                reassembled = receiving.handle_incoming_frame("AA:BB:CC:DD:EE:FF", frame).unwrap();
+            }
+
+            let IncomingFrame::Packet(packet_bytes) = reassembled else {
+                panic!("Packet was not reassembled");
+            };
+            let received = Packet::from_bytes(&packet_bytes).unwrap();
+            let decrypted = received.decrypt(&receiver).unwrap();
+            match (&decrypted.payload, &message.payload) {
+                (MessagePayload::Voice(a), MessagePayload::Voice(b)) => assert_eq!(a, b),
+                (MessagePayload::Text(a), MessagePayload::Text(b)) => assert_eq!(a, b),
+                _ => panic!("Payload type mismatch"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_voice_completes_across_two_encounters() {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("fragments.cbor");
+
+        let mut sending = TransportManager::new();
+        sending.add_transport(Box::new(BleTransport::with_interface(create_capturing_interface())));
+
+        let voice = Message::voice(sender.id.clone(), vec![0x33; 8_000]).unwrap();
+        let packet = create_packet(&voice, &sender, &receiver);
+        SENT_FRAMES.lock().unwrap().clear();
+        sending.send_packet(&packet, "11:22:33:44:55:66").await.unwrap();
+        let frames = std::mem::take(&mut *SENT_FRAMES.lock().unwrap());
+        assert!(frames.len() > 4);
+
+        // First encounter: the link drops after half of the fragments
+        let first = TransportManager::new();
+        for frame in &frames[..frames.len() / 2] {
+            assert_eq!(first.handle_incoming_frame("11:22:33:44:55:66", frame).unwrap(), IncomingFrame::Pending);
+        }
+        first.save_partial_fragments(&state_file).unwrap();
+        drop(first);
+
+        // Second encounter with the same peer after an app restart
+        let second = TransportManager::new();
+        assert_eq!(second.load_partial_fragments(&state_file).unwrap(), 1);
+        let nacks = second.pending_nacks("11:22:33:44:55:66");
+        assert_eq!(nacks.len(), 1);
+
+        // Sender answers the NACK with the missing fragments only
+        let IncomingFrame::Retransmit(resent) = sending.handle_incoming_frame("77:88", &nacks[0]).unwrap() else {
+            panic!("Expected retransmission");
+        };
+        assert_eq!(resent.len(), frames.len() - frames.len() / 2);
+
+        let mut result = IncomingFrame::Pending;
+        for frame in &resent {
+            result = second.handle_incoming_frame("11:22:33:44:55:66", frame).unwrap();
+        }
+        let IncomingFrame::Packet(packet_bytes) = result else {
+            panic!("Packet was not reassembled");
+        };
+        let decrypted = Packet::from_bytes(&packet_bytes).unwrap().decrypt(&receiver).unwrap();
+        assert!(matches!(decrypted.payload, MessagePayload::Voice(ref data) if data.len() == 8_000));
+    }
+
+    #[test]
+    fn test_nack_frame_roundtrip() {
+        let nack = crate::transport::chunking::ChunkNack::from_received(
+            fragmentation::packet_hash(b"packet"), 12, [0, 5, 11],
+        );
+        let frame = fragmentation::encode_nack(&nack).unwrap();
+
+        assert!(fragmentation::is_nack(&frame));
+        assert!(!fragmentation::is_fragment(&frame));
+        assert_eq!(fragmentation::decode_nack(&frame).unwrap(), nack);
+    }
+
+    #[tokio::test]
+    async fn test_fec_survives_lossy_one_way_link() {
+        let sender = Identity::new();
+        let receiver = Identity::new();
+
+        let mut sending = TransportManager::new();
+        sending.add_transport(Box::new(BleTransport::with_interface(create_capturing_interface())));
+        sending.set_chunking_mode(TransportType::Ble, ChunkingMode::Fec { overhead_percent: 50 });
+        let receiving = TransportManager::new();
+
+        let voice = Message::voice(sender.id.clone(), vec![0x77; 6_000]).unwrap();
+        let packet = create_packet(&voice, &sender, &receiver);
+        SENT_FRAMES.lock().unwrap().clear();
+        sending.send_packet(&packet, "FF:FF:FF:FF:FF:FF").await.unwrap();
+        let frames = std::mem::take(&mut *SENT_FRAMES.lock().unwrap());
+        assert!(frames.iter().all(|f| f.len() <= BLE_MTU && fragmentation::is_fec_fragment(f)));
+
+        // Every third frame is lost and nothing is ever retransmitted
+        let mut result = IncomingFrame::Pending;
+        for (i, frame) in frames.iter().enumerate().filter(|(i, _)| i % 3 != 0) {
+            let incoming = receiving.handle_incoming_frame(&format!("adv-{}", i), frame).unwrap();
+            if incoming != IncomingFrame::Pending {
+                result = incoming;
+            }
+        }
+
+        let IncomingFrame::Packet(packet_bytes) = result else {
+            panic!("Packet was not recovered");
+        };
+        let decrypted = Packet::from_bytes(&packet_bytes).unwrap().decrypt(&receiver).unwrap();
+        assert!(matches!(decrypted.payload, MessagePayload::Voice(ref data) if data.len() == 6_000));
+    }
+
+    #[test]
+    fn test_forged_fec_shard_does_not_block_message() {
+        let payload: Vec<u8> = (0..4_000u32).map(|i| (i % 253) as u8).collect();
+        let frames = fragmentation::fec_fragment_packet(&payload, BLE_MTU, 50).unwrap();
+
+        // A forged shard with a known id and a valid CRC arrives first
+        let mut forged = fragmentation::decode_fec_fragment(&frames[0]).unwrap();
+        forged.data.iter_mut().for_each(|byte| *byte ^= 0x5A);
+        forged.checksum = Some(crc32fast::hash(&forged.data));
+        let forged = fragmentation::encode_fec_fragment(&forged).unwrap();
+
+        let mut reassembler = FragmentReassembler::new();
+        assert!(reassembler.add_fec_fragment(&forged).unwrap().is_none());
+        let mut mismatched = false;
+        for frame in &frames[1..] {
+            match reassembler.add_fec_fragment(frame) {
+                Err(FragmentationError::HashMismatch) => mismatched = true,
+                result => assert!(result.unwrap().is_none()),
+            }
+        }
+        assert!(mismatched);
+
+        // The next round of genuine shards still completes the message
+        let mut result = None;
+        for frame in &frames {
+            if let Some(bytes) = reassembler.add_fec_fragment(frame).unwrap() {
+                result = Some(bytes);
+            }
+        }
+        assert_eq!(result, Some(payload));
+        assert_eq!(reassembler.pending_count(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+//! Подключение к relay по TCP в стиле Happy Eyeballs (RFC 8305)
+//!
+//! Имя relay может разрешаться в IPv6 и IPv4 адреса, и один из путей
+//! бывает сломан (IPv6 без маршрута, фильтрующий NAT64). Адреса
+//! перемежаются по семействам, начиная с IPv6; следующая попытка
+//! стартует, когда предыдущая не ответила за `ATTEMPT_DELAY` или
+//! сразу после ее ошибки. Побеждает первое установленное соединение,
+//! остальные попытки отменяются.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use yaok_relay_proto::Endpoint;
+
+/// Задержка перед следующей попыткой (рекомендация RFC 8305)
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Разрешить имя relay и подключиться к самому быстрому адресу
+pub async fn connect(endpoint: &Endpoint) -> io::Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(endpoint.authority()).await?.collect();
+    let stream = race(interleave(addrs), ATTEMPT_DELAY, TcpStream::connect).await?;
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+/// Порядок попыток: IPv6 и IPv4 по очереди, начиная с IPv6, без повторов
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let mut unique = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = unique.into_iter().partition(SocketAddr::is_ipv6);
+    v6.reverse();
+    v4.reverse();
+
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop());
+        ordered.extend(v4.pop());
+    }
+    ordered
+}
+
+/// Запускать `connect` по адресам с интервалом `delay`, вернуть первое
+/// успешное соединение или последнюю ошибку
+pub async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, delay: Duration, connect: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+{
+    let mut pending = addrs.into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        // Новая попытка, если прошлые еще идут или все уже завершились ошибкой
+        if let Some(addr) = pending.next() {
+            attempts.spawn(connect(addr));
+        } else if attempts.is_empty() {
+            return Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No addresses to connect to")));
+        }
+
+        tokio::select! {
+            Some(result) = attempts.join_next() => match result {
+                // JoinSet отменяет оставшиеся попытки при drop
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = Some(e),
+                Err(e) => last_error = Some(io::Error::other(e)),
+            },
+            _ = tokio::time::sleep(delay), if !pending.as_slice().is_empty() => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn interleave_alternates_families_starting_with_ipv6() {
+        let addrs = vec![
+            addr("192.0.2.1:40100"),
+            addr("192.0.2.2:40100"),
+            addr("[2001:db8::1]:40100"),
+            addr("192.0.2.1:40100"),
+            addr("[::1]:40100"),
+            addr("192.0.2.3:40100"),
+        ];
+        assert_eq!(
+            interleave(addrs),
+            vec![
+                addr("[2001:db8::1]:40100"),
+                addr("192.0.2.1:40100"),
+                addr("[::1]:40100"),
+                addr("192.0.2.2:40100"),
+                addr("192.0.2.3:40100"),
+            ]
+        );
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn stalled_address_does_not_block_the_next_one() {
+        // Первый адрес не отвечает вовсе (IPv6 без маршрута)
+        let started = Instant::now();
+        let winner = race(vec![addr("[2001:db8::1]:1"), addr("[::1]:2")], Duration::from_millis(50), |addr| async move {
+            if addr.port() == 1 {
+                std::future::pending::<()>().await;
+            }
+            Ok(addr)
+        })
+        .await
+        .unwrap();
+        assert_eq!(winner, addr("[::1]:2"));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn failed_attempt_starts_the_next_one_at_once() {
+        let started = Instant::now();
+        let winner = race(vec![addr("[::1]:1"), addr("127.0.0.1:2")], Duration::from_secs(30), |addr| async move {
+            if addr.is_ipv6() {
+                Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            } else {
+                Ok(addr)
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(winner, addr("127.0.0.1:2"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let error = race(vec![addr("[::1]:1"), addr("127.0.0.1:1")], Duration::from_secs(30), |_| async {
+            Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(race(Vec::new(), ATTEMPT_DELAY, |_| async { Ok(()) }).await.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn connects_to_ipv6_loopback_and_falls_back_to_ipv4() {
+        let v6 = TcpListener::bind("[::1]:0").await.unwrap();
+        let endpoint: Endpoint = format!("[::1]:{}", v6.local_addr().unwrap().port()).parse().unwrap();
+        let stream = connect(&endpoint).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v6.local_addr().unwrap());
+
+        // Порт занят только на IPv4: попытка по ::1 отклоняется, побеждает IPv4
+        let v4 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = v4.local_addr().unwrap().port();
+        let addrs = vec![addr(&format!("127.0.0.1:{}", port)), addr(&format!("[::1]:{}", port))];
+        let stream = race(interleave(addrs), ATTEMPT_DELAY, TcpStream::connect).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), v4.local_addr().unwrap());
+    }
+}
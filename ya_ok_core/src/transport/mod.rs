@@ -3,7 +3,7 @@
 //! Абстракция над различными каналами связи:
 //! - BLE (Bluetooth Low Energy)
 //! - Wi-Fi Direct
-//! - UDP/IP (когда есть интернет)
+//! - UDP/IP (когда есть интернет), в том числе прямые каналы между узлами
 //! - Спутниковые каналы (абстракция)
 
 pub mod ble;
@@ -11,7 +11,13 @@ pub mod wifi_direct;
 pub mod udp;
 pub mod satellite;
 pub mod chunking;
+pub mod fragmentation;
 pub mod dtls;
+pub mod happy_eyeballs;
+pub mod relay;
+pub mod relay_connection;
+pub mod relay_pool;
+pub mod direct;
 
 #[cfg(test)]
 mod udp_tests;
@@ -19,10 +25,17 @@ mod udp_tests;
 mod dtls_tests;
 #[cfg(test)]
 mod ble_tests;
+#[cfg(test)]
+mod fragmentation_tests;
 
 use crate::core::Packet;
 use async_trait::async_trait;
+use chunking::{ChunkingMode, RetransmitWindow};
+use fragmentation::{FragmentReassembler, FragmentationError};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
 
 /// Тип транспортного канала
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -45,6 +58,17 @@ pub trait Transport: Send + Sync {
     /// Отправить пакет
     async fn send_packet(&self, packet: &Packet, destination: &str) -> Result<(), TransportError>;
 
+    /// Максимальный размер кадра (байты). `None` - без ограничения,
+    /// пакеты передаются целиком через `send_packet`.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// Отправить сырой кадр (фрагмент пакета)
+    async fn send_frame(&self, _frame: &[u8], _destination: &str) -> Result<(), TransportError> {
+        Err(TransportError::NotSupported)
+    }
+
     /// Получить доступные узлы для обнаружения
     async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError>;
 
@@ -74,15 +98,35 @@ pub struct Peer {
     pub x25519_public_key: Option<Vec<u8>>,
 }
 
+/// Результат обработки входящего кадра
+#[derive(Debug, PartialEq)]
+pub enum IncomingFrame {
+    /// Пакет целиком (получен сразу или собран из фрагментов)
+    Packet(Vec<u8>),
+    /// Фрагмент принят, пакет ещё не собран
+    Pending,
+    /// Получен NACK: кадры для повторной отправки этому отправителю
+    Retransmit(Vec<Vec<u8>>),
+}
+
 /// Менеджер транспорта
 pub struct TransportManager {
     transports: Vec<Box<dyn Transport>>,
+    /// Сборка входящих фрагментов
+    reassembler: Mutex<FragmentReassembler>,
+    /// Отправленные фрагменты для selective repeat
+    retransmit: Mutex<RetransmitWindow>,
+    /// Режим фрагментации, заданный для транспортов (иначе `ChunkingMode::default_for`)
+    chunking_modes: Mutex<HashMap<TransportType, ChunkingMode>>,
 }
 
 impl TransportManager {
     pub fn new() -> Self {
         Self {
             transports: Vec::new(),
+            reassembler: Mutex::new(FragmentReassembler::new()),
+            retransmit: Mutex::new(RetransmitWindow::new()),
+            chunking_modes: Mutex::new(HashMap::new()),
         }
     }
 
@@ -107,8 +151,29 @@ impl TransportManager {
             .map(|t| t.as_ref())
     }
 
+    /// Задать режим фрагментации для транспорта
+    pub fn set_chunking_mode(&self, transport_type: TransportType, mode: ChunkingMode) {
+        if let Ok(mut modes) = self.chunking_modes.lock() {
+            modes.insert(transport_type, mode);
+        }
+    }
+
+    /// Режим фрагментации транспорта
+    pub fn chunking_mode(&self, transport_type: &TransportType) -> ChunkingMode {
+        self.chunking_modes
+            .lock()
+            .ok()
+            .and_then(|modes| modes.get(transport_type).copied())
+            .unwrap_or_else(|| ChunkingMode::default_for(transport_type))
+    }
+
     /// Отправить пакет через лучший доступный транспорт
     pub async fn send_packet(&self, packet: &Packet, destination: &str) -> Result<(), TransportError> {
+        // Добавляем время пребывания на этом узле к возрасту пакета
+        let mut packet = packet.clone();
+        packet.stamp_age();
+        let packet = &packet;
+
         // Выбираем транспорт по приоритету
         let transport_order = [
             TransportType::Udp,        // Интернет - самый быстрый
@@ -120,7 +185,7 @@ impl TransportManager {
         for transport_type in &transport_order {
             if let Some(transport) = self.transports.iter().find(|t| t.transport_type() == *transport_type) {
                 if transport.is_available().await {
-                    return transport.send_packet(packet, destination).await;
+                    return self.send_via(transport.as_ref(), packet, destination).await;
                 }
             }
         }
@@ -128,6 +193,125 @@ impl TransportManager {
         Err(TransportError::NoTransportAvailable)
     }
 
+    /// Отправить пакет через конкретный транспорт, фрагментируя по его MTU
+    /// в режиме, заданном для транспорта
+    pub async fn send_via(&self, transport: &dyn Transport, packet: &Packet, destination: &str) -> Result<(), TransportError> {
+        if let Some(mtu) = transport.mtu() {
+            let packet_bytes = packet.to_wire_bytes()
+                .map_err(|e| TransportError::SendFailed(format!("Serialization failed: {}", e)))?;
+
+            if let Some(frames) = self.frame_packet(&transport.transport_type(), &packet_bytes, mtu)? {
+                for frame in frames {
+                    transport.send_frame(&frame, destination).await?;
+                }
+                return Ok(());
+            }
+        }
+
+        transport.send_packet(packet, destination).await
+    }
+
+    /// Разбить сериализованный пакет на кадры не больше `mtu` в режиме,
+    /// заданном для транспорта. `None` - пакет уходит одним кадром как есть.
+    /// Фрагменты запоминаются для повторной отправки по NACK.
+    pub fn frame_packet(&self, transport_type: &TransportType, packet_bytes: &[u8], mtu: usize) -> Result<Option<Vec<Vec<u8>>>, TransportError> {
+        match self.chunking_mode(transport_type) {
+            ChunkingMode::Fec { overhead_percent } => {
+                // Обратного канала нет: избыточность нужна и пакету в один кадр
+                Ok(Some(fragmentation::fec_fragment_packet(packet_bytes, mtu, overhead_percent)?))
+            }
+            ChunkingMode::Plain if packet_bytes.len() > mtu => {
+                let chunks = fragmentation::fragment_chunks(packet_bytes, mtu)?;
+                let frames = chunks
+                    .iter()
+                    .map(fragmentation::encode_fragment)
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // Запоминаем фрагменты до отправки: NACK может прийти в любой момент
+                if let Ok(mut window) = self.retransmit.lock() {
+                    window.cleanup_expired();
+                    window.record(chunks);
+                }
+                Ok(Some(frames))
+            }
+            ChunkingMode::Plain => Ok(None),
+        }
+    }
+
+    /// Обработать входящий кадр от отправителя `sender` (адрес транспорта):
+    /// целый пакет, фрагмент, FEC-shard или NACK на ранее отправленные фрагменты.
+    pub fn handle_incoming_frame(&self, sender: &str, frame: &[u8]) -> Result<IncomingFrame, TransportError> {
+        if fragmentation::is_nack(frame) {
+            let nack = fragmentation::decode_nack(frame)?;
+            let mut window = self.retransmit.lock()
+                .map_err(|_| TransportError::ReceiveFailed("Retransmit window lock poisoned".to_string()))?;
+            let frames = window
+                .handle_nack(&nack)
+                .iter()
+                .map(fragmentation::encode_fragment)
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(IncomingFrame::Retransmit(frames));
+        }
+
+        let is_fec = fragmentation::is_fec_fragment(frame);
+        if !is_fec && !fragmentation::is_fragment(frame) {
+            return Ok(IncomingFrame::Packet(frame.to_vec()));
+        }
+
+        let mut reassembler = self.reassembler.lock()
+            .map_err(|_| TransportError::ReceiveFailed("Reassembler lock poisoned".to_string()))?;
+        reassembler.cleanup_expired();
+        let result = if is_fec {
+            reassembler.add_fec_fragment(frame)?
+        } else {
+            reassembler.add_fragment(sender, frame)?
+        };
+        match result {
+            Some(packet_bytes) => Ok(IncomingFrame::Packet(packet_bytes)),
+            None => Ok(IncomingFrame::Pending),
+        }
+    }
+
+    /// Кадры-NACK с битовой картой полученных фрагментов для всех
+    /// незавершённых сборок от `sender` (отправляются при встрече с ним)
+    pub fn pending_nacks(&self, sender: &str) -> Vec<Vec<u8>> {
+        self.reassembler.lock().map(|r| r.nack_frames(sender)).unwrap_or_default()
+    }
+
+    /// Кадры-NACK для незавершённых сборок от всех отправителей
+    /// (для каналов, где кадры получают все соседи)
+    pub fn all_pending_nacks(&self) -> Vec<Vec<u8>> {
+        self.reassembler.lock().map(|r| r.all_nack_frames()).unwrap_or_default()
+    }
+
+    /// Отправить готовые кадры (повторные фрагменты, NACK) через транспорт данного типа
+    pub async fn send_frames(&self, transport_type: TransportType, frames: &[Vec<u8>], destination: &str) -> Result<(), TransportError> {
+        let transport = self.get_transport(transport_type).ok_or(TransportError::NotAvailable)?;
+        for frame in frames {
+            transport.send_frame(frame, destination).await?;
+        }
+        Ok(())
+    }
+
+    /// Очистить истекшие сборки фрагментов
+    pub fn cleanup_expired_fragments(&self) -> usize {
+        self.reassembler.lock().map(|mut r| r.cleanup_expired()).unwrap_or(0)
+    }
+
+    /// Сохранить незавершённые сборки фрагментов
+    pub fn save_partial_fragments<P: AsRef<Path>>(&self, path: P) -> Result<(), TransportError> {
+        let reassembler = self.reassembler.lock()
+            .map_err(|_| TransportError::ReceiveFailed("Reassembler lock poisoned".to_string()))?;
+        Ok(reassembler.save_to_file(path)?)
+    }
+
+    /// Загрузить сохранённые незавершённые сборки фрагментов
+    pub fn load_partial_fragments<P: AsRef<Path>>(&self, path: P) -> Result<usize, TransportError> {
+        let mut reassembler = self.reassembler.lock()
+            .map_err(|_| TransportError::ReceiveFailed("Reassembler lock poisoned".to_string()))?;
+        Ok(reassembler.load_from_file(path)?)
+    }
+
     /// Обнаружить всех доступных пиров
     pub async fn discover_all_peers(&self) -> Result<Vec<Peer>, TransportError> {
         let mut all_peers = Vec::new();
@@ -181,9 +365,15 @@ pub enum TransportError {
     #[error("Security error: {0}")]
     SecurityError(String),
 
+    #[error("Relay certificate rejected: {0}")]
+    Certificate(dtls::CertVerifyError),
+
     #[error("Transport not supported")]
     NotSupported,
     
     #[error("Already listening")]
     AlreadyListening,
+
+    #[error("Fragmentation error: {0}")]
+    Fragmentation(#[from] FragmentationError),
 }
\ No newline at end of file
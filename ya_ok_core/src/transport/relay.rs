@@ -0,0 +1,481 @@
+//! Клиент протокола relay (см. `yaok-relay-proto`)
+//!
+//! Одна аутентифицированная сессия на соединение: Hello/Challenge/Auth,
+//! keepalive Ping/Pong и адресная доставка `Send` → `Deliver` по node id.
+//! Пакеты для узлов не в сети relay хранит в почтовом ящике и доставляет
+//! при подключении; такие доставки подтверждаются `Ack` (см. `ack`).
+//! Узел может получать пакеты и по сменяемым меткам получателя
+//! (`register_tags`); рассылка всем (`flood`) - только для экстренных пакетов.
+//! Через relay узлы договариваются о прямом UDP-канале (`punch`, сигналы
+//! rendezvous - `rendezvous_signals`, см. `direct`).
+//! Спящий телефон relay будит push-уведомлением без содержимого
+//! (`register_push`), когда для него сохранен пакет.
+//! Соединение - поток (TCP/TLS) или зашифрованные датаграммы (`dtls`).
+
+use crate::core::Identity;
+use crate::transport::TransportError;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use yaok_relay_proto::pow::{solve_pow, MAX_POW_DIFFICULTY};
+use yaok_relay_proto::rendezvous::{BindToken, PeerLink};
+use yaok_relay_proto::tags::{encode_tags, RecipientTag};
+use yaok_relay_proto::{
+    auth_payload, register_payload, stream_link, DeliveryStatus, ErrorCode, MessageLink, ProtoError, RelayMessage,
+    PROTOCOL_VERSION,
+};
+
+/// Время на рукопожатие с relay
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Время ожидания `SendResult`
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Очередь входящих доставок
+const INCOMING_QUEUE: usize = 256;
+
+/// Очередь сигналов rendezvous
+const SIGNAL_QUEUE: usize = 64;
+
+/// Пакет, доставленный через relay
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    /// Node id отправителя (подтвержден relay при аутентификации)
+    pub from: String,
+    pub packet: Vec<u8>,
+    /// Id в почтовом ящике relay: пакет хранится там, пока не подтвержден
+    pub mailbox_id: Option<u64>,
+}
+
+/// Сигнал rendezvous от relay (см. `yaok_relay_proto::rendezvous`)
+#[derive(Debug, Clone)]
+pub enum RendezvousSignal {
+    /// Узел `peer` просит прямой канал с этим узлом
+    Offer { peer: String },
+    /// Для канала с `peer`: отправить `Bind { token }` на порт rendezvous
+    Bind { peer: String, token: BindToken, port: u16 },
+    /// Оба узла согласны: пробивать канал к `addr`
+    Introduce { peer: String, addr: SocketAddr, link: PeerLink },
+}
+
+struct Shared {
+    pending: Mutex<HashMap<u64, oneshot::Sender<DeliveryStatus>>>,
+    /// Ожидающие `Pong` на `ping`
+    pongs: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    /// Ожидающие `Registered`; relay отвечает по порядку
+    registrations: Mutex<VecDeque<oneshot::Sender<u32>>>,
+    /// Ожидающие `PushRegistered`; тоже по порядку
+    push_registrations: Mutex<VecDeque<oneshot::Sender<bool>>>,
+    next_ping: AtomicU64,
+    last_seen: Mutex<Instant>,
+    closed: AtomicBool,
+}
+
+impl Shared {
+    fn mark_closed(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // Ожидающие `send` получат ошибку закрытой сессии
+        self.pending.lock().unwrap().clear();
+        self.pongs.lock().unwrap().clear();
+        self.registrations.lock().unwrap().clear();
+        self.push_registrations.lock().unwrap().clear();
+    }
+}
+
+/// Аутентифицированная сессия с relay
+pub struct RelaySession {
+    identity: Identity,
+    outgoing: mpsc::Sender<RelayMessage>,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<Delivery>>,
+    signals: broadcast::Sender<RendezvousSignal>,
+    shared: Arc<Shared>,
+    next_seq: AtomicU64,
+    task: JoinHandle<()>,
+}
+
+impl RelaySession {
+    /// Выполнить рукопожатие поверх готового потока без привязки к каналу
+    /// (TCP; для TLS см. `dtls::tls_link`) и запустить фоновую задачу сессии
+    pub async fn establish<S>(stream: S, identity: &Identity) -> Result<Self, TransportError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self::establish_link(stream_link(stream), identity).await
+    }
+
+    /// То же поверх готового канала сообщений (например, UDP, см. `dtls`)
+    pub async fn establish_link(mut link: MessageLink, identity: &Identity) -> Result<Self, TransportError> {
+        let keepalive_secs = timeout(HANDSHAKE_TIMEOUT, handshake(&mut link, identity))
+            .await
+            .map_err(|_| TransportError::Timeout)??;
+
+        let shared = Arc::new(Shared {
+            pending: Mutex::new(HashMap::new()),
+            pongs: Mutex::new(HashMap::new()),
+            registrations: Mutex::new(VecDeque::new()),
+            push_registrations: Mutex::new(VecDeque::new()),
+            next_ping: AtomicU64::new(1),
+            last_seen: Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        });
+        let (in_tx, in_rx) = mpsc::channel(INCOMING_QUEUE);
+        let (signals, _) = broadcast::channel(SIGNAL_QUEUE);
+
+        let task = tokio::spawn(run(
+            link.incoming,
+            link.outgoing.clone(),
+            shared.clone(),
+            in_tx,
+            signals.clone(),
+            identity.id.clone(),
+            Duration::from_secs(u64::from(keepalive_secs.max(1))),
+        ));
+
+        Ok(Self {
+            identity: identity.clone(),
+            outgoing: link.outgoing,
+            incoming: tokio::sync::Mutex::new(in_rx),
+            signals,
+            shared,
+            next_seq: AtomicU64::new(1),
+            task,
+        })
+    }
+
+    /// Node id, под которым зарегистрирована сессия
+    pub fn node_id(&self) -> &str {
+        &self.identity.id
+    }
+
+    /// Отправить пакет узлу `to` и дождаться результата доставки.
+    /// Если получатель не в сети, relay хранит пакет до `ttl_secs`
+    /// (не дольше своего предела); сохраненный пакет считается отправленным.
+    pub async fn send(&self, to: &str, packet: Vec<u8>, ttl_secs: Option<u32>) -> Result<(), TransportError> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let message = RelayMessage::Send { seq, to: to.to_string(), packet, ttl_secs };
+        match self.request(seq, message).await? {
+            DeliveryStatus::Delivered | DeliveryStatus::Stored => Ok(()),
+            DeliveryStatus::Offline => {
+                Err(TransportError::SendFailed(format!("Recipient {} is not connected to relay", to)))
+            }
+            DeliveryStatus::MailboxFull => Err(TransportError::SendFailed(format!("Relay mailbox of {} is full", to))),
+            status => Err(TransportError::SendFailed(format!("Relay rejected packet: {:?}", status))),
+        }
+    }
+
+    /// Разослать экстренный пакет всем сессиям relay. Relay допускает
+    /// одну рассылку от узла за интервал, остальные получают `RateLimited`.
+    pub async fn flood(&self, packet: Vec<u8>) -> Result<(), TransportError> {
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        match self.request(seq, RelayMessage::Flood { seq, packet }).await? {
+            DeliveryStatus::Delivered => Ok(()),
+            DeliveryStatus::Offline => Err(TransportError::SendFailed("No other node is connected to relay".to_string())),
+            status => Err(TransportError::SendFailed(format!("Relay rejected flood: {:?}", status))),
+        }
+    }
+
+    /// Отправить `Send`/`Flood` с номером `seq` и дождаться `SendResult`
+    async fn request(&self, seq: u64, message: RelayMessage) -> Result<DeliveryStatus, TransportError> {
+        if self.is_closed() {
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(seq, tx);
+
+        if self.outgoing.send(message).await.is_err() {
+            self.shared.pending.lock().unwrap().remove(&seq);
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        match timeout(SEND_TIMEOUT, rx).await {
+            Ok(Ok(status)) => Ok(status),
+            Ok(Err(_)) => Err(TransportError::SendFailed("Relay session closed".to_string())),
+            Err(_) => {
+                self.shared.pending.lock().unwrap().remove(&seq);
+                Err(TransportError::Timeout)
+            }
+        }
+    }
+
+    /// Зарегистрировать метки получателя до `expires_at` (unix секунды),
+    /// заменив прежние. Relay сокращает слишком долгий срок и не отдает
+    /// метки, занятые другим узлом; возвращает число принятых меток.
+    pub async fn register_tags(&self, tags: &[RecipientTag], expires_at: u64) -> Result<u32, TransportError> {
+        if self.is_closed() {
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        let tags = encode_tags(tags);
+        let signature = self
+            .identity
+            .sign(&register_payload(&self.identity.id, &tags, expires_at))
+            .map_err(|e| TransportError::SecurityError(format!("Cannot sign tag registration: {}", e)))?;
+
+        let (tx, rx) = oneshot::channel();
+        self.shared.registrations.lock().unwrap().push_back(tx);
+        let message = RelayMessage::Register { tags, expires_at, signature: signature.to_bytes().to_vec() };
+        if self.outgoing.send(message).await.is_err() {
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        match timeout(SEND_TIMEOUT, rx).await {
+            Ok(Ok(accepted)) => Ok(accepted),
+            Ok(Err(_)) => Err(TransportError::SendFailed("Relay session closed".to_string())),
+            Err(_) => Err(TransportError::Timeout),
+        }
+    }
+
+    /// Передать relay push-токен `provider` (например `"fcm"`): relay будит
+    /// узел, когда сохраняет для него пакет. Пустой токен отключает push.
+    /// Возвращает false, если relay push не поддерживает или токен удален.
+    pub async fn register_push(&self, provider: &str, token: &[u8]) -> Result<bool, TransportError> {
+        if self.is_closed() {
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.shared.push_registrations.lock().unwrap().push_back(tx);
+        let message = RelayMessage::RegisterPush { provider: provider.to_string(), token: token.to_vec() };
+        if self.outgoing.send(message).await.is_err() {
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        match timeout(SEND_TIMEOUT, rx).await {
+            Ok(Ok(enabled)) => Ok(enabled),
+            Ok(Err(_)) => Err(TransportError::SendFailed("Relay session closed".to_string())),
+            Err(_) => Err(TransportError::Timeout),
+        }
+    }
+
+    /// Подтвердить доставку из почтового ящика: relay удаляет пакет.
+    /// Неподтвержденные пакеты доставляются снова при следующем подключении.
+    pub async fn ack(&self, mailbox_id: u64) -> Result<(), TransportError> {
+        self.outgoing
+            .send(RelayMessage::Ack { mailbox_id })
+            .await
+            .map_err(|_| TransportError::SendFailed("Relay session closed".to_string()))
+    }
+
+    /// Попросить relay о прямом канале с `peer` (или согласиться на
+    /// предложенный). Relay отвечает сигналами (`rendezvous_signals`) или
+    /// молчит, если rendezvous у него нет.
+    pub async fn punch(&self, peer: &str) -> Result<(), TransportError> {
+        self.outgoing
+            .send(RelayMessage::Punch { peer: peer.to_string() })
+            .await
+            .map_err(|_| TransportError::SendFailed("Relay session closed".to_string()))
+    }
+
+    /// Сигналы rendezvous, пришедшие после подписки
+    pub fn rendezvous_signals(&self) -> broadcast::Receiver<RendezvousSignal> {
+        self.signals.subscribe()
+    }
+
+    /// Время отклика relay (Ping/Pong)
+    pub async fn ping(&self) -> Result<Duration, TransportError> {
+        if self.is_closed() {
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        let seq = self.shared.next_ping.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.shared.pongs.lock().unwrap().insert(seq, tx);
+
+        let started = Instant::now();
+        if self.outgoing.send(RelayMessage::Ping { seq }).await.is_err() {
+            self.shared.pongs.lock().unwrap().remove(&seq);
+            return Err(TransportError::SendFailed("Relay session closed".to_string()));
+        }
+
+        match timeout(SEND_TIMEOUT, rx).await {
+            Ok(Ok(())) => Ok(started.elapsed()),
+            Ok(Err(_)) => Err(TransportError::SendFailed("Relay session closed".to_string())),
+            Err(_) => {
+                self.shared.pongs.lock().unwrap().remove(&seq);
+                Err(TransportError::Timeout)
+            }
+        }
+    }
+
+    /// Следующий доставленный пакет; `None` после закрытия сессии
+    pub async fn recv(&self) -> Option<Delivery> {
+        self.incoming.lock().await.recv().await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// Закрыть сессию (relay получает `Bye`)
+    pub async fn close(&self) {
+        if self.outgoing.send(RelayMessage::Bye).await.is_err() {
+            self.task.abort();
+        }
+    }
+}
+
+impl Drop for RelaySession {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn proto_error(error: ProtoError) -> TransportError {
+    match error {
+        ProtoError::AuthFailed | ProtoError::Rejected(ErrorCode::AuthFailed, _) => {
+            TransportError::SecurityError(format!("Relay authentication failed: {}", error))
+        }
+        other => TransportError::SendFailed(format!("Relay protocol error: {}", other)),
+    }
+}
+
+async fn handshake(link: &mut MessageLink, identity: &Identity) -> Result<u32, TransportError> {
+    let hello = RelayMessage::Hello {
+        version: PROTOCOL_VERSION,
+        node_id: identity.id.clone(),
+        public_key: identity.public_key_bytes().to_vec(),
+    };
+    send(link, hello).await?;
+
+    let (nonce, pow_difficulty) = match recv(link).await? {
+        RelayMessage::Challenge { nonce, pow_difficulty } => (nonce, pow_difficulty),
+        RelayMessage::Error { code, message } => return Err(proto_error(ProtoError::Rejected(code, message))),
+        other => return Err(proto_error(ProtoError::Unexpected(other.kind()))),
+    };
+
+    let signature = identity
+        .sign(&auth_payload(&nonce, &link.binding, &identity.id))
+        .map_err(|e| TransportError::SecurityError(format!("Cannot sign relay challenge: {}", e)))?;
+
+    // Перегруженный relay требует proof of work; сложность сверх предела
+    // протокола не решаем, чтобы relay не мог занять CPU узла
+    let pow = if pow_difficulty == 0 {
+        None
+    } else if pow_difficulty > MAX_POW_DIFFICULTY {
+        return Err(proto_error(ProtoError::Unexpected("proof of work too hard")));
+    } else {
+        let node_id = identity.id.clone();
+        let nonce = nonce.clone();
+        let solved = tokio::task::spawn_blocking(move || solve_pow(&nonce, &node_id, pow_difficulty))
+            .await
+            .map_err(|e| TransportError::SendFailed(format!("Proof of work failed: {}", e)))?;
+        Some(solved)
+    };
+    send(link, RelayMessage::Auth { signature: signature.to_bytes().to_vec(), pow }).await?;
+
+    match recv(link).await? {
+        RelayMessage::Welcome { keepalive_secs } => Ok(keepalive_secs),
+        RelayMessage::Error { code, message } => Err(proto_error(ProtoError::Rejected(code, message))),
+        other => Err(proto_error(ProtoError::Unexpected(other.kind()))),
+    }
+}
+
+async fn send(link: &MessageLink, message: RelayMessage) -> Result<(), TransportError> {
+    link.outgoing.send(message).await.map_err(|_| proto_error(ProtoError::Closed))
+}
+
+async fn recv(link: &mut MessageLink) -> Result<RelayMessage, TransportError> {
+    link.incoming.recv().await.ok_or_else(|| proto_error(ProtoError::Closed))
+}
+
+/// Фоновая задача: входящие сообщения и keepalive
+async fn run(
+    mut incoming: mpsc::Receiver<RelayMessage>,
+    outgoing: mpsc::Sender<RelayMessage>,
+    shared: Arc<Shared>,
+    deliveries: mpsc::Sender<Delivery>,
+    signals: broadcast::Sender<RendezvousSignal>,
+    node_id: String,
+    keepalive: Duration,
+) {
+    let mut ticker = tokio::time::interval(keepalive);
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            message = incoming.recv() => {
+                let Some(message) = message else { break };
+                *shared.last_seen.lock().unwrap() = Instant::now();
+
+                match message {
+                    RelayMessage::Deliver { from, packet, mailbox_id } => {
+                        // Не блокируем сессию, если пакеты никто не читает
+                        // (пакет из почтового ящика придет снова без `Ack`)
+                        if deliveries.try_send(Delivery { from, packet, mailbox_id }).is_err() {
+                            tracing::warn!("Relay delivery queue full, packet dropped");
+                        }
+                    }
+                    RelayMessage::SendResult { seq, status } => {
+                        if let Some(waiter) = shared.pending.lock().unwrap().remove(&seq) {
+                            let _ = waiter.send(status);
+                        }
+                    }
+                    RelayMessage::Registered { accepted, .. } => {
+                        if let Some(waiter) = shared.registrations.lock().unwrap().pop_front() {
+                            let _ = waiter.send(accepted);
+                        }
+                    }
+                    RelayMessage::PushRegistered { enabled } => {
+                        if let Some(waiter) = shared.push_registrations.lock().unwrap().pop_front() {
+                            let _ = waiter.send(enabled);
+                        }
+                    }
+                    RelayMessage::Ping { seq } => {
+                        let _ = outgoing.try_send(RelayMessage::Pong { seq });
+                    }
+                    RelayMessage::Punch { peer } => {
+                        let _ = signals.send(RendezvousSignal::Offer { peer });
+                    }
+                    RelayMessage::PunchBind { peer, token, port } => {
+                        // Кривой сигнал не повод рвать сессию
+                        if let Ok(token) = token.try_into() {
+                            let _ = signals.send(RendezvousSignal::Bind { peer, token, port });
+                        }
+                    }
+                    RelayMessage::Introduce { peer, addr, link_id, key } => {
+                        if let (Ok(addr), Ok(link)) = (addr.parse(), PeerLink::new(link_id, &key, &node_id, &peer)) {
+                            let _ = signals.send(RendezvousSignal::Introduce { peer, addr, link });
+                        }
+                    }
+                    RelayMessage::Pong { seq } => {
+                        if let Some(waiter) = shared.pongs.lock().unwrap().remove(&seq) {
+                            let _ = waiter.send(());
+                        }
+                    }
+                    RelayMessage::Error { code, message } => {
+                        tracing::warn!("Relay closed session: {:?} {}", code, message);
+                        break;
+                    }
+                    RelayMessage::Bye => {
+                        tracing::info!("Relay closed session (shutting down)");
+                        break;
+                    }
+                    other => {
+                        tracing::debug!("Unexpected relay frame: {}", other.kind());
+                        break;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                // Соединение считается мертвым после трех пропущенных keepalive
+                if shared.last_seen.lock().unwrap().elapsed() > keepalive * 3 {
+                    tracing::warn!("Relay session timed out");
+                    break;
+                }
+                let seq = shared.next_ping.fetch_add(1, Ordering::Relaxed);
+                if outgoing.send(RelayMessage::Ping { seq }).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    shared.mark_closed();
+}
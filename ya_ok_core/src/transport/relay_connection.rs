@@ -0,0 +1,357 @@
+//! Постоянное соединение с relay
+//!
+//! Одна мультиплексированная сессия (`RelaySession`) на relay: отправка и
+//! прием идут через нее, пока она жива. Фоновая задача прослушивания
+//! переподключается с экспоненциальной задержкой и останавливается по
+//! `stop`.
+
+use crate::transport::relay::{Delivery, RelaySession};
+use crate::transport::TransportError;
+use async_trait::async_trait;
+use rand::Rng;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Первая задержка перед повторным подключением
+const RECONNECT_INITIAL: Duration = Duration::from_secs(1);
+
+/// Максимальная задержка перед повторным подключением
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+/// Время на закрытие задачи прослушивания в `stop`
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Способ установить аутентифицированную сессию с relay
+#[async_trait]
+pub trait RelayConnector: Send + Sync {
+    async fn connect(&self) -> Result<RelaySession, TransportError>;
+}
+
+/// Экспоненциальная задержка с джиттером: случайное значение из
+/// `[base / 2, base]`, где `base` удваивается после каждой неудачи
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, attempt: 0 }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.initial
+            .saturating_mul(1u32 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = base / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(RECONNECT_INITIAL, RECONNECT_MAX)
+    }
+}
+
+/// Счетчики соединения с relay
+#[derive(Debug, Clone, Default, Serialize, PartialEq, Eq)]
+pub struct RelayStats {
+    /// Есть действующая сессия
+    pub connected: bool,
+    /// Задача прослушивания запущена
+    pub listening: bool,
+    /// Успешные подключения (включая первое)
+    pub connects: u64,
+    /// Подключения после обрыва сессии
+    pub reconnects: u64,
+    pub connect_failures: u64,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub send_failures: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Подтвержденные доставки из почтового ящика relay
+    pub packets_acked: u64,
+}
+
+struct Listener {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+/// Менеджер соединения с одним relay
+pub struct RelayConnection {
+    connector: Box<dyn RelayConnector>,
+    /// Текущая сессия; блокировка удерживается на время подключения,
+    /// чтобы одновременные отправки не открывали лишних сессий
+    session: tokio::sync::Mutex<Option<Arc<RelaySession>>>,
+    stats: Mutex<RelayStats>,
+    listener: Mutex<Option<Listener>>,
+    backoff: Mutex<Backoff>,
+}
+
+impl RelayConnection {
+    pub fn new(connector: Box<dyn RelayConnector>) -> Self {
+        Self::with_backoff(connector, Backoff::default())
+    }
+
+    pub fn with_backoff(connector: Box<dyn RelayConnector>, backoff: Backoff) -> Self {
+        Self {
+            connector,
+            session: tokio::sync::Mutex::new(None),
+            stats: Mutex::new(RelayStats::default()),
+            listener: Mutex::new(None),
+            backoff: Mutex::new(backoff),
+        }
+    }
+
+    /// Действующая сессия; при необходимости подключается заново
+    pub async fn session(&self) -> Result<Arc<RelaySession>, TransportError> {
+        let mut current = self.session.lock().await;
+        let had_session = match current.as_ref() {
+            Some(session) if !session.is_closed() => return Ok(session.clone()),
+            Some(_) => true,
+            None => false,
+        };
+
+        match self.connector.connect().await {
+            Ok(session) => {
+                let session = Arc::new(session);
+                *current = Some(session.clone());
+                let mut stats = self.stats.lock().unwrap();
+                stats.connects += 1;
+                if had_session {
+                    stats.reconnects += 1;
+                }
+                Ok(session)
+            }
+            Err(e) => {
+                self.stats.lock().unwrap().connect_failures += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Проверка доступности: подключиться при необходимости и измерить
+    /// время отклика
+    pub async fn probe(&self) -> Result<Duration, TransportError> {
+        self.session().await?.ping().await
+    }
+
+    /// Есть действующая сессия (без ожидания идущего подключения)
+    pub fn is_connected(&self) -> bool {
+        self.session
+            .try_lock()
+            .map(|session| session.as_ref().is_some_and(|s| !s.is_closed()))
+            .unwrap_or(false)
+    }
+
+    /// Отправить пакет узлу `to` через текущую сессию. Если сессия
+    /// оборвалась во время отправки, пакет отправляется повторно через новую.
+    /// `ttl_secs` - сколько relay хранит пакет, если получатель не в сети.
+    pub async fn send(&self, to: &str, packet: Vec<u8>, ttl_secs: Option<u32>) -> Result<(), TransportError> {
+        let len = packet.len() as u64;
+        let session = self.session().await;
+        let result = match session {
+            Ok(session) => match session.send(to, packet.clone(), ttl_secs).await {
+                Err(_) if session.is_closed() => match self.session().await {
+                    Ok(session) => session.send(to, packet, ttl_secs).await,
+                    Err(e) => Err(e),
+                },
+                result => result,
+            },
+            Err(e) => Err(e),
+        };
+
+        let mut stats = self.stats.lock().unwrap();
+        match result {
+            Ok(()) => {
+                stats.packets_sent += 1;
+                stats.bytes_sent += len;
+            }
+            Err(_) => stats.send_failures += 1,
+        }
+        result
+    }
+
+    /// Разослать экстренный пакет всем сессиям relay (см. `RelaySession::flood`)
+    pub async fn flood(&self, packet: Vec<u8>) -> Result<(), TransportError> {
+        let len = packet.len() as u64;
+        let result = self.session().await?.flood(packet).await;
+
+        let mut stats = self.stats.lock().unwrap();
+        match result {
+            Ok(()) => {
+                stats.packets_sent += 1;
+                stats.bytes_sent += len;
+            }
+            Err(_) => stats.send_failures += 1,
+        }
+        result
+    }
+
+    /// Запустить фоновое прослушивание: доставленные пакеты передаются в
+    /// `on_delivery` (доставки из почтового ящика подтверждаются после
+    /// обработки), обрыв сессии ведет к переподключению
+    pub fn start(self: &Arc<Self>, on_delivery: Box<dyn Fn(Delivery) + Send + Sync>) -> Result<(), TransportError> {
+        let mut listener = self.listener.lock().unwrap();
+        if listener.as_ref().is_some_and(|listener| !listener.task.is_finished()) {
+            return Err(TransportError::AlreadyListening);
+        }
+
+        let (stop, stop_rx) = watch::channel(false);
+        let task = tokio::spawn(self.clone().listen(on_delivery, stop_rx));
+        *listener = Some(Listener { stop, task });
+        Ok(())
+    }
+
+    /// Остановить прослушивание и закрыть сессию
+    pub async fn stop(&self) {
+        let listener = self.listener.lock().unwrap().take();
+        if let Some(listener) = listener {
+            let _ = listener.stop.send(true);
+            let mut task = listener.task;
+            if tokio::time::timeout(STOP_TIMEOUT, &mut task).await.is_err() {
+                task.abort();
+            }
+        }
+
+        if let Some(session) = self.session.lock().await.take() {
+            session.close().await;
+        }
+    }
+
+    /// Прервать прослушивание без ожидания (при уничтожении транспорта)
+    pub fn abort(&self) {
+        if let Some(listener) = self.listener.lock().unwrap().take() {
+            listener.task.abort();
+        }
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.connected = self.is_connected();
+        stats.listening = self.listener
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|listener| !listener.task.is_finished());
+        stats
+    }
+
+    async fn listen(self: Arc<Self>, on_delivery: Box<dyn Fn(Delivery) + Send + Sync>, mut stop: watch::Receiver<bool>) {
+        loop {
+            let session = tokio::select! {
+                _ = stop.changed() => return,
+                session = self.session() => session,
+            };
+
+            let session = match session {
+                Ok(session) => {
+                    self.backoff.lock().unwrap().reset();
+                    session
+                }
+                Err(e) => {
+                    let delay = self.backoff.lock().unwrap().next_delay();
+                    tracing::warn!("Relay connection failed: {}; retrying in {:?}", e, delay);
+                    tokio::select! {
+                        _ = stop.changed() => return,
+                        _ = tokio::time::sleep(delay) => continue,
+                    }
+                }
+            };
+
+            loop {
+                let delivery = tokio::select! {
+                    _ = stop.changed() => return,
+                    delivery = session.recv() => delivery,
+                };
+                let Some(delivery) = delivery else { break };
+
+                {
+                    let mut stats = self.stats.lock().unwrap();
+                    stats.packets_received += 1;
+                    stats.bytes_received += delivery.packet.len() as u64;
+                }
+                let mailbox_id = delivery.mailbox_id;
+                on_delivery(delivery);
+                if let Some(id) = mailbox_id {
+                    if session.ack(id).await.is_ok() {
+                        self.stats.lock().unwrap().packets_acked += 1;
+                    }
+                }
+            }
+            tracing::info!("Relay session ended, reconnecting");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay()).collect();
+
+        for (attempt, delay) in delays.iter().enumerate() {
+            let base = Duration::from_millis(100 << attempt).min(Duration::from_secs(1));
+            assert!(*delay >= base / 2 && *delay <= base, "attempt {}: {:?}", attempt, delay);
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    struct FailingConnector(Arc<AtomicU32>);
+
+    #[async_trait]
+    impl RelayConnector for FailingConnector {
+        async fn connect(&self) -> Result<RelaySession, TransportError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(TransportError::SendFailed("unreachable".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_listener_retries_and_stops() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(40));
+        let connection = Arc::new(RelayConnection::with_backoff(
+            Box::new(FailingConnector(attempts.clone())),
+            backoff,
+        ));
+
+        connection.start(Box::new(|_| {})).unwrap();
+        assert!(matches!(connection.start(Box::new(|_| {})), Err(TransportError::AlreadyListening)));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let stats = connection.stats();
+        assert!(stats.listening);
+        assert!(!stats.connected);
+        assert!(stats.connect_failures >= 3, "{:?}", stats);
+
+        connection.stop().await;
+        let stopped_at = attempts.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), stopped_at);
+        assert!(!connection.stats().listening);
+
+        // После остановки прослушивание можно запустить снова
+        connection.start(Box::new(|_| {})).unwrap();
+        connection.stop().await;
+    }
+}
@@ -0,0 +1,760 @@
+//! Пул relay
+//!
+//! Несколько relay с собственными pins: периодическая проверка (Ping/Pong)
+//! дает оценку по задержке и отказам, отправка идет через основной relay
+//! (он не меняется, пока работает) с переключением на следующий при
+//! отказе, а пакеты высокого приоритета публикуются сразу через несколько
+//! relay. Прослушивание идет на всех relay пула, чтобы узел оставался
+//! доступным для отправителей, переключившихся на другой relay.
+//!
+//! Список relay задается напрямую или подписанным обновлением
+//! ([`SignedRelayList`]) с защитой от отката версии.
+
+use crate::core::packet::Priority;
+use crate::core::{Identity, Packet};
+use crate::transport::relay_connection::RelayStats;
+use crate::transport::udp::{RelayLink, UdpTransport, UdpTransportConfig, DEFAULT_RELAY_URL};
+use crate::transport::{Peer, Transport, TransportError, TransportType};
+use async_trait::async_trait;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::task::{JoinHandle, JoinSet};
+use yaok_relay_proto::dedup::RecentIds;
+
+/// Время на одну проверку relay
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Задержка для relay, который еще не проверялся
+const UNKNOWN_LATENCY: Duration = Duration::from_secs(1);
+
+/// Штраф к оценке за каждый отказ подряд
+const FAILURE_PENALTY: Duration = Duration::from_secs(2);
+
+/// Вес нового замера в сглаженной задержке
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// Сколько id пакетов помнить для отбрасывания дублей (multi-publish)
+const RECENT_PACKETS: usize = 1024;
+
+/// Домен подписи списка relay
+const RELAY_LIST_CONTEXT: &[u8] = b"yaok-relay-list-v1\0";
+
+/// Ключ Ed25519 издателя списков relay (hex), задаётся при сборке
+const RELAY_LIST_PUBLISHER: Option<&str> = option_env!("YAOK_RELAY_LIST_PUBLISHER");
+
+/// Relay в пуле
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayEndpoint {
+    /// Адрес relay ("host:port")
+    pub url: String,
+    /// Основной pin (SPKI для TLS, ключ relay для датаграмм)
+    #[serde(default)]
+    pub pin: Option<String>,
+    /// Резервные SPKI pins для TLS
+    #[serde(default)]
+    pub backup_pins: Vec<String>,
+    #[serde(default)]
+    pub link: RelayLink,
+}
+
+impl RelayEndpoint {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            pin: None,
+            backup_pins: Vec::new(),
+            link: RelayLink::Stream,
+        }
+    }
+
+    fn transport_config(&self, template: &UdpTransportConfig) -> UdpTransportConfig {
+        UdpTransportConfig {
+            relay_url: self.url.clone(),
+            pinned_cert_fingerprint: self.pin.clone(),
+            backup_pins: self.backup_pins.clone(),
+            pins_expire_at: template.pins_expire_at,
+            tls_disabled: template.tls_disabled,
+            link: self.link,
+        }
+    }
+}
+
+/// Настройки пула
+#[derive(Clone, Debug)]
+pub struct RelayPoolConfig {
+    /// Интервал проверки relay
+    pub probe_interval: Duration,
+    /// Через сколько relay публиковать пакеты высокого приоритета
+    pub multi_publish: usize,
+    /// Отказов подряд, после которых relay считается недоступным
+    pub failure_threshold: u32,
+    /// Общие настройки соединений (TLS, срок pins)
+    pub transport: UdpTransportConfig,
+    /// Ключ издателя подписанных списков relay; без него списки не применяются
+    pub list_publisher: Option<VerifyingKey>,
+}
+
+impl Default for RelayPoolConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(30),
+            multi_publish: 2,
+            failure_threshold: 3,
+            transport: UdpTransportConfig::default(),
+            list_publisher: RELAY_LIST_PUBLISHER.and_then(parse_publisher_key),
+        }
+    }
+}
+
+/// Ключ издателя из hex; неверный ключ в сборке отключает списки relay
+fn parse_publisher_key(key_hex: &str) -> Option<VerifyingKey> {
+    let key = hex::decode(key_hex)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok());
+    if key.is_none() {
+        tracing::warn!("Invalid relay list publisher key, signed relay lists are disabled");
+    }
+    key
+}
+
+/// Состояние relay для статистики
+#[derive(Clone, Debug, Serialize)]
+pub struct RelayHealth {
+    pub url: String,
+    pub primary: bool,
+    pub healthy: bool,
+    /// Сглаженная задержка, мс
+    pub latency_ms: Option<u64>,
+    pub consecutive_failures: u32,
+    pub probes_ok: u64,
+    pub probes_failed: u64,
+    pub connection: RelayStats,
+}
+
+/// Статистика пула для `ya_ok_get_stats`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RelayPoolStats {
+    pub primary: Option<String>,
+    pub list_version: u64,
+    pub failovers: u64,
+    pub multi_publishes: u64,
+    pub relays: Vec<RelayHealth>,
+}
+
+#[derive(Default)]
+struct Health {
+    latency: Option<Duration>,
+    consecutive_failures: u32,
+    probes_ok: u64,
+    probes_failed: u64,
+}
+
+impl Health {
+    fn record_success(&mut self, rtt: Duration) {
+        self.latency = Some(match self.latency {
+            Some(latency) => latency.mul_f64(1.0 - LATENCY_WEIGHT) + rtt.mul_f64(LATENCY_WEIGHT),
+            None => rtt,
+        });
+        self.consecutive_failures = 0;
+        self.probes_ok += 1;
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        self.probes_failed += 1;
+    }
+
+    /// Меньше - лучше
+    fn score(&self) -> Duration {
+        self.latency.unwrap_or(UNKNOWN_LATENCY) + FAILURE_PENALTY * self.consecutive_failures
+    }
+}
+
+struct Member {
+    endpoint: RelayEndpoint,
+    transport: Arc<UdpTransport>,
+    health: Mutex<Health>,
+}
+
+impl Member {
+    fn is_healthy(&self, threshold: u32) -> bool {
+        self.health.lock().unwrap().consecutive_failures < threshold
+    }
+
+    fn score(&self) -> Duration {
+        self.health.lock().unwrap().score()
+    }
+
+    async fn probe(&self) -> Result<Duration, TransportError> {
+        let result = match tokio::time::timeout(PROBE_TIMEOUT, self.transport.probe()).await {
+            Ok(result) => result,
+            Err(_) => Err(TransportError::Timeout),
+        };
+        let mut health = self.health.lock().unwrap();
+        match result {
+            Ok(rtt) => health.record_success(rtt),
+            Err(_) => health.record_failure(),
+        }
+        result
+    }
+}
+
+type PacketCallback = Arc<dyn Fn(Packet) + Send + Sync>;
+
+/// Ошибки обновления списка relay
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RelayListError {
+    #[error("Malformed relay list: {0}")]
+    Malformed(String),
+
+    #[error("Relay list signature is invalid")]
+    BadSignature,
+
+    #[error("Relay list expired")]
+    Expired,
+
+    #[error("Relay list version {received} is not newer than {current}")]
+    Rollback { current: u64, received: u64 },
+
+    #[error("Relay list is empty")]
+    Empty,
+
+    #[error("No relay list publisher key configured")]
+    NoPublisher,
+}
+
+/// Список relay от издателя
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayList {
+    /// Монотонно растущая версия
+    pub version: u64,
+    /// После этого момента (unix секунды) список не принимается
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    pub relays: Vec<RelayEndpoint>,
+}
+
+/// Подписанный список relay: JSON списка и подпись Ed25519 издателя (hex)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedRelayList {
+    pub payload: String,
+    pub signature: String,
+}
+
+impl SignedRelayList {
+    pub fn sign(list: &RelayList, key: &SigningKey) -> Self {
+        let payload = serde_json::to_string(list).expect("relay list serializes to JSON");
+        let signature = key.sign(&Self::signed_bytes(&payload));
+        Self {
+            payload,
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+
+    /// Проверить подпись и срок; возвращает список
+    pub fn verify(&self, publisher: &VerifyingKey, now: u64) -> Result<RelayList, RelayListError> {
+        let signature: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(RelayListError::BadSignature)?;
+        publisher
+            .verify(&Self::signed_bytes(&self.payload), &Signature::from_bytes(&signature))
+            .map_err(|_| RelayListError::BadSignature)?;
+
+        let list: RelayList = serde_json::from_str(&self.payload)
+            .map_err(|e| RelayListError::Malformed(e.to_string()))?;
+        if list.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(RelayListError::Expired);
+        }
+        if list.relays.is_empty() {
+            return Err(RelayListError::Empty);
+        }
+        Ok(list)
+    }
+
+    fn signed_bytes(payload: &str) -> Vec<u8> {
+        [RELAY_LIST_CONTEXT, payload.as_bytes()].concat()
+    }
+}
+
+/// Пул соединений с несколькими relay
+pub struct RelayPool {
+    identity: Identity,
+    config: RelayPoolConfig,
+    members: RwLock<Vec<Arc<Member>>>,
+    /// URL основного relay
+    primary: Mutex<Option<String>>,
+    list_version: Mutex<u64>,
+    /// Колбэк прослушивания (для relay, добавленных позже)
+    listening: Mutex<Option<PacketCallback>>,
+    prober: Mutex<Option<JoinHandle<()>>>,
+    failovers: Mutex<u64>,
+    multi_publishes: Mutex<u64>,
+}
+
+impl RelayPool {
+    pub fn new(identity: Identity, relays: Vec<RelayEndpoint>, config: RelayPoolConfig) -> Arc<Self> {
+        let pool = Arc::new(Self {
+            identity,
+            config,
+            members: RwLock::new(Vec::new()),
+            primary: Mutex::new(None),
+            list_version: Mutex::new(0),
+            listening: Mutex::new(None),
+            prober: Mutex::new(None),
+            failovers: Mutex::new(0),
+            multi_publishes: Mutex::new(0),
+        });
+        *pool.members.write().unwrap() = relays.into_iter().map(|endpoint| pool.member(endpoint)).collect();
+        pool
+    }
+
+    /// Пул из relay по умолчанию
+    pub fn with_default_relay(identity: Identity) -> Arc<Self> {
+        Self::new(identity, vec![RelayEndpoint::new(DEFAULT_RELAY_URL)], RelayPoolConfig::default())
+    }
+
+    fn member(&self, endpoint: RelayEndpoint) -> Arc<Member> {
+        let config = endpoint.transport_config(&self.config.transport);
+        Arc::new(Member {
+            transport: Arc::new(UdpTransport::with_identity(config, self.identity.clone())),
+            endpoint,
+            health: Mutex::new(Health::default()),
+        })
+    }
+
+    pub fn relays(&self) -> Vec<RelayEndpoint> {
+        self.members.read().unwrap().iter().map(|m| m.endpoint.clone()).collect()
+    }
+
+    /// Заменить список relay. Соединения с оставшимися relay сохраняются,
+    /// с удаленными закрываются.
+    pub async fn set_relays(&self, relays: Vec<RelayEndpoint>) -> Result<(), TransportError> {
+        if relays.is_empty() {
+            return Err(TransportError::InvalidAddress("Relay list is empty".to_string()));
+        }
+
+        let callback = self.listening.lock().unwrap().clone();
+        let (added, removed) = {
+            let mut members = self.members.write().unwrap();
+            let mut kept = Vec::with_capacity(relays.len());
+            let mut added = Vec::new();
+            for endpoint in relays {
+                if kept.iter().any(|m: &Arc<Member>| m.endpoint.url == endpoint.url) {
+                    continue;
+                }
+                match members.iter().find(|m| m.endpoint == endpoint) {
+                    Some(existing) => kept.push(existing.clone()),
+                    None => {
+                        let member = self.member(endpoint);
+                        added.push(member.clone());
+                        kept.push(member);
+                    }
+                }
+            }
+            let removed: Vec<Arc<Member>> = members
+                .iter()
+                .filter(|m| !kept.iter().any(|k| Arc::ptr_eq(k, m)))
+                .cloned()
+                .collect();
+            *members = kept;
+            (added, removed)
+        };
+
+        {
+            let mut primary = self.primary.lock().unwrap();
+            if primary.as_ref().is_some_and(|url| removed.iter().any(|m| &m.endpoint.url == url)) {
+                *primary = None;
+            }
+        }
+        for member in removed {
+            let _ = member.transport.stop_listening().await;
+        }
+        if let Some(callback) = callback {
+            for member in added {
+                Self::listen_on(&member, callback.clone()).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Применить подписанный список relay, проверив подпись ключом издателя
+    /// из настроек пула. Версия должна быть новее примененной ранее.
+    pub async fn apply_signed_list(&self, signed: &SignedRelayList, now: u64) -> Result<u64, RelayListError> {
+        let publisher = self.config.list_publisher.as_ref().ok_or(RelayListError::NoPublisher)?;
+        let list = signed.verify(publisher, now)?;
+        let current = *self.list_version.lock().unwrap();
+        if list.version <= current {
+            return Err(RelayListError::Rollback { current, received: list.version });
+        }
+
+        self.set_relays(list.relays)
+            .await
+            .map_err(|e| RelayListError::Malformed(e.to_string()))?;
+        *self.list_version.lock().unwrap() = list.version;
+        tracing::info!("Relay list updated to version {}", list.version);
+        Ok(list.version)
+    }
+
+    /// Проверить все relay и при необходимости выбрать новый основной
+    pub async fn probe_all(&self) {
+        let members = self.members.read().unwrap().clone();
+        let mut probes = JoinSet::new();
+        for member in members {
+            probes.spawn(async move {
+                if let Err(e) = member.probe().await {
+                    tracing::debug!("Relay {} probe failed: {}", member.endpoint.url, e);
+                }
+            });
+        }
+        while probes.join_next().await.is_some() {}
+        self.primary_member();
+    }
+
+    /// Основной relay: текущий, пока он доступен, иначе лучший по оценке
+    fn primary_member(&self) -> Option<Arc<Member>> {
+        let members = self.members.read().unwrap().clone();
+        let threshold = self.config.failure_threshold;
+        let mut primary = self.primary.lock().unwrap();
+
+        if let Some(current) = primary.as_ref().and_then(|url| members.iter().find(|m| &m.endpoint.url == url)) {
+            if current.is_healthy(threshold) {
+                return Some(current.clone());
+            }
+        }
+
+        let best = members
+            .iter()
+            .filter(|m| m.is_healthy(threshold))
+            .min_by_key(|m| m.score())
+            .or_else(|| members.iter().min_by_key(|m| m.score()))
+            .cloned()?;
+        if primary.as_ref() != Some(&best.endpoint.url) {
+            if primary.is_some() {
+                *self.failovers.lock().unwrap() += 1;
+            }
+            tracing::info!("Primary relay: {}", best.endpoint.url);
+            *primary = Some(best.endpoint.url.clone());
+        }
+        Some(best)
+    }
+
+    /// Relay в порядке отправки: основной, затем доступные по оценке,
+    /// затем недоступные
+    fn ranked(&self) -> Vec<Arc<Member>> {
+        let primary = self.primary_member();
+        let threshold = self.config.failure_threshold;
+        let mut others: Vec<Arc<Member>> = self.members
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|m| !primary.as_ref().is_some_and(|p| Arc::ptr_eq(p, m)))
+            .cloned()
+            .collect();
+        others.sort_by_key(|m| (!m.is_healthy(threshold), m.score()));
+        primary.into_iter().chain(others).collect()
+    }
+
+    fn demote(&self, member: &Member) {
+        member.health.lock().unwrap().consecutive_failures = self.config.failure_threshold;
+        let mut primary = self.primary.lock().unwrap();
+        if primary.as_ref() == Some(&member.endpoint.url) {
+            *primary = None;
+            *self.failovers.lock().unwrap() += 1;
+        }
+    }
+
+    async fn send_with_failover(&self, packet: &Packet, destination: &str) -> Result<(), TransportError> {
+        let mut last_error = TransportError::NoTransportAvailable;
+        for member in self.ranked() {
+            match member.transport.send_packet(packet, destination).await {
+                Ok(()) => return Ok(()),
+                // Relay на связи: пакет отклонен из-за получателя
+                Err(e) if member.transport.is_connected() => return Err(e),
+                Err(e) => {
+                    tracing::warn!("Relay {} failed: {}; trying next relay", member.endpoint.url, e);
+                    self.demote(&member);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Публикация через несколько лучших relay одновременно
+    async fn publish(&self, packet: &Packet, destination: &str, fanout: usize) -> Result<(), TransportError> {
+        *self.multi_publishes.lock().unwrap() += 1;
+        let mut sends = JoinSet::new();
+        for member in self.ranked().into_iter().take(fanout) {
+            let packet = packet.clone();
+            let destination = destination.to_string();
+            sends.spawn(async move {
+                let result = member.transport.send_packet(&packet, &destination).await;
+                (member, result)
+            });
+        }
+
+        let mut delivered = false;
+        let mut last_error = TransportError::NoTransportAvailable;
+        while let Some(joined) = sends.join_next().await {
+            let Ok((member, result)) = joined else { continue };
+            match result {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    if !member.transport.is_connected() {
+                        self.demote(&member);
+                    }
+                    last_error = e;
+                }
+            }
+        }
+
+        if delivered {
+            Ok(())
+        } else {
+            Err(last_error)
+        }
+    }
+
+    async fn listen_on(member: &Member, callback: PacketCallback) {
+        let result = member.transport.start_listening(Box::new(move |packet| callback(packet))).await;
+        if let Err(e) = result {
+            tracing::warn!("Cannot listen on relay {}: {}", member.endpoint.url, e);
+        }
+    }
+
+    /// Запустить периодическую проверку relay
+    pub fn start_probing(self: &Arc<Self>) {
+        let mut prober = self.prober.lock().unwrap();
+        if prober.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+
+        let pool = Arc::downgrade(self);
+        let interval = self.config.probe_interval;
+        *prober = Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let Some(pool) = pool.upgrade() else { break };
+                pool.probe_all().await;
+            }
+        }));
+    }
+
+    /// Остановить периодическую проверку
+    pub fn stop_probing(&self) {
+        if let Some(prober) = self.prober.lock().unwrap().take() {
+            prober.abort();
+        }
+    }
+
+    pub fn stats(&self) -> RelayPoolStats {
+        let primary = self.primary.lock().unwrap().clone();
+        let threshold = self.config.failure_threshold;
+        let relays = self.members
+            .read()
+            .unwrap()
+            .iter()
+            .map(|member| {
+                let health = member.health.lock().unwrap();
+                RelayHealth {
+                    url: member.endpoint.url.clone(),
+                    primary: primary.as_ref() == Some(&member.endpoint.url),
+                    healthy: health.consecutive_failures < threshold,
+                    latency_ms: health.latency.map(|latency| latency.as_millis() as u64),
+                    consecutive_failures: health.consecutive_failures,
+                    probes_ok: health.probes_ok,
+                    probes_failed: health.probes_failed,
+                    connection: member.transport.stats(),
+                }
+            })
+            .collect();
+
+        RelayPoolStats {
+            primary,
+            list_version: *self.list_version.lock().unwrap(),
+            failovers: *self.failovers.lock().unwrap(),
+            multi_publishes: *self.multi_publishes.lock().unwrap(),
+            relays,
+        }
+    }
+}
+
+impl Drop for RelayPool {
+    fn drop(&mut self) {
+        if let Some(prober) = self.prober.lock().unwrap().take() {
+            prober.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for RelayPool {
+    fn transport_type(&self) -> TransportType {
+        TransportType::Udp
+    }
+
+    async fn is_available(&self) -> bool {
+        let members = self.members.read().unwrap().clone();
+        for member in members {
+            if member.transport.is_available().await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn send_packet(&self, packet: &Packet, destination: &str) -> Result<(), TransportError> {
+        let fanout = self.config.multi_publish.max(1);
+        if packet.priority == Priority::High && fanout > 1 && self.members.read().unwrap().len() > 1 {
+            self.publish(packet, destination, fanout).await
+        } else {
+            self.send_with_failover(packet, destination).await
+        }
+    }
+
+    async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError> {
+        Ok(Vec::new())
+    }
+
+    async fn start_listening(&self, callback: Box<dyn Fn(Packet) + Send + Sync>) -> Result<(), TransportError> {
+        // Один пакет может прийти через несколько relay
+        let recent = Mutex::new(RecentIds::new(RECENT_PACKETS));
+        let callback: PacketCallback = Arc::new(move |packet: Packet| {
+            if recent.lock().unwrap().insert(packet.message_id.clone()) {
+                callback(packet);
+            }
+        });
+
+        {
+            let mut listening = self.listening.lock().unwrap();
+            if listening.is_some() {
+                return Err(TransportError::AlreadyListening);
+            }
+            *listening = Some(callback.clone());
+        }
+
+        let members = self.members.read().unwrap().clone();
+        for member in &members {
+            Self::listen_on(member, callback.clone()).await;
+        }
+        Ok(())
+    }
+
+    async fn stop_listening(&self) -> Result<(), TransportError> {
+        self.listening.lock().unwrap().take();
+        let members = self.members.read().unwrap().clone();
+        for member in members {
+            member.transport.stop_listening().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Ячейка для пула relay, который создаётся позже маршрутизатора (нужна
+/// identity). Маршрутизатор отправляет через пул, лежащий в ячейке;
+/// пока ячейка пуста, транспорт недоступен.
+#[derive(Clone, Default)]
+pub struct RelaySlot {
+    pool: Arc<Mutex<Option<Arc<RelayPool>>>>,
+}
+
+impl RelaySlot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Текущий пул, если он уже создан
+    pub fn get(&self) -> Option<Arc<RelayPool>> {
+        self.pool.lock().unwrap().clone()
+    }
+
+    /// Пул из ячейки; при пустой ячейке создаётся через `create`
+    pub fn get_or_try_insert<E>(&self, create: impl FnOnce() -> Result<Arc<RelayPool>, E>) -> Result<Arc<RelayPool>, E> {
+        let mut pool = self.pool.lock().unwrap();
+        if let Some(pool) = pool.as_ref() {
+            return Ok(pool.clone());
+        }
+        Ok(pool.insert(create()?).clone())
+    }
+}
+
+#[async_trait]
+impl Transport for RelaySlot {
+    fn transport_type(&self) -> TransportType {
+        TransportType::Udp
+    }
+
+    async fn is_available(&self) -> bool {
+        match self.get() {
+            Some(pool) => pool.is_available().await,
+            None => false,
+        }
+    }
+
+    async fn send_packet(&self, packet: &Packet, destination: &str) -> Result<(), TransportError> {
+        let pool = self.get().ok_or(TransportError::NoTransportAvailable)?;
+        pool.send_packet(packet, destination).await
+    }
+
+    async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError> {
+        Ok(Vec::new())
+    }
+
+    async fn start_listening(&self, callback: Box<dyn Fn(Packet) + Send + Sync>) -> Result<(), TransportError> {
+        let pool = self.get().ok_or(TransportError::NoTransportAvailable)?;
+        pool.start_listening(callback).await
+    }
+
+    async fn stop_listening(&self) -> Result<(), TransportError> {
+        match self.get() {
+            Some(pool) => pool.stop_listening().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_score_prefers_fast_and_reliable() {
+        let mut fast = Health::default();
+        fast.record_success(Duration::from_millis(20));
+        let mut slow = Health::default();
+        slow.record_success(Duration::from_millis(200));
+        assert!(fast.score() < slow.score());
+
+        // Отказ хуже медленного relay
+        fast.record_failure();
+        assert!(fast.score() > slow.score());
+        fast.record_success(Duration::from_millis(20));
+        assert_eq!(fast.consecutive_failures, 0);
+        assert!(fast.score() < slow.score());
+    }
+
+    #[test]
+    fn test_signed_relay_list() {
+        let publisher = SigningKey::from_bytes(&rand::random());
+        let list = RelayList {
+            version: 3,
+            expires_at: Some(2_000),
+            relays: vec![RelayEndpoint::new("relay-a.example:40100"), RelayEndpoint::new("relay-b.example:40100")],
+        };
+        let signed = SignedRelayList::sign(&list, &publisher);
+        assert_eq!(signed.verify(&publisher.verifying_key(), 1_000), Ok(list.clone()));
+        assert_eq!(signed.verify(&publisher.verifying_key(), 2_000), Err(RelayListError::Expired));
+
+        let other = SigningKey::from_bytes(&rand::random());
+        assert_eq!(signed.verify(&other.verifying_key(), 1_000), Err(RelayListError::BadSignature));
+
+        let mut tampered = signed.clone();
+        tampered.payload = tampered.payload.replace("relay-b", "relay-x");
+        assert_eq!(tampered.verify(&publisher.verifying_key(), 1_000), Err(RelayListError::BadSignature));
+    }
+}
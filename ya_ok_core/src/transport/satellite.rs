@@ -3,6 +3,10 @@
 use crate::transport::{Transport, TransportType, TransportError, Peer};
 use async_trait::async_trait;
 
+/// Максимальный размер кадра спутникового канала (байты, как у коротких
+/// burst-сообщений); обратного канала нет, поэтому по умолчанию используется FEC
+pub const SATELLITE_MTU: usize = 340;
+
 pub struct SatelliteTransport;
 
 impl SatelliteTransport {
@@ -26,6 +30,14 @@ impl Transport for SatelliteTransport {
         Err(TransportError::NotAvailable)
     }
 
+    fn mtu(&self) -> Option<usize> {
+        Some(SATELLITE_MTU)
+    }
+
+    async fn send_frame(&self, _frame: &[u8], _destination: &str) -> Result<(), TransportError> {
+        Err(TransportError::NotAvailable)
+    }
+
     async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError> {
         Ok(Vec::new())
     }
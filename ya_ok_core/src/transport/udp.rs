@@ -1,81 +1,220 @@
-//! UDP транспорт с поддержкой DTLS (UDP over TLS)
+//! UDP транспорт: постоянная сессия с relay по протоколу `yaok-relay-proto`
+//! поверх TLS или зашифрованных датаграмм (Noise over UDP)
 
+use crate::core::packet::Priority;
+use crate::core::{Identity, Packet};
+use crate::transport::dtls::{CertVerifyError, PinSet};
+use crate::transport::happy_eyeballs;
+use crate::transport::relay::RelaySession;
+use crate::transport::relay_connection::{RelayConnection, RelayConnector, RelayStats};
 use crate::transport::{Transport, TransportType, TransportError, Peer};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use tokio::net::TcpStream;
 use tokio_rustls::TlsConnector;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use yaok_relay_proto::datagram::{ResumptionTicket, MAX_DATAGRAM_PACKET};
+use yaok_relay_proto::endpoint::Scheme;
+use yaok_relay_proto::tags::{recipient_tag, tag_epoch, MAX_TAG_LIFETIME_SECS};
+use yaok_relay_proto::Endpoint;
+
+/// Канал до relay
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayLink {
+    /// TCP с TLS (или без TLS для локального relay)
+    #[default]
+    Stream,
+    /// Зашифрованные датаграммы UDP; `pinned_cert_fingerprint` задает
+    /// fingerprint статического ключа relay
+    Datagram,
+}
 
 /// Конфигурация для UDP транспорта с DTLS
+#[derive(Clone, Debug)]
 pub struct UdpTransportConfig {
-    /// URL relay сервера (например, "i-am-ok-relay.fly.dev:40100")
+    /// Адрес relay: "i-am-ok-relay.fly.dev:40100", "[2001:db8::1]:40100",
+    /// можно со схемой канала ("tls://", "tcp://", "udp://"), см. [`Endpoint`]
     pub relay_url: String,
     
-    /// SHA-256 fingerprint сертификата для certificate pinning
+    /// Основной pin: SHA-256 fingerprint SPKI сертификата relay (или
+    /// промежуточного/корневого CA) для TLS, ключа relay для датаграмм
     /// Формат: "A1:B2:C3:D4:..." (hex с двоеточиями)
     pub pinned_cert_fingerprint: Option<String>,
+
+    /// Резервные SPKI pins для TLS (следующий ключ, другой CA)
+    pub backup_pins: Vec<String>,
+
+    /// После этого момента (unix секунды) pins для TLS не проверяются
+    pub pins_expire_at: Option<u64>,
     
-    /// Отключить проверку TLS (только для тестирования!)
+    /// Отключить TLS (только для тестирования!). Допускается лишь
+    /// для relay на loopback-адресе.
     pub tls_disabled: bool,
+
+    /// Канал до relay
+    pub link: RelayLink,
 }
 
+/// Relay по умолчанию
+pub const DEFAULT_RELAY_URL: &str = "i-am-ok-relay.fly.dev:40100";
+
 impl Default for UdpTransportConfig {
     fn default() -> Self {
         Self {
-            relay_url: "i-am-ok-relay.fly.dev:40100".to_string(),
+            relay_url: DEFAULT_RELAY_URL.to_string(),
             pinned_cert_fingerprint: None,
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
+        }
+    }
+}
+
+impl UdpTransportConfig {
+    /// Разобранный `relay_url`; схема, если указана, должна совпадать с каналом
+    pub fn endpoint(&self) -> Result<Endpoint, TransportError> {
+        let endpoint: Endpoint = self.relay_url.parse()
+            .map_err(|e| TransportError::InvalidAddress(format!("{}: {}", self.relay_url, e)))?;
+        let expected = match (self.link, self.tls_disabled) {
+            (RelayLink::Datagram, _) => Scheme::Udp,
+            (RelayLink::Stream, true) => Scheme::Tcp,
+            (RelayLink::Stream, false) => Scheme::Tls,
+        };
+        match endpoint.scheme {
+            Some(scheme) if scheme != expected => Err(TransportError::InvalidAddress(format!(
+                "{}: scheme {}:// does not match the configured link ({}://)",
+                self.relay_url, scheme.as_str(), expected.as_str()
+            ))),
+            _ => Ok(endpoint),
         }
     }
 }
 
 pub struct UdpTransport {
-    pub(crate) config: UdpTransportConfig,
+    pub(crate) config: Arc<UdpTransportConfig>,
+    /// Постоянное соединение с relay (сессия создается при первом использовании)
+    connection: Arc<RelayConnection>,
+    has_identity: bool,
 }
 
 impl UdpTransport {
     pub fn new() -> Self {
-        Self {
-            config: UdpTransportConfig::default(),
-        }
+        Self::with_config(UdpTransportConfig::default())
     }
     
     pub fn with_config(config: UdpTransportConfig) -> Self {
-        Self { config }
+        Self::build(config, None)
     }
-    
-    /// Create TLS connection to relay server
-    async fn connect_tls(&self) -> Result<tokio_rustls::client::TlsStream<TcpStream>, TransportError> {
-        // Parse hostname from relay URL
-        let host = self.config.relay_url
-            .split(':')
-            .next()
-            .ok_or_else(|| TransportError::SendFailed("Invalid relay URL".to_string()))?;
-        
-        // Create TLS config with optional certificate pinning
-        let tls_config = crate::transport::dtls::create_tls_config(
-            self.config.pinned_cert_fingerprint.clone()
-        ).map_err(|e| TransportError::SecurityError(format!("TLS config failed: {}", e)))?;
-        
-        let connector = TlsConnector::from(tls_config);
-        
-        // Connect TCP socket
-        let tcp_stream = TcpStream::connect(&self.config.relay_url)
-            .await
-            .map_err(|e| TransportError::SendFailed(format!("TCP connect failed: {}", e)))?;
-        
-        // Perform TLS handshake
-        let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
-            .map_err(|e| TransportError::SecurityError(format!("Invalid server name: {}", e)))?;
-        
-        let tls_stream = connector.connect(server_name, tcp_stream)
-            .await
-            .map_err(|e| TransportError::SecurityError(format!("TLS handshake failed: {}", e)))?;
-        
-        Ok(tls_stream)
+
+    /// Транспорт, который регистрируется на relay под `identity.id`
+    pub fn with_identity(config: UdpTransportConfig, identity: Identity) -> Self {
+        Self::build(config, Some(identity))
+    }
+
+    fn build(config: UdpTransportConfig, identity: Option<Identity>) -> Self {
+        let config = Arc::new(config);
+        let has_identity = identity.is_some();
+        let dialer = RelayDialer {
+            config: config.clone(),
+            identity,
+            ticket: std::sync::Mutex::new(None),
+        };
+        Self {
+            config,
+            connection: Arc::new(RelayConnection::new(Box::new(dialer))),
+            has_identity,
+        }
+    }
+
+    /// Счетчики соединения с relay
+    pub fn stats(&self) -> RelayStats {
+        self.connection.stats()
+    }
+
+    /// Время отклика relay; подключается при необходимости
+    pub async fn probe(&self) -> Result<Duration, TransportError> {
+        self.check_tls_policy()?;
+        self.check_identity()?;
+        self.connection.probe().await
+    }
+
+    /// Действующая сессия с relay; подключается при необходимости
+    pub(crate) async fn session(&self) -> Result<Arc<RelaySession>, TransportError> {
+        self.check_tls_policy()?;
+        self.check_identity()?;
+        self.connection.session().await
+    }
+
+    /// Есть действующая сессия с relay
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_connected()
+    }
+
+    /// Разослать экстренный пакет всем узлам на relay. Relay рассылает
+    /// только по явному запросу, поэтому допускаются лишь пакеты высокого
+    /// приоритета; остальные отправляются адресно (`send_packet`).
+    pub async fn flood_packet(&self, packet: &Packet) -> Result<(), TransportError> {
+        self.check_tls_policy()?;
+        self.check_identity()?;
+        if packet.priority != Priority::High {
+            return Err(TransportError::SendFailed("Only high-priority packets may be flooded".to_string()));
+        }
+
+        let packet_bytes = packet.to_wire_bytes()
+            .map_err(|e| TransportError::SendFailed(format!("Serialization failed: {}", e)))?;
+        self.check_packet_size(&packet_bytes)?;
+        self.connection.flood(packet_bytes).await
+    }
+
+    /// Получать пакеты по меткам, выведенным из `secret` (общего с
+    /// контактами), на текущую и следующую эпохи. Регистрация действует и
+    /// после обрыва сессии; ее нужно повторять раз в эпоху.
+    pub async fn register_recipient_tags(&self, secret: &[u8]) -> Result<u32, TransportError> {
+        self.check_tls_policy()?;
+        self.check_identity()?;
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let epoch = tag_epoch(now);
+        let tags = [recipient_tag(secret, epoch), recipient_tag(secret, epoch + 1)];
+        let session = self.connection.session().await?;
+        session.register_tags(&tags, now + MAX_TAG_LIFETIME_SECS).await
+    }
+
+    /// Без TLS можно подключаться только к локальному relay на
+    /// loopback-адресе (датаграммы шифруются всегда)
+    fn check_tls_policy(&self) -> Result<(), TransportError> {
+        let endpoint = self.config.endpoint()?;
+        if self.config.link == RelayLink::Stream && self.config.tls_disabled && !endpoint.is_loopback() {
+            return Err(TransportError::SecurityError("TLS is required for production".to_string()));
+        }
+        Ok(())
+    }
+
+    fn check_identity(&self) -> Result<(), TransportError> {
+        if !self.has_identity {
+            return Err(TransportError::SecurityError("Relay session requires an identity".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Датаграммная сессия несет одно сообщение в датаграмме: больший
+    /// пакет relay все равно отвергнет, отказываем сразу
+    fn check_packet_size(&self, packet_bytes: &[u8]) -> Result<(), TransportError> {
+        if self.config.link == RelayLink::Datagram && packet_bytes.len() > MAX_DATAGRAM_PACKET {
+            return Err(TransportError::SendFailed(format!(
+                "Packet of {} bytes exceeds the datagram link limit of {}",
+                packet_bytes.len(),
+                MAX_DATAGRAM_PACKET
+            )));
+        }
+        Ok(())
     }
-    
     /// Проверить certificate pinning (deprecated - now handled by PinnedCertVerifier)
     /// 
     /// Addresses FR-RELAY-001-05: System SHALL verify relay TLS certificate
@@ -93,6 +232,102 @@ impl UdpTransport {
     }
 }
 
+impl Drop for UdpTransport {
+    fn drop(&mut self) {
+        self.connection.abort();
+    }
+}
+
+/// Подключение к relay по конфигурации транспорта
+struct RelayDialer {
+    config: Arc<UdpTransportConfig>,
+    /// Идентичность для аутентификации на relay
+    identity: Option<Identity>,
+    /// Билет возобновления датаграммной сессии
+    ticket: std::sync::Mutex<Option<ResumptionTicket>>,
+}
+
+#[async_trait]
+impl RelayConnector for RelayDialer {
+    async fn connect(&self) -> Result<RelaySession, TransportError> {
+        let identity = self.identity.as_ref().ok_or_else(|| {
+            TransportError::SecurityError("Relay session requires an identity".to_string())
+        })?;
+
+        let endpoint = self.config.endpoint()?;
+        if self.config.link == RelayLink::Datagram {
+            let ticket = self.ticket.lock().unwrap().take();
+            let (link, ticket) = crate::transport::dtls::connect_datagram(
+                &endpoint.authority(),
+                self.config.pinned_cert_fingerprint.clone(),
+                ticket,
+            ).await?;
+            *self.ticket.lock().unwrap() = ticket;
+            RelaySession::establish_link(link, identity).await
+        } else if self.config.tls_disabled {
+            let tcp_stream = happy_eyeballs::connect(&endpoint)
+                .await
+                .map_err(|e| TransportError::SendFailed(format!("TCP connect failed: {}", e)))?;
+            RelaySession::establish(tcp_stream, identity).await
+        } else {
+            let link = crate::transport::dtls::tls_link(self.connect_tls(&endpoint).await?)?;
+            RelaySession::establish_link(link, identity).await
+        }
+    }
+}
+
+impl RelayDialer {
+    /// Pins для проверки сертификата relay
+    fn tls_pins(&self) -> Option<PinSet> {
+        let pins: Vec<String> = self.config.pinned_cert_fingerprint.iter()
+            .chain(&self.config.backup_pins)
+            .cloned()
+            .collect();
+        if pins.is_empty() {
+            return None;
+        }
+        let pin_set = PinSet::new(pins);
+        Some(match self.config.pins_expire_at {
+            Some(expires_at) => pin_set.with_expiry(expires_at),
+            None => pin_set,
+        })
+    }
+
+    /// Create TLS connection to relay server
+    async fn connect_tls(&self, endpoint: &Endpoint) -> Result<tokio_rustls::client::TlsStream<TcpStream>, TransportError> {
+        // Create TLS config with optional SPKI pinning
+        let tls_config = crate::transport::dtls::create_tls_config(
+            self.tls_pins()
+        ).map_err(|e| TransportError::SecurityError(format!("TLS config failed: {}", e)))?;
+        
+        let connector = TlsConnector::from(tls_config);
+        
+        // Connect TCP socket (IPv6 and IPv4 raced)
+        let tcp_stream = happy_eyeballs::connect(endpoint)
+            .await
+            .map_err(|e| TransportError::SendFailed(format!("TCP connect failed: {}", e)))?;
+        
+        // Perform TLS handshake; an IP address is checked against the
+        // certificate's IP SANs
+        let server_name = rustls::pki_types::ServerName::try_from(endpoint.server_name())
+            .map_err(|e| TransportError::SecurityError(format!("Invalid server name: {}", e)))?;
+        
+        let tls_stream = connector.connect(server_name, tcp_stream)
+            .await
+            .map_err(|e| {
+                let rejected = e.get_ref()
+                    .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+                    .and_then(CertVerifyError::from_tls_error);
+                match rejected {
+                    Some(reason) => TransportError::Certificate(reason),
+                    None => TransportError::SecurityError(format!("TLS handshake failed: {}", e)),
+                }
+            })?;
+        
+        Ok(tls_stream)
+    }
+}
+
 #[async_trait]
 impl Transport for UdpTransport {
     fn transport_type(&self) -> TransportType {
@@ -101,115 +336,63 @@ impl Transport for UdpTransport {
 
     async fn is_available(&self) -> bool {
         // Check if we can resolve relay URL
-        if self.config.tls_disabled {
+        if self.check_tls_policy().is_err() {
             return false; // TLS required for production
         }
         
         // Try to resolve relay hostname
         use tokio::net::lookup_host;
-        match lookup_host(&self.config.relay_url).await {
+        let Ok(endpoint) = self.config.endpoint() else {
+            return false;
+        };
+        match lookup_host(endpoint.authority()).await {
             Ok(mut addrs) => addrs.next().is_some(),
             Err(_) => false,
         }
     }
 
-    async fn send_packet(&self, packet: &crate::core::Packet, _destination: &str) -> Result<(), TransportError> {
+    async fn send_packet(&self, packet: &Packet, destination: &str) -> Result<(), TransportError> {
         // Check if TLS is disabled (only for testing)
-        if self.config.tls_disabled {
-            return Err(TransportError::SecurityError("TLS is required for production".to_string()));
+        self.check_tls_policy()?;
+        self.check_identity()?;
+
+        // Relay доставляет по node id получателя
+        if destination.is_empty() {
+            return Err(TransportError::InvalidAddress("Relay destination must be a node id".to_string()));
         }
         
-        // Serialize packet to CBOR
-        let mut packet_bytes = Vec::new();
-        ciborium::ser::into_writer(packet, &mut packet_bytes)
+        let packet_bytes = packet.to_wire_bytes()
             .map_err(|e| TransportError::SendFailed(format!("Serialization failed: {}", e)))?;
+        self.check_packet_size(&packet_bytes)?;
         
-        // Create length-prefixed frame: [4 bytes length][packet data]
-        let packet_len = packet_bytes.len() as u32;
-        let mut frame = Vec::with_capacity(4 + packet_bytes.len());
-        frame.extend_from_slice(&packet_len.to_be_bytes());
-        frame.extend_from_slice(&packet_bytes);
-        
-        // Connect with TLS
-        let mut tls_stream = self.connect_tls().await?;
-        
-        // Send packet
-        tls_stream.write_all(&frame)
-            .await
-            .map_err(|e| TransportError::SendFailed(format!("Write failed: {}", e)))?;
-        
-        // Flush to ensure packet is sent
-        tls_stream.flush()
-            .await
-            .map_err(|e| TransportError::SendFailed(format!("Flush failed: {}", e)))?;
-        
-        Ok(())
+        // Relay хранит пакет для получателя не в сети не дольше его TTL
+        let ttl_secs = packet.ttl.saturating_sub(packet.current_age());
+        self.connection.send(destination, packet_bytes, Some(ttl_secs)).await
     }
 
     async fn discover_peers(&self) -> Result<Vec<Peer>, TransportError> {
         Ok(Vec::new())
     }
 
-    async fn start_listening(&self, callback: Box<dyn Fn(crate::core::Packet) + Send + Sync>) -> Result<(), TransportError> {
+    async fn start_listening(&self, callback: Box<dyn Fn(Packet) + Send + Sync>) -> Result<(), TransportError> {
         // Check if TLS is disabled
-        if self.config.tls_disabled {
-            return Err(TransportError::SecurityError("TLS is required for production".to_string()));
-        }
-        
-        // Connect with TLS
-        let mut tls_stream = self.connect_tls().await?;
-        
-        // Wrap callback in Arc for sharing across async tasks
-        let callback = Arc::new(callback);
-        
-        // Read packets in a loop
-        loop {
-            // Read length prefix (4 bytes, big-endian)
-            let mut len_bytes = [0u8; 4];
-            match tls_stream.read_exact(&mut len_bytes).await {
-                Ok(_) => {},
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                    // Connection closed gracefully
-                    break;
-                },
-                Err(e) => {
-                    return Err(TransportError::ReceiveFailed(format!("Read length failed: {}", e)));
-                }
-            }
-            
-            let packet_len = u32::from_be_bytes(len_bytes) as usize;
-            
-            // Sanity check: reject packets larger than 128KB
-            if packet_len > 128 * 1024 {
-                return Err(TransportError::ReceiveFailed(
-                    format!("Packet too large: {} bytes", packet_len)
-                ));
-            }
-            
-            // Read packet data
-            let mut packet_bytes = vec![0u8; packet_len];
-            tls_stream.read_exact(&mut packet_bytes)
-                .await
-                .map_err(|e| TransportError::ReceiveFailed(format!("Read packet failed: {}", e)))?;
-            
-            // Deserialize packet
-            match ciborium::de::from_reader(&packet_bytes[..]) {
-                Ok(packet) => {
-                    // Call callback with received packet
-                    callback(packet);
-                },
+        self.check_tls_policy()?;
+        self.check_identity()?;
+
+        // Доставленные пакеты читаются в фоне до `stop_listening`
+        self.connection.start(Box::new(move |delivery| {
+            match Packet::from_bytes(&delivery.packet) {
+                Ok(packet) => callback(packet),
                 Err(e) => {
                     // Log deserialization error but continue listening
-                    eprintln!("Failed to deserialize packet: {}", e);
-                    continue;
+                    tracing::warn!("Failed to deserialize packet from {}: {}", delivery.from, e);
                 }
             }
-        }
-        
-        Ok(())
+        }))
     }
 
     async fn stop_listening(&self) -> Result<(), TransportError> {
+        self.connection.stop().await;
         Ok(())
     }
 }
\ No newline at end of file
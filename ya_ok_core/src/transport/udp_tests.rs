@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod udp_tests {
     use crate::transport::{Transport, TransportType, TransportError};
-    use crate::transport::udp::{UdpTransport, UdpTransportConfig};
+    use crate::transport::udp::{RelayLink, UdpTransport, UdpTransportConfig};
 
     #[test]
     fn test_udp_transport_default_config() {
@@ -16,7 +16,10 @@ mod udp_tests {
         let config = UdpTransportConfig {
             relay_url: "custom-relay.example.com:8443".to_string(),
             pinned_cert_fingerprint: Some("A1:B2:C3:D4:E5:F6".to_string()),
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
         };
         
         let transport = UdpTransport::with_config(config);
@@ -33,7 +36,10 @@ mod udp_tests {
         let config = UdpTransportConfig {
             relay_url: "relay.example.com:40100".to_string(),
             pinned_cert_fingerprint: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
         };
         
         let transport = UdpTransport::with_config(config);
@@ -49,7 +55,10 @@ mod udp_tests {
         let config = UdpTransportConfig {
             relay_url: "relay.example.com:40100".to_string(),
             pinned_cert_fingerprint: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
         };
         
         let transport = UdpTransport::with_config(config);
@@ -72,7 +81,10 @@ mod udp_tests {
         let config = UdpTransportConfig {
             relay_url: "relay.example.com:40100".to_string(),
             pinned_cert_fingerprint: None,
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
         };
         
         let transport = UdpTransport::with_config(config);
@@ -100,9 +112,60 @@ mod udp_tests {
         let config = UdpTransportConfig {
             relay_url: "invalid-host-that-does-not-exist.local:40100".to_string(),
             pinned_cert_fingerprint: None,
+            backup_pins: Vec::new(),
+            pins_expire_at: None,
             tls_disabled: false,
+            link: RelayLink::Stream,
         };
         let transport = UdpTransport::with_config(config);
         assert!(!transport.is_available().await, "Invalid hostname should not be available");
     }
+
+    #[test]
+    fn test_relay_url_accepts_ipv6_and_link_schemes() {
+        let config = |relay_url: &str, link: RelayLink, tls_disabled: bool| UdpTransportConfig {
+            relay_url: relay_url.to_string(),
+            tls_disabled,
+            link,
+            ..UdpTransportConfig::default()
+        };
+
+        let endpoint = config("[2001:db8::1]:8443", RelayLink::Stream, false).endpoint().unwrap();
+        assert_eq!(endpoint.authority(), "[2001:db8::1]:8443");
+        assert_eq!(endpoint.server_name(), "2001:db8::1");
+        assert!(config("tls://relay.example.com", RelayLink::Stream, false).endpoint().is_ok());
+        assert!(config("udp://[::1]:40100", RelayLink::Datagram, false).endpoint().is_ok());
+        assert!(config("tcp://[::1]:40100", RelayLink::Stream, true).endpoint().is_ok());
+
+        // Схема должна совпадать с каналом
+        let mismatched = config("udp://relay.example.com:40100", RelayLink::Stream, false).endpoint();
+        assert!(matches!(mismatched, Err(TransportError::InvalidAddress(_))));
+        let mismatched = config("tls://relay.example.com:40100", RelayLink::Stream, true).endpoint();
+        assert!(matches!(mismatched, Err(TransportError::InvalidAddress(_))));
+        // IPv6 без скобок неоднозначен
+        assert!(matches!(config("2001:db8::1:40100", RelayLink::Stream, false).endpoint(), Err(TransportError::InvalidAddress(_))));
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_allowed_only_for_loopback_endpoints() {
+        let identity = crate::core::Identity::new();
+        let transport = |relay_url: &str| {
+            let config = UdpTransportConfig {
+                relay_url: relay_url.to_string(),
+                tls_disabled: true,
+                ..UdpTransportConfig::default()
+            };
+            UdpTransport::with_identity(config, identity.clone())
+        };
+
+        // Порт закрыт: отказ в подключении, а не в политике TLS
+        for relay_url in ["[::1]:1", "tcp://localhost:1", "[::ffff:127.0.0.1]:1"] {
+            let result = transport(relay_url).probe().await;
+            assert!(matches!(result, Err(TransportError::SendFailed(_))), "{}: {:?}", relay_url, result);
+        }
+        for relay_url in ["[2001:db8::1]:40100", "localhost.example.com:40100"] {
+            let result = transport(relay_url).probe().await;
+            assert!(matches!(result, Err(TransportError::SecurityError(_))), "{}: {:?}", relay_url, result);
+        }
+    }
 }
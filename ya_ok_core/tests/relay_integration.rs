@@ -0,0 +1,1816 @@
+//! Интеграционный тест: локальные relay (включая федерацию из трех relay,
+//! почтовый ящик для узлов не в сети, метки получателя и экстренную
+//! рассылку, TLS, административный API, перезагрузку конфигурации,
+//! rendezvous для прямых каналов через эмулированный NAT, push-пробуждение
+//! через тестовый провайдер) и ядра на localhost
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc;
+use ed25519_dalek::{Signer, SigningKey};
+use ya_ok_core::core::packet::Priority;
+use ya_ok_core::core::{Identity, Message, Packet, StatusType};
+use ya_ok_core::routing::{DtnRouter, Router};
+use ya_ok_core::storage::Storage;
+use ya_ok_core::transport::direct::{DirectConfig, DirectTransport, PunchSocket};
+use ya_ok_core::transport::dtls::{connect_datagram, tls_config_for, tls_link, PinSet, PinnedCertVerifier};
+use ya_ok_core::transport::relay::RelaySession;
+use ya_ok_core::transport::relay_pool::{
+    RelayEndpoint, RelayList, RelayListError, RelayPool, RelayPoolConfig, RelaySlot, SignedRelayList,
+};
+use ya_ok_core::transport::udp::{RelayLink, UdpTransport, UdpTransportConfig};
+use ya_ok_core::transport::{Peer, Transport, TransportError, TransportManager, TransportType};
+use yaok_relay::{
+    serve, serve_datagram, serve_rendezvous, serve_tls, start_federation, start_push, Admin, AdminRequest, AdminTokens,
+    AuditLog, Config, FederationConfig, FederationPeer, Mailbox, MailboxConfig, MockPushProvider, PushConfig, PushGateway,
+    Relay, RelayConfig, RelayState, Rendezvous, Store, TlsCertificates,
+};
+use yaok_relay::mailbox::unix_now;
+use yaok_relay_proto::datagram::{ClientEvent, ClientHandshake, Responder, StaticKeypair, MAX_DATAGRAM_PACKET};
+use yaok_relay_proto::tags::{recipient_tag, tag_epoch};
+use yaok_relay_proto::{
+    auth_payload, node_id_for_key, peer_auth_payload, stream_link, verify_peer_auth, ErrorCode, MessageLink, PeerRole,
+    PeerTranscript, ProtoError, RelayMessage, NONCE_LEN, PROTOCOL_VERSION,
+};
+
+async fn start_relay() -> (String, Arc<RelayState>) {
+    start_relay_with(RelayConfig::default()).await
+}
+
+async fn start_relay_with(config: RelayConfig) -> (String, Arc<RelayState>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(RelayState::new(config));
+    tokio::spawn(serve(listener, state.clone()));
+    (addr.to_string(), state)
+}
+
+/// Relay с датаграммным входом; возвращает адрес и fingerprint ключа
+async fn start_datagram_relay() -> (String, String, Arc<RelayState>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let responder = Responder::new(StaticKeypair::generate());
+    let fingerprint = responder.fingerprint();
+    let state = Arc::new(RelayState::new(RelayConfig::default()));
+    tokio::spawn(serve_datagram(socket, responder, state.clone()));
+    (addr.to_string(), fingerprint, state)
+}
+
+fn datagram_transport(relay_url: &str, pin: &str, identity: &Identity) -> Arc<UdpTransport> {
+    let config = UdpTransportConfig {
+        relay_url: relay_url.to_string(),
+        pinned_cert_fingerprint: Some(pin.to_string()),
+        backup_pins: Vec::new(),
+        pins_expire_at: None,
+        tls_disabled: false,
+        link: RelayLink::Datagram,
+    };
+    Arc::new(UdpTransport::with_identity(config, identity.clone()))
+}
+
+fn local_transport(relay_url: &str, identity: &Identity) -> Arc<UdpTransport> {
+    let config = UdpTransportConfig {
+        relay_url: relay_url.to_string(),
+        pinned_cert_fingerprint: None,
+        backup_pins: Vec::new(),
+        pins_expire_at: None,
+        tls_disabled: true,
+        link: RelayLink::Stream,
+    };
+    Arc::new(UdpTransport::with_identity(config, identity.clone()))
+}
+
+async fn wait_registered(state: &RelayState, node_id: &str) {
+    for _ in 0..100 {
+        if state.is_registered(node_id) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} did not register on relay", node_id);
+}
+
+#[tokio::test]
+async fn test_packet_delivered_between_two_cores() {
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let alice_transport = local_transport(&relay_url, &alice);
+    let bob_transport = local_transport(&relay_url, &bob);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let listener = bob_transport.clone();
+    let listen_task = tokio::spawn(async move {
+        listener
+            .start_listening(Box::new(move |packet| {
+                let _ = tx.send(packet);
+            }))
+            .await
+    });
+    wait_registered(&state, &bob.id).await;
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet not delivered")
+        .unwrap();
+    let decrypted = received.decrypt(&bob).unwrap();
+    assert_eq!(decrypted.id, message.id);
+    assert_eq!(decrypted.sender_id, alice.id);
+
+    // Релей не рассылает пакет остальным: у отправителя своя сессия
+    assert!(state.is_registered(&alice.id));
+    let stats = state.take_stats();
+    assert_eq!(stats.forwarded, 1);
+
+    bob_transport.stop_listening().await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), listen_task).await.unwrap().unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn test_dual_stack_relay_serves_ipv6_and_ipv4_clients() {
+    let listener = yaok_relay::net::bind_tcp(yaok_relay::net::dual_stack(0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let state = Arc::new(RelayState::new(RelayConfig::default()));
+    tokio::spawn(serve(listener, state.clone()));
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    // alice по IPv6, bob по IPv4 на тот же сокет
+    let alice_transport = local_transport(&format!("[::1]:{}", port), &alice);
+    let bob_transport = local_transport(&format!("tcp://127.0.0.1:{}", port), &bob);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    // IPv4-клиент виден relay как IPv4, а не как ::ffff:127.0.0.1
+    let peers = state.peers();
+    let address = |node_id: &str| peers.iter().find(|peer| peer.node_id == node_id).unwrap().ip;
+    assert_eq!(address(&alice.id), "::1".parse::<std::net::IpAddr>().unwrap());
+    assert_eq!(address(&bob.id), "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_datagram_link_over_ipv6() {
+    let socket = yaok_relay::net::bind_udp(yaok_relay::net::dual_stack(0)).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    let responder = Responder::new(StaticKeypair::generate());
+    let pin = responder.fingerprint();
+    let state = Arc::new(RelayState::new(RelayConfig::default()));
+    tokio::spawn(serve_datagram(socket, responder, state.clone()));
+    let alice = Identity::new();
+
+    let transport = datagram_transport(&format!("udp://[::1]:{}", port), &pin, &alice);
+    transport.probe().await.unwrap();
+    assert!(state.is_registered(&alice.id));
+    assert_eq!(state.take_stats().udp_handshakes, 1);
+}
+
+#[tokio::test]
+async fn test_send_to_unknown_node_fails() {
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let stranger = Identity::new();
+
+    let transport = local_transport(&relay_url, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &stranger.x25519_public_bytes().unwrap()).unwrap();
+
+    let result = transport.send_packet(&packet, &stranger.id).await;
+    assert!(matches!(result, Err(TransportError::SendFailed(_))));
+    assert_eq!(state.take_stats().dropped_offline, 1);
+}
+
+#[tokio::test]
+async fn test_plaintext_refused_for_remote_relay() {
+    let identity = Identity::new();
+    let transport = local_transport("relay.example.com:40100", &identity);
+
+    assert!(!transport.is_available().await);
+    let message = Message::status(identity.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &identity, &identity.x25519_public_bytes().unwrap()).unwrap();
+    let result = transport.send_packet(&packet, &identity.id).await;
+    assert!(matches!(result, Err(TransportError::SecurityError(_))));
+}
+
+#[tokio::test]
+async fn test_datagram_link_delivers_and_resumes() {
+    let (relay_url, pin, state) = start_datagram_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let alice_transport = datagram_transport(&relay_url, &pin, &alice);
+    let bob_transport = datagram_transport(&relay_url, &pin, &bob);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let listener = bob_transport.clone();
+    let listen_task = tokio::spawn(async move {
+        listener
+            .start_listening(Box::new(move |packet| {
+                let _ = tx.send(packet);
+            }))
+            .await
+    });
+    wait_registered(&state, &bob.id).await;
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    let stats = state.take_stats();
+    assert_eq!(stats.udp_handshakes, 2);
+    assert_eq!(stats.udp_resumptions, 0);
+
+    // Повторное подключение использует билет возобновления
+    alice_transport.stop_listening().await.unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+    let stats = state.take_stats();
+    assert_eq!(stats.udp_resumptions, 1);
+    assert_eq!(stats.forwarded, 1);
+
+    bob_transport.stop_listening().await.unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), listen_task).await.unwrap().unwrap();
+    assert!(result.is_ok());
+}
+
+#[tokio::test]
+async fn test_datagram_link_rejects_wrong_pin() {
+    let (relay_url, _pin, state) = start_datagram_relay().await;
+    let alice = Identity::new();
+    let other_key = StaticKeypair::generate().fingerprint();
+
+    let transport = datagram_transport(&relay_url, &other_key, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &alice.x25519_public_bytes().unwrap()).unwrap();
+
+    let result = transport.send_packet(&packet, &alice.id).await;
+    assert!(matches!(result, Err(TransportError::SecurityError(_))));
+    assert!(!state.is_registered(&alice.id));
+}
+
+#[tokio::test]
+async fn test_datagram_sessions_limited_per_address() {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = socket.local_addr().unwrap();
+    let config = RelayConfig { max_sessions_per_ip: 2, ..RelayConfig::default() };
+    let state = Arc::new(RelayState::new(config));
+    tokio::spawn(serve_datagram(socket, Responder::new(StaticKeypair::generate()), state.clone()));
+
+    // Рукопожатия без аутентификации: слоты занимают первые две сессии,
+    // третью relay с того же адреса не открывает
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    let mut established = 0;
+    for _ in 0..3 {
+        let handshake = ClientHandshake::full(None);
+        let mut cookie = None;
+        for _ in 0..2 {
+            client.send_to(&handshake.initiation(cookie.as_deref()), relay_addr).await.unwrap();
+            let Ok(received) = tokio::time::timeout(Duration::from_millis(500), client.recv_from(&mut buf)).await else {
+                break;
+            };
+            let (len, _) = received.unwrap();
+            match handshake.read_reply(&buf[..len], unix_now()).unwrap() {
+                ClientEvent::Retry(new_cookie) => cookie = Some(new_cookie),
+                ClientEvent::Established { .. } => {
+                    established += 1;
+                    break;
+                }
+                ClientEvent::Rejected => unreachable!(),
+            }
+        }
+    }
+    assert_eq!(established, 2);
+    let stats = state.take_stats();
+    assert_eq!(stats.udp_handshakes, 2);
+    assert_eq!(stats.rejected_ip_sessions, 1);
+}
+
+#[tokio::test]
+async fn test_datagram_session_refuses_oversize_packets() {
+    let (relay_url, pin, state) = start_datagram_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let (link, _) = connect_datagram(&relay_url, Some(pin.clone()), None).await.unwrap();
+    let alice_session = RelaySession::establish_link(link, &alice).await.unwrap();
+    let (link, _) = connect_datagram(&relay_url, Some(pin), None).await.unwrap();
+    let bob_session = RelaySession::establish_link(link, &bob).await.unwrap();
+
+    // Пакет, который не помещается в датаграмму, отвергается с ошибкой,
+    // а не теряется молча; сессия продолжает работать
+    let result = alice_session.send(&bob.id, vec![7; MAX_DATAGRAM_PACKET + 1], None).await;
+    assert!(matches!(result, Err(TransportError::SendFailed(reason)) if reason.contains("TooLarge")));
+    assert_eq!(state.take_stats().dropped_datagram_size, 1);
+
+    alice_session.send(&bob.id, vec![7; MAX_DATAGRAM_PACKET], None).await.unwrap();
+    let delivery = tokio::time::timeout(Duration::from_secs(5), bob_session.recv()).await.unwrap().unwrap();
+    assert_eq!(delivery.packet.len(), MAX_DATAGRAM_PACKET);
+}
+
+#[tokio::test]
+async fn test_persistent_session_reconnects_after_drop() {
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let alice_transport = local_transport(&relay_url, &alice);
+    let bob_transport = local_transport(&relay_url, &bob);
+
+    // Прослушивание работает в фоне, вызов возвращается сразу
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    // Сессию bob вытесняет другое подключение с тем же node id
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let intruder = RelaySession::establish(stream, &bob).await.unwrap();
+    for _ in 0..100 {
+        if bob_transport.stats().reconnects > 0 && intruder.is_closed() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(bob_transport.stats().reconnects, 1);
+    wait_registered(&state, &bob.id).await;
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    for _ in 0..3 {
+        alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("packet not delivered")
+            .unwrap();
+        assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+    }
+
+    // Все пакеты идут через одну сессию
+    let sent = alice_transport.stats();
+    assert_eq!(sent.connects, 1);
+    assert_eq!(sent.packets_sent, 3);
+    assert!(sent.connected);
+    let received = bob_transport.stats();
+    assert_eq!(received.packets_received, 3);
+    assert_eq!(received.bytes_received, sent.bytes_sent);
+    assert!(received.listening);
+
+    bob_transport.stop_listening().await.unwrap();
+    let stopped = bob_transport.stats();
+    assert!(!stopped.listening);
+    assert!(!stopped.connected);
+    for _ in 0..100 {
+        if !state.is_registered(&bob.id) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!state.is_registered(&bob.id));
+}
+
+fn local_pool(identity: &Identity, relays: &[&str]) -> Arc<RelayPool> {
+    let config = RelayPoolConfig {
+        transport: UdpTransportConfig {
+            tls_disabled: true,
+            ..UdpTransportConfig::default()
+        },
+        ..RelayPoolConfig::default()
+    };
+    let relays = relays.iter().map(|url| RelayEndpoint::new(*url)).collect();
+    RelayPool::new(identity.clone(), relays, config)
+}
+
+/// Адрес, на котором никто не слушает
+async fn dead_relay_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_pool_fails_over_to_next_relay() {
+    let dead_url = dead_relay_url().await;
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    // Первым в списке стоит недоступный relay
+    let pool = local_pool(&alice, &[&dead_url, &relay_url]);
+    let message = Message::text(alice.id.clone(), "hello".to_string()).unwrap();
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    pool.send_packet(&packet, &bob.id).await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    let stats = pool.stats();
+    assert_eq!(stats.failovers, 1);
+    assert!(!stats.relays[0].healthy);
+
+    // Основной relay закрепляется за рабочим
+    pool.send_packet(&packet, &bob.id).await.unwrap();
+    assert_eq!(pool.stats().primary.as_deref(), Some(relay_url.as_str()));
+    assert_eq!(pool.stats().failovers, 1);
+    assert_eq!(state.take_stats().forwarded, 2);
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_pool_probes_score_relays() {
+    let dead_url = dead_relay_url().await;
+    let (first_url, _first) = start_relay().await;
+    let (second_url, _second) = start_relay().await;
+    let alice = Identity::new();
+
+    let pool = local_pool(&alice, &[&dead_url, &first_url, &second_url]);
+    pool.probe_all().await;
+
+    let stats = pool.stats();
+    let dead = &stats.relays[0];
+    assert_eq!(dead.probes_failed, 1);
+    assert!(dead.latency_ms.is_none());
+    for relay in &stats.relays[1..] {
+        assert_eq!(relay.probes_ok, 1);
+        assert!(relay.latency_ms.is_some());
+        assert!(relay.connection.connected);
+    }
+    let primary = stats.primary.expect("primary relay selected");
+    assert_ne!(primary, dead_url);
+}
+
+#[tokio::test]
+async fn test_pool_multi_publishes_high_priority_once() {
+    let (first_url, first) = start_relay().await;
+    let (second_url, second) = start_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    // Получатель слушает на обоих relay
+    let bob_pool = local_pool(&bob, &[&first_url, &second_url]);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_pool
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&first, &bob.id).await;
+    wait_registered(&second, &bob.id).await;
+
+    let alice_pool = local_pool(&alice, &[&first_url, &second_url]);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    assert_eq!(packet.priority, Priority::High);
+    alice_pool.send_packet(&packet, &bob.id).await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    // Пакет прошел через оба relay, но доставлен один раз
+    assert_eq!(first.take_stats().forwarded, 1);
+    assert_eq!(second.take_stats().forwarded, 1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(rx.try_recv().is_err());
+    assert_eq!(alice_pool.stats().multi_publishes, 1);
+
+    bob_pool.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_pool_applies_signed_relay_list() {
+    let alice = Identity::new();
+    let publisher = SigningKey::from_bytes(&rand::random());
+    let config = RelayPoolConfig {
+        list_publisher: Some(publisher.verifying_key()),
+        ..RelayPoolConfig::default()
+    };
+    let pool = RelayPool::new(alice.clone(), vec![RelayEndpoint::new("127.0.0.1:1")], config);
+
+    let list = RelayList {
+        version: 2,
+        expires_at: None,
+        relays: vec![RelayEndpoint::new("127.0.0.1:2"), RelayEndpoint::new("127.0.0.1:3")],
+    };
+    let signed = SignedRelayList::sign(&list, &publisher);
+    assert_eq!(pool.apply_signed_list(&signed, 0).await, Ok(2));
+    assert_eq!(pool.relays(), list.relays);
+    assert_eq!(pool.stats().list_version, 2);
+
+    // Откат к старой версии отклоняется
+    let old = SignedRelayList::sign(&RelayList { version: 1, ..list.clone() }, &publisher);
+    assert_eq!(
+        pool.apply_signed_list(&old, 0).await,
+        Err(RelayListError::Rollback { current: 2, received: 1 })
+    );
+
+    // Список от чужого ключа не применяется
+    let forger = SigningKey::from_bytes(&rand::random());
+    let forged = SignedRelayList::sign(&RelayList { version: 3, ..list.clone() }, &forger);
+    assert_eq!(pool.apply_signed_list(&forged, 0).await, Err(RelayListError::BadSignature));
+    assert_eq!(pool.relays(), list.relays);
+
+    // Без ключа издателя в настройках списки не применяются вовсе
+    let unconfigured = local_pool(&alice, &["127.0.0.1:1"]);
+    let newer = SignedRelayList::sign(&RelayList { version: 4, ..list.clone() }, &publisher);
+    assert_eq!(unconfigured.apply_signed_list(&newer, 0).await, Err(RelayListError::NoPublisher));
+}
+
+#[tokio::test]
+async fn test_router_sends_through_relay_slot() {
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let storage = Arc::new(Mutex::new(Storage::new(dir.path().join("router.db")).unwrap()));
+    let slot = RelaySlot::new();
+    let mut transports = TransportManager::new();
+    transports.add_transport(Box::new(slot.clone()));
+    let router = DtnRouter::new(storage, transports);
+    // Адрес пира - адрес транспорта, через который он пришёл; relay
+    // адресует узел по id
+    router
+        .update_peers(vec![Peer {
+            id: bob.id.clone(),
+            transport_type: TransportType::Ble,
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            last_seen: chrono::Utc::now(),
+            signal_strength: None,
+            ed25519_public_key: None,
+            x25519_public_key: bob.x25519_public_bytes().map(|key| key.to_vec()),
+        }])
+        .await;
+
+    let message = Message::text(alice.id.clone(), "hello".to_string()).unwrap();
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+
+    // Пока пула нет, отправлять не через что
+    assert!(router.send_to(&packet, &bob.id).await.is_err());
+
+    let pool = local_pool(&alice, &[&relay_url]);
+    slot.get_or_try_insert(|| Ok::<_, ()>(pool.clone())).unwrap();
+    router.send_to(&packet, &bob.id).await.unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+/// Relay в федерации с `peers`: клиенты подключаются к `listener`, другие
+/// relay - по TLS к `peer_listener` (сертификат из `dir`, доверие к `roots`).
+/// Слушатели созданы заранее, чтобы адреса всех relay были известны до запуска
+fn start_federated_relay(
+    listener: TcpListener,
+    peer_listener: TcpListener,
+    dir: &std::path::Path,
+    key: SigningKey,
+    peers: Vec<FederationPeer>,
+    roots: rustls::RootCertStore,
+) -> Arc<RelayState> {
+    let federation = FederationConfig { tls_roots: Arc::new(roots), ..FederationConfig::new(key, peers) };
+    let state = Arc::new(RelayState::new(RelayConfig {
+        federation: Some(federation),
+        ..RelayConfig::default()
+    }));
+    let certs = Arc::new(TlsCertificates::load(dir.join("relay-cert.pem"), dir.join("relay-key.pem")).unwrap());
+    tokio::spawn(serve(listener, state.clone()));
+    tokio::spawn(serve_tls(peer_listener, certs, state.clone()));
+    start_federation(&state);
+    state
+}
+
+fn relay_id(key: &SigningKey) -> String {
+    node_id_for_key(key.verifying_key().as_bytes())
+}
+
+/// `count` relay, каждый связан с каждым
+async fn start_federation_mesh(count: usize) -> Vec<(String, Arc<RelayState>)> {
+    let keys: Vec<SigningKey> = (0..count).map(|_| SigningKey::from_bytes(&rand::random())).collect();
+    let mut listeners = Vec::new();
+    for _ in 0..count {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listeners.push((listener, peer_listener));
+    }
+    let addrs: Vec<String> = listeners.iter().map(|(l, _)| l.local_addr().unwrap().to_string()).collect();
+    let peer_addrs: Vec<String> = listeners.iter().map(|(_, l)| l.local_addr().unwrap().to_string()).collect();
+    let dirs: Vec<tempfile::TempDir> = (0..count).map(|_| tempfile::tempdir().unwrap()).collect();
+    let mut roots = rustls::RootCertStore::empty();
+    for dir in &dirs {
+        roots.add(write_relay_cert(dir.path())).unwrap();
+    }
+
+    let relays: Vec<(String, Arc<RelayState>)> = listeners
+        .into_iter()
+        .zip(&keys)
+        .enumerate()
+        .map(|(i, ((listener, peer_listener), key))| {
+            let peers = (0..count)
+                .filter(|&j| j != i)
+                .map(|j| FederationPeer { relay_id: relay_id(&keys[j]), addr: Some(peer_addrs[j].clone()) })
+                .collect();
+            let state = start_federated_relay(listener, peer_listener, dirs[i].path(), key.clone(), peers, roots.clone());
+            (addrs[i].clone(), state)
+        })
+        .collect();
+
+    for (_, state) in &relays {
+        for _ in 0..250 {
+            if state.federation_peers().len() == count - 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(state.federation_peers().len(), count - 1, "federation links not established");
+    }
+    relays
+}
+
+async fn wait_reachable(state: &RelayState, node_id: &str, reachable: bool) {
+    for _ in 0..100 {
+        if state.is_reachable(node_id) == reachable {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("{} reachable={} not observed", node_id, reachable);
+}
+
+/// Пройти по `link` рукопожатие федеративного relay с ключом `key`
+async fn peer_handshake(mut link: MessageLink, key: &SigningKey) -> Result<MessageLink, ProtoError> {
+    let own_id = relay_id(key);
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    link.outgoing
+        .send(RelayMessage::PeerHello {
+            version: PROTOCOL_VERSION,
+            relay_id: own_id.clone(),
+            public_key: key.verifying_key().to_bytes().to_vec(),
+            nonce: nonce.to_vec(),
+        })
+        .await
+        .unwrap();
+
+    match link.incoming.recv().await.ok_or(ProtoError::Closed)? {
+        RelayMessage::PeerChallenge { relay_id, public_key, nonce: peer_nonce, signature } => {
+            let transcript = PeerTranscript { dialer_nonce: &nonce, acceptor_nonce: &peer_nonce, binding: &link.binding };
+            verify_peer_auth(PeerRole::Acceptor, &transcript, &relay_id, &own_id, &public_key, &signature)?;
+            let signature = key.sign(&peer_auth_payload(PeerRole::Dialer, &transcript, &own_id, &relay_id));
+            link.outgoing.send(RelayMessage::Auth { signature: signature.to_bytes().to_vec(), pow: None }).await.unwrap();
+        }
+        RelayMessage::Error { code, message } => return Err(ProtoError::Rejected(code, message)),
+        other => return Err(ProtoError::Unexpected(other.kind())),
+    }
+    match link.incoming.recv().await.ok_or(ProtoError::Closed)? {
+        RelayMessage::Welcome { .. } => Ok(link),
+        RelayMessage::Error { code, message } => Err(ProtoError::Rejected(code, message)),
+        other => Err(ProtoError::Unexpected(other.kind())),
+    }
+}
+
+#[tokio::test]
+async fn test_federated_relays_deliver_across_homes() {
+    let relays = start_federation_mesh(3).await;
+    let (alice, bob, carol) = (Identity::new(), Identity::new(), Identity::new());
+
+    // Каждый пользователь подключен к своему relay
+    let mut inboxes = Vec::new();
+    let mut transports = Vec::new();
+    for ((relay_url, state), identity) in relays.iter().zip([&alice, &bob, &carol]) {
+        let transport = local_transport(relay_url, identity);
+        let (tx, rx) = mpsc::unbounded_channel();
+        transport
+            .start_listening(Box::new(move |packet| {
+                let _ = tx.send(packet);
+            }))
+            .await
+            .unwrap();
+        wait_registered(state, &identity.id).await;
+        inboxes.push(rx);
+        transports.push(transport);
+    }
+    let (a_state, b_state, c_state) = (&relays[0].1, &relays[1].1, &relays[2].1);
+    wait_reachable(a_state, &carol.id, true).await;
+    wait_reachable(c_state, &bob.id, true).await;
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &carol.x25519_public_bytes().unwrap()).unwrap();
+    transports[0].send_packet(&packet, &carol.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), inboxes[2].recv())
+        .await
+        .expect("packet not delivered across relays")
+        .unwrap();
+    assert_eq!(received.decrypt(&carol).unwrap().id, message.id);
+
+    let message = Message::status(carol.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &carol, &bob.x25519_public_bytes().unwrap()).unwrap();
+    transports[2].send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), inboxes[1].recv())
+        .await
+        .expect("packet not delivered across relays")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    let (a, b, c) = (a_state.take_stats(), b_state.take_stats(), c_state.take_stats());
+    assert_eq!((a.federated_out, a.federated_in), (1, 0));
+    assert_eq!((b.federated_in, b.forwarded), (1, 1));
+    assert_eq!((c.federated_out, c.federated_in, c.forwarded), (1, 1, 1));
+    assert_eq!(a.federation_links, 4);
+
+    // После отключения carol relay A перестает ее видеть
+    transports[2].stop_listening().await.unwrap();
+    wait_reachable(a_state, &carol.id, false).await;
+    let result = transports[0].send_packet(&packet, &carol.id).await;
+    assert!(matches!(result, Err(TransportError::SendFailed(_))));
+    assert_eq!(a_state.take_stats().dropped_offline, 1);
+
+    for transport in &transports[..2] {
+        transport.stop_listening().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_federation_drops_looping_forward() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_url = listener.local_addr().unwrap().to_string();
+    let peer_key = SigningKey::from_bytes(&rand::random());
+    let peer_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer_url = peer_listener.local_addr().unwrap().to_string();
+    let dir = tempfile::tempdir().unwrap();
+    let cert = write_relay_cert(dir.path());
+    let state = start_federated_relay(
+        listener,
+        peer_listener,
+        dir.path(),
+        SigningKey::from_bytes(&rand::random()),
+        vec![FederationPeer { relay_id: relay_id(&peer_key), addr: None }],
+        rustls::RootCertStore::empty(),
+    );
+    let dial_as_peer = |key: SigningKey| {
+        let (peer_url, cert) = (peer_url.clone(), cert.clone());
+        async move { peer_handshake(tls_link(tls_stream(&peer_url, &cert).await.unwrap()).unwrap(), &key).await }
+    };
+
+    // Чужой relay не принимается
+    let stranger = SigningKey::from_bytes(&rand::random());
+    let rejected = dial_as_peer(stranger).await;
+    assert!(matches!(rejected, Err(ProtoError::Rejected(ErrorCode::AuthFailed, _))));
+
+    // Доверенный relay без TLS тоже: кадры после рукопожатия ничем не защищены
+    let plain = stream_link(tokio::net::TcpStream::connect(&relay_url).await.unwrap());
+    let rejected = peer_handshake(plain, &peer_key).await;
+    assert!(matches!(rejected, Err(ProtoError::Rejected(ErrorCode::AuthFailed, _))));
+
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    let peer = dial_as_peer(peer_key.clone()).await.unwrap();
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    let forward = RelayMessage::Forward {
+        id: vec![7u8; 16],
+        from: alice.id.clone(),
+        to: bob.id.clone(),
+        hops: 1,
+        packet: packet.to_bytes().unwrap(),
+        ttl_secs: None,
+    };
+
+    // Тот же пакет вернулся по кругу: доставляется один раз
+    peer.outgoing.send(forward.clone()).await.unwrap();
+    peer.outgoing.send(forward).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("forwarded packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    // Получателя здесь нет, а переходы исчерпаны
+    peer.outgoing
+        .send(RelayMessage::Forward {
+            id: vec![8u8; 16],
+            from: alice.id.clone(),
+            to: alice.id.clone(),
+            hops: 0,
+            packet: packet.to_bytes().unwrap(),
+            ttl_secs: None,
+        })
+        .await
+        .unwrap();
+
+    let mut stats = state.take_stats();
+    for _ in 0..100 {
+        if stats.dropped_loop >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let more = state.take_stats();
+        stats.dropped_loop += more.dropped_loop;
+        stats.federated_in += more.federated_in;
+        stats.forwarded += more.forwarded;
+    }
+    assert_eq!(stats.federated_in, 3);
+    assert_eq!(stats.forwarded, 1);
+    assert_eq!(stats.dropped_loop, 2);
+    assert!(rx.try_recv().is_err());
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+/// Relay с почтовым ящиком; `dir` - каталог для хранения на диске
+async fn start_mailbox_relay(dir: Option<std::path::PathBuf>) -> (String, Arc<RelayState>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mailbox = Mailbox::open(MailboxConfig { dir, ..MailboxConfig::default() }).unwrap();
+    let state = Arc::new(RelayState::new(RelayConfig::default()).with_mailbox(mailbox));
+    tokio::spawn(serve(listener, state.clone()));
+    (addr.to_string(), state)
+}
+
+async fn wait_mailbox_empty(state: &RelayState, node_id: &str) {
+    for _ in 0..100 {
+        if state.mailbox_pending(node_id) == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("mailbox of {} was not drained", node_id);
+}
+
+#[tokio::test]
+async fn test_mailbox_delivers_after_recipient_connects() {
+    let (relay_url, state) = start_mailbox_relay(None).await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    // Bob не в сети: relay сохраняет пакет, отправка успешна
+    let alice_transport = local_transport(&relay_url, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+    assert_eq!(state.mailbox_pending(&bob.id), 1);
+
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("stored packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    // Подтвержденный пакет удален из почтового ящика
+    wait_mailbox_empty(&state, &bob.id).await;
+    assert_eq!(bob_transport.stats().packets_acked, 1);
+    let stats = state.take_stats();
+    assert_eq!(stats.stored, 1);
+    assert_eq!(stats.mailbox_acked, 1);
+    assert_eq!(stats.mailbox_packets, 0);
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_mailbox_redelivers_unacked_packet() {
+    let (relay_url, state) = start_mailbox_relay(None).await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let alice_transport = local_transport(&relay_url, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+
+    // Первая сессия получает пакет, но не подтверждает его
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let session = RelaySession::establish(stream, &bob).await.unwrap();
+    let first = tokio::time::timeout(Duration::from_secs(5), session.recv()).await.unwrap().unwrap();
+    let mailbox_id = first.mailbox_id.expect("delivery from mailbox");
+    assert_eq!(first.from, alice.id);
+    session.close().await;
+    drop(session);
+    assert_eq!(state.mailbox_pending(&bob.id), 1);
+
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let session = RelaySession::establish(stream, &bob).await.unwrap();
+    let second = tokio::time::timeout(Duration::from_secs(5), session.recv()).await.unwrap().unwrap();
+    assert_eq!(second.mailbox_id, Some(mailbox_id));
+    assert_eq!(second.packet, first.packet);
+
+    session.ack(mailbox_id).await.unwrap();
+    wait_mailbox_empty(&state, &bob.id).await;
+}
+
+#[tokio::test]
+async fn test_mailbox_survives_relay_restart() {
+    let dir = tempfile::tempdir().unwrap();
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    {
+        let (relay_url, state) = start_mailbox_relay(Some(dir.path().to_path_buf())).await;
+        local_transport(&relay_url, &alice).send_packet(&packet, &bob.id).await.unwrap();
+        assert_eq!(state.mailbox_pending(&bob.id), 1);
+    }
+
+    // Новый relay над тем же каталогом
+    let (relay_url, state) = start_mailbox_relay(Some(dir.path().to_path_buf())).await;
+    assert_eq!(state.mailbox_pending(&bob.id), 1);
+
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("persisted packet not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+    wait_mailbox_empty(&state, &bob.id).await;
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_recipient_tags_route_to_registered_node() {
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let mallory = Identity::new();
+
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+    assert_eq!(bob_transport.register_recipient_tags(b"bob-and-alice").await.unwrap(), 2);
+
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    let tag = recipient_tag(b"bob-and-alice", tag_epoch(now));
+    assert_eq!(state.tag_owner(&tag), Some(bob.id.clone()));
+
+    // Чужую метку перехватить нельзя
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let intruder = RelaySession::establish(stream, &mallory).await.unwrap();
+    assert_eq!(intruder.register_tags(&[tag], now + 600).await.unwrap(), 0);
+
+    let alice_transport = local_transport(&relay_url, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &hex::encode(tag)).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("packet for tag not delivered")
+        .unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    let unknown = recipient_tag(b"nobody", tag_epoch(now));
+    let result = alice_transport.send_packet(&packet, &hex::encode(unknown)).await;
+    assert!(matches!(result, Err(TransportError::SendFailed(_))));
+    assert!(tokio::time::timeout(Duration::from_millis(200), intruder.recv()).await.is_err());
+
+    let stats = state.take_stats();
+    assert_eq!(stats.tag_routed, 1);
+    assert_eq!(stats.tag_registrations, 2);
+    assert_eq!(stats.registered_tags, 2);
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_flood_reaches_every_session_once_per_interval() {
+    let (relay_url, state) = start_relay().await;
+    let alice = Identity::new();
+    let listeners = [Identity::new(), Identity::new()];
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut transports = Vec::new();
+    for identity in &listeners {
+        let transport = local_transport(&relay_url, identity);
+        let tx = tx.clone();
+        let id = identity.id.clone();
+        transport
+            .start_listening(Box::new(move |packet| {
+                let _ = tx.send((id.clone(), packet));
+            }))
+            .await
+            .unwrap();
+        wait_registered(&state, &identity.id).await;
+        transports.push(transport);
+    }
+
+    let alice_transport = local_transport(&relay_url, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &listeners[0].x25519_public_bytes().unwrap()).unwrap();
+    assert_eq!(packet.priority, Priority::High);
+    alice_transport.flood_packet(&packet).await.unwrap();
+
+    let mut reached = Vec::new();
+    for _ in 0..listeners.len() {
+        let (id, _) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("flood not delivered")
+            .unwrap();
+        reached.push(id);
+    }
+    reached.sort();
+    let mut expected: Vec<_> = listeners.iter().map(|identity| identity.id.clone()).collect();
+    expected.sort();
+    assert_eq!(reached, expected);
+
+    // Повторная рассылка до конца интервала отклоняется
+    let result = alice_transport.flood_packet(&packet).await;
+    assert!(matches!(result, Err(TransportError::SendFailed(_))));
+    let mut low = packet.clone();
+    low.priority = Priority::Low;
+    assert!(alice_transport.flood_packet(&low).await.is_err());
+
+    let stats = state.take_stats();
+    assert_eq!(stats.flooded, 1);
+    assert_eq!(stats.flood_deliveries, 2);
+    assert_eq!(stats.max_fanout, 2);
+    assert_eq!(stats.dropped_flood_rate, 1);
+    assert_eq!(stats.forwarded, 0);
+
+    for transport in transports {
+        transport.stop_listening().await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_relay_restart_keeps_tag_registrations_and_counters() {
+    let dir = tempfile::tempdir().unwrap();
+    let mailbox_dir = dir.path().join("mailbox");
+    let store_dir = dir.path().join("store");
+    let open_state = || {
+        let mailbox = Mailbox::open(MailboxConfig { dir: Some(mailbox_dir.clone()), ..MailboxConfig::default() }).unwrap();
+        RelayState::new(RelayConfig::default()).with_mailbox(mailbox)
+    };
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let carol = Identity::new();
+    let now = unix_now();
+    let tag = recipient_tag(b"bob-and-alice", tag_epoch(now));
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+
+    {
+        let store = Store::open(&store_dir, now).unwrap();
+        let state = Arc::new(open_state().with_store(store));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_url = listener.local_addr().unwrap().to_string();
+        let relay = Relay::new(state.clone(), listener);
+        let shutdown = relay.shutdown_handle();
+        let running = tokio::spawn(relay.run(|_| {}));
+
+        let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+        let session = RelaySession::establish(stream, &bob).await.unwrap();
+        wait_registered(&state, &bob.id).await;
+        assert_eq!(session.register_tags(&[tag], now + 600).await.unwrap(), 1);
+        let alice_transport = local_transport(&relay_url, &alice);
+        alice_transport.send_packet(&packet, &hex::encode(tag)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), session.recv()).await.unwrap().unwrap();
+        // Carol не в сети: пакет получает номер в почтовом ящике
+        alice_transport.send_packet(&packet, &carol.id).await.unwrap();
+        assert_eq!(state.mailbox_pending(&carol.id), 1);
+
+        // Relay останавливается, пока сессия bob открыта
+        shutdown.shutdown();
+        tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+        drop(alice_transport);
+        drop(session);
+        drop(state);
+        let stream = tokio::net::TcpStream::connect(&relay_url).await;
+        assert!(stream.is_err());
+    }
+
+    // База освобождается, когда завершаются последние задачи сессий
+    let mut store = None;
+    for _ in 0..100 {
+        if let Ok(opened) = Store::open(&store_dir, unix_now()) {
+            store = Some(opened);
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let state = Arc::new(open_state().with_store(store.expect("store not released")));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_url = listener.local_addr().unwrap().to_string();
+    tokio::spawn(serve(listener, state.clone()));
+    assert_eq!(state.restarts(), 1);
+    assert_eq!(state.tag_owner(&tag), Some(bob.id.clone()));
+    assert_eq!(state.mailbox_pending(&carol.id), 1);
+
+    // Метка по-прежнему ведет к bob: пакет ждет его в почтовом ящике
+    local_transport(&relay_url, &alice).send_packet(&packet, &hex::encode(tag)).await.unwrap();
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let session = RelaySession::establish(stream, &bob).await.unwrap();
+    let delivered = tokio::time::timeout(Duration::from_secs(5), session.recv()).await.unwrap().unwrap();
+    assert_eq!(delivered.from, alice.id);
+    // Тот же пакет; отправитель добавил к `age` время пребывания
+    let delivered_packet = Packet::from_bytes(&delivered.packet).unwrap();
+    assert_eq!(delivered_packet.content_id().unwrap(), packet.content_id().unwrap());
+    assert!(delivered_packet.age >= 1);
+    // Номера почтового ящика не повторяются после перезапуска
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let carol_session = RelaySession::establish(stream, &carol).await.unwrap();
+    let kept = tokio::time::timeout(Duration::from_secs(5), carol_session.recv()).await.unwrap().unwrap();
+    assert!(delivered.mailbox_id.unwrap() > kept.mailbox_id.unwrap());
+
+    let totals = state.total_stats();
+    assert_eq!(totals.tag_routed, 2);
+    assert_eq!(totals.forwarded, 1);
+    assert_eq!(totals.stored, 2);
+    assert_eq!(totals.tag_registrations, 1);
+}
+
+#[tokio::test]
+async fn test_relay_shutdown_closes_sessions_and_flushes_stats() {
+    let relay = Relay::bind(RelayConfig::default(), "127.0.0.1:0").await.unwrap();
+    let relay_url = relay.local_addr().unwrap().to_string();
+    let state = relay.state();
+    let shutdown = relay.shutdown_handle();
+    let running = tokio::spawn(relay.run(|_| {}));
+
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let bob_transport = local_transport(&relay_url, &bob);
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_transport
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    local_transport(&relay_url, &alice).send_packet(&packet, &bob.id).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+
+    // Счетчики за последний интервал не теряются при остановке
+    shutdown.shutdown();
+    let stats = tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+    assert_eq!(stats.forwarded, 1);
+    assert_eq!(stats.active_peers, 0);
+
+    // Сессия закрыта relay, переподключиться некуда
+    for _ in 0..100 {
+        if !bob_transport.is_connected() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(!bob_transport.is_connected());
+
+    bob_transport.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_relay_requires_proof_of_work_under_load() {
+    // Нагрузка 0%: proof of work нужен всегда
+    let config = RelayConfig { pow_difficulty: 8, pow_load_percent: 0, ..RelayConfig::default() };
+    let (relay_url, state) = start_relay_with(config).await;
+
+    // Ядро решает задачу само
+    let alice = Identity::new();
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let session = RelaySession::establish(stream, &alice).await.unwrap();
+    assert!(state.is_registered(session.node_id()));
+
+    // Верная подпись без proof of work не принимается
+    let key = SigningKey::from_bytes(&rand::random());
+    let node_id = node_id_for_key(key.verifying_key().as_bytes());
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let mut link = stream_link(stream);
+    link.outgoing
+        .send(RelayMessage::Hello {
+            version: PROTOCOL_VERSION,
+            node_id: node_id.clone(),
+            public_key: key.verifying_key().to_bytes().to_vec(),
+        })
+        .await
+        .unwrap();
+    let Some(RelayMessage::Challenge { nonce, pow_difficulty }) = link.incoming.recv().await else {
+        panic!("expected challenge");
+    };
+    assert_eq!(pow_difficulty, 8);
+    let signature = key.sign(&auth_payload(&nonce, &link.binding, &node_id)).to_bytes().to_vec();
+    link.outgoing.send(RelayMessage::Auth { signature, pow: None }).await.unwrap();
+    match link.incoming.recv().await {
+        Some(RelayMessage::Error { code, .. }) => assert_eq!(code, ErrorCode::AuthFailed),
+        other => panic!("expected auth error, got {:?}", other),
+    }
+    assert!(!state.is_registered(&node_id));
+
+    let stats = state.take_stats();
+    assert_eq!(stats.pow_challenges, 2);
+    assert_eq!(stats.rejected_pow, 1);
+    assert_eq!(stats.auth_failures, 0);
+}
+
+#[tokio::test]
+async fn test_relay_limits_sessions_per_ip_and_node_rate() {
+    let config = RelayConfig { max_sessions_per_ip: 2, node_rate_limit_pps: 3, ..RelayConfig::default() };
+    let (relay_url, state) = start_relay_with(config).await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    let connect = |identity: Identity| {
+        let relay_url = relay_url.clone();
+        async move {
+            let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+            RelaySession::establish(stream, &identity).await
+        }
+    };
+    let alice_session = connect(alice.clone()).await.unwrap();
+    let _bob_session = connect(bob.clone()).await.unwrap();
+
+    // Третье подключение с того же адреса отклоняется
+    let result = connect(Identity::new()).await;
+    assert!(matches!(result, Err(TransportError::SendFailed(_))));
+
+    // Квота узла: в пределах секунды проходят три пакета
+    let mut delivered = 0;
+    for _ in 0..5 {
+        if alice_session.send(&bob.id, vec![1, 2, 3], None).await.is_ok() {
+            delivered += 1;
+        }
+    }
+    assert_eq!(delivered, 3);
+
+    let stats = state.take_stats();
+    assert_eq!(stats.rejected_ip_sessions, 1);
+    assert_eq!(stats.dropped_node_rate, 2);
+    assert_eq!(stats.dropped_rate, 0);
+    assert_eq!(stats.forwarded, 3);
+}
+
+async fn wait_session_closed(session: &RelaySession) {
+    for _ in 0..100 {
+        if session.is_closed() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("session was not closed by relay");
+}
+
+#[tokio::test]
+async fn test_admin_api_evicts_and_bans_peers() {
+    const OPERATOR_TOKEN: &str = "operator-token-0123456789";
+    let (relay_url, state) = start_relay().await;
+    let tokens = AdminTokens::parse(&format!("alice:operator:{}", OPERATOR_TOKEN)).unwrap();
+    let admin = Admin::new(state.clone(), tokens, AuditLog::new());
+    let authorization = format!("Bearer {}", OPERATOR_TOKEN);
+    let call = |method: &str, path: &str, body: &str| {
+        admin.handle(&AdminRequest { method, path, authorization: Some(&authorization), body: body.as_bytes() })
+    };
+
+    let connect = |identity: Identity| {
+        let relay_url = relay_url.clone();
+        async move {
+            let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+            RelaySession::establish(stream, &identity).await
+        }
+    };
+    let bob = Identity::new();
+    let carol = Identity::new();
+    let bob_session = connect(bob.clone()).await.unwrap();
+    let carol_session = connect(carol.clone()).await.unwrap();
+
+    // Список узлов содержит обе сессии
+    let peers = call("GET", "/admin/peers", "");
+    assert_eq!(peers.status, 200);
+    assert!(peers.body.contains(&bob.id) && peers.body.contains(&carol.id));
+
+    // Выселение закрывает сессию, но узел может переподключиться
+    let evicted = call("DELETE", &format!("/admin/peers/{}", bob.id), "");
+    assert_eq!(evicted.status, 200);
+    wait_session_closed(&bob_session).await;
+    assert!(!state.is_registered(&bob.id));
+    let _bob_session = connect(bob.clone()).await.unwrap();
+
+    // Бан узла закрывает его сессию и отклоняет повторное подключение
+    let banned = call("POST", "/admin/bans", &format!(r#"{{"node_id": "{}"}}"#, carol.id));
+    assert_eq!(banned.status, 200);
+    wait_session_closed(&carol_session).await;
+    assert!(connect(carol.clone()).await.is_err());
+
+    // Бан диапазона адресов отклоняет любые новые подключения
+    assert_eq!(call("POST", "/admin/bans", r#"{"ip_range": "127.0.0.0/8"}"#).status, 200);
+    assert!(connect(Identity::new()).await.is_err());
+    assert_eq!(call("DELETE", "/admin/bans", r#"{"ip_range": "127.0.0.0/8"}"#).status, 200);
+    let _dave_session = connect(Identity::new()).await.unwrap();
+
+    let stats = state.take_stats();
+    assert_eq!(stats.evicted, 3);
+    assert_eq!(stats.rejected_banned, 2);
+    assert_eq!(admin.audit().recent().len(), 4);
+}
+
+#[tokio::test]
+async fn test_config_reload_applies_limits_and_bans_to_running_relay() {
+    let initial = Config::default();
+    let (relay_url, state) = start_relay_with(initial.relay_config().unwrap()).await;
+    initial.apply(None, &state);
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let mallory = Identity::new();
+
+    let connect = |identity: Identity| {
+        let relay_url = relay_url.clone();
+        async move {
+            let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+            RelaySession::establish(stream, &identity).await
+        }
+    };
+    let alice_session = connect(alice.clone()).await.unwrap();
+    let bob_session = connect(bob.clone()).await.unwrap();
+    let mallory_session = connect(mallory.clone()).await.unwrap();
+
+    // Перечитанный файл: меньшая квота узла и бан mallory
+    let reloaded = Config::from_toml(&format!(
+        "[limits]\nnode_rate_limit_pps = 2\n\n[bans]\nnode_ids = [\"{}\"]\n",
+        mallory.id
+    ))
+    .unwrap();
+    reloaded.validate().unwrap();
+    assert!(reloaded.apply(Some(&initial), &state).is_empty());
+
+    // Сессии alice и bob сохраняются и сразу подчиняются новой квоте
+    wait_session_closed(&mallory_session).await;
+    assert!(!alice_session.is_closed() && !bob_session.is_closed());
+    let mut delivered = 0;
+    for _ in 0..4 {
+        if alice_session.send(&bob.id, vec![7], None).await.is_ok() {
+            delivered += 1;
+        }
+    }
+    assert_eq!(delivered, 2);
+    assert!(bob_session.recv().await.is_some());
+    assert!(connect(mallory.clone()).await.is_err());
+
+    // Бан, убранный из файла, снимается при следующей перезагрузке
+    assert!(initial.apply(Some(&reloaded), &state).is_empty());
+    let _mallory_session = connect(mallory).await.unwrap();
+    assert_eq!(state.rate_limits(), (200, 50));
+}
+
+/// Выпустить самоподписанный сертификат relay.test (и 127.0.0.1) в `dir`; возвращает DER
+fn write_relay_cert(dir: &std::path::Path) -> rustls::pki_types::CertificateDer<'static> {
+    let cert = rcgen::generate_simple_self_signed(vec!["relay.test".to_string(), "127.0.0.1".to_string()]).unwrap();
+    std::fs::write(dir.join("relay-cert.pem"), cert.cert.pem()).unwrap();
+    std::fs::write(dir.join("relay-key.pem"), cert.key_pair.serialize_pem()).unwrap();
+    cert.cert.der().clone()
+}
+
+/// Сессия через TLS, как в `connect_tls`, но с доверием к `root` и pin его ключа
+async fn tls_stream(
+    relay_url: &str,
+    root: &rustls::pki_types::CertificateDer<'static>,
+) -> Result<tokio_rustls::client::TlsStream<tokio::net::TcpStream>, TransportError> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(root.clone()).unwrap();
+    let pin = PinnedCertVerifier::spki_fingerprint(root).unwrap();
+    let verifier = PinnedCertVerifier::with_roots(roots, Some(PinSet::new(vec![pin]))).unwrap();
+    let connector = tokio_rustls::TlsConnector::from(tls_config_for(verifier).unwrap());
+
+    let stream = tokio::net::TcpStream::connect(relay_url).await.unwrap();
+    let server_name = rustls::pki_types::ServerName::try_from("relay.test").unwrap();
+    connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| TransportError::SecurityError(format!("TLS handshake failed: {}", e)))
+}
+
+async fn tls_session(
+    relay_url: &str,
+    root: &rustls::pki_types::CertificateDer<'static>,
+    identity: &Identity,
+) -> Result<RelaySession, TransportError> {
+    let link = tls_link(tls_stream(relay_url, root).await?)?;
+    RelaySession::establish_link(link, identity).await
+}
+
+#[tokio::test]
+async fn test_tls_relay_serves_sessions_and_reloads_certificate() {
+    let dir = std::env::temp_dir().join(format!("yaok-relay-tls-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let first_cert = write_relay_cert(&dir);
+    let certs = Arc::new(TlsCertificates::load(dir.join("relay-cert.pem"), dir.join("relay-key.pem")).unwrap());
+
+    let relay = Relay::bind(RelayConfig::default(), "127.0.0.1:0").await.unwrap().with_tls(certs.clone());
+    let relay_url = relay.local_addr().unwrap().to_string();
+    let state = relay.state();
+    let shutdown = relay.shutdown_handle();
+    let running = tokio::spawn(relay.run(|_| {}));
+
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let alice_session = tls_session(&relay_url, &first_cert, &alice).await.unwrap();
+    let bob_session = tls_session(&relay_url, &first_cert, &bob).await.unwrap();
+    alice_session.send(&bob.id, vec![1, 2, 3], None).await.unwrap();
+    let delivery = tokio::time::timeout(Duration::from_secs(5), bob_session.recv()).await.unwrap().unwrap();
+    assert_eq!(delivery.from, alice.id);
+    assert_eq!(delivery.packet, vec![1, 2, 3]);
+
+    // Без TLS relay сессию не принимает
+    let plain = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    assert!(RelaySession::establish(plain, &Identity::new()).await.is_err());
+
+    // Подпись без привязки к этому TLS-каналу (как пересланная relay-посредником
+    // из его собственного соединения) не принимается
+    let unbound = tls_stream(&relay_url, &first_cert).await.unwrap();
+    assert!(RelaySession::establish(unbound, &Identity::new()).await.is_err());
+    assert_eq!(state.take_stats().auth_failures, 1);
+
+    // Новый сертификат (как по SIGHUP): новые подключения видят его,
+    // открытые сессии продолжают работать
+    let second_cert = write_relay_cert(&dir);
+    certs.reload().unwrap();
+    assert!(matches!(
+        tls_session(&relay_url, &first_cert, &Identity::new()).await,
+        Err(TransportError::SecurityError(_))
+    ));
+    let carol = Identity::new();
+    let _carol_session = tls_session(&relay_url, &second_cert, &carol).await.unwrap();
+    wait_registered(&state, &carol.id).await;
+    alice_session.send(&bob.id, vec![4], None).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_secs(5), bob_session.recv()).await.unwrap().is_some());
+
+    shutdown.shutdown();
+    tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Relay с портом rendezvous; возвращает адрес relay и адрес rendezvous
+async fn start_rendezvous_relay() -> (String, SocketAddr, Arc<RelayState>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let rendezvous = socket.local_addr().unwrap();
+    let state = Arc::new(RelayState::new(RelayConfig::default()).with_rendezvous(Rendezvous::new(rendezvous.port())));
+    tokio::spawn(serve(listener, state.clone()));
+    tokio::spawn(serve_rendezvous(socket, state.clone()));
+    (addr.to_string(), rendezvous, state)
+}
+
+/// Эмуляция NAT: датаграммы принимаются только с адресов, на которые узел
+/// уже отправлял (фильтрация по адресу и порту). `firewalled` - прямой
+/// канал не пробить: проходят только ответы rendezvous. Отправленные
+/// датаграммы сохраняются в `sent`.
+struct NatSocket {
+    socket: UdpSocket,
+    rendezvous: SocketAddr,
+    firewalled: bool,
+    opened: Mutex<HashSet<SocketAddr>>,
+    sent: Mutex<Vec<Vec<u8>>>,
+}
+
+impl NatSocket {
+    async fn bind(rendezvous: SocketAddr, firewalled: bool) -> Arc<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        Arc::new(Self { socket, rendezvous, firewalled, opened: Mutex::new(HashSet::new()), sent: Mutex::new(Vec::new()) })
+    }
+}
+
+#[async_trait::async_trait]
+impl PunchSocket for NatSocket {
+    async fn send_to(&self, datagram: &[u8], target: SocketAddr) -> std::io::Result<()> {
+        self.opened.lock().unwrap().insert(target);
+        self.sent.lock().unwrap().push(datagram.to_vec());
+        self.socket.send_to(datagram, target).await.map(|_| ())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        loop {
+            let (len, from) = self.socket.recv_from(buf).await?;
+            let passes = if self.firewalled { from == self.rendezvous } else { self.opened.lock().unwrap().contains(&from) };
+            if passes {
+                return Ok((len, from));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+fn punch_config() -> DirectConfig {
+    DirectConfig { punch_timeout: Duration::from_secs(2), probe_interval: Duration::from_millis(50), ..DirectConfig::default() }
+}
+
+#[tokio::test]
+async fn test_direct_link_punched_through_nat() {
+    let (relay_url, rendezvous, state) = start_rendezvous_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let alice_direct = DirectTransport::with_socket(
+        local_transport(&relay_url, &alice),
+        punch_config(),
+        NatSocket::bind(rendezvous, false).await,
+    );
+    let bob_direct =
+        DirectTransport::with_socket(local_transport(&relay_url, &bob), punch_config(), NatSocket::bind(rendezvous, false).await);
+    bob_direct.allow_peer(&alice.id);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_direct
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    // Первый пакет идет через relay, канал пробивается в фоне
+    let first = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&first, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_direct.send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, first.id);
+
+    for _ in 0..100 {
+        if alice_direct.is_direct(&bob.id) && bob_direct.is_direct(&alice.id) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(alice_direct.is_direct(&bob.id));
+    assert!(bob_direct.is_direct(&alice.id));
+    let relay_stats = state.take_stats();
+    assert_eq!(relay_stats.forwarded, 1);
+    assert_eq!(relay_stats.punch_introductions, 1);
+    assert_eq!(relay_stats.rendezvous_binds, 2);
+
+    // Второй пакет - напрямую, мимо relay
+    let second = Message::status(alice.id.clone(), StatusType::Busy);
+    let packet = Packet::from_message(&second, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_direct.send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, second.id);
+    assert_eq!(state.take_stats().forwarded, 0);
+
+    let stats = alice_direct.stats();
+    assert_eq!((stats.links, stats.punches, stats.direct_sent, stats.relayed), (1, 1, 1, 1));
+    assert_eq!(bob_direct.stats().direct_received, 1);
+
+    bob_direct.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_direct_link_ignores_replayed_and_reflected_datagrams() {
+    let (relay_url, rendezvous, state) = start_rendezvous_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let alice_socket = NatSocket::bind(rendezvous, false).await;
+    let bob_socket = NatSocket::bind(rendezvous, false).await;
+    let alice_direct = DirectTransport::with_socket(local_transport(&relay_url, &alice), punch_config(), alice_socket.clone());
+    let bob_direct = DirectTransport::with_socket(local_transport(&relay_url, &bob), punch_config(), bob_socket.clone());
+    bob_direct.allow_peer(&alice.id);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_direct
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+    alice_direct.connect_direct(&bob.id).await.unwrap();
+    for _ in 0..100 {
+        if bob_direct.is_direct(&alice.id) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(bob_direct.is_direct(&alice.id));
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_direct.send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+
+    // Злоумышленник на пути повторяет записанную датаграмму с данными со
+    // своего адреса и отражает ее обратно alice
+    let data = alice_socket.sent.lock().unwrap().iter().rev().find(|d| d.first() == Some(&0x24)).unwrap().clone();
+    let mallory = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mallory_addr = mallory.local_addr().unwrap();
+    bob_socket.opened.lock().unwrap().insert(mallory_addr);
+    alice_socket.opened.lock().unwrap().insert(mallory_addr);
+    mallory.send_to(&data, bob_socket.local_addr().unwrap()).await.unwrap();
+    mallory.send_to(&data, alice_socket.local_addr().unwrap()).await.unwrap();
+
+    // Ни подтверждений, ни повторной доставки
+    let mut buf = [0u8; 2048];
+    assert!(tokio::time::timeout(Duration::from_millis(300), mallory.recv_from(&mut buf)).await.is_err());
+    assert!(rx.try_recv().is_err());
+    assert_eq!(bob_direct.stats().direct_received, 1);
+
+    // Адрес канала не сдвинулся: следующий пакет снова идет напрямую
+    let message = Message::status(alice.id.clone(), StatusType::Busy);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_direct.send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+    let stats = alice_direct.stats();
+    assert_eq!((stats.direct_sent, stats.fallbacks), (2, 0));
+    assert_eq!(bob_direct.stats().direct_received, 2);
+
+    bob_direct.stop_listening().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_direct_falls_back_to_relay_when_punching_fails() {
+    let (relay_url, rendezvous, state) = start_rendezvous_relay().await;
+    let alice = Identity::new();
+    let bob = Identity::new();
+    let mallory = Identity::new();
+    let alice_direct = DirectTransport::with_socket(
+        local_transport(&relay_url, &alice),
+        punch_config(),
+        NatSocket::bind(rendezvous, true).await,
+    );
+    let bob_direct =
+        DirectTransport::with_socket(local_transport(&relay_url, &bob), punch_config(), NatSocket::bind(rendezvous, true).await);
+    bob_direct.allow_peer(&alice.id);
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    bob_direct
+        .start_listening(Box::new(move |packet| {
+            let _ = tx.send(packet);
+        }))
+        .await
+        .unwrap();
+    wait_registered(&state, &bob.id).await;
+
+    // bob не разрешал mallory: знакомства нет, хотя она просила
+    let mallory_direct = DirectTransport::with_socket(
+        local_transport(&relay_url, &mallory),
+        punch_config(),
+        NatSocket::bind(rendezvous, false).await,
+    );
+    assert!(mallory_direct.connect_direct(&bob.id).await.is_err());
+    assert_eq!(state.take_stats().punch_introductions, 0);
+
+    // Узлы знакомятся, но NAT не пропускает пробы
+    assert!(alice_direct.connect_direct(&bob.id).await.is_err());
+    assert!(!alice_direct.is_direct(&bob.id));
+    assert_eq!(state.take_stats().punch_introductions, 1);
+
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_direct.send_packet(&packet, &bob.id).await.unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(received.decrypt(&bob).unwrap().id, message.id);
+    assert_eq!(state.take_stats().forwarded, 1);
+
+    let stats = alice_direct.stats();
+    assert_eq!((stats.links, stats.punch_failures, stats.direct_sent, stats.relayed), (0, 1, 0, 1));
+
+    bob_direct.stop_listening().await.unwrap();
+}
+
+async fn wait_woken(mock: &MockPushProvider, count: usize) {
+    for _ in 0..100 {
+        if mock.woken().len() >= count {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("push wakeup was not sent");
+}
+
+#[tokio::test]
+async fn test_stored_packet_wakes_recipient_by_push() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let relay_url = listener.local_addr().unwrap().to_string();
+    let mock = Arc::new(MockPushProvider::default());
+    let push = PushGateway::new(PushConfig::default()).with_provider(mock.clone());
+    let mailbox = Mailbox::open(MailboxConfig::default()).unwrap();
+    let state = Arc::new(RelayState::new(RelayConfig::default()).with_mailbox(mailbox).with_push(push));
+    tokio::spawn(serve(listener, state.clone()));
+    start_push(&state);
+
+    let alice = Identity::new();
+    let bob = Identity::new();
+
+    // Bob оставляет токен и засыпает; чужого провайдера relay не принимает
+    let stream = tokio::net::TcpStream::connect(&relay_url).await.unwrap();
+    let session = RelaySession::establish(stream, &bob).await.unwrap();
+    assert!(!session.register_push("apns", b"bob-device").await.unwrap());
+    assert!(session.register_push("mock", b"bob-device").await.unwrap());
+    session.close().await;
+    wait_reachable(&state, &bob.id, false).await;
+
+    let alice_transport = local_transport(&relay_url, &alice);
+    let message = Message::status(alice.id.clone(), StatusType::Ok);
+    let packet = Packet::from_message(&message, &alice, &bob.x25519_public_bytes().unwrap()).unwrap();
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+    wait_woken(&mock, 1).await;
+    assert_eq!(mock.woken(), vec![b"bob-device".to_vec()]);
+
+    // Второй пакет сразу следом не будит повторно
+    alice_transport.send_packet(&packet, &bob.id).await.unwrap();
+    assert_eq!(state.mailbox_pending(&bob.id), 2);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(mock.woken().len(), 1);
+
+    let stats = state.take_stats();
+    assert_eq!(stats.push_registrations, 2);
+    assert_eq!((stats.push_queued, stats.push_rate_limited, stats.push_sent), (1, 1, 1));
+    assert_eq!(stats.push_tokens, 1);
+}